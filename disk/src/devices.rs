@@ -1,12 +1,29 @@
 use fancy_regex::Regex;
 use libparted::{Device, Disk};
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
 use tracing::{debug, info};
 
-use crate::PartitionError;
+use crate::{
+    partition::{find_root_mount_point, parent_disk},
+    PartitionError,
+};
 
 pub fn list_devices() -> impl Iterator<Item = Device<'static>> {
-    Device::devices(true).filter(|dev| {
+    list_devices_with_md(false)
+}
+
+/// Like [`list_devices`], `include_md` also lists mdadm RAID arrays such as /dev/md0
+pub fn list_devices_with_md(include_md: bool) -> impl Iterator<Item = Device<'static>> {
+    Device::devices(true).filter(move |dev| {
+        if include_md && is_md_device(dev.path()) {
+            info!("{} is md", dev.path().display());
+            return true;
+        }
+
         let is_sata = device_is_sata(dev.path());
         info!("{} is sata: {is_sata}", dev.path().display());
 
@@ -40,6 +57,255 @@ pub fn is_root_device(root: &str, d: &mut Device) -> Result<bool, PartitionError
     Ok(false)
 }
 
+/// Get the live medium device path, its parent disk and all partitions on that disk
+pub fn live_device_paths() -> Result<Vec<PathBuf>, PartitionError> {
+    let root = find_root_mount_point()?;
+    let mut res = vec![PathBuf::from(&root)];
+
+    for mut dev in list_devices() {
+        let dev_path = dev.path().to_path_buf();
+        let disk = match Disk::new(&mut dev) {
+            Ok(disk) => disk,
+            Err(_) => continue,
+        };
+
+        let parts = disk
+            .parts()
+            .filter(|p| p.num() > 0)
+            .filter_map(|p| p.get_path().map(|x| x.to_path_buf()))
+            .collect::<Vec<_>>();
+
+        if parts.iter().any(|p| p.to_string_lossy() == root) {
+            res.push(dev_path);
+            res.extend(parts.into_iter().filter(|p| p.to_string_lossy() != root));
+        }
+    }
+
+    for dev in live_dm_parents() {
+        if !res.contains(&dev) {
+            res.push(dev);
+        }
+    }
+
+    debug!("Live devices: {res:?}");
+
+    Ok(res)
+}
+
+/// Whether `path`, a disk or a partition, is the live medium or on it
+pub fn is_on_live_medium(path: &Path) -> Result<bool, PartitionError> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let parent = parent_disk(&path);
+
+    Ok(live_device_paths()?.into_iter().any(|x| {
+        let x = fs::canonicalize(&x).unwrap_or(x);
+        x == path || parent.as_ref() == Some(&x)
+    }))
+}
+
+/// Device-mapper names of the live system, see also `remove_all_lvm_devive`
+const LIVE_DM_NAMES: &[&str] = &["live-rw", "live-base"];
+
+/// Physical disks under the live-rw/live-base device-mapper devices, i.e. the boot medium
+pub fn live_dm_parents() -> Vec<PathBuf> {
+    live_dm_parents_in(Path::new("/sys/block"))
+}
+
+fn live_dm_parents_in(sys_block: &Path) -> Vec<PathBuf> {
+    let mut res = vec![];
+
+    let entries = match fs::read_dir(sys_block) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Failed to read {}: {e}", sys_block.display());
+            return res;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = fs::read_to_string(path.join("dm/name")).unwrap_or_default();
+
+        if LIVE_DM_NAMES.contains(&name.trim()) {
+            collect_slave_disks(&path, &mut res);
+        }
+    }
+
+    debug!("Live device-mapper parents: {res:?}");
+
+    res
+}
+
+/// Physical disks under a device-mapper or md device, e.g. the member disks of /dev/md0
+/// Empty if `dev` is not stacked on other devices
+pub fn slave_disks(dev: &Path) -> Vec<PathBuf> {
+    // /dev/md/<name> 是指向 /dev/mdN 的符号链接
+    let dev = fs::canonicalize(dev).unwrap_or_else(|_| dev.to_path_buf());
+    let name = dev.file_name().unwrap_or(dev.as_os_str());
+    let mut res = vec![];
+    collect_slave_disks(&Path::new("/sys/block").join(name), &mut res);
+
+    res
+}
+
+fn collect_slave_disks(sys_dev: &Path, res: &mut Vec<PathBuf>) {
+    let mut leaves = vec![];
+    collect_slave_leaves(sys_dev, &mut leaves);
+
+    for leaf in leaves {
+        if let Some(name) = sysfs_disk_of(&leaf).and_then(|x| x.file_name()) {
+            let dev = Path::new("/dev").join(name);
+            if !res.contains(&dev) {
+                res.push(dev);
+            }
+        }
+    }
+}
+
+/// 沿 slaves 向下查找 device-mapper、md 设备所在的分区或磁盘，结果为 sysfs 中的路径
+fn collect_slave_leaves(sys_dev: &Path, res: &mut Vec<PathBuf>) {
+    let slaves = match fs::read_dir(sys_dev.join("slaves")) {
+        Ok(slaves) => slaves,
+        Err(_) => return,
+    };
+
+    for slave in slaves.flatten() {
+        let slave = match fs::canonicalize(slave.path()) {
+            Ok(slave) => slave,
+            Err(_) => continue,
+        };
+
+        // 下层仍是 device-mapper 或 md 设备，继续查找
+        if slave.join("dm").is_dir() || slave.join("md").is_dir() {
+            collect_slave_leaves(&slave, res);
+            continue;
+        }
+
+        res.push(slave);
+    }
+}
+
+/// 分区的上级目录即为所在的磁盘
+fn sysfs_disk_of(sys_dev: &Path) -> Option<&Path> {
+    if sys_dev.join("partition").is_file() {
+        sys_dev.parent()
+    } else {
+        Some(sys_dev)
+    }
+}
+
+/// Names of the LVM logical volumes using `dev`, a disk or a partition, e.g. `vg0-root`
+pub fn lvm_devices_on(dev: &Path) -> io::Result<Vec<String>> {
+    lvm_devices_on_in(Path::new("/sys/block"), dev)
+}
+
+fn lvm_devices_on_in(sys_block: &Path, dev: &Path) -> io::Result<Vec<String>> {
+    let dev = fs::canonicalize(dev).unwrap_or_else(|_| dev.to_path_buf());
+    let name = match dev.file_name() {
+        Some(name) => name,
+        None => return Ok(vec![]),
+    };
+
+    let mut res = vec![];
+
+    for entry in fs::read_dir(sys_block)? {
+        let path = entry?.path();
+
+        // LVM 创建的 device-mapper 设备 UUID 以 LVM- 开头
+        let uuid = fs::read_to_string(path.join("dm/uuid")).unwrap_or_default();
+        if !uuid.starts_with("LVM-") {
+            continue;
+        }
+
+        let mut leaves = vec![];
+        collect_slave_leaves(&path, &mut leaves);
+
+        let is_on_dev = leaves.iter().any(|leaf| {
+            leaf.file_name() == Some(name)
+                || sysfs_disk_of(leaf).and_then(|x| x.file_name()) == Some(name)
+        });
+
+        if is_on_dev {
+            res.push(fs::read_to_string(path.join("dm/name"))?.trim().to_string());
+        }
+    }
+
+    res.sort();
+
+    Ok(res)
+}
+
+/// How a disk is connected to the machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Usb,
+    Sata,
+    Nvme,
+    Mmc,
+    Virtio,
+    Unknown,
+}
+
+/// Hardware traits of a disk read from sysfs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceTraits {
+    pub removable: bool,
+    pub rotational: bool,
+    pub transport: Transport,
+}
+
+/// Read the traits of a disk, e.g. /dev/sda, from /sys/block
+pub fn device_traits(path: &Path) -> DeviceTraits {
+    let name = path.file_name().unwrap_or(path.as_os_str());
+
+    device_traits_in(&Path::new("/sys/block").join(name))
+}
+
+fn device_traits_in(sys_dev: &Path) -> DeviceTraits {
+    let read_flag =
+        |file: &str| fs::read_to_string(sys_dev.join(file)).is_ok_and(|x| x.trim() == "1");
+
+    // /sys/block/<dev> 是指向 /sys/devices 下完整设备路径的符号链接，从中可以看出总线类型
+    let transport = fs::canonicalize(sys_dev)
+        .map(|p| transport_of(&p))
+        .unwrap_or(Transport::Unknown);
+
+    DeviceTraits {
+        removable: read_flag("removable"),
+        rotational: read_flag("queue/rotational"),
+        transport,
+    }
+}
+
+fn transport_of(device_path: &Path) -> Transport {
+    let names = device_path
+        .components()
+        .skip_while(|c| c.as_os_str() != "devices")
+        .filter_map(|c| match c {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let has = |f: fn(&str) -> bool| names.iter().any(|x| f(x));
+
+    // USB 存储设备同样挂在 SCSI host 下，需要先判断
+    if has(|x| x.starts_with("usb")) {
+        Transport::Usb
+    } else if has(|x| x == "nvme") {
+        Transport::Nvme
+    } else if has(|x| x.starts_with("mmc")) {
+        Transport::Mmc
+    } else if has(|x| x.starts_with("ata")) {
+        Transport::Sata
+    } else if has(|x| x.starts_with("virtio")) {
+        Transport::Virtio
+    } else {
+        Transport::Unknown
+    }
+}
+
 pub fn sync_disk() {
     rustix::fs::sync();
 }
@@ -52,6 +318,11 @@ fn device_is_sdcard(path: &Path) -> bool {
     device_is_match(path, r"^(mmcblk[0-9]+)$")
 }
 
+/// Whether `path` is an mdadm RAID array, e.g. /dev/md0
+pub fn is_md_device(path: &Path) -> bool {
+    device_is_match(path, r"^(md[0-9]+)$")
+}
+
 fn device_is_nvme(path: &Path) -> bool {
     device_is_match(path, r"^(nvme[0-9]+n[0-9]+)$")
 }
@@ -68,3 +339,85 @@ fn device_is_match(path: &Path, pattern: &str) -> bool {
         })
         .unwrap_or(false)
 }
+
+#[test]
+fn test_device_traits() {
+    let sys_block = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/sys/block");
+
+    assert_eq!(
+        device_traits_in(&sys_block.join("sda")),
+        DeviceTraits {
+            removable: true,
+            rotational: false,
+            transport: Transport::Usb,
+        }
+    );
+    assert_eq!(
+        device_traits_in(&sys_block.join("sdb")),
+        DeviceTraits {
+            removable: false,
+            rotational: true,
+            transport: Transport::Sata,
+        }
+    );
+    assert_eq!(
+        device_traits_in(&sys_block.join("nvme0n1")),
+        DeviceTraits {
+            removable: false,
+            rotational: false,
+            transport: Transport::Nvme,
+        }
+    );
+    assert_eq!(
+        device_traits_in(&sys_block.join("mmcblk0")),
+        DeviceTraits {
+            removable: false,
+            rotational: false,
+            transport: Transport::Mmc,
+        }
+    );
+    assert_eq!(
+        device_traits_in(&sys_block.join("sdz")),
+        DeviceTraits {
+            removable: false,
+            rotational: false,
+            transport: Transport::Unknown,
+        }
+    );
+}
+
+#[test]
+fn test_transport_of() {
+    assert_eq!(
+        transport_of(Path::new(
+            "/sys/devices/pci0000:00/0000:00:04.0/virtio1/host0/target0:0:0/0:0:0:0/block/sda"
+        )),
+        Transport::Virtio
+    );
+}
+
+#[test]
+fn test_live_dm_parents() {
+    let sys_block = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/sys/block");
+
+    // live-rw 叠加在 live-base 之上，live-base 位于 U 盘 sdc 的分区上，dm-2 是无关的 LUKS 设备
+    assert_eq!(
+        live_dm_parents_in(&sys_block),
+        vec![PathBuf::from("/dev/sdc")]
+    );
+    assert!(live_dm_parents_in(Path::new("/nonexistent")).is_empty());
+}
+
+#[test]
+fn test_lvm_devices_on() {
+    let sys_block = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/sys/block");
+    let lvm_devices_on = |dev: &str| lvm_devices_on_in(&sys_block, Path::new(dev)).unwrap();
+
+    // vg0-root 位于 sdb3，vg1-data 直接使用整个 nvme0n1，dm-2 是 sdb2 上的 LUKS 设备
+    assert_eq!(lvm_devices_on("/dev/sdb"), vec!["vg0-root"]);
+    assert_eq!(lvm_devices_on("/dev/sdb3"), vec!["vg0-root"]);
+    assert!(lvm_devices_on("/dev/sdb2").is_empty());
+    assert_eq!(lvm_devices_on("/dev/nvme0n1"), vec!["vg1-data"]);
+    assert!(lvm_devices_on("/dev/sda").is_empty());
+    assert!(lvm_devices_on_in(Path::new("/nonexistent"), Path::new("/dev/sda")).is_err());
+}
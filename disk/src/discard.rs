@@ -0,0 +1,79 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Seek, SeekFrom},
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+use tracing::info;
+
+/// _IO(0x12, 119), see linux/fs.h
+const BLKDISCARD: u64 = 0x1277;
+
+/// Whether the device supports discard (TRIM), see `queue/discard_max_bytes` in sysfs
+pub fn supports_discard(path: &Path) -> bool {
+    let name = match fs::canonicalize(path)
+        .ok()
+        .and_then(|p| p.file_name().map(|x| x.to_owned()))
+    {
+        Some(name) => name,
+        None => return false,
+    };
+
+    fs::read_to_string(
+        Path::new("/sys/class/block")
+            .join(name)
+            .join("queue/discard_max_bytes"),
+    )
+    .is_ok_and(|s| parse_discard_max_bytes(&s))
+}
+
+/// 不支持 discard 的设备 discard_max_bytes 为 0
+fn parse_discard_max_bytes(s: &str) -> bool {
+    s.trim().parse::<u64>().is_ok_and(|x| x > 0)
+}
+
+/// Discard all blocks of a whole device, everything on it is lost
+/// Returns `false` without touching the device if it does not support discard
+pub fn discard_device(path: &Path) -> io::Result<bool> {
+    if !supports_discard(path) {
+        info!("{} does not support discard, skipping", path.display());
+        return Ok(false);
+    }
+
+    let mut f = OpenOptions::new().write(true).open(path)?;
+    let size = f.seek(SeekFrom::End(0))?;
+    let range: [u64; 2] = [0, size];
+
+    info!("Discarding {} ({size} bytes)", path.display());
+
+    // SAFETY: BLKDISCARD 只读取 range 中的两个 u64
+    let ret = unsafe { libc::ioctl(f.as_raw_fd(), BLKDISCARD as _, range.as_ptr()) };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(true)
+}
+
+#[test]
+fn test_parse_discard_max_bytes() {
+    assert!(parse_discard_max_bytes("2147450880\n"));
+    assert!(!parse_discard_max_bytes("0\n"));
+    assert!(!parse_discard_max_bytes(""));
+}
+
+#[test]
+fn test_discard_regular_file() {
+    let path = std::env::temp_dir().join(format!("dk-discard-test-{}", std::process::id()));
+    fs::write(&path, b"deploykit").unwrap();
+
+    // 普通文件在 sysfs 中没有对应的块设备，应跳过且不改动文件
+    let res = discard_device(&path).unwrap();
+    let content = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(!res);
+    assert_eq!(content, b"deploykit");
+}
@@ -0,0 +1,82 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+// BitLocker 卷引导扇区中的 OEM ID
+const BITLOCKER_SIGNATURE: &[u8] = b"-FVE-FS-";
+const BITLOCKER_SIGNATURE_OFFSET: usize = 3;
+
+/// Encryption of the partition, `luks` or `bitlocker`
+/// Returns `None` if the partition is not encrypted or can't be read
+pub fn detect_encryption(partition: &Path) -> Option<String> {
+    let f = File::open(partition).ok()?;
+
+    detect_encryption_from(f)
+        .ok()
+        .flatten()
+        .map(|x| x.to_string())
+}
+
+fn detect_encryption_from(r: impl Read) -> io::Result<Option<&'static str>> {
+    let mut head = vec![];
+    r.take(512).read_to_end(&mut head)?;
+
+    if head.starts_with(LUKS_MAGIC) {
+        return Ok(Some("luks"));
+    }
+
+    if head
+        .get(BITLOCKER_SIGNATURE_OFFSET..)
+        .is_some_and(|x| x.starts_with(BITLOCKER_SIGNATURE))
+    {
+        return Ok(Some("bitlocker"));
+    }
+
+    Ok(None)
+}
+
+#[test]
+fn test_detect_encryption() {
+    use std::{
+        fs,
+        io::{Seek, SeekFrom, Write},
+    };
+
+    let dir = std::env::temp_dir().join(format!("dk-encryption-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let sparse = |name: &str, offset: u64, content: &[u8]| {
+        let path = dir.join(name);
+        let mut f = File::create(&path).unwrap();
+        f.set_len(16 * 1024 * 1024).unwrap();
+        f.seek(SeekFrom::Start(offset)).unwrap();
+        f.write_all(content).unwrap();
+
+        path
+    };
+
+    let luks = sparse("luks", 0, b"LUKS\xba\xbe\x00\x02");
+    let bitlocker = sparse("bitlocker", 0, b"\xeb\x58\x90-FVE-FS-");
+    let ntfs = sparse("ntfs", 0, b"\xeb\x52\x90NTFS    ");
+    // 签名不在开头时不算
+    let misplaced = sparse("misplaced", 4096, b"LUKS\xba\xbe");
+
+    let res =
+        [&luks, &bitlocker, &ntfs, &misplaced, &dir.join("missing")].map(|p| detect_encryption(p));
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(
+        res,
+        [
+            Some("luks".to_string()),
+            Some("bitlocker".to_string()),
+            None,
+            None,
+            None
+        ]
+    );
+    assert_eq!(detect_encryption_from(&b"LUKS"[..]).unwrap(), None);
+}
@@ -1,4 +1,4 @@
-use crate::partition::get_partition_table_type;
+use crate::partition::{get_partition_table_type, has_bios_boot_partition};
 use std::{
     fmt::Display,
     io,
@@ -11,9 +11,19 @@ use snafu::Snafu;
 use thiserror::Error;
 
 pub mod devices;
+pub mod discard;
+pub mod encryption;
+pub mod flush;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod loop_device;
+pub mod luks;
+pub mod mounts;
+pub mod os_detect;
 pub mod partition;
+pub mod subvol;
 
 pub use disk_types;
+pub use partition_identity;
 
 #[derive(Debug, Error)]
 pub enum PartitionError {
@@ -59,8 +69,64 @@ pub enum PartitionError {
     ReadMounts(std::io::Error),
     #[error("Failed to open dmsetup")]
     DmSetup { source: std::io::Error },
-    #[error("Failed to open lvs")]
-    OpenLvs(std::io::Error),
+    #[error("Failed to read device-mapper devices: {0:?}")]
+    ReadDeviceMapper(std::io::Error),
+    #[error("Invalid data partition layout: {0}")]
+    InvalidDataLayout(String),
+    #[error("Invalid auto partition options: {0}")]
+    InvalidAutoPartitionOptions(String),
+    #[error("Failed to read /proc/swaps: {0:?}")]
+    ReadSwaps(std::io::Error),
+    #[error("{path} is an active swap and could not be turned off")]
+    ActiveSwap { path: String },
+    #[error("Failed to snapshot partition table of {path}: {err}")]
+    TableSnapshot { path: String, err: std::io::Error },
+    #[error("Failed to restore partition table of {path}: {err}")]
+    RestoreTable { path: String, err: std::io::Error },
+    #[error("Failed to back up partition table to {path}: {err}")]
+    TableBackup { path: String, err: std::io::Error },
+    #[error("{path} is in use: {}", .mounted_points.join(", "))]
+    DeviceInUse {
+        path: String,
+        mounted_points: Vec<String>,
+    },
+    #[error("{path} has encrypted partitions: {}", .partitions.join(", "))]
+    EncryptedPartitions {
+        path: String,
+        partitions: Vec<String>,
+    },
+    #[error("Device node {path} did not appear after {secs} seconds")]
+    DeviceNodeTimeout { path: String, secs: u64 },
+    #[error("Failed to create btrfs subvolumes on {path}: {err}")]
+    BtrfsSubvolume { path: String, err: std::io::Error },
+    #[error("Failed to umount {path}: {err}")]
+    Umount { path: String, err: std::io::Error },
+    #[error("Invalid partition options: {0}")]
+    InvalidPartitionOptions(String),
+    #[error("Not enough free space on {path} for {size} bytes at {start}")]
+    NoFreeSpace { path: String, start: u64, size: u64 },
+    #[error("New partition overlaps partition {num} of {path}")]
+    PartitionOverlap { path: String, num: u32 },
+    #[error("No free partition entry on {path}")]
+    NoFreeSlot { path: String },
+    #[error("Partition {num} of {path} does not exist")]
+    PartitionNotFound { path: String, num: u32 },
+    #[error("{path} is in use: {used_by}")]
+    PartitionInUse { path: String, used_by: String },
+    #[error("Failed to shrink {path}: {reason}")]
+    ShrinkPartition { path: String, reason: String },
+    #[error("Failed to resize filesystem on {path}: {err}")]
+    ResizeFilesystem { path: String, err: std::io::Error },
+    #[error("Largest free space of {path} is {size} bytes, at least {min_size} bytes is required")]
+    FreeSpaceTooSmall {
+        path: String,
+        size: u64,
+        min_size: u64,
+    },
+    #[error("Failed to run cryptsetup on {path}: {err}")]
+    Cryptsetup { path: String, err: std::io::Error },
+    #[error("Failed to wipe signatures on {path}: {err}")]
+    WipeSignatures { path: String, err: std::io::Error },
 }
 
 impl Serialize for PartitionError {
@@ -133,6 +199,16 @@ impl PartitionError {
     }
 }
 
+/// Whether the development shortcuts (fake partitions, /dev/loop30, no fstab ...) are enabled
+/// Set `DEPLOYKIT_DEV_MODE=1` to enable
+pub fn is_dev_mode() -> bool {
+    dev_mode_from_env(std::env::var("DEPLOYKIT_DEV_MODE").ok().as_deref())
+}
+
+fn dev_mode_from_env(value: Option<&str>) -> bool {
+    matches!(value, Some("1" | "true" | "yes"))
+}
+
 #[cfg(not(target_arch = "mips64"))]
 pub fn is_efi_booted() -> bool {
     Path::new("/sys/firmware/efi").exists()
@@ -176,6 +252,11 @@ pub fn right_combine(device_path: &Path) -> Result<(), CombineError> {
         return Ok(());
     }
 
+    // GRUB 可以在 BIOS 下从 GPT 磁盘启动，但需要 BIOS boot 分区
+    if partition_table_t == "gpt" && has_bios_boot_partition(device_path) {
+        return Ok(());
+    }
+
     let table = Table::try_from(partition_table_t.as_str())?;
 
     match table {
@@ -197,3 +278,12 @@ pub fn right_combine(device_path: &Path) -> Result<(), CombineError> {
 pub fn right_combine(device_path: &Path) -> Result<(), CombineError> {
     Ok(())
 }
+
+#[test]
+fn test_dev_mode_from_env() {
+    assert!(dev_mode_from_env(Some("1")));
+    assert!(dev_mode_from_env(Some("true")));
+    assert!(!dev_mode_from_env(Some("0")));
+    assert!(!dev_mode_from_env(Some("")));
+    assert!(!dev_mode_from_env(None));
+}
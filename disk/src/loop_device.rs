@@ -0,0 +1,74 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Loop device on a sparse image file for tests that need root
+/// Detached and the image removed on drop, also when the test fails
+pub struct LoopDevice {
+    path: PathBuf,
+    img: PathBuf,
+}
+
+impl LoopDevice {
+    /// Attach a new image of `size` bytes, `name` tells apart the images of different tests
+    pub fn new(name: &str, size: u64) -> Self {
+        Self::attach(name, size, false)
+    }
+
+    /// Same as [`LoopDevice::new`], the kernel also scans the partitions (`losetup -P`)
+    pub fn partitioned(name: &str, size: u64) -> Self {
+        Self::attach(name, size, true)
+    }
+
+    fn attach(name: &str, size: u64, partscan: bool) -> Self {
+        let img = std::env::temp_dir().join(format!("dk-{name}-{}.img", std::process::id()));
+        fs::File::create(&img).unwrap().set_len(size).unwrap();
+
+        // 先构造，losetup 失败时也能删除镜像
+        let mut dev = Self {
+            path: PathBuf::new(),
+            img,
+        };
+
+        let mut cmd = Command::new("losetup");
+        cmd.args(["--show", "-f"]);
+        if partscan {
+            cmd.arg("-P");
+        }
+
+        let output = cmd.arg(&dev.img).output().unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        dev.path = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+
+        dev
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path of partition `num`, e.g. `/dev/loop0p1`
+    pub fn partition(&self, num: u32) -> PathBuf {
+        PathBuf::from(format!("{}p{num}", self.path.display()))
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            Command::new("losetup")
+                .arg("-d")
+                .arg(&self.path)
+                .status()
+                .ok();
+        }
+
+        fs::remove_file(&self.img).ok();
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use tracing::info;
+
+use crate::{partition::DkPartition, PartitionError};
+
+/// Format the partition as a LUKS2 container protected by `passphrase`
+pub fn luks_format(partition: &DkPartition, passphrase: &str) -> Result<(), PartitionError> {
+    let path = partition_path(partition)?;
+
+    info!("Formatting {} as LUKS2", path.display());
+
+    // 口令经由标准输入传递，避免出现在进程参数中
+    // GRUB 无法解锁使用 argon2 的密钥槽，/boot 位于加密分区中时需使用 pbkdf2
+    run_cryptsetup(
+        Command::new("cryptsetup")
+            .args([
+                "luksFormat",
+                "--batch-mode",
+                "--type",
+                "luks2",
+                "--pbkdf",
+                "pbkdf2",
+                "--key-file=-",
+            ])
+            .arg(path),
+        path,
+        Some(passphrase),
+    )
+}
+
+/// Open the LUKS container as `/dev/mapper/{name}`
+/// Returns the path of the mapped device
+pub fn luks_open(
+    partition: &DkPartition,
+    name: &str,
+    passphrase: &str,
+) -> Result<PathBuf, PartitionError> {
+    let path = partition_path(partition)?;
+
+    info!("Opening LUKS container {} as {name}", path.display());
+
+    run_cryptsetup(
+        Command::new("cryptsetup")
+            .args(["open", "--type", "luks", "--key-file=-"])
+            .arg(path)
+            .arg(name),
+        path,
+        Some(passphrase),
+    )?;
+
+    Ok(mapper_path(name))
+}
+
+/// Close the mapped device `/dev/mapper/{name}`
+pub fn luks_close(name: &str) -> Result<(), PartitionError> {
+    let path = mapper_path(name);
+
+    info!("Closing LUKS container {name}");
+
+    run_cryptsetup(
+        Command::new("cryptsetup").arg("close").arg(name),
+        &path,
+        None,
+    )
+}
+
+/// Whether `/dev/mapper/{name}` exists
+pub fn is_luks_open(name: &str) -> bool {
+    mapper_path(name).exists()
+}
+
+/// UUID of the LUKS header, used by /etc/crypttab
+pub fn luks_uuid(partition: &DkPartition) -> Result<String, PartitionError> {
+    let path = partition_path(partition)?;
+
+    let output = Command::new("cryptsetup")
+        .arg("luksUUID")
+        .arg(path)
+        .output()
+        .map_err(|e| PartitionError::Cryptsetup {
+            path: path.display().to_string(),
+            err: e,
+        })?;
+
+    let uuid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    if !output.status.success() || uuid.is_empty() {
+        return Err(PartitionError::Cryptsetup {
+            path: path.display().to_string(),
+            err: io::Error::new(
+                io::ErrorKind::Other,
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ),
+        });
+    }
+
+    Ok(uuid)
+}
+
+pub fn mapper_path(name: &str) -> PathBuf {
+    Path::new("/dev/mapper").join(name)
+}
+
+fn partition_path(partition: &DkPartition) -> Result<&Path, PartitionError> {
+    partition
+        .path
+        .as_deref()
+        .ok_or_else(|| PartitionError::Cryptsetup {
+            path: String::new(),
+            err: io::Error::new(io::ErrorKind::NotFound, "partition.path is empty"),
+        })
+}
+
+fn run_cryptsetup(
+    cmd: &mut Command,
+    path: &Path,
+    stdin: Option<&str>,
+) -> Result<(), PartitionError> {
+    let err = |e: io::Error| PartitionError::Cryptsetup {
+        path: path.display().to_string(),
+        err: e,
+    };
+
+    let mut child = cmd
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(err)?;
+
+    if let (Some(input), Some(mut child_stdin)) = (stdin, child.stdin.take()) {
+        // --key-file=- 会读取全部输入作为口令，故不追加换行
+        child_stdin.write_all(input.as_bytes()).map_err(err)?;
+    }
+
+    let output = child.wait_with_output().map_err(err)?;
+
+    if !output.status.success() {
+        return Err(err(io::Error::new(
+            io::ErrorKind::Other,
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        )));
+    }
+
+    Ok(())
+}
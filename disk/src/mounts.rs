@@ -0,0 +1,148 @@
+use std::{
+    ffi::OsString,
+    fs, io,
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
+};
+
+/// An entry of /proc/mounts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountEntry {
+    pub source: PathBuf,
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+}
+
+/// Decode the octal escapes used by /proc/mounts, /proc/swaps and fstab
+/// e.g. `/run/media/My\040Disk` -> `/run/media/My Disk`
+pub fn decode_octal_escapes(field: &[u8]) -> OsString {
+    let bytes = field;
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        // 内核使用 \ooo 转义空格、制表符、换行与反斜杠
+        if bytes[i] == b'\\' {
+            if let Some(digits) = bytes.get(i + 1..i + 4) {
+                let value = digits
+                    .iter()
+                    .try_fold(0u16, |acc, c| match c {
+                        b'0'..=b'7' => Some(acc * 8 + (c - b'0') as u16),
+                        _ => None,
+                    })
+                    .and_then(|x| u8::try_from(x).ok());
+
+                if let Some(value) = value {
+                    res.push(value);
+                    i += 4;
+                    continue;
+                }
+            }
+        }
+
+        res.push(bytes[i]);
+        i += 1;
+    }
+
+    OsString::from_vec(res)
+}
+
+/// Split a line of /proc/mounts, /proc/swaps or fstab into decoded fields
+pub fn split_fields(line: &[u8]) -> impl Iterator<Item = OsString> + '_ {
+    line.split(|c| c.is_ascii_whitespace())
+        .filter(|field| !field.is_empty())
+        .map(decode_octal_escapes)
+}
+
+/// Parse the content of /proc/mounts
+/// 路径可能不是合法的 UTF-8，故按字节解析
+pub fn parse_mounts(content: &[u8]) -> Vec<MountEntry> {
+    content
+        .split(|c| *c == b'\n')
+        .filter_map(|line| {
+            let mut split = split_fields(line);
+            let source = split.next()?;
+            let mount_point = split.next()?;
+            let fs_type = split.next()?;
+
+            Some(MountEntry {
+                source: PathBuf::from(source),
+                mount_point: PathBuf::from(mount_point),
+                fs_type: fs_type.to_string_lossy().to_string(),
+            })
+        })
+        .collect()
+}
+
+pub fn read_mounts() -> io::Result<Vec<MountEntry>> {
+    let content = fs::read(Path::new("/proc/mounts"))?;
+
+    Ok(parse_mounts(&content))
+}
+
+#[test]
+fn test_decode_octal_escapes() {
+    use std::os::unix::ffi::OsStrExt;
+
+    assert_eq!(
+        decode_octal_escapes(b"/dev/sda1"),
+        OsString::from("/dev/sda1")
+    );
+    assert_eq!(
+        decode_octal_escapes(b"/run/media/My\\040Disk"),
+        OsString::from("/run/media/My Disk")
+    );
+    assert_eq!(
+        decode_octal_escapes(b"/a\\011b\\012c\\134d"),
+        OsString::from("/a\tb\nc\\d")
+    );
+    // 非 UTF-8 字节
+    assert_eq!(
+        decode_octal_escapes(b"/mnt/\\377\\376").as_bytes(),
+        b"/mnt/\xff\xfe"
+    );
+    // 不完整或非法的转义保持原样
+    assert_eq!(
+        decode_octal_escapes(b"/mnt/\\04"),
+        OsString::from("/mnt/\\04")
+    );
+    assert_eq!(
+        decode_octal_escapes(b"/mnt/\\089"),
+        OsString::from("/mnt/\\089")
+    );
+    assert_eq!(
+        decode_octal_escapes(b"/mnt/\\777"),
+        OsString::from("/mnt/\\777")
+    );
+    assert_eq!(decode_octal_escapes(b"\\"), OsString::from("\\"));
+}
+
+#[test]
+fn test_parse_mounts() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let content = b"/dev/sda2 / ext4 rw,relatime 0 0\n\
+                    /dev/sdb1 /run/media/live\\040user/My\\040Disk vfat rw 0 0\n\
+                    /dev/sdc1 /mnt/\xff vfat rw 0 0\n\
+                    broken-line\n";
+
+    let mounts = parse_mounts(content);
+
+    assert_eq!(
+        mounts[..2],
+        vec![
+            MountEntry {
+                source: PathBuf::from("/dev/sda2"),
+                mount_point: PathBuf::from("/"),
+                fs_type: "ext4".to_string(),
+            },
+            MountEntry {
+                source: PathBuf::from("/dev/sdb1"),
+                mount_point: PathBuf::from("/run/media/live user/My Disk"),
+                fs_type: "vfat".to_string(),
+            },
+        ]
+    );
+    assert_eq!(mounts[2].mount_point.as_os_str().as_bytes(), b"/mnt/\xff");
+    assert_eq!(mounts.len(), 3);
+}
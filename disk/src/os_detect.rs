@@ -0,0 +1,207 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use rustix::mount::{self, MountFlags, UnmountFlags};
+use tracing::debug;
+
+use crate::mounts::read_mounts;
+
+// 这些分区上不可能装有操作系统
+const SKIP_FS_TYPES: &[&str] = &["linux-swap(v0)", "linux-swap(v1)", "swap"];
+
+/// Partition path, filesystem type and filesystem UUID
+type ProbeKey = (PathBuf, String, Option<String>);
+
+// 每次列出分区都挂载探测开销较大，重新格式化后 UUID 改变，缓存随之失效
+static OS_CACHE: Mutex<BTreeMap<ProbeKey, Option<String>>> = Mutex::new(BTreeMap::new());
+
+/// Look for an installed operating system on the partition
+/// Returns `None` if the partition can't be mounted or no OS is found
+/// The result is cached by the partition path, `fs_type` and filesystem `uuid`
+pub fn detect_os(partition: &Path, fs_type: &str, uuid: Option<&str>) -> Option<String> {
+    if SKIP_FS_TYPES.contains(&fs_type) {
+        return None;
+    }
+
+    let key = (
+        partition.to_path_buf(),
+        fs_type.to_string(),
+        uuid.map(|x| x.to_string()),
+    );
+
+    if let Some(os) = OS_CACHE.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+        return os.clone();
+    }
+
+    let os = detect_os_uncached(partition, fs_type);
+
+    OS_CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key, os.clone());
+
+    os
+}
+
+fn detect_os_uncached(partition: &Path, fs_type: &str) -> Option<String> {
+    // 已挂载的分区直接检查其挂载点，再次挂载可能因挂载选项不同而失败
+    let source = fs::canonicalize(partition).unwrap_or_else(|_| partition.to_path_buf());
+    if let Some(entry) = read_mounts().ok().and_then(|mounts| {
+        mounts
+            .into_iter()
+            .find(|m| fs::canonicalize(&m.source).is_ok_and(|x| x == source))
+    }) {
+        return probe_os(&entry.mount_point);
+    }
+
+    let target = probe_mount_point(partition);
+    fs::create_dir_all(&target).ok()?;
+
+    let res = match mount_read_only(partition, &target, fs_type) {
+        Some(()) => {
+            let os = probe_os(&target);
+            mount::unmount(&target, UnmountFlags::empty()).ok();
+            os
+        }
+        None => {
+            debug!("Failed to mount {} to detect OS", partition.display());
+            None
+        }
+    };
+
+    fs::remove_dir(&target).ok();
+
+    res
+}
+
+fn probe_mount_point(partition: &Path) -> PathBuf {
+    let name = partition
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    std::env::temp_dir().join(format!("dk-os-probe-{}-{name}", std::process::id()))
+}
+
+fn mount_read_only(partition: &Path, target: &Path, fs_type: &str) -> Option<()> {
+    let flags = MountFlags::RDONLY | MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC;
+
+    mount_fs_types(fs_type)
+        .iter()
+        .find(|(fs_type, data)| mount::mount(partition, target, *fs_type, flags, *data).is_ok())
+        .map(|_| ())
+}
+
+/// Kernel filesystem types and mount options to try for a libparted filesystem name
+fn mount_fs_types(fs_type: &str) -> &'static [(&'static str, &'static str)] {
+    match fs_type {
+        x if x.starts_with("fat") => &[("vfat", "")],
+        // 不重放日志，避免改动分区上的数据
+        "ext3" | "ext4" => &[("ext4", "noload")],
+        "ext2" => &[("ext2", "")],
+        // 较新的内核使用 ntfs3 驱动
+        "ntfs" => &[("ntfs3", ""), ("ntfs", "")],
+        "xfs" => &[("xfs", "norecovery")],
+        "btrfs" => &[("btrfs", "")],
+        "f2fs" => &[("f2fs", "")],
+        "hfs+" => &[("hfsplus", "")],
+        "exfat" => &[("exfat", "")],
+        _ => &[],
+    }
+}
+
+/// Look for an installed operating system in a mounted filesystem
+fn probe_os(root: &Path) -> Option<String> {
+    for os_release in ["etc/os-release", "usr/lib/os-release"] {
+        if let Some(name) = fs::read_to_string(root.join(os_release))
+            .ok()
+            .and_then(|s| parse_os_release_name(&s))
+        {
+            return Some(name);
+        }
+    }
+
+    if root.join("Windows/System32").is_dir() {
+        return Some("Windows".to_string());
+    }
+
+    None
+}
+
+/// PRETTY_NAME in os-release, or NAME if it is not set
+fn parse_os_release_name(content: &str) -> Option<String> {
+    let get = |key: &str| {
+        content.lines().find_map(|line| {
+            let (k, v) = line.trim().split_once('=')?;
+            if k != key {
+                return None;
+            }
+
+            let v = v.trim().trim_matches(|c| c == '"' || c == '\'').trim();
+            if v.is_empty() {
+                None
+            } else {
+                Some(v.to_string())
+            }
+        })
+    };
+
+    get("PRETTY_NAME").or_else(|| get("NAME"))
+}
+
+#[test]
+fn test_parse_os_release_name() {
+    assert_eq!(
+        parse_os_release_name("NAME=\"AOSC OS\"\nPRETTY_NAME=\"AOSC OS (12.0.0)\"\nID=aosc\n"),
+        Some("AOSC OS (12.0.0)".to_string())
+    );
+    assert_eq!(
+        parse_os_release_name("NAME='Fedora Linux'\nPRETTY_NAME=\n"),
+        Some("Fedora Linux".to_string())
+    );
+    assert_eq!(parse_os_release_name("ID=debian\n"), None);
+}
+
+#[test]
+fn test_probe_os() {
+    let root = std::env::temp_dir().join(format!("dk-os-probe-test-{}", std::process::id()));
+    fs::create_dir_all(root.join("Windows/System32")).unwrap();
+    let windows = probe_os(&root);
+
+    fs::create_dir_all(root.join("usr/lib")).unwrap();
+    fs::write(
+        root.join("usr/lib/os-release"),
+        "PRETTY_NAME=\"Arch Linux\"\n",
+    )
+    .unwrap();
+    let linux = probe_os(&root);
+
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(windows.as_deref(), Some("Windows"));
+    assert_eq!(linux.as_deref(), Some("Arch Linux"));
+}
+
+#[test]
+fn test_detect_os_cached() {
+    let path = Path::new("/dev/dk-os-probe-nonexistent");
+    OS_CACHE.lock().unwrap().insert(
+        (
+            path.to_path_buf(),
+            "ext4".to_string(),
+            Some("1234".to_string()),
+        ),
+        Some("AOSC OS".to_string()),
+    );
+
+    assert_eq!(
+        detect_os(path, "ext4", Some("1234")).as_deref(),
+        Some("AOSC OS")
+    );
+    // 重新格式化后 UUID 改变，需要重新探测
+    assert_eq!(detect_os(path, "ext4", Some("5678")), None);
+}
@@ -1,33 +1,141 @@
 use std::{
     ffi::CStr,
     fs,
-    io::{self, BufRead, BufReader, ErrorKind, Seek, SeekFrom, Write},
-    path::{Path, PathBuf},
-    process::Command,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
 };
 
 use gptman::GPT;
 use libparted::{Device, Disk, IsZero};
 use mbrman::MBR;
+use partition_identity::{PartitionID, PartitionSource};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use snafu::Snafu;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 use uuid::{uuid, Uuid};
 
-use crate::{devices::list_devices, is_efi_booted, PartitionError};
+use crate::{
+    devices::{list_devices, lvm_devices_on},
+    discard::discard_device,
+    encryption::detect_encryption,
+    is_dev_mode, is_efi_booted,
+    mounts::{read_mounts, split_fields, MountEntry},
+    os_detect::detect_os,
+    BootMode, PartitionError, Table,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DkPartition {
     pub path: Option<PathBuf>,
     pub parent_path: Option<PathBuf>,
     pub fs_type: Option<String>,
     pub size: u64,
+    /// Format the partition during installation, `false` keeps the existing `fs_type` and data
+    #[serde(default = "default_format")]
+    pub format: bool,
+    #[serde(default)]
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub partuuid: Option<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub part_number: Option<u32>,
+    /// Name of the operating system installed on the partition, e.g. `Windows`
+    #[serde(default)]
+    pub os: Option<String>,
+    /// `luks` or `bitlocker` if the partition is encrypted, see [`detect_encryption`]
+    #[serde(default)]
+    pub encryption: Option<String>,
+}
+
+fn default_format() -> bool {
+    true
+}
+
+impl DkPartition {
+    /// Fill in the partition number, and the UUID, PARTUUID and label from `/dev/disk/by-*`
+    fn with_identity(mut self, part_number: i32) -> Self {
+        self.part_number = u32::try_from(part_number).ok();
+
+        if let Some(path) = &self.path {
+            self.uuid = PartitionID::get_uuid(path).map(|x| x.id);
+            self.partuuid = PartitionID::get_partuuid(path).map(|x| x.id);
+            self.label = PartitionID::get_source(PartitionSource::Label, path).map(|x| x.id);
+        }
+
+        self
+    }
+}
+
+/// Layout of the optional data partition created by auto partitioning
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataLayout {
+    /// Size of the system partition in bytes, the data partition uses the rest of the disk
+    pub root_size: u64,
+    pub fs_type: String,
+    pub mount_point: PathBuf,
+}
+
+const SUPPORTED_FS_TYPES: &[&str] = &["ext4", "xfs", "btrfs", "f2fs"];
+// 系统分区至少 8GiB
+const MIN_ROOT_SIZE: u64 = 8 * 1024 * 1024 * 1024;
+pub const DEFAULT_EFI_SIZE: u64 = 512 * 1024 * 1024;
+const MIN_EFI_SIZE: u64 = 64 * 1024 * 1024;
+const MIN_SWAP_SIZE: u64 = 1024 * 1024;
+
+impl DataLayout {
+    pub fn check(&self) -> Result<(), PartitionError> {
+        if !SUPPORTED_FS_TYPES.contains(&self.fs_type.as_str()) {
+            return Err(PartitionError::InvalidDataLayout(format!(
+                "unsupported filesystem: {}",
+                self.fs_type
+            )));
+        }
+
+        if !self.mount_point.is_absolute()
+            || self
+                .mount_point
+                .components()
+                .any(|c| !matches!(c, Component::RootDir | Component::Normal(_)))
+        {
+            return Err(PartitionError::InvalidDataLayout(format!(
+                "mount point must be a normalized absolute path: {}",
+                self.mount_point.display()
+            )));
+        }
+
+        if [Path::new("/"), Path::new("/efi"), Path::new("/boot")]
+            .contains(&self.mount_point.as_path())
+        {
+            return Err(PartitionError::InvalidDataLayout(format!(
+                "mount point is reserved: {}",
+                self.mount_point.display()
+            )));
+        }
+
+        if self.root_size < MIN_ROOT_SIZE {
+            return Err(PartitionError::InvalidDataLayout(format!(
+                "root size is too small: {}",
+                self.root_size
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 const SUPPORT_PARTITION_TYPE: &[&str] = &["primary", "logical"];
 const EFI: Uuid = uuid!("C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
 const LINUX_FS: Uuid = uuid!("0FC63DAF-8483-4772-8E79-3D69D8477DE4");
+const LINUX_SWAP: Uuid = uuid!("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F");
+const BIOS_BOOT: Uuid = uuid!("21686148-6449-6E6F-744E-656564454649");
+// GRUB 的 core.img 放在 BIOS boot 分区中，1MiB 足够
+const BIOS_BOOT_SIZE: u64 = 1024 * 1024;
 
 #[derive(Debug, Snafu)]
 pub enum PartitionErr {
@@ -61,83 +169,687 @@ pub fn get_partition_table_type(device_path: &Path) -> Result<String, io::Error>
     Ok(partition_t)
 }
 
+/// Options of auto partitioning
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoPartitionOptions {
+    /// Filesystem of the system partition
+    pub root_fs_type: String,
+    /// Size of the ESP in bytes, only used on UEFI systems
+    pub efi_size: u64,
+    /// Size of the swap partition in bytes, no swap partition is created if not set
+    pub swap_size: Option<u64>,
+    /// Optional data partition, see [`DataLayout`]
+    pub data: Option<DataLayout>,
+    /// Keep the existing ESP of the disk (e.g. of Windows) and create the other partitions
+    /// in the largest free space, the disk is repartitioned as usual if it has no ESP
+    pub reuse_esp: bool,
+    /// Put /home on its own partition, turned into `data` by [`AutoPartitionOptions::resolve_home_split`]
+    pub home_split: HomeSplit,
+    /// Keep the existing GPT and all its partitions, create the partitions in the largest
+    /// free space only, the existing ESP is reused if any
+    pub free_space_only: bool,
+    /// Minimum size in bytes of the free space used by `free_space_only`
+    pub min_free_space: u64,
+    /// Discard (TRIM) the whole disk before repartitioning it, skipped if the disk does not
+    /// support discard or the existing ESP is reused
+    pub discard: bool,
+    /// Repartition the whole disk even if it holds LUKS or BitLocker partitions
+    pub force: bool,
+}
+
+/// Stage of auto partitioning reported by [`auto_create_partitions_with_progress`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutoPartitionStage {
+    /// Discarding the whole disk, may take a while on large disks
+    Discard,
+    /// Writing the partition table and formatting the partitions
+    Partition,
+}
+
+/// How to split /home from the system partition
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HomeSplit {
+    #[default]
+    None,
+    /// /home takes this percentage of the space left for / and /home
+    Percent(u8),
+    /// /home takes this many GiB
+    FixedGiB(u64),
+}
+
+impl HomeSplit {
+    /// Size of /home out of `available` bytes
+    fn home_size(&self, available: u64) -> Result<Option<u64>, PartitionError> {
+        match *self {
+            HomeSplit::None => Ok(None),
+            HomeSplit::Percent(p @ 1..=99) => Ok(Some(available / 100 * p as u64)),
+            HomeSplit::Percent(p) => Err(PartitionError::InvalidAutoPartitionOptions(format!(
+                "home percentage must be between 1 and 99: {p}"
+            ))),
+            HomeSplit::FixedGiB(0) => Err(PartitionError::InvalidAutoPartitionOptions(
+                "home size must not be zero".to_string(),
+            )),
+            HomeSplit::FixedGiB(size) => {
+                size.checked_mul(1024 * 1024 * 1024)
+                    .map(Some)
+                    .ok_or_else(|| {
+                        PartitionError::InvalidAutoPartitionOptions(format!(
+                            "home size is too large: {size}GiB"
+                        ))
+                    })
+            }
+        }
+    }
+}
+
+/// 只使用空闲空间安装时，空闲空间至少 15GiB
+pub const MIN_FREE_SPACE_SIZE: u64 = 15 * 1024 * 1024 * 1024;
+
+/// 分区表、对齐所需的空间
+const TABLE_RESERVED_SIZE: u64 = 2 * 1024 * 1024;
+
+impl Default for AutoPartitionOptions {
+    fn default() -> Self {
+        Self {
+            root_fs_type: "ext4".to_string(),
+            efi_size: DEFAULT_EFI_SIZE,
+            swap_size: None,
+            data: None,
+            reuse_esp: false,
+            home_split: HomeSplit::None,
+            free_space_only: false,
+            min_free_space: MIN_FREE_SPACE_SIZE,
+            discard: false,
+            force: false,
+        }
+    }
+}
+
+impl AutoPartitionOptions {
+    /// Turn `home_split` into a data partition mounted at /home on a disk of `disk_size` bytes
+    fn resolve_home_split_with_size(
+        &mut self,
+        disk_size: u64,
+        is_efi: bool,
+    ) -> Result<(), PartitionError> {
+        let err = |reason: &str| PartitionError::InvalidAutoPartitionOptions(reason.to_string());
+
+        if self.home_split == HomeSplit::None {
+            return Ok(());
+        }
+
+        if self.data.is_some() {
+            return Err(err("home_split can not be used with a data partition"));
+        }
+
+        // 复用 ESP 时只能使用磁盘的空闲空间，无法按整个磁盘计算
+        if self.reuse_esp {
+            return Err(err("home_split can not be used with reuse_esp"));
+        }
+
+        if self.free_space_only {
+            return Err(err("home_split can not be used with free_space_only"));
+        }
+
+        let available = disk_size
+            .checked_sub(if is_efi { self.efi_size } else { 0 })
+            .and_then(|x| x.checked_sub(self.swap_size.unwrap_or(0)))
+            .and_then(|x| x.checked_sub(TABLE_RESERVED_SIZE))
+            .ok_or_else(|| err("disk is too small"))?;
+
+        let home_size = self.home_split.home_size(available)?.unwrap_or(0);
+        let root_size = available
+            .checked_sub(home_size)
+            .ok_or_else(|| err("home is larger than the disk"))?;
+
+        self.data = Some(DataLayout {
+            root_size,
+            fs_type: self.root_fs_type.clone(),
+            mount_point: PathBuf::from("/home"),
+        });
+        self.home_split = HomeSplit::None;
+
+        Ok(())
+    }
+
+    /// Turn `home_split` into a data partition mounted at /home, see [`HomeSplit`]
+    pub fn resolve_home_split(
+        &mut self,
+        dev_path: &Path,
+        is_efi: bool,
+    ) -> Result<(), PartitionError> {
+        if self.home_split == HomeSplit::None {
+            return Ok(());
+        }
+
+        self.resolve_home_split_with_size(device_size(dev_path)?, is_efi)
+    }
+
+    pub fn check(&self) -> Result<(), PartitionError> {
+        if !SUPPORTED_FS_TYPES.contains(&self.root_fs_type.as_str()) {
+            return Err(PartitionError::InvalidAutoPartitionOptions(format!(
+                "unsupported root filesystem: {}, supported: {}",
+                self.root_fs_type,
+                SUPPORTED_FS_TYPES.join(", ")
+            )));
+        }
+
+        // 只使用空闲空间时绝不能 discard 整个磁盘
+        if self.discard && self.free_space_only {
+            return Err(PartitionError::InvalidAutoPartitionOptions(
+                "discard can not be used with free_space_only".to_string(),
+            ));
+        }
+
+        if self.efi_size < MIN_EFI_SIZE {
+            return Err(PartitionError::InvalidAutoPartitionOptions(format!(
+                "ESP size is too small: {}",
+                self.efi_size
+            )));
+        }
+
+        if let Some(swap_size) = self.swap_size {
+            if swap_size < MIN_SWAP_SIZE {
+                return Err(PartitionError::InvalidAutoPartitionOptions(format!(
+                    "swap size is too small: {swap_size}"
+                )));
+            }
+        }
+
+        if let Some(ref data) = self.data {
+            data.check()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Partitions created by auto partitioning
+#[derive(Debug, Clone, Serialize)]
+pub struct AutoPartitions {
+    pub efi: Option<DkPartition>,
+    pub system: DkPartition,
+    pub data: Option<DkPartition>,
+    pub swap: Option<DkPartition>,
+    /// Device-mapper names of the LVM logical volumes removed before repartitioning
+    pub removed_lvm: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PartitionRole {
+    Efi,
+    BiosBoot,
+    System,
+    Data,
+    Swap,
+}
+
+impl PartitionRole {
+    fn gpt_type(&self) -> Uuid {
+        match self {
+            PartitionRole::Efi => EFI,
+            PartitionRole::BiosBoot => BIOS_BOOT,
+            PartitionRole::System | PartitionRole::Data => LINUX_FS,
+            PartitionRole::Swap => LINUX_SWAP,
+        }
+    }
+
+    fn mbr_type(&self) -> u8 {
+        match self {
+            PartitionRole::Swap => 0x82,
+            // MBR 下不会创建 ESP
+            _ => 0x83,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            PartitionRole::Efi => "esp",
+            PartitionRole::BiosBoot => "bios_grub",
+            PartitionRole::System => "system",
+            PartitionRole::Data => "data",
+            PartitionRole::Swap => "swap",
+        }
+    }
+}
+
+/// A partition to be created, `size` of `None` means using the rest of the disk
+#[derive(Debug)]
+struct PlannedPartition {
+    role: PartitionRole,
+    size: Option<u64>,
+    fs_type: String,
+}
+
+/// Partitions to create in on-disk order
+fn planned_partitions(options: &AutoPartitionOptions, is_efi: bool) -> Vec<PlannedPartition> {
+    let mut res = vec![];
+
+    match options.data {
+        Some(ref data) => {
+            res.push(PlannedPartition {
+                role: PartitionRole::System,
+                size: Some(data.root_size),
+                fs_type: options.root_fs_type.clone(),
+            });
+            res.push(PlannedPartition {
+                role: PartitionRole::Data,
+                size: None,
+                fs_type: data.fs_type.clone(),
+            });
+        }
+        None => res.push(PlannedPartition {
+            role: PartitionRole::System,
+            size: None,
+            fs_type: options.root_fs_type.clone(),
+        }),
+    }
+
+    // swap 分区放在磁盘末尾
+    if let Some(swap_size) = options.swap_size {
+        res.push(PlannedPartition {
+            role: PartitionRole::Swap,
+            size: Some(swap_size),
+            fs_type: "swap".to_string(),
+        });
+    }
+
+    if is_efi {
+        let efi = PlannedPartition {
+            role: PartitionRole::Efi,
+            size: Some(options.efi_size),
+            fs_type: "vfat".to_string(),
+        };
+
+        // 开发模式下系统分区在前，EFI 分区在后
+        if is_dev_mode() {
+            res.push(efi);
+        } else {
+            res.insert(0, efi);
+        }
+    }
+
+    res
+}
+
+/// Lay out partitions from `starting_lba`, every partition is aligned to 1MiB
+/// At most one size can be `None`, which takes the rest of the space
+/// Returns (starting lba, ending lba) of every partition, or `None` if there is not enough space
+fn plan_partitions(
+    sizes: &[Option<u64>],
+    sector_size: u64,
+    starting_lba: u64,
+    last_usable_lba: u64,
+) -> Option<Vec<(u64, u64)>> {
+    let align = (1024 * 1024 / sector_size).max(1);
+    let sectors_of = |size: u64| size / sector_size / align * align;
+
+    let mut fixed = 0u64;
+    for size in sizes.iter().flatten() {
+        let sectors = sectors_of(*size);
+        if sectors == 0 {
+            return None;
+        }
+
+        fixed = fixed.checked_add(sectors)?;
+    }
+
+    let rest = last_usable_lba
+        .checked_sub(starting_lba)?
+        .checked_sub(fixed)?
+        / align
+        * align;
+
+    // 剩余空间至少需要 1MiB
+    if rest == 0 && sizes.iter().any(|x| x.is_none()) {
+        return None;
+    }
+
+    let mut res = vec![];
+    let mut start = starting_lba;
+
+    for size in sizes {
+        let sectors = match size {
+            Some(size) => sectors_of(*size),
+            None => rest,
+        };
+
+        res.push((start, start + sectors - 1));
+        start += sectors;
+    }
+
+    Some(res)
+}
+
+/// Wipe the disk and create the ESP of `efi_size` bytes (on UEFI) and a system partition
+/// formatted as `root_fs`
 pub fn auto_create_partitions(
     dev_path: &Path,
+    root_fs: &str,
+    efi_size: u64,
+) -> Result<(Option<DkPartition>, DkPartition), PartitionError> {
+    let options = AutoPartitionOptions {
+        root_fs_type: root_fs.to_string(),
+        efi_size,
+        ..Default::default()
+    };
+
+    let res = auto_create_partitions_with_options(dev_path, &options)?;
+
+    Ok((res.efi, res.system))
+}
+
+/// Create the ESP (on UEFI), a system partition and a /home partition split by `home_split`
+pub fn auto_create_partitions_with_home(
+    dev_path: &Path,
+    home_split: HomeSplit,
+) -> Result<AutoPartitions, PartitionError> {
+    let options = AutoPartitionOptions {
+        home_split,
+        ..Default::default()
+    };
+
+    auto_create_partitions_with_options(dev_path, &options)
+}
+
+/// Keep the existing GPT, create the ESP (on UEFI, if the disk has none) and a system partition
+/// formatted as `root_fs` in the largest free space of the disk
+/// Fails if the free space is smaller than `min_size` bytes
+pub fn auto_partition_in_free_space(
+    dev_path: &Path,
+    root_fs: &str,
+    min_size: u64,
 ) -> Result<(Option<DkPartition>, DkPartition), PartitionError> {
-    // 处理 lvm 的情况
-    if is_lvm_device(dev_path)? {
-        remove_all_lvm_devive()?;
+    let options = AutoPartitionOptions {
+        root_fs_type: root_fs.to_string(),
+        free_space_only: true,
+        min_free_space: min_size,
+        ..Default::default()
+    };
+
+    let res = auto_create_partitions_with_options(dev_path, &options)?;
+
+    Ok((res.efi, res.system))
+}
+
+/// Like [`auto_create_partitions`], but the layout is described by `options`
+pub fn auto_create_partitions_with_options(
+    dev_path: &Path,
+    options: &AutoPartitionOptions,
+) -> Result<AutoPartitions, PartitionError> {
+    auto_create_partitions_with_progress(dev_path, options, |_| {})
+}
+
+/// Like [`auto_create_partitions_with_options`], `progress` is called when a stage begins
+pub fn auto_create_partitions_with_progress(
+    dev_path: &Path,
+    options: &AutoPartitionOptions,
+    progress: impl Fn(AutoPartitionStage),
+) -> Result<AutoPartitions, PartitionError> {
+    let is_efi = is_efi_booted();
+
+    let mut options = options.clone();
+    options.resolve_home_split(dev_path, is_efi)?;
+    let options = &options;
+
+    options.check()?;
+
+    // 重新分区前关闭磁盘上正在使用的 swap
+    swapoff_active_swaps(dev_path)?;
+
+    // 磁盘上仍有分区在使用时，内核无法重新读取分区表
+    // 在只用空闲空间与保留 ESP 的分支之前检查，Live 系统可能自动挂载了 ESP 或 Windows 分区
+    check_device_not_in_use(dev_path)?;
+
+    if options.free_space_only {
+        return create_partitions_in_free_space_only(dev_path, options, is_efi);
+    }
+
+    if options.reuse_esp && is_efi {
+        match list_esp_partitions(dev_path)?.into_iter().next() {
+            Some(esp) => {
+                info!("Reusing ESP {:?} of {}", esp.path, dev_path.display());
+                return create_partitions_in_free_space(dev_path, options, Some(esp), 0);
+            }
+            None => info!(
+                "No ESP on {}, repartitioning the whole disk",
+                dev_path.display()
+            ),
+        }
+    }
+
+    // 加密分区中的数据无法恢复，除非明确要求否则拒绝覆盖
+    if !options.force {
+        check_no_encrypted_partitions(dev_path)?;
+    }
+
+    // 处理 lvm 的情况，只移除位于此磁盘上的逻辑卷
+    let lvm_names = is_lvm_device(dev_path)?;
+    if !lvm_names.is_empty() {
+        debug!("LVM volumes on {}: {:?}", dev_path.display(), lvm_names);
+        check_lvm_not_in_use(&lvm_names)?;
+        remove_all_lvm_devive(&lvm_names)?;
     }
 
-    if is_efi_booted() {
-        let (efi, system) = auto_create_partitions_gpt(dev_path)?;
-        return Ok((Some(efi), system));
+    if options.discard {
+        progress(AutoPartitionStage::Discard);
+
+        // discard 只是优化，失败时照常分区
+        if let Err(e) = discard_device(dev_path) {
+            warn!("Failed to discard {}: {e}", dev_path.display());
+        }
     }
 
-    Ok((None, auto_create_partitions_mbr(dev_path)?))
+    progress(AutoPartitionStage::Partition);
+
+    let table = match is_efi {
+        true => AutoTable::GptEfi,
+        // MBR 无法使用超过 2TiB 的空间
+        false if exceeds_mbr_limit(dev_path)? => {
+            info!(
+                "{} is too large for MBR, using GPT with a BIOS boot partition",
+                dev_path.display()
+            );
+            AutoTable::GptBios
+        }
+        false => AutoTable::Mbr,
+    };
+
+    let mut res = create_partitions(dev_path, options, table)?;
+    res.removed_lvm = lvm_names;
+
+    Ok(res)
 }
 
-fn remove_all_lvm_devive() -> Result<(), PartitionError> {
-    let output = Command::new("dmsetup")
-        .arg("ls")
-        .output()
-        .map_err(|e| PartitionError::DmSetup { source: e })?;
+/// Size of the device in bytes
+fn device_size(dev_path: &Path) -> Result<u64, PartitionError> {
+    fs::File::open(dev_path)
+        .and_then(|mut f| f.seek(SeekFrom::End(0)))
+        .map_err(|e| PartitionError::OpenDevice {
+            path: dev_path.display().to_string(),
+            err: e,
+        })
+}
 
-    let output = String::from_utf8_lossy(&output.stdout);
-    let lines = output.lines();
+/// The ESP must not take more than a quarter of the disk
+fn check_efi_size(efi_size: u64, disk_size: u64) -> Result<(), PartitionError> {
+    if efi_size > disk_size / 4 {
+        return Err(PartitionError::InvalidAutoPartitionOptions(format!(
+            "ESP size is too large: {efi_size}, at most a quarter of the disk ({disk_size})"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Whether the disk is larger than MBR can address (2^32 sectors)
+fn exceeds_mbr_limit(dev_path: &Path) -> Result<bool, PartitionError> {
+    let mut f = fs::File::open(dev_path).map_err(|e| PartitionError::OpenDevice {
+        path: dev_path.display().to_string(),
+        err: e,
+    })?;
 
-    for line in lines {
-        let mut line = line.split_whitespace();
-        let lvm_name = line.next().ok_or_else(|| PartitionError::DmSetup {
-            source: io::Error::new(ErrorKind::BrokenPipe, "Failed to read dmsetup stdout"),
+    let sector_size = gptman::linux::get_sector_size(&mut f).map_err(PartitionError::GetTable)?;
+    let size = f
+        .seek(SeekFrom::End(0))
+        .map_err(|e| PartitionError::OpenDevice {
+            path: dev_path.display().to_string(),
+            err: e,
         })?;
 
-        if lvm_name != "live-base" && lvm_name != "live-rw" {
-            info!("Running dmsetup remove {}", lvm_name);
-            let remove = Command::new("dmsetup")
-                .arg("remove")
-                .arg(lvm_name)
-                .output()
-                .map_err(|e| PartitionError::DmSetup { source: e })?;
-
-            debug!("Stdout: {}", String::from_utf8_lossy(&remove.stdout));
-            debug!("Stderr: {}", String::from_utf8_lossy(&remove.stderr));
-
-            if !remove.status.success() {
-                return Err(PartitionError::DmSetup {
-                    source: io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Failed to remove lvm device: {}", lvm_name),
-                    ),
-                });
-            }
+    Ok(size / sector_size > u32::MAX as u64)
+}
+
+/// Refuse to repartition a disk holding LUKS or BitLocker partitions
+fn check_no_encrypted_partitions(dev_path: &Path) -> Result<(), PartitionError> {
+    let mut f = fs::File::open(dev_path).map_err(|e| PartitionError::OpenDevice {
+        path: dev_path.display().to_string(),
+        err: e,
+    })?;
+
+    let sector_size = gptman::linux::get_sector_size(&mut f).map_err(PartitionError::GetTable)?;
+
+    // 整个磁盘也可能直接被加密
+    let encrypted = std::iter::once(dev_path.to_path_buf())
+        .chain(
+            table_entries(&mut f, sector_size)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(num, _)| partition_path(dev_path, num)),
+        )
+        .filter_map(|path| {
+            detect_encryption(&path).map(|encryption| format!("{} ({encryption})", path.display()))
+        })
+        .collect::<Vec<_>>();
+
+    if !encrypted.is_empty() {
+        return Err(PartitionError::EncryptedPartitions {
+            path: dev_path.display().to_string(),
+            partitions: encrypted,
+        });
+    }
+
+    Ok(())
+}
+
+/// Refuse to remove LVM logical volumes that are mounted or used as swap
+fn check_lvm_not_in_use(lvm_names: &[String]) -> Result<(), PartitionError> {
+    let mounts = read_mounts().map_err(PartitionError::ReadMounts)?;
+    let swaps = active_swaps()?;
+
+    for lvm_name in lvm_names {
+        let path = Path::new("/dev/mapper").join(lvm_name);
+
+        // /dev/mapper/* 与 /dev/<vg>/<lv> 都指向 /dev/dm-*
+        let mounted_points = find_mounted_points(&path, &mounts, &swaps, |p| {
+            (
+                fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf()),
+                None,
+            )
+        });
+
+        if !mounted_points.is_empty() {
+            return Err(PartitionError::DeviceInUse {
+                path: path.display().to_string(),
+                mounted_points,
+            });
         }
     }
 
     Ok(())
 }
 
-pub fn is_lvm_device(p: &Path) -> Result<bool, PartitionError> {
-    let cmd = Command::new("lvs")
-        .arg("--segments")
-        .arg("-o")
-        .arg("+devices")
-        .output()
-        .map_err(PartitionError::OpenLvs)?;
-
-    let output = String::from_utf8_lossy(&cmd.stdout);
-    for i in output.lines().skip(1) {
-        let mut split = i.split_whitespace();
-        if split
-            .next_back()
-            .map(|x| x.trim().starts_with(&p.to_string_lossy().to_string()))
-            .unwrap_or(false)
-        {
-            return Ok(true);
+/// Remove the device-mapper devices of the LVM logical volumes found by [`is_lvm_device`]
+fn remove_all_lvm_devive(lvm_names: &[String]) -> Result<(), PartitionError> {
+    for lvm_name in lvm_names {
+        info!("Running dmsetup remove {}", lvm_name);
+        let remove = Command::new("dmsetup")
+            .arg("remove")
+            .arg(lvm_name)
+            .output()
+            .map_err(|e| PartitionError::DmSetup { source: e })?;
+
+        debug!("Stdout: {}", String::from_utf8_lossy(&remove.stdout));
+        debug!("Stderr: {}", String::from_utf8_lossy(&remove.stderr));
+
+        if !remove.status.success() {
+            return Err(PartitionError::DmSetup {
+                source: io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Failed to remove lvm device: {}", lvm_name),
+                ),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Device-mapper names of the LVM logical volumes on the disk or partition, empty if not used by LVM
+pub fn is_lvm_device(p: &Path) -> Result<Vec<String>, PartitionError> {
+    lvm_devices_on(p).map_err(PartitionError::ReadDeviceMapper)
+}
+
+// 开头 8MiB 覆盖了 ext4、xfs、btrfs、f2fs、vfat、NTFS、swap、ZFS、mdadm 1.1/1.2 的超级块与 LUKS2 的备份头
+const HEAD_WIPE_SIZE: u64 = 8 * 1024 * 1024;
+// 末尾 1MiB 覆盖了 mdadm 0.90/1.0 的超级块与 ZFS 的后两份标签
+const TAIL_WIPE_SIZE: u64 = 1024 * 1024;
+// btrfs 在 64MiB 处还有一份超级块副本
+const BTRFS_MIRROR_OFFSET: u64 = 64 * 1024 * 1024;
+const BTRFS_SUPER_SIZE: u64 = 4096;
+
+/// Clear old filesystem, RAID and LUKS signatures on the partition like `wipefs -a`
+/// mkfs does not always overwrite them, e.g. mkfs.vfat leaves RAID and ZFS signatures behind
+pub fn wipe_signatures(partition: &DkPartition) -> Result<(), PartitionError> {
+    let path = partition
+        .path
+        .as_deref()
+        .ok_or_else(|| PartitionError::WipeSignatures {
+            path: String::new(),
+            err: io::Error::new(io::ErrorKind::NotFound, "partition.path is empty"),
+        })?;
+
+    let err = |e: io::Error| PartitionError::WipeSignatures {
+        path: path.display().to_string(),
+        err: e,
+    };
+
+    let mut f = fs::OpenOptions::new().write(true).open(path).map_err(err)?;
+    let size = f.seek(SeekFrom::End(0)).map_err(err)?;
+    let zeros = vec![0; 1024 * 1024];
+
+    for (offset, len) in wipe_ranges(size) {
+        f.seek(SeekFrom::Start(offset)).map_err(err)?;
+
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(zeros.len() as u64);
+            f.write_all(&zeros[..n as usize]).map_err(err)?;
+            left -= n;
         }
     }
 
-    Ok(false)
+    f.sync_all().map_err(PartitionError::Flush)?;
+
+    Ok(())
+}
+
+/// Ranges (offset, length) to zero on a partition of `size` bytes
+fn wipe_ranges(size: u64) -> Vec<(u64, u64)> {
+    let head = HEAD_WIPE_SIZE.min(size);
+    let mut ranges = vec![(0, head)];
+
+    if BTRFS_MIRROR_OFFSET >= head && BTRFS_MIRROR_OFFSET + BTRFS_SUPER_SIZE <= size {
+        ranges.push((BTRFS_MIRROR_OFFSET, BTRFS_SUPER_SIZE));
+    }
+
+    let tail = size.saturating_sub(TAIL_WIPE_SIZE).max(head);
+    if tail < size {
+        ranges.push((tail, size - tail));
+    }
+
+    ranges
 }
 
 pub fn format_partition(partition: &DkPartition) -> Result<(), PartitionError> {
@@ -148,7 +860,12 @@ pub fn format_partition(partition: &DkPartition) -> Result<(), PartitionError> {
         ))
     })?;
 
-    let mut command = Command::new(format!("mkfs.{fs_type}"));
+    wipe_signatures(partition)?;
+
+    let mut command = match fs_type.as_str() {
+        "swap" => Command::new("mkswap"),
+        _ => Command::new(format!("mkfs.{fs_type}")),
+    };
 
     let cmd = match fs_type.as_str() {
         "ext4" => command.arg("-Fq"),
@@ -201,12 +918,26 @@ pub fn list_partitions(device_path: PathBuf) -> Vec<DkPartition> {
                 };
 
                 if SUPPORT_PARTITION_TYPE.contains(&part.type_get_name()) {
-                    partitions.push(DkPartition {
+                    let encryption = part.get_path().and_then(detect_encryption);
+
+                    let mut p = DkPartition {
                         path: part.get_path().map(|path| path.to_owned()),
                         parent_path: Some(device_path.clone()),
                         size: sector_size * part_length,
                         fs_type,
-                    });
+                        format: true,
+                        encryption,
+                        ..Default::default()
+                    }
+                    .with_identity(part.num());
+
+                    // 无法挂载的分区视为没有操作系统
+                    p.os = match (&p.path, &p.fs_type) {
+                        (Some(path), Some(fs_type)) => detect_os(path, fs_type, p.uuid.as_deref()),
+                        _ => None,
+                    };
+
+                    partitions.push(p);
                 }
             }
         }
@@ -216,162 +947,660 @@ pub fn list_partitions(device_path: PathBuf) -> Vec<DkPartition> {
 }
 
 pub fn find_esp_partition(device_path: &Path) -> Result<DkPartition, PartitionError> {
+    list_esp_partitions(device_path)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| PartitionError::FindEspPartition {
+            path: device_path.display().to_string(),
+            err: io::Error::new(io::ErrorKind::Other, "Unexcept error"),
+        })
+}
+
+/// List all ESPs on a device in partition order, so that the user can choose one
+/// 一块磁盘上可能同时存在厂商预置的和用户创建的 ESP
+pub fn list_esp_partitions(device_path: &Path) -> Result<Vec<DkPartition>, PartitionError> {
     let mut device =
         Device::get(device_path).map_err(|e| PartitionError::open_device(device_path, e))?;
+    let sector_size = device.sector_size();
+    let mut res = vec![];
+
+    // 没有分区表的磁盘上没有 ESP
     if let Ok(disk) = libparted::Disk::new(&mut device) {
         for mut part in disk.parts() {
-            if part.num() < 0 {
+            if part.num() < 0 || !part.get_flag(libparted::PartitionFlag::PED_PARTITION_ESP) {
                 continue;
             }
-            if part.get_flag(libparted::PartitionFlag::PED_PARTITION_ESP) {
-                let fs_type = if let Ok(type_) = part.get_geom().probe_fs() {
-                    Some(type_.name().to_owned())
-                } else {
-                    None
-                };
-                let path = part
-                    .get_path()
-                    .ok_or_else(|| PartitionError::FindEspPartition {
-                        path: device_path.display().to_string(),
-                        err: io::Error::new(io::ErrorKind::Other, "Unexcept error"),
-                    })?;
-                return Ok(DkPartition {
-                    path: Some(path.to_owned()),
-                    parent_path: None,
-                    size: 0,
-                    fs_type,
-                });
-            }
+
+            let path = match part.get_path() {
+                Some(path) => path.to_path_buf(),
+                None => continue,
+            };
+
+            res.push(
+                DkPartition {
+                    path: Some(path),
+                    parent_path: Some(device_path.to_path_buf()),
+                    fs_type: part
+                        .get_geom()
+                        .probe_fs()
+                        .ok()
+                        .map(|x| x.name().to_string()),
+                    size: match part.geom_length() {
+                        ..=0 => 0,
+                        x @ 1.. => x as u64 * sector_size,
+                    },
+                    format: true,
+                    ..Default::default()
+                }
+                .with_identity(part.num()),
+            );
         }
     }
 
-    Err(PartitionError::FindEspPartition {
-        path: device_path.display().to_string(),
-        err: io::Error::new(io::ErrorKind::Other, "Unexcept error"),
-    })
+    Ok(res)
 }
 
 pub fn auto_create_partitions_gpt(
     device_path: &Path,
+    root_fs_type: &str,
+    efi_size: u64,
 ) -> Result<(DkPartition, DkPartition), PartitionError> {
-    let mut f = fs::OpenOptions::new()
-        .write(true)
-        .open(device_path)
-        .map_err(|e| PartitionError::OpenDevice {
-            path: device_path.display().to_string(),
-            err: e,
-        })?;
+    let options = AutoPartitionOptions {
+        root_fs_type: root_fs_type.to_string(),
+        efi_size,
+        ..Default::default()
+    };
+    options.check()?;
 
-    let sector_size = gptman::linux::get_sector_size(&mut f).map_err(PartitionError::GetTable)?;
-    clear_start_sector(&mut f, sector_size)?;
+    let res = create_partitions(device_path, &options, AutoTable::GptEfi)?;
+    let efi = res
+        .efi
+        .ok_or_else(|| not_found(device_path, PartitionRole::Efi))?;
 
-    // 创建新的分区表
-    let mut gpt = GPT::new_from(&mut f, sector_size, generate_gpt_random_uuid())?;
+    Ok((efi, res.system))
+}
 
-    // 写一个假的 MBR 保护分区头
-    GPT::write_protective_mbr_into(&mut f, sector_size).map_err(PartitionError::GptMan)?;
+pub fn auto_create_partitions_mbr(
+    device_path: &Path,
+    root_fs_type: &str,
+) -> Result<DkPartition, PartitionError> {
+    let options = AutoPartitionOptions {
+        root_fs_type: root_fs_type.to_string(),
+        ..Default::default()
+    };
+    options.check()?;
 
-    // 起始扇区为 1MiB 除以扇区大小
-    let starting_lba = 1024 * 1024 / sector_size;
+    let res = create_partitions(device_path, &options, AutoTable::Mbr)?;
 
-    // EFI 的大小
-    let efi_size = 512 * 1024 * 1024;
+    Ok(res.system)
+}
 
-    // 分区方案
-    gpt_partition(&mut gpt, efi_size, sector_size, starting_lba);
+/// Create a GPT with a BIOS boot partition for GRUB and a system partition, for BIOS systems
+/// 适用于 MBR 无法完整使用的 2TiB 以上磁盘
+pub fn auto_create_partitions_gpt_bios(
+    device_path: &Path,
+    root_fs_type: &str,
+) -> Result<DkPartition, PartitionError> {
+    let options = AutoPartitionOptions {
+        root_fs_type: root_fs_type.to_string(),
+        ..Default::default()
+    };
+    options.check()?;
 
-    // 应用分区表的修改
-    gpt.write_into(&mut f)?;
-    f.sync_all().map_err(PartitionError::Flush)?;
+    let res = create_partitions(device_path, &options, AutoTable::GptBios)?;
 
-    // 重新读取分区表以读取刚刚的修改
-    gptman::linux::reread_partition_table(&mut f).map_err(PartitionError::GetTable)?;
+    Ok(res.system)
+}
 
-    // 关闭文件，确保 libparted 能正确地读到分区
-    drop(f);
+/// Whether the GPT of the disk has a BIOS boot partition, which GRUB needs on BIOS systems
+pub fn has_bios_boot_partition(device_path: &Path) -> bool {
+    let mut f = match fs::File::open(device_path) {
+        Ok(f) => f,
+        Err(e) => {
+            debug!("Failed to open {}: {e}", device_path.display());
+            return false;
+        }
+    };
 
-    // 使用 libparted 便利分区表，找到分区路径并格式化
-    // TODO: 自己实现设备路径寻找逻辑，彻底扔掉 libparted
-    let mut device =
-        libparted::Device::new(device_path).map_err(|e| PartitionError::OpenDevice {
+    let gpt = gptman::linux::get_sector_size(&mut f)
+        .ok()
+        .and_then(|sector_size| GPT::read_from(&mut f, sector_size).ok());
+
+    gpt.is_some_and(|gpt| {
+        gpt.iter()
+            .any(|(_, p)| p.is_used() && p.partition_type_guid == BIOS_BOOT.to_bytes_le())
+    })
+}
+
+/// Partition table written by auto partitioning
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AutoTable {
+    /// GPT with an ESP
+    GptEfi,
+    /// GPT with a BIOS boot partition for GRUB on BIOS systems
+    GptBios,
+    Mbr,
+}
+
+/// Write a new partition table and format the created partitions
+fn create_partitions(
+    device_path: &Path,
+    options: &AutoPartitionOptions,
+    table: AutoTable,
+) -> Result<AutoPartitions, PartitionError> {
+    if table == AutoTable::GptEfi {
+        check_efi_size(options.efi_size, device_size(device_path)?)?;
+    }
+
+    let mut planned = planned_partitions(options, table == AutoTable::GptEfi);
+    if table == AutoTable::GptBios {
+        planned.insert(
+            0,
+            PlannedPartition {
+                role: PartitionRole::BiosBoot,
+                size: Some(BIOS_BOOT_SIZE),
+                fs_type: "".to_string(),
+            },
+        );
+    }
+
+    let sizes = planned.iter().map(|p| p.size).collect::<Vec<_>>();
+
+    let no_space = || PartitionError::CreatePartition {
+        path: device_path.display().to_string(),
+        err: io::Error::new(
+            io::ErrorKind::StorageFull,
+            "Not enough space for partitions",
+        ),
+    };
+
+    let sector_size = if table != AutoTable::Mbr {
+        create_gpt_table(device_path, |gpt, sector_size, starting_lba| {
+            let ranges = plan_partitions(
+                &sizes,
+                sector_size,
+                starting_lba,
+                gpt.header.last_usable_lba,
+            )
+            .ok_or_else(no_space)?;
+
+            for (i, (p, (starting_lba, ending_lba))) in planned.iter().zip(ranges).enumerate() {
+                gpt[i as u32 + 1] = gptman::GPTPartitionEntry {
+                    partition_type_guid: p.role.gpt_type().to_bytes_le(),
+                    unique_partition_guid: generate_gpt_random_uuid(),
+                    starting_lba,
+                    ending_lba,
+                    attribute_bits: 0,
+                    partition_name: "".into(),
+                };
+            }
+
+            Ok(())
+        })?
+    } else {
+        create_mbr_table(device_path, |mbr, sector_size| {
+            let sectors = mbr.get_maximum_partition_size()?;
+            let starting_lba = mbr
+                .find_optimal_place(sectors)
+                .ok_or(PartitionError::GetOptimalPlace)?;
+
+            let ranges = plan_partitions(
+                &sizes,
+                sector_size as u64,
+                starting_lba as u64,
+                starting_lba as u64 + sectors as u64,
+            )
+            .ok_or_else(no_space)?;
+
+            for (i, (p, (starting_lba, ending_lba))) in planned.iter().zip(ranges).enumerate() {
+                let starting_lba = u32::try_from(starting_lba).map_err(|_| no_space())?;
+                let sectors =
+                    u32::try_from(ending_lba - starting_lba as u64 + 1).map_err(|_| no_space())?;
+
+                mbr[i + 1] = mbr_partition(p.role.mbr_type(), starting_lba, sectors);
+            }
+
+            Ok(())
+        })? as u64
+    };
+
+    let numbered = planned
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (i as u32 + 1, p))
+        .collect::<Vec<_>>();
+
+    format_created_partitions(device_path, sector_size, &numbered, None)
+}
+
+/// Create the partitions in the free space of an existing GPT, for dual booting
+/// 只支持 UEFI，BIOS 下 GPT 磁盘还需要 BIOS boot 分区
+fn create_partitions_in_free_space_only(
+    device_path: &Path,
+    options: &AutoPartitionOptions,
+    is_efi: bool,
+) -> Result<AutoPartitions, PartitionError> {
+    let table =
+        get_partition_table_type(device_path).map_err(|e| PartitionError::GetPartitionType {
+            path: device_path.display().to_string(),
+            err: e,
+        })?;
+
+    if table != "gpt" {
+        return Err(PartitionError::UnsupportedTable(format!(
+            "{table}, installing into free space requires a GPT disk"
+        )));
+    }
+
+    if !is_efi {
+        return Err(PartitionError::WrongCombo {
+            table: Table::GPT,
+            bootmode: BootMode::BIOS,
+            path: device_path.display().to_string(),
+        });
+    }
+
+    let esp = list_esp_partitions(device_path)?.into_iter().next();
+    match esp {
+        Some(ref esp) => info!("Reusing ESP {:?} of {}", esp.path, device_path.display()),
+        None => info!("No ESP on {}, creating one", device_path.display()),
+    }
+
+    create_partitions_in_free_space(device_path, options, esp, options.min_free_space)
+}
+
+/// Create the planned partitions in the largest free space of the disk, existing partitions
+/// are left untouched
+/// `esp` is an existing ESP to keep, an ESP is planned if it is `None` on UEFI
+/// Fails if the free space is smaller than `min_size` bytes
+fn create_partitions_in_free_space(
+    device_path: &Path,
+    options: &AutoPartitionOptions,
+    esp: Option<DkPartition>,
+    min_size: u64,
+) -> Result<AutoPartitions, PartitionError> {
+    let planned = planned_partitions(options, esp.is_none() && is_efi_booted());
+
+    if planned.iter().any(|p| p.role == PartitionRole::Efi) {
+        check_efi_size(options.efi_size, device_size(device_path)?)?;
+    }
+    let sizes = planned.iter().map(|p| p.size).collect::<Vec<_>>();
+
+    let no_space = || PartitionError::CreatePartition {
+        path: device_path.display().to_string(),
+        err: io::Error::new(
+            io::ErrorKind::StorageFull,
+            "Not enough free space for partitions",
+        ),
+    };
+
+    let mut f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device_path)
+        .map_err(|e| PartitionError::OpenDevice {
             path: device_path.display().to_string(),
             err: e,
         })?;
 
-    let disk = Disk::new(&mut device).map_err(|e| PartitionError::OpenDisk {
+    let sector_size = gptman::linux::get_sector_size(&mut f).map_err(PartitionError::GetTable)?;
+    let mut gpt = GPT::read_from(&mut f, sector_size)?;
+
+    let used = gpt
+        .iter()
+        .filter(|(_, p)| p.is_used())
+        .map(|(_, p)| (p.starting_lba, p.ending_lba))
+        .collect::<Vec<_>>();
+
+    let (first, last) = largest_free_region(
+        gpt.header.first_usable_lba,
+        gpt.header.last_usable_lba,
+        &used,
+    )
+    .ok_or_else(no_space)?;
+
+    let free_size = (last - first + 1) * sector_size;
+    if free_size < min_size {
+        return Err(PartitionError::FreeSpaceTooSmall {
+            path: device_path.display().to_string(),
+            size: free_size,
+            min_size,
+        });
+    }
+
+    // 起始扇区对齐到 1MiB
+    let align = (1024 * 1024 / sector_size).max(1);
+    let ranges = plan_partitions(&sizes, sector_size, first.div_ceil(align) * align, last)
+        .ok_or_else(no_space)?;
+
+    let slots = gpt
+        .iter()
+        .filter(|(_, p)| p.is_unused())
+        .map(|(i, _)| i)
+        .take(planned.len())
+        .collect::<Vec<_>>();
+
+    if slots.len() < planned.len() {
+        return Err(PartitionError::NoFreeSlot {
+            path: device_path.display().to_string(),
+        });
+    }
+
+    for ((num, p), (starting_lba, ending_lba)) in slots.iter().zip(&planned).zip(ranges) {
+        gpt[*num] = gptman::GPTPartitionEntry {
+            partition_type_guid: p.role.gpt_type().to_bytes_le(),
+            unique_partition_guid: generate_gpt_random_uuid(),
+            starting_lba,
+            ending_lba,
+            attribute_bits: 0,
+            partition_name: "".into(),
+        };
+    }
+
+    gpt.write_into(&mut f)?;
+    f.sync_all().map_err(PartitionError::Flush)?;
+    gptman::linux::reread_partition_table(&mut f).map_err(PartitionError::GetTable)?;
+    drop(f);
+
+    let numbered = slots.into_iter().zip(&planned).collect::<Vec<_>>();
+
+    format_created_partitions(device_path, sector_size, &numbered, esp)
+}
+
+/// Largest free range (first, last) of sectors between `first_usable` and `last_usable`
+/// `used` are the inclusive ranges of existing partitions
+fn largest_free_region(
+    first_usable: u64,
+    last_usable: u64,
+    used: &[(u64, u64)],
+) -> Option<(u64, u64)> {
+    free_gaps(first_usable, last_usable, used)
+        .into_iter()
+        .max_by_key(|(first, last)| last - first)
+}
+
+/// Free ranges (first, last) of sectors between `first_usable` and `last_usable`, in disk order
+fn free_gaps(first_usable: u64, last_usable: u64, used: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut used = used.to_vec();
+    used.sort_unstable();
+
+    let mut gaps = vec![];
+    let mut cur = first_usable;
+
+    for (first, last) in used {
+        if first > cur {
+            gaps.push((cur, first - 1));
+        }
+
+        cur = cur.max(last + 1);
+    }
+
+    if cur <= last_usable {
+        gaps.push((cur, last_usable));
+    }
+
+    gaps.into_iter()
+        .map(|(first, last)| (first, last.min(last_usable)))
+        .filter(|(first, last)| first <= last)
+        .collect()
+}
+
+/// An unallocated region of a disk, usable by [`create_partition`] as is
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FreeRegion {
+    pub start_lba: u64,
+    pub end_lba: u64,
+    pub size_bytes: u64,
+}
+
+/// Unallocated regions of the disk, read from the GPT or MBR without libparted
+/// Regions are aligned to 1MiB like [`create_partition`], those smaller than 1MiB are left out
+pub fn get_free_space(device_path: &Path) -> Result<Vec<FreeRegion>, PartitionError> {
+    let mut f = fs::File::open(device_path).map_err(|e| PartitionError::OpenDevice {
         path: device_path.display().to_string(),
         err: e,
     })?;
 
-    let mut efi = None;
+    // 普通文件（如磁盘镜像）无法通过 ioctl 获取扇区大小
+    let is_file = f.metadata().is_ok_and(|m| m.is_file());
+    let sector_size = if is_file {
+        512
+    } else {
+        gptman::linux::get_sector_size(&mut f).map_err(PartitionError::GetTable)?
+    };
+
+    free_space_in(&mut f, sector_size)
+        .ok_or_else(|| PartitionError::UnsupportedTable(device_path.display().to_string()))
+}
+
+fn free_space_in<R: Read + Seek>(f: &mut R, sector_size: u64) -> Option<Vec<FreeRegion>> {
+    let (usable, used) = if let Ok(gpt) = GPT::read_from(f, sector_size) {
+        let used = gpt
+            .iter()
+            .filter(|(_, p)| p.is_used())
+            .map(|(_, p)| (p.starting_lba, p.ending_lba))
+            .collect::<Vec<_>>();
+
+        (
+            (gpt.header.first_usable_lba, gpt.header.last_usable_lba),
+            used,
+        )
+    } else {
+        let mbr = MBR::read_from(f, sector_size as u32).ok()?;
+        // 扩展分区整体视为已用，其中的空闲空间只能用于逻辑分区
+        let used = mbr
+            .iter()
+            .filter(|(_, p)| p.is_used())
+            .map(|(_, p)| {
+                (
+                    p.starting_lba as u64,
+                    p.starting_lba as u64 + p.sectors as u64 - 1,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        ((1, (mbr.disk_size as u64).saturating_sub(1)), used)
+    };
+
+    Some(aligned_free_regions(usable, &used, sector_size))
+}
+
+fn aligned_free_regions(
+    (first_usable, last_usable): (u64, u64),
+    used: &[(u64, u64)],
+    sector_size: u64,
+) -> Vec<FreeRegion> {
+    let align = (1024 * 1024 / sector_size).max(1);
+
+    free_gaps(first_usable, last_usable, used)
+        .into_iter()
+        .filter_map(|(first, last)| {
+            let start_lba = first.div_ceil(align) * align;
+            let sectors = (last + 1).checked_sub(start_lba)? / align * align;
+
+            if sectors == 0 {
+                return None;
+            }
+
+            Some(FreeRegion {
+                start_lba,
+                end_lba: start_lba + sectors - 1,
+                size_bytes: sectors * sector_size,
+            })
+        })
+        .collect()
+}
+
+/// Format the created partitions, `planned` is the partition number and plan of each of them
+/// `efi` is an existing ESP to keep
+fn format_created_partitions(
+    device_path: &Path,
+    sector_size: u64,
+    planned: &[(u32, &PlannedPartition)],
+    mut efi: Option<DkPartition>,
+) -> Result<AutoPartitions, PartitionError> {
     let mut system = None;
+    let mut data = None;
+    let mut swap = None;
 
-    for i in disk.parts() {
-        if i.num() < 0 {
-            continue;
-        }
+    // 慢速设备（如 USB SSD）上 udev 创建分区设备节点可能晚于分区表重新读取
+    udev_settle();
 
-        if i.get_flag(libparted::PartitionFlag::PED_PARTITION_ESP) {
-            let e = DkPartition {
-                path: i.get_path().map(|x| x.to_path_buf()),
-                parent_path: Some(device_path.to_path_buf()),
-                fs_type: Some("vfat".to_string()),
-                size: match i.geom_length() {
-                    ..=0 => 0,
-                    x @ 1.. => x as u64 * sector_size,
-                },
-            };
+    for (num, mut p) in find_created_partitions(device_path, sector_size)? {
+        let planned = match planned.iter().find(|(n, _)| *n as i32 == num) {
+            Some((_, planned)) => planned,
+            None => continue,
+        };
 
-            format_partition(&e)?;
-            efi = Some(e);
+        if let Some(ref path) = p.path {
+            wait_for_device_node(path)?;
+        }
 
+        // BIOS boot 分区不含文件系统，由 grub-install 直接写入
+        if planned.role == PartitionRole::BiosBoot {
+            wipe_signatures(&p)?;
             continue;
         }
 
-        let s = DkPartition {
-            path: i.get_path().map(|x| x.to_path_buf()),
-            parent_path: Some(device_path.to_path_buf()),
-            fs_type: Some("ext4".to_string()),
-            size: match i.geom_length() {
-                ..=0 => 0,
-                x @ 1.. => x as u64 * sector_size,
-            },
-        };
+        p.fs_type = Some(planned.fs_type.clone());
+        format_partition(&p)?;
 
-        format_partition(&s)?;
-        system = Some(s);
+        match planned.role {
+            PartitionRole::Efi => efi = Some(p),
+            PartitionRole::BiosBoot => {}
+            PartitionRole::System => system = Some(p),
+            PartitionRole::Data => data = Some(p),
+            PartitionRole::Swap => swap = Some(p),
+        }
     }
 
-    let efi = efi.ok_or_else(|| PartitionError::CreatePartition {
-        path: device_path.display().to_string(),
-        err: io::Error::new(
-            io::ErrorKind::NotFound,
-            "Failed to find created esp partition",
+    let check = |p: &Option<DkPartition>, role: PartitionRole| {
+        if p.is_none() && planned.iter().any(|(_, x)| x.role == role) {
+            return Err(not_found(device_path, role));
+        }
+
+        Ok(())
+    };
+
+    check(&efi, PartitionRole::Efi)?;
+    check(&data, PartitionRole::Data)?;
+    check(&swap, PartitionRole::Swap)?;
+
+    Ok(AutoPartitions {
+        efi,
+        system: system.ok_or_else(|| not_found(device_path, PartitionRole::System))?,
+        data,
+        swap,
+        removed_lvm: vec![],
+    })
+}
+
+/// 等待分区设备节点出现的最长时间
+const DEVICE_NODE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait for udev to process the events of the new partition table
+fn udev_settle() {
+    let res = Command::new("udevadm")
+        .arg("settle")
+        .arg(format!("--timeout={}", DEVICE_NODE_TIMEOUT.as_secs()))
+        .output();
+
+    match res {
+        Ok(out) if out.status.success() => {}
+        Ok(out) => warn!(
+            "udevadm settle failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
         ),
-    })?;
+        Err(e) => warn!("Failed to run udevadm settle: {e}"),
+    }
+}
+
+/// Wait until the device node of a created partition appears
+fn wait_for_device_node(path: &Path) -> Result<(), PartitionError> {
+    if !wait_for_path(path, DEVICE_NODE_TIMEOUT, Duration::from_millis(100)) {
+        return Err(PartitionError::DeviceNodeTimeout {
+            path: path.display().to_string(),
+            secs: DEVICE_NODE_TIMEOUT.as_secs(),
+        });
+    }
+
+    Ok(())
+}
+
+fn wait_for_path(path: &Path, timeout: Duration, interval: Duration) -> bool {
+    let start = Instant::now();
+
+    loop {
+        if path.exists() {
+            return true;
+        }
+
+        if start.elapsed() >= timeout {
+            return false;
+        }
+
+        debug!("Waiting for {} to appear", path.display());
+        thread::sleep(interval);
+    }
+}
 
-    let system: DkPartition = system.ok_or_else(|| PartitionError::CreatePartition {
+fn not_found(device_path: &Path, role: PartitionRole) -> PartitionError {
+    PartitionError::CreatePartition {
         path: device_path.display().to_string(),
         err: io::Error::new(
             io::ErrorKind::NotFound,
-            "Failed to find created system partition",
+            format!("Failed to find created {} partition", role.name()),
         ),
-    })?;
-
-    Ok((efi, system))
+    }
 }
 
-fn clear_start_sector(f: &mut fs::File, sector_size: u64) -> Result<(), PartitionError> {
-    f.seek(SeekFrom::Start(0))
-        .map_err(PartitionError::SeekSector)?;
-    let buf: Vec<u8> = vec![0; sector_size as usize];
-    f.write_all(&buf).map_err(PartitionError::ClearSector)?;
+/// Wipe the start of the device and write a new GPT, `layout` fills in partition entries
+/// Returns the sector size of the device
+fn create_gpt_table<F>(device_path: &Path, layout: F) -> Result<u64, PartitionError>
+where
+    F: FnOnce(&mut GPT, u64, u64) -> Result<(), PartitionError>,
+{
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .open(device_path)
+        .map_err(|e| PartitionError::OpenDevice {
+            path: device_path.display().to_string(),
+            err: e,
+        })?;
+
+    let sector_size = gptman::linux::get_sector_size(&mut f).map_err(PartitionError::GetTable)?;
+    clear_start_sector(&mut f, sector_size)?;
+
+    // 创建新的分区表
+    let mut gpt = GPT::new_from(&mut f, sector_size, generate_gpt_random_uuid())?;
+
+    // 写一个假的 MBR 保护分区头
+    GPT::write_protective_mbr_into(&mut f, sector_size).map_err(PartitionError::GptMan)?;
+
+    // 起始扇区为 1MiB 除以扇区大小
+    let starting_lba = 1024 * 1024 / sector_size;
+
+    layout(&mut gpt, sector_size, starting_lba)?;
+
+    // 应用分区表的修改
+    gpt.write_into(&mut f)?;
     f.sync_all().map_err(PartitionError::Flush)?;
 
-    Ok(())
+    // 重新读取分区表以读取刚刚的修改
+    gptman::linux::reread_partition_table(&mut f).map_err(PartitionError::GetTable)?;
+
+    // 关闭文件，之后由 find_created_partitions 重新打开设备读取分区表
+    drop(f);
+
+    Ok(sector_size)
 }
 
-pub fn auto_create_partitions_mbr(device_path: &Path) -> Result<DkPartition, PartitionError> {
+/// Wipe the start of the device and write a new MBR, `layout` fills in partition entries
+/// Returns the sector size of the device
+fn create_mbr_table<F>(device_path: &Path, layout: F) -> Result<u32, PartitionError>
+where
+    F: FnOnce(&mut MBR, u32) -> Result<(), PartitionError>,
+{
     let mut f = fs::OpenOptions::new()
         .write(true)
         .open(device_path)
@@ -386,129 +1615,873 @@ pub fn auto_create_partitions_mbr(device_path: &Path) -> Result<DkPartition, Par
     clear_start_sector(&mut f, sector_size as u64)?;
 
     let mut mbr = MBR::new_from(&mut f, sector_size, mbr_disk_signature())?;
-    let sectors = mbr.get_maximum_partition_size()?;
-    let starting_lba = mbr
-        .find_optimal_place(sectors)
-        .ok_or(PartitionError::GetOptimalPlace)?;
 
-    mbr[1] = mbrman::MBRPartitionEntry {
-        boot: mbrman::BOOT_INACTIVE,     // boot flag
-        first_chs: mbrman::CHS::empty(), // first CHS address (only useful for old computers)
-        sys: 0x83,                       // Linux filesystem
-        last_chs: mbrman::CHS::empty(),  // last CHS address (only useful for old computers)
-        starting_lba,                    // the sector where the partition starts
-        sectors,                         // the number of sectors in that partition
-    };
+    layout(&mut mbr, sector_size)?;
 
     mbr.write_into(&mut f)?;
+    f.sync_all().map_err(PartitionError::Flush)?;
+
+    // 重新读取分区表，以便内核创建分区设备
+    gptman::linux::reread_partition_table(&mut f).map_err(PartitionError::GetTable)?;
     drop(f);
 
-    // TODO: 自己实现设备路径寻找逻辑，彻底扔掉 libparted
-    let mut device =
-        libparted::Device::new(device_path).map_err(|e| PartitionError::OpenDevice {
-            path: device_path.display().to_string(),
-            err: e,
-        })?;
+    Ok(sector_size)
+}
 
-    let disk = Disk::new(&mut device).map_err(|e| PartitionError::OpenDisk {
+/// Find the number and path of every partition on the device
+/// 直接读取分区表，通过 sysfs 找到分区的设备节点
+fn find_created_partitions(
+    device_path: &Path,
+    sector_size: u64,
+) -> Result<Vec<(i32, DkPartition)>, PartitionError> {
+    let mut f = fs::File::open(device_path).map_err(|e| PartitionError::OpenDevice {
         path: device_path.display().to_string(),
         err: e,
     })?;
 
-    let part =
-        disk.parts()
-            .find(|x| x.num() > 0)
-            .ok_or_else(|| PartitionError::CreatePartition {
-                path: device_path.display().to_string(),
-                err: io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "Failed to find created system partition",
-                ),
-            })?;
+    let entries = table_entries(&mut f, sector_size)
+        .ok_or_else(|| PartitionError::UnsupportedTable(device_path.display().to_string()))?;
+
+    Ok(entries
+        .into_iter()
+        .map(|(num, sectors)| {
+            (
+                num as i32,
+                DkPartition {
+                    path: Some(partition_path(device_path, num)),
+                    parent_path: Some(device_path.to_path_buf()),
+                    size: sectors * sector_size,
+                    format: true,
+                    part_number: Some(num),
+                    ..Default::default()
+                },
+            )
+        })
+        .collect())
+}
 
-    let system = DkPartition {
-        path: part.get_path().map(|x| x.to_path_buf()),
-        parent_path: Some(device_path.to_path_buf()),
-        fs_type: Some("ext4".to_string()),
-        size: match part.geom_length() {
-            ..=0 => 0,
-            x @ 1.. => x as u64 * sector_size as u64,
-        },
-    };
+/// Number and size in sectors of every used entry of the GPT or MBR
+fn table_entries<R: Read + Seek>(f: &mut R, sector_size: u64) -> Option<Vec<(u32, u64)>> {
+    if let Ok(gpt) = GPT::read_from(f, sector_size) {
+        return Some(
+            gpt.iter()
+                .filter(|(_, p)| p.is_used())
+                .map(|(num, p)| (num, p.ending_lba - p.starting_lba + 1))
+                .collect(),
+        );
+    }
 
-    format_partition(&system)?;
+    let mbr = MBR::read_from(f, sector_size as u32).ok()?;
 
-    Ok(system)
+    Some(
+        mbr.iter()
+            .filter(|(_, p)| p.is_used())
+            .map(|(num, p)| (num as u32, p.sectors as u64))
+            .collect(),
+    )
 }
 
-fn generate_gpt_random_uuid() -> [u8; 16] {
-    rand::thread_rng().gen()
+/// Device node of partition `num` of a disk, e.g. /dev/sda1 or /dev/nvme0n1p1
+fn partition_path(device_path: &Path, num: u32) -> PathBuf {
+    // 设备路径可能是 /dev/disk/by-* 下的符号链接
+    let device_path = fs::canonicalize(device_path).unwrap_or_else(|_| device_path.to_path_buf());
+
+    sysfs_partition_path(&device_path, num)
+        .unwrap_or_else(|| partition_path_by_name(&device_path, num))
 }
 
-fn mbr_disk_signature() -> [u8; 4] {
-    rand::thread_rng().gen()
+/// Find the partition through /sys/class/block/<dev>/<part>/partition
+fn sysfs_partition_path(device_path: &Path, num: u32) -> Option<PathBuf> {
+    let name = device_path.file_name()?;
+
+    fs::read_dir(Path::new("/sys/class/block").join(name))
+        .ok()?
+        .flatten()
+        .find(|entry| {
+            fs::read_to_string(entry.path().join("partition"))
+                .is_ok_and(|x| x.trim().parse::<u32>() == Ok(num))
+        })
+        .map(|entry| Path::new("/dev").join(entry.file_name()))
 }
 
-#[cfg(debug_assertions)]
-fn gpt_partition(gpt: &mut GPT, efi_size: u64, sector_size: u64, starting_lba: u64) {
-    // 系统分区
-    // 所经历的扇区数为最后一个有用的扇区减去 efi 扇区
-    let sector = gpt.header.last_usable_lba - efi_size / sector_size;
+/// 名称以数字结尾的设备（nvme0n1、mmcblk0、loop0）在分区号前加 p
+fn partition_path_by_name(device_path: &Path, num: u32) -> PathBuf {
+    let mut path = device_path.as_os_str().to_owned();
 
-    // 需要取整以保证对齐，最终得到系统分区的末尾扇区
-    let mmod = sector % (1024 * 1024 / sector_size);
-    let system_ending_lba = sector - mmod + starting_lba - 1;
+    if path
+        .to_string_lossy()
+        .ends_with(|c: char| c.is_ascii_digit())
+    {
+        path.push("p");
+    }
 
-    gpt[1] = gptman::GPTPartitionEntry {
-        partition_type_guid: LINUX_FS.to_bytes_le(),
-        unique_partition_guid: generate_gpt_random_uuid(),
-        starting_lba,
-        ending_lba: system_ending_lba,
-        attribute_bits: 0,
-        partition_name: "".into(),
-    };
+    path.push(num.to_string());
 
-    let efi_starting_lba = system_ending_lba + 1;
+    PathBuf::from(path)
+}
 
-    let mmod = (gpt.header.last_usable_lba - efi_starting_lba) % (1024 * 1024 / sector_size);
-    let ending_lba = gpt.header.last_usable_lba - mmod - 1;
+fn clear_start_sector(f: &mut fs::File, sector_size: u64) -> Result<(), PartitionError> {
+    f.seek(SeekFrom::Start(0))
+        .map_err(PartitionError::SeekSector)?;
+    let buf: Vec<u8> = vec![0; sector_size as usize];
+    f.write_all(&buf).map_err(PartitionError::ClearSector)?;
+    f.sync_all().map_err(PartitionError::Flush)?;
 
-    // EFI 分区
-    gpt[2] = gptman::GPTPartitionEntry {
-        partition_type_guid: EFI.to_bytes_le(),
-        unique_partition_guid: generate_gpt_random_uuid(),
-        starting_lba: efi_starting_lba,
-        ending_lba,
-        attribute_bits: 0,
-        partition_name: "".into(),
-    };
+    Ok(())
 }
 
-#[cfg(not(debug_assertions))]
-fn gpt_partition(gpt: &mut GPT, efi_size: u64, sector_size: u64, starting_lba: u64) {
-    let efi_ending_lba = efi_size / sector_size + starting_lba - 1;
-    gpt[1] = gptman::GPTPartitionEntry {
-        partition_type_guid: EFI.to_bytes_le(),
-        unique_partition_guid: generate_gpt_random_uuid(),
-        starting_lba,
-        ending_lba: efi_ending_lba,
-        attribute_bits: 0,
-        partition_name: "".into(),
-    };
+fn mbr_partition(sys: u8, starting_lba: u32, sectors: u32) -> mbrman::MBRPartitionEntry {
+    mbrman::MBRPartitionEntry {
+        boot: mbrman::BOOT_INACTIVE,     // boot flag
+        first_chs: mbrman::CHS::empty(), // first CHS address (only useful for old computers)
+        sys,                             // 0x83: Linux filesystem, 0x82: Linux swap
+        last_chs: mbrman::CHS::empty(),  // last CHS address (only useful for old computers)
+        starting_lba,                    // the sector where the partition starts
+        sectors,                         // the number of sectors in that partition
+    }
+}
 
-    let system_starting_lba = efi_ending_lba + 1;
+fn generate_gpt_random_uuid() -> [u8; 16] {
+    rand::thread_rng().gen()
+}
 
-    let mmod = (gpt.header.last_usable_lba - system_starting_lba) % (1024 * 1024 / sector_size);
-    let ending_lba = gpt.header.last_usable_lba - mmod - 1;
+fn mbr_disk_signature() -> [u8; 4] {
+    rand::thread_rng().gen()
+}
 
-    gpt[2] = gptman::GPTPartitionEntry {
-        partition_type_guid: LINUX_FS.to_bytes_le(),
-        unique_partition_guid: generate_gpt_random_uuid(),
-        starting_lba: system_starting_lba,
-        ending_lba,
-        attribute_bits: 0,
-        partition_name: "".into(),
+/// Flags accepted by [`create_partition`]
+const PARTITION_FLAGS: &[&str] = &["esp", "boot"];
+
+/// GPT attribute bit 2: Legacy BIOS bootable
+const GPT_LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+
+/// Create a partition of `size` bytes at `start` in the free space of a disk and format it
+/// `start` is rounded up and `size` is rounded down to 1MiB
+/// The kernel must be able to reread the partition table, so no partition of the disk can be in use
+pub fn create_partition(
+    device_path: &Path,
+    start: u64,
+    size: u64,
+    fs_type: &str,
+    flags: &[String],
+) -> Result<DkPartition, PartitionError> {
+    check_new_partition(fs_type, flags)?;
+    // 分区表写入后才发现设备忙（无法重读分区表）会使内核与磁盘上的分区表不一致
+    check_device_not_in_use(device_path)?;
+
+    let is_esp = flags.iter().any(|x| x == "esp");
+    let is_boot = flags.iter().any(|x| x == "boot");
+
+    let mut f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device_path)
+        .map_err(|e| PartitionError::OpenDevice {
+            path: device_path.display().to_string(),
+            err: e,
+        })?;
+
+    let sector_size = gptman::linux::get_sector_size(&mut f).map_err(PartitionError::GetTable)?;
+    let table =
+        get_partition_table_type(device_path).map_err(|e| PartitionError::GetPartitionType {
+            path: device_path.display().to_string(),
+            err: e,
+        })?;
+
+    let num = match table.as_str() {
+        "gpt" => {
+            let mut gpt = GPT::read_from(&mut f, sector_size)?;
+            let used = gpt
+                .iter()
+                .filter(|(_, p)| p.is_used())
+                .map(|(i, p)| (i, p.starting_lba, p.ending_lba))
+                .collect::<Vec<_>>();
+
+            let (starting_lba, ending_lba) = new_partition_range(
+                device_path,
+                start,
+                size,
+                sector_size,
+                (gpt.header.first_usable_lba, gpt.header.last_usable_lba),
+                &used,
+            )?;
+
+            let num = gpt
+                .iter()
+                .find(|(_, p)| p.is_unused())
+                .map(|(i, _)| i)
+                .ok_or_else(|| PartitionError::NoFreeSlot {
+                    path: device_path.display().to_string(),
+                })?;
+
+            let partition_type = if is_esp {
+                EFI
+            } else if fs_type == "swap" {
+                LINUX_SWAP
+            } else {
+                LINUX_FS
+            };
+
+            gpt[num] = gptman::GPTPartitionEntry {
+                partition_type_guid: partition_type.to_bytes_le(),
+                unique_partition_guid: generate_gpt_random_uuid(),
+                starting_lba,
+                ending_lba,
+                attribute_bits: if is_boot { GPT_LEGACY_BIOS_BOOTABLE } else { 0 },
+                partition_name: "".into(),
+            };
+
+            gpt.write_into(&mut f)?;
+
+            num
+        }
+        "msdos" => {
+            let mut mbr = MBR::read_from(&mut f, sector_size as u32)?;
+            let used = mbr
+                .iter()
+                .filter(|(_, p)| p.is_used())
+                .map(|(i, p)| {
+                    (
+                        i as u32,
+                        p.starting_lba as u64,
+                        p.starting_lba as u64 + p.sectors as u64 - 1,
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            let (starting_lba, ending_lba) = new_partition_range(
+                device_path,
+                start,
+                size,
+                sector_size,
+                (1, (mbr.disk_size as u64).saturating_sub(1)),
+                &used,
+            )?;
+
+            // 只创建主分区
+            let num = (1..=4).find(|i| mbr[*i].is_unused()).ok_or_else(|| {
+                PartitionError::NoFreeSlot {
+                    path: device_path.display().to_string(),
+                }
+            })?;
+
+            let no_space = || PartitionError::NoFreeSpace {
+                path: device_path.display().to_string(),
+                start,
+                size,
+            };
+
+            let starting_lba = u32::try_from(starting_lba).map_err(|_| no_space())?;
+            let sectors =
+                u32::try_from(ending_lba - starting_lba as u64 + 1).map_err(|_| no_space())?;
+
+            let sys = if is_esp {
+                0xef
+            } else if fs_type == "swap" {
+                0x82
+            } else {
+                0x83
+            };
+
+            let mut entry = mbr_partition(sys, starting_lba, sectors);
+            if is_boot {
+                entry.boot = mbrman::BOOT_ACTIVE;
+            }
+
+            mbr[num] = entry;
+            mbr.write_into(&mut f)?;
+
+            num as u32
+        }
+        t => return Err(PartitionError::UnsupportedTable(t.to_string())),
+    };
+
+    f.sync_all().map_err(PartitionError::Flush)?;
+    gptman::linux::reread_partition_table(&mut f).map_err(PartitionError::GetTable)?;
+    drop(f);
+
+    let mut p = find_created_partitions(device_path, sector_size)?
+        .into_iter()
+        .find(|(n, _)| *n as u32 == num)
+        .map(|(_, p)| p)
+        .ok_or_else(|| PartitionError::CreatePartition {
+            path: device_path.display().to_string(),
+            err: io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Failed to find created partition {num}"),
+            ),
+        })?;
+
+    p.fs_type = Some(fs_type.to_string());
+    format_partition(&p)?;
+
+    Ok(p)
+}
+
+/// MBR partition types of extended partitions
+const MBR_EXTENDED_TYPES: &[u8] = &[0x05, 0x0f, 0x85];
+
+/// Delete partition `num` of a disk and reread the partition table
+/// Refuses to delete if any partition of the disk is mounted or an active swap
+/// Only primary partitions can be deleted from MBR
+pub fn delete_partition(device_path: &Path, num: u32) -> Result<(), PartitionError> {
+    let not_found = || PartitionError::PartitionNotFound {
+        path: device_path.display().to_string(),
+        num,
+    };
+
+    let mut f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device_path)
+        .map_err(|e| PartitionError::OpenDevice {
+            path: device_path.display().to_string(),
+            err: e,
+        })?;
+
+    let sector_size = gptman::linux::get_sector_size(&mut f).map_err(PartitionError::GetTable)?;
+
+    let part_path = find_created_partitions(device_path, sector_size)?
+        .into_iter()
+        .find(|(n, _)| *n as u32 == num)
+        .and_then(|(_, p)| p.path)
+        .ok_or_else(not_found)?;
+
+    check_partition_not_in_use(&part_path)?;
+    // 其他分区在使用中时内核无法重读分区表，须在写入前检查
+    check_device_not_in_use(device_path)?;
+
+    let table =
+        get_partition_table_type(device_path).map_err(|e| PartitionError::GetPartitionType {
+            path: device_path.display().to_string(),
+            err: e,
+        })?;
+
+    match table.as_str() {
+        "gpt" => {
+            let mut gpt = GPT::read_from(&mut f, sector_size)?;
+
+            if num == 0 || num > gpt.header.number_of_partition_entries || gpt[num].is_unused() {
+                return Err(not_found());
+            }
+
+            gpt[num] = gptman::GPTPartitionEntry::empty();
+            gpt.write_into(&mut f)?;
+        }
+        "msdos" => {
+            let mut mbr = MBR::read_from(&mut f, sector_size as u32)?;
+            let i = num as usize;
+
+            if !(1..=4).contains(&i) {
+                return Err(PartitionError::InvalidPartitionOptions(format!(
+                    "partition {num} is a logical partition, only primary partitions can be deleted"
+                )));
+            }
+
+            if mbr[i].is_unused() {
+                return Err(not_found());
+            }
+
+            // 删除扩展分区会连带删除其中的逻辑分区
+            if MBR_EXTENDED_TYPES.contains(&mbr[i].sys) && !mbr.logical_partitions.is_empty() {
+                return Err(PartitionError::InvalidPartitionOptions(format!(
+                    "extended partition {num} still contains logical partitions"
+                )));
+            }
+
+            mbr[i] = mbrman::MBRPartitionEntry::empty();
+            mbr.write_into(&mut f)?;
+        }
+        t => return Err(PartitionError::UnsupportedTable(t.to_string())),
+    }
+
+    f.sync_all().map_err(PartitionError::Flush)?;
+    gptman::linux::reread_partition_table(&mut f).map_err(PartitionError::GetTable)?;
+
+    info!("Deleted partition {num} of {}", device_path.display());
+
+    Ok(())
+}
+
+/// Error if the partition is mounted or used as swap
+fn check_partition_not_in_use(part_path: &Path) -> Result<(), PartitionError> {
+    let target = fs::canonicalize(part_path).unwrap_or_else(|_| part_path.to_path_buf());
+    let in_use = |used_by: String| PartitionError::PartitionInUse {
+        path: part_path.display().to_string(),
+        used_by,
+    };
+
+    for i in read_mounts().map_err(PartitionError::ReadMounts)? {
+        if fs::canonicalize(&i.source).unwrap_or(i.source) == target {
+            return Err(in_use(i.mount_point.display().to_string()));
+        }
+    }
+
+    for i in active_swaps()? {
+        if fs::canonicalize(&i.path).unwrap_or(i.path) == target {
+            return Err(in_use("swap".to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Filesystems that [`shrink_partition`] can shrink
+const SHRINKABLE_FS_TYPES: &[&str] = &["ext2", "ext3", "ext4", "ntfs"];
+
+/// Shrink a partition and its filesystem to `new_size` bytes (rounded down to 1MiB)
+/// The filesystem is shrunk first, then the end of the partition is moved, the start is kept
+/// Only ext2/3/4 and NTFS are supported, no partition of the disk may be in use
+/// The returned partition keeps its data, i.e. `format` is `false`
+/// Only primary partitions can be shrunk on MBR
+pub fn shrink_partition(part_path: &Path, new_size: u64) -> Result<DkPartition, PartitionError> {
+    let part_path = fs::canonicalize(part_path).unwrap_or_else(|_| part_path.to_path_buf());
+    let err = |reason: String| PartitionError::ShrinkPartition {
+        path: part_path.display().to_string(),
+        reason,
+    };
+
+    let device_path =
+        parent_disk(&part_path).ok_or_else(|| err("not a partition of a disk".to_string()))?;
+    let num = partition_number(&part_path)
+        .ok_or_else(|| err("failed to read partition number".to_string()))?;
+
+    check_partition_not_in_use(&part_path)?;
+    // 文件系统缩小后才写入分区表，须先确认内核能够重读分区表
+    check_device_not_in_use(&device_path)?;
+
+    let fs_type = probe_fs_type(&part_path)
+        .ok_or_else(|| err("failed to probe the filesystem".to_string()))?;
+
+    if !SHRINKABLE_FS_TYPES.contains(&fs_type.as_str()) {
+        return Err(err(format!(
+            "shrinking {fs_type} is not supported, supported: {}",
+            SHRINKABLE_FS_TYPES.join(", ")
+        )));
+    }
+
+    let mut f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&device_path)
+        .map_err(|e| PartitionError::OpenDevice {
+            path: device_path.display().to_string(),
+            err: e,
+        })?;
+
+    let sector_size = gptman::linux::get_sector_size(&mut f).map_err(PartitionError::GetTable)?;
+    let table =
+        get_partition_table_type(&device_path).map_err(|e| PartitionError::GetPartitionType {
+            path: device_path.display().to_string(),
+            err: e,
+        })?;
+
+    let mut gpt = None;
+    let mut mbr = None;
+
+    let (starting_lba, ending_lba) = match table.as_str() {
+        "gpt" => {
+            let t = GPT::read_from(&mut f, sector_size)?;
+            if num == 0 || num > t.header.number_of_partition_entries || t[num].is_unused() {
+                return Err(err(format!(
+                    "partition {num} is not in the partition table"
+                )));
+            }
+
+            let range = (t[num].starting_lba, t[num].ending_lba);
+            gpt = Some(t);
+            range
+        }
+        "msdos" => {
+            let t = MBR::read_from(&mut f, sector_size as u32)?;
+            let i = num as usize;
+            if !(1..=4).contains(&i) || t[i].is_unused() {
+                return Err(err(format!(
+                    "partition {num} is not a primary partition, only primary partitions can be shrunk"
+                )));
+            }
+
+            let range = (
+                t[i].starting_lba as u64,
+                t[i].starting_lba as u64 + t[i].sectors as u64 - 1,
+            );
+            mbr = Some(t);
+            range
+        }
+        t => return Err(PartitionError::UnsupportedTable(t.to_string())),
+    };
+
+    let new_ending_lba =
+        shrunk_ending_lba(starting_lba, ending_lba, sector_size, new_size).map_err(err)?;
+    let new_size = (new_ending_lba - starting_lba + 1) * sector_size;
+
+    let min_size = fs_min_size(&part_path, &fs_type)?;
+    if new_size < min_size {
+        return Err(err(format!(
+            "{new_size} bytes is smaller than the minimum size of the filesystem: {min_size} bytes"
+        )));
+    }
+
+    info!(
+        "Shrinking {} ({fs_type}) to {new_size} bytes",
+        part_path.display()
+    );
+
+    // 先缩小文件系统，再修改分区表
+    resize_fs(&part_path, &fs_type, new_size)?;
+
+    let write_table = |f: &mut fs::File| -> Result<(), PartitionError> {
+        if let Some(mut gpt) = gpt {
+            gpt[num].ending_lba = new_ending_lba;
+            gpt.write_into(f)?;
+        } else if let Some(mut mbr) = mbr {
+            mbr[num as usize].sectors = u32::try_from(new_ending_lba - starting_lba + 1)
+                .map_err(|_| err("partition is too large for MBR".to_string()))?;
+            mbr.write_into(f)?;
+        }
+
+        f.sync_all().map_err(PartitionError::Flush)
     };
+
+    if let Err(e) = write_table(&mut f) {
+        // 分区表未能修改，将文件系统恢复到原来的大小
+        let old_size = (ending_lba - starting_lba + 1) * sector_size;
+        warn!(
+            "Failed to write the partition table, growing {} back to {old_size} bytes",
+            part_path.display()
+        );
+
+        if let Err(e) = resize_fs(&part_path, &fs_type, old_size) {
+            warn!("Failed to grow {} back: {e}", part_path.display());
+        }
+
+        return Err(e);
+    }
+
+    // 磁盘已确认不在使用中；即使重读失败，缩小后的文件系统也仍在新旧分区范围之内
+    gptman::linux::reread_partition_table(&mut f).map_err(PartitionError::GetTable)?;
+
+    // 缩小分区是为了保留其中的数据，不应格式化
+    Ok(DkPartition {
+        path: Some(part_path.clone()),
+        parent_path: Some(device_path),
+        fs_type: Some(fs_type),
+        size: new_size,
+        ..Default::default()
+    }
+    .with_identity(num as i32))
+}
+
+/// Shrink partition `num` of `device_path` and its filesystem to `new_size` bytes
+/// See [`shrink_partition`], the partition table is not touched if shrinking the filesystem fails,
+/// and the filesystem is grown back if writing the partition table fails
+pub fn resize_partition(
+    device_path: &Path,
+    num: u32,
+    new_size: u64,
+) -> Result<DkPartition, PartitionError> {
+    let mut f = fs::File::open(device_path).map_err(|e| PartitionError::OpenDevice {
+        path: device_path.display().to_string(),
+        err: e,
+    })?;
+
+    let sector_size = gptman::linux::get_sector_size(&mut f).map_err(PartitionError::GetTable)?;
+    drop(f);
+
+    let part_path = find_created_partitions(device_path, sector_size)?
+        .into_iter()
+        .find(|(n, _)| *n as u32 == num)
+        .and_then(|(_, p)| p.path)
+        .ok_or_else(|| PartitionError::PartitionNotFound {
+            path: device_path.display().to_string(),
+            num,
+        })?;
+
+    shrink_partition(&part_path, new_size)
+}
+
+/// New ending sector of a partition from `starting_lba` shrunk to `new_size` bytes
+/// The new size is rounded down to 1MiB and must be smaller than the current one
+fn shrunk_ending_lba(
+    starting_lba: u64,
+    ending_lba: u64,
+    sector_size: u64,
+    new_size: u64,
+) -> Result<u64, String> {
+    let align = (1024 * 1024 / sector_size).max(1);
+    let sectors = new_size / sector_size / align * align;
+    let cur_sectors = ending_lba - starting_lba + 1;
+
+    if sectors == 0 {
+        return Err(format!("new size is too small: {new_size}"));
+    }
+
+    if sectors >= cur_sectors {
+        return Err(format!(
+            "new size {} must be smaller than the current size {}",
+            sectors * sector_size,
+            cur_sectors * sector_size
+        ));
+    }
+
+    Ok(starting_lba + sectors - 1)
+}
+
+/// Partition number of a partition through sysfs
+fn partition_number(part_path: &Path) -> Option<u32> {
+    let name = part_path.file_name()?;
+    let num =
+        fs::read_to_string(Path::new("/sys/class/block").join(name).join("partition")).ok()?;
+
+    num.trim().parse().ok()
+}
+
+/// Filesystem type of a partition, read from the device by blkid
+fn probe_fs_type(part_path: &Path) -> Option<String> {
+    let output = Command::new("blkid")
+        .args(["-p", "-s", "TYPE", "-o", "value"])
+        .arg(part_path)
+        .output()
+        .ok()?;
+
+    let fs_type = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    (output.status.success() && !fs_type.is_empty()).then_some(fs_type)
+}
+
+/// Run a filesystem tool, returns stdout
+fn run_fs_tool(cmd: &mut Command, part_path: &Path) -> Result<String, PartitionError> {
+    info!("{cmd:?}");
+
+    let err = |e: io::Error| PartitionError::ResizeFilesystem {
+        path: part_path.display().to_string(),
+        err: e,
+    };
+
+    let output = cmd.output().map_err(err)?;
+
+    if !output.status.success() {
+        return Err(err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "{} {}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Minimum size in bytes the filesystem can be shrunk to
+fn fs_min_size(part_path: &Path, fs_type: &str) -> Result<u64, PartitionError> {
+    let parse_err = |tool: &str| PartitionError::ShrinkPartition {
+        path: part_path.display().to_string(),
+        reason: format!("failed to parse the output of {tool}"),
+    };
+
+    if fs_type == "ntfs" {
+        let output = run_fs_tool(
+            Command::new("ntfsresize")
+                .args(["--info", "--force", "--no-progress-bar"])
+                .arg(part_path),
+            part_path,
+        )?;
+
+        return parse_ntfsresize_min_size(&output).ok_or_else(|| parse_err("ntfsresize"));
+    }
+
+    // resize2fs 要求先检查文件系统，退出码 1 表示已修复错误
+    let status = Command::new("e2fsck")
+        .args(["-f", "-p"])
+        .arg(part_path)
+        .status()
+        .map_err(|e| PartitionError::ResizeFilesystem {
+            path: part_path.display().to_string(),
+            err: e,
+        })?;
+
+    if !matches!(status.code(), Some(0 | 1)) {
+        return Err(PartitionError::ShrinkPartition {
+            path: part_path.display().to_string(),
+            reason: format!("e2fsck failed: {status}"),
+        });
+    }
+
+    let blocks = run_fs_tool(
+        Command::new("resize2fs").arg("-P").arg(part_path),
+        part_path,
+    )?;
+    let header = run_fs_tool(Command::new("dumpe2fs").arg("-h").arg(part_path), part_path)?;
+
+    let blocks = parse_resize2fs_min_blocks(&blocks).ok_or_else(|| parse_err("resize2fs"))?;
+    let block_size = parse_dumpe2fs_block_size(&header).ok_or_else(|| parse_err("dumpe2fs"))?;
+
+    Ok(blocks * block_size)
+}
+
+fn resize_fs(part_path: &Path, fs_type: &str, new_size: u64) -> Result<(), PartitionError> {
+    if fs_type == "ntfs" {
+        // 先试运行一次，确认可以缩小
+        run_fs_tool(
+            Command::new("ntfsresize")
+                .args(["--no-action", "--force", "--no-progress-bar", "--size"])
+                .arg(new_size.to_string())
+                .arg(part_path),
+            part_path,
+        )?;
+
+        let mut child = Command::new("ntfsresize")
+            .args(["--force", "--no-progress-bar", "--size"])
+            .arg(new_size.to_string())
+            .arg(part_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| PartitionError::ResizeFilesystem {
+                path: part_path.display().to_string(),
+                err: e,
+            })?;
+
+        // 回答 ntfsresize 的确认提示
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(b"y\n").ok();
+        }
+
+        let output = child
+            .wait_with_output()
+            .map_err(|e| PartitionError::ResizeFilesystem {
+                path: part_path.display().to_string(),
+                err: e,
+            })?;
+
+        if !output.status.success() {
+            return Err(PartitionError::ResizeFilesystem {
+                path: part_path.display().to_string(),
+                err: io::Error::new(
+                    io::ErrorKind::Other,
+                    String::from_utf8_lossy(&output.stderr).to_string(),
+                ),
+            });
+        }
+
+        return Ok(());
+    }
+
+    run_fs_tool(
+        Command::new("resize2fs")
+            .arg(part_path)
+            .arg(format!("{}K", new_size / 1024)),
+        part_path,
+    )?;
+
+    Ok(())
+}
+
+/// Parse `You might resize at 123456789 bytes or ...` printed by `ntfsresize --info`
+fn parse_ntfsresize_min_size(output: &str) -> Option<u64> {
+    output.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("You might resize at ")?
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    })
+}
+
+/// Parse `Estimated minimum size of the filesystem: 12345` printed by `resize2fs -P`
+fn parse_resize2fs_min_blocks(output: &str) -> Option<u64> {
+    output.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("Estimated minimum size of the filesystem:")?
+            .trim()
+            .parse()
+            .ok()
+    })
+}
+
+/// Parse `Block size: 4096` printed by `dumpe2fs -h`
+fn parse_dumpe2fs_block_size(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("Block size:")?.trim().parse().ok())
+}
+
+fn check_new_partition(fs_type: &str, flags: &[String]) -> Result<(), PartitionError> {
+    if !SUPPORTED_FS_TYPES.contains(&fs_type) && fs_type != "vfat" && fs_type != "swap" {
+        return Err(PartitionError::InvalidPartitionOptions(format!(
+            "unsupported filesystem: {fs_type}"
+        )));
+    }
+
+    for flag in flags {
+        if !PARTITION_FLAGS.contains(&flag.as_str()) {
+            return Err(PartitionError::InvalidPartitionOptions(format!(
+                "unsupported flag: {flag}"
+            )));
+        }
+    }
+
+    if flags.iter().any(|x| x == "esp") && fs_type != "vfat" {
+        return Err(PartitionError::InvalidPartitionOptions(
+            "ESP must be formatted as vfat".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Sector range (first, last) of a new partition, aligned to 1MiB
+/// `usable` is the (first, last) usable sector, `used` is (number, first, last) of existing partitions
+fn new_partition_range(
+    device_path: &Path,
+    start: u64,
+    size: u64,
+    sector_size: u64,
+    usable: (u64, u64),
+    used: &[(u32, u64, u64)],
+) -> Result<(u64, u64), PartitionError> {
+    let align = (1024 * 1024 / sector_size).max(1);
+    let (first_usable, last_usable) = usable;
+
+    let starting_lba = start
+        .div_ceil(sector_size)
+        .max(first_usable)
+        .div_ceil(align)
+        * align;
+    let sectors = size / sector_size / align * align;
+
+    if sectors == 0 {
+        return Err(PartitionError::InvalidPartitionOptions(format!(
+            "partition size is too small: {size}"
+        )));
+    }
+
+    let ending_lba = starting_lba + sectors - 1;
+
+    if ending_lba > last_usable {
+        return Err(PartitionError::NoFreeSpace {
+            path: device_path.display().to_string(),
+            start,
+            size,
+        });
+    }
+
+    // 新分区不能与已有分区重叠
+    if let Some((num, _, _)) = used
+        .iter()
+        .find(|(_, first, last)| *first <= ending_lba && starting_lba <= *last)
+    {
+        return Err(PartitionError::PartitionOverlap {
+            path: device_path.display().to_string(),
+            num: *num,
+        });
+    }
+
+    Ok((starting_lba, ending_lba))
 }
 
 pub fn all_esp_partitions() -> Result<Vec<DkPartition>, PartitionError> {
@@ -549,19 +2522,24 @@ pub fn all_esp_partitions() -> Result<Vec<DkPartition>, PartitionError> {
                 let part = disk.get_partition_by_sector(lba as i64);
 
                 if let Some(mut part) = part {
-                    res.push(DkPartition {
-                        path: part.get_path().map(|x| x.to_path_buf()),
-                        parent_path: Some(path),
-                        fs_type: part
-                            .get_geom()
-                            .probe_fs()
-                            .ok()
-                            .map(|x| x.name().to_string()),
-                        size: match part.geom_length() {
-                            ..=0 => 0,
-                            x @ 1.. => x as u64 * sector_size,
-                        },
-                    });
+                    res.push(
+                        DkPartition {
+                            path: part.get_path().map(|x| x.to_path_buf()),
+                            parent_path: Some(path),
+                            fs_type: part
+                                .get_geom()
+                                .probe_fs()
+                                .ok()
+                                .map(|x| x.name().to_string()),
+                            size: match part.geom_length() {
+                                ..=0 => 0,
+                                x @ 1.. => x as u64 * sector_size,
+                            },
+                            format: true,
+                            ..Default::default()
+                        }
+                        .with_identity(part.num()),
+                    );
                 }
             }
         }
@@ -571,24 +2549,22 @@ pub fn all_esp_partitions() -> Result<Vec<DkPartition>, PartitionError> {
 }
 
 pub fn find_root_mount_point() -> Result<String, PartitionError> {
-    let f = fs::File::open("/proc/mounts").map_err(PartitionError::ReadMounts)?;
-    let lines = BufReader::new(f).lines();
+    let mounts = read_mounts().map_err(PartitionError::ReadMounts)?;
 
     let mut match_livemnt = None;
     let mut match_rootfs = None;
 
-    for i in lines.map_while(Result::ok) {
+    for i in mounts {
         if match_livemnt.is_some() && match_rootfs.is_some() {
             break;
         }
 
-        let i = i.split_ascii_whitespace().collect::<Vec<_>>();
-        if i[1] == "/" {
+        if i.mount_point == Path::new("/") {
             // Livekit
-            match_rootfs = Some(i[0].to_string());
-        } else if i[1] == "/run/livekit/livemnt" {
+            match_rootfs = Some(i.source.to_string_lossy().to_string());
+        } else if i.mount_point == Path::new("/run/livekit/livemnt") {
             // Installer
-            match_livemnt = Some(i[0].to_string());
+            match_livemnt = Some(i.source.to_string_lossy().to_string());
         }
     }
 
@@ -603,3 +2579,1249 @@ pub fn find_root_mount_point() -> Result<String, PartitionError> {
         "Failed to read /proc/mounts",
     )))
 }
+
+// MBR、GPT 主分区表及备份分区表均在磁盘首尾 1MiB 以内
+const TABLE_SNAPSHOT_SIZE: u64 = 1024 * 1024;
+
+/// Magic of a partition table backup file written by [`TableSnapshot::save_backup`]
+const TABLE_BACKUP_MAGIC: &[u8; 8] = b"DKTABLE1";
+
+/// Raw copy of the head and tail of a disk, which contain the partition table
+/// Restoring it brings back the previous partitions, but not the data overwritten by mkfs
+#[derive(Debug, Clone)]
+pub struct TableSnapshot {
+    pub device_path: PathBuf,
+    disk_size: u64,
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl TableSnapshot {
+    pub fn capture(device_path: &Path) -> Result<Self, PartitionError> {
+        let err = |e: io::Error| PartitionError::TableSnapshot {
+            path: device_path.display().to_string(),
+            err: e,
+        };
+
+        let mut f = fs::File::open(device_path).map_err(err)?;
+        let disk_size = f.seek(SeekFrom::End(0)).map_err(err)?;
+        let len = TABLE_SNAPSHOT_SIZE.min(disk_size);
+
+        let mut head = vec![0; len as usize];
+        f.seek(SeekFrom::Start(0)).map_err(err)?;
+        f.read_exact(&mut head).map_err(err)?;
+
+        let mut tail = vec![0; len as usize];
+        f.seek(SeekFrom::Start(disk_size - len)).map_err(err)?;
+        f.read_exact(&mut tail).map_err(err)?;
+
+        Ok(Self {
+            device_path: device_path.to_path_buf(),
+            disk_size,
+            head,
+            tail,
+        })
+    }
+
+    pub fn restore(&self) -> Result<(), PartitionError> {
+        let err = |e: io::Error| PartitionError::RestoreTable {
+            path: self.device_path.display().to_string(),
+            err: e,
+        };
+
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .open(&self.device_path)
+            .map_err(err)?;
+
+        // 防止写到另一块磁盘上
+        if f.seek(SeekFrom::End(0)).map_err(err)? != self.disk_size {
+            return Err(err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "disk size has changed since the snapshot",
+            )));
+        }
+
+        f.seek(SeekFrom::Start(0)).map_err(err)?;
+        f.write_all(&self.head).map_err(err)?;
+        f.seek(SeekFrom::Start(self.disk_size - self.tail.len() as u64))
+            .map_err(err)?;
+        f.write_all(&self.tail).map_err(err)?;
+        f.sync_all().map_err(PartitionError::Flush)?;
+
+        gptman::linux::reread_partition_table(&mut f).map_err(PartitionError::GetTable)?;
+
+        Ok(())
+    }
+
+    /// Write the snapshot to `/tmp/deploykit-table-backup-<dev>-<timestamp>.bin`
+    /// Returns the path of the backup file
+    pub fn save_backup(&self) -> Result<PathBuf, PartitionError> {
+        let name = self
+            .device_path
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let path =
+            std::env::temp_dir().join(format!("deploykit-table-backup-{name}-{timestamp}.bin"));
+
+        fs::write(&path, self.to_bytes()).map_err(|e| PartitionError::TableBackup {
+            path: path.display().to_string(),
+            err: e,
+        })?;
+
+        info!(
+            "Backed up partition table of {} to {}",
+            self.device_path.display(),
+            path.display()
+        );
+
+        Ok(path)
+    }
+
+    /// Read a backup file written by [`TableSnapshot::save_backup`], to be restored to `device_path`
+    pub fn load_backup(backup_path: &Path, device_path: &Path) -> Result<Self, PartitionError> {
+        let err = |e: io::Error| PartitionError::RestoreTable {
+            path: device_path.display().to_string(),
+            err: e,
+        };
+
+        let bytes = fs::read(backup_path).map_err(err)?;
+
+        Self::from_bytes(&bytes, device_path).ok_or_else(|| {
+            err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a partition table backup", backup_path.display()),
+            ))
+        })
+    }
+
+    /// 格式：魔数、磁盘大小、首尾各自的长度（均为小端 u64）、磁盘首部、磁盘尾部
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(24 + self.head.len() + self.tail.len());
+        bytes.extend_from_slice(TABLE_BACKUP_MAGIC);
+        bytes.extend_from_slice(&self.disk_size.to_le_bytes());
+        bytes.extend_from_slice(&(self.head.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.head);
+        bytes.extend_from_slice(&self.tail);
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8], device_path: &Path) -> Option<Self> {
+        let read_u64 = |pos: usize| -> Option<u64> {
+            Some(u64::from_le_bytes(
+                bytes.get(pos..pos + 8)?.try_into().ok()?,
+            ))
+        };
+
+        if bytes.get(..8)? != TABLE_BACKUP_MAGIC {
+            return None;
+        }
+
+        let disk_size = read_u64(8)?;
+        let len = usize::try_from(read_u64(16)?).ok()?;
+        let data = bytes.get(24..)?;
+
+        if len as u64 > disk_size || data.len() != len.checked_mul(2)? {
+            return None;
+        }
+
+        Some(Self {
+            device_path: device_path.to_path_buf(),
+            disk_size,
+            head: data[..len].to_vec(),
+            tail: data[len..].to_vec(),
+        })
+    }
+}
+
+/// Fail with [`PartitionError::DeviceInUse`] if the disk or any of its partitions is mounted
+/// or used as swap
+pub fn check_device_not_in_use(device_path: &Path) -> Result<(), PartitionError> {
+    let mounts = read_mounts().map_err(PartitionError::ReadMounts)?;
+    let swaps = active_swaps()?;
+
+    let mounted_points = find_mounted_points(device_path, &mounts, &swaps, |p| {
+        let p = fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf());
+        let parent = parent_disk(&p);
+
+        (p, parent)
+    });
+
+    if !mounted_points.is_empty() {
+        return Err(PartitionError::DeviceInUse {
+            path: device_path.display().to_string(),
+            mounted_points,
+        });
+    }
+
+    Ok(())
+}
+
+/// Mount points and swaps on `device_path` or its partitions
+/// `resolve` returns the canonical path of a device and its parent disk
+fn find_mounted_points(
+    device_path: &Path,
+    mounts: &[MountEntry],
+    swaps: &[SwapEntry],
+    resolve: impl Fn(&Path) -> (PathBuf, Option<PathBuf>),
+) -> Vec<String> {
+    let (target, _) = resolve(device_path);
+    let on_target = |source: &Path| {
+        let (source, parent) = resolve(source);
+        source == target || parent.as_ref() == Some(&target)
+    };
+
+    let mounted = mounts
+        .iter()
+        .filter(|m| on_target(&m.source))
+        .map(|m| m.mount_point.display().to_string());
+
+    let swaps = swaps
+        .iter()
+        .filter(|s| s.swap_type == "partition" && on_target(&s.path))
+        .map(|s| format!("{} (swap)", s.path.display()));
+
+    mounted.chain(swaps).collect()
+}
+
+/// Umount every mounted partition of a disk
+pub fn umount_partitions_on(device_path: &Path) -> Result<(), PartitionError> {
+    let target = fs::canonicalize(device_path).unwrap_or_else(|_| device_path.to_path_buf());
+
+    let mut mount_points = vec![];
+
+    for i in read_mounts().map_err(PartitionError::ReadMounts)? {
+        let source = fs::canonicalize(&i.source).unwrap_or(i.source);
+
+        if source == target || parent_disk(&source).as_ref() == Some(&target) {
+            mount_points.push(i.mount_point);
+        }
+    }
+
+    // 先卸载子挂载点
+    mount_points.sort_by_key(|p| std::cmp::Reverse(p.components().count()));
+
+    for mount_point in mount_points {
+        info!("Umounting {}", mount_point.display());
+        rustix::mount::unmount(&mount_point, rustix::mount::UnmountFlags::empty()).map_err(
+            |e| PartitionError::Umount {
+                path: mount_point.display().to_string(),
+                err: e.into(),
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// An entry of /proc/swaps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwapEntry {
+    pub path: PathBuf,
+    /// `partition` or `file`
+    pub swap_type: String,
+}
+
+/// Parse the content of /proc/swaps
+pub fn parse_proc_swaps(content: &[u8]) -> Vec<SwapEntry> {
+    // 第一行为表头
+    content
+        .split(|c| *c == b'\n')
+        .skip(1)
+        .filter_map(|line| {
+            let mut split = split_fields(line);
+            let path = split.next()?;
+            let swap_type = split.next()?;
+
+            Some(SwapEntry {
+                path: PathBuf::from(path),
+                swap_type: swap_type.to_string_lossy().to_string(),
+            })
+        })
+        .collect()
+}
+
+pub fn active_swaps() -> Result<Vec<SwapEntry>, PartitionError> {
+    let content = fs::read("/proc/swaps").map_err(PartitionError::ReadSwaps)?;
+
+    Ok(parse_proc_swaps(&content))
+}
+
+/// Active swap partitions on `path`, which is either a partition or a whole disk
+pub fn active_swaps_on(path: &Path) -> Result<Vec<PathBuf>, PartitionError> {
+    // 设备路径可能是 /dev/disk/by-* 下的符号链接
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+
+    Ok(active_swaps()?
+        .into_iter()
+        .filter(|swap| swap.swap_type == "partition")
+        .map(|swap| fs::canonicalize(&swap.path).unwrap_or(swap.path))
+        .filter(|swap| *swap == target || parent_disk(swap).as_ref() == Some(&target))
+        .collect())
+}
+
+/// Find the disk of a partition through sysfs, e.g. /dev/sda2 -> /dev/sda
+pub(crate) fn parent_disk(partition: &Path) -> Option<PathBuf> {
+    let name = partition.file_name()?;
+    let sys_path = fs::canonicalize(Path::new("/sys/class/block").join(name)).ok()?;
+
+    if !sys_path.join("partition").exists() {
+        return None;
+    }
+
+    Some(Path::new("/dev").join(sys_path.parent()?.file_name()?))
+}
+
+/// Turn off active swaps on `path` before it is formatted or repartitioned
+/// Returns the swaps turned off
+pub fn swapoff_active_swaps(path: &Path) -> Result<Vec<PathBuf>, PartitionError> {
+    let swaps = active_swaps_on(path)?;
+
+    for swap in &swaps {
+        warn!("{} is an active swap, turning it off", swap.display());
+
+        let success = Command::new("swapoff")
+            .arg(swap)
+            .output()
+            .map(|out| out.status.success())
+            .unwrap_or(false);
+
+        if !success {
+            return Err(PartitionError::ActiveSwap {
+                path: swap.display().to_string(),
+            });
+        }
+    }
+
+    Ok(swaps)
+}
+
+#[test]
+fn test_data_layout_check() {
+    let layout = |mount_point: &str, fs_type: &str| DataLayout {
+        root_size: 32 * 1024 * 1024 * 1024,
+        fs_type: fs_type.to_string(),
+        mount_point: PathBuf::from(mount_point),
+    };
+
+    assert!(layout("/data", "ext4").check().is_ok());
+    assert!(layout("/srv/data", "xfs").check().is_ok());
+    assert!(layout("/data", "ntfs").check().is_err());
+    assert!(layout("data", "ext4").check().is_err());
+    assert!(layout("/", "ext4").check().is_err());
+    assert!(layout("/efi", "ext4").check().is_err());
+    assert!(layout("/data/../", "ext4").check().is_err());
+    assert!(DataLayout {
+        root_size: 1024,
+        ..layout("/data", "ext4")
+    }
+    .check()
+    .is_err());
+}
+
+#[test]
+fn test_auto_partition_options_check() {
+    assert!(AutoPartitionOptions::default().check().is_ok());
+
+    assert!(AutoPartitionOptions {
+        root_fs_type: "btrfs".to_string(),
+        swap_size: Some(4 * 1024 * 1024 * 1024),
+        ..Default::default()
+    }
+    .check()
+    .is_ok());
+
+    assert!(AutoPartitionOptions {
+        root_fs_type: "ntfs".to_string(),
+        ..Default::default()
+    }
+    .check()
+    .is_err());
+    assert!(AutoPartitionOptions {
+        efi_size: 1024 * 1024,
+        ..Default::default()
+    }
+    .check()
+    .is_err());
+    assert!(AutoPartitionOptions {
+        swap_size: Some(0),
+        ..Default::default()
+    }
+    .check()
+    .is_err());
+    assert!(AutoPartitionOptions {
+        discard: true,
+        ..Default::default()
+    }
+    .check()
+    .is_ok());
+    assert!(AutoPartitionOptions {
+        discard: true,
+        free_space_only: true,
+        ..Default::default()
+    }
+    .check()
+    .is_err());
+}
+
+#[test]
+fn test_plan_partitions() {
+    const MIB: u64 = 1024 * 1024;
+    // 1GiB 磁盘，512 字节扇区
+    let last_usable_lba = 1024 * MIB / 512 - 34;
+
+    let plan = plan_partitions(
+        &[Some(512 * MIB), None, Some(128 * MIB)],
+        512,
+        2048,
+        last_usable_lba,
+    )
+    .unwrap();
+
+    assert_eq!(plan[0], (2048, 2048 + 512 * 2048 - 1));
+    assert_eq!(plan[1].0, plan[0].1 + 1);
+    assert_eq!(plan[2].0, plan[1].1 + 1);
+    assert_eq!(plan[2].1 - plan[2].0 + 1, 128 * 2048);
+    assert!(plan[2].1 <= last_usable_lba);
+    assert!(plan.iter().all(|(start, _)| start % 2048 == 0));
+
+    // 空间不足
+    assert!(plan_partitions(&[Some(1024 * MIB), None], 512, 2048, last_usable_lba).is_none());
+    assert!(plan_partitions(&[Some(512 * MIB)], 4096, 256, 1024).is_none());
+    // 小于 1MiB 的分区
+    assert!(plan_partitions(&[Some(1024), None], 512, 2048, last_usable_lba).is_none());
+}
+
+#[test]
+fn test_new_partition_range() {
+    const MIB: u64 = 1024 * 1024;
+    let dev = Path::new("/dev/sda");
+    // 1GiB 磁盘，已有分区位于 1MiB..257MiB
+    let usable = (34, 2 * 1024 * 1024 - 34);
+    let used = [(1, 2048, 257 * 2048 - 1)];
+
+    assert_eq!(
+        new_partition_range(dev, 257 * MIB, 100 * MIB, 512, usable, &used).unwrap(),
+        (257 * 2048, 357 * 2048 - 1)
+    );
+
+    // 起始位置向上对齐到 1MiB
+    assert_eq!(
+        new_partition_range(dev, 257 * MIB + 1, 100 * MIB, 512, usable, &used).unwrap(),
+        (258 * 2048, 358 * 2048 - 1)
+    );
+
+    // 起始位置不能早于第一个可用扇区
+    assert_eq!(
+        new_partition_range(dev, 0, MIB, 512, usable, &[]).unwrap(),
+        (2048, 2 * 2048 - 1)
+    );
+
+    assert!(matches!(
+        new_partition_range(dev, 200 * MIB, 100 * MIB, 512, usable, &used),
+        Err(PartitionError::PartitionOverlap { num: 1, .. })
+    ));
+    assert!(matches!(
+        new_partition_range(dev, 512 * MIB, 1024 * MIB, 512, usable, &used),
+        Err(PartitionError::NoFreeSpace { .. })
+    ));
+    assert!(matches!(
+        new_partition_range(dev, 512 * MIB, MIB - 1, 512, usable, &used),
+        Err(PartitionError::InvalidPartitionOptions(_))
+    ));
+}
+
+#[test]
+fn test_shrunk_ending_lba() {
+    // 从 1MiB 开始的 100MiB 分区
+    let (start, end) = (2048, 2048 + 100 * 2048 - 1);
+
+    assert_eq!(
+        shrunk_ending_lba(start, end, 512, 60 * 1024 * 1024),
+        Ok(2048 + 60 * 2048 - 1)
+    );
+    // 向下对齐到 1MiB
+    assert_eq!(
+        shrunk_ending_lba(start, end, 512, 60 * 1024 * 1024 + 4096),
+        Ok(2048 + 60 * 2048 - 1)
+    );
+    assert!(shrunk_ending_lba(start, end, 512, 100 * 1024 * 1024).is_err());
+    assert!(shrunk_ending_lba(start, end, 512, 200 * 1024 * 1024).is_err());
+    assert!(shrunk_ending_lba(start, end, 512, 1024).is_err());
+}
+
+#[test]
+fn test_parse_fs_tools_output() {
+    let ntfs = "ntfsresize v2022.10.3 (libntfs-3g)\n\
+                Device name        : /dev/sda3\n\
+                NTFS volume version: 3.1\n\
+                Current volume size: 107374178816 bytes (107375 MB)\n\
+                Space in use       : 32212 MB (30.0%)\n\
+                You might resize at 32211300352 bytes or 32212 MB (freeing 75163 MB).\n";
+    assert_eq!(parse_ntfsresize_min_size(ntfs), Some(32211300352));
+    assert_eq!(parse_ntfsresize_min_size("ERROR"), None);
+
+    let resize2fs =
+        "resize2fs 1.47.0 (5-Feb-2023)\nEstimated minimum size of the filesystem: 1234567\n";
+    assert_eq!(parse_resize2fs_min_blocks(resize2fs), Some(1234567));
+
+    let dumpe2fs = "Filesystem volume name:   <none>\nBlock count:              26214400\nBlock size:               4096\n";
+    assert_eq!(parse_dumpe2fs_block_size(dumpe2fs), Some(4096));
+}
+
+#[test]
+fn test_resolve_home_split() {
+    const GIB: u64 = 1024 * 1024 * 1024;
+    let disk_size = 100 * GIB;
+    let available = disk_size - DEFAULT_EFI_SIZE - TABLE_RESERVED_SIZE;
+
+    let split = |home_split| AutoPartitionOptions {
+        home_split,
+        ..Default::default()
+    };
+
+    let mut options = split(HomeSplit::None);
+    options
+        .resolve_home_split_with_size(disk_size, true)
+        .unwrap();
+    assert!(options.data.is_none());
+
+    let mut options = split(HomeSplit::FixedGiB(60));
+    options
+        .resolve_home_split_with_size(disk_size, true)
+        .unwrap();
+    let data = options.data.as_ref().unwrap();
+    assert_eq!(data.root_size, available - 60 * GIB);
+    assert_eq!(data.mount_point, Path::new("/home"));
+    assert_eq!(data.fs_type, "ext4");
+    assert_eq!(options.home_split, HomeSplit::None);
+    assert!(options.check().is_ok());
+
+    let mut options = split(HomeSplit::Percent(50));
+    options
+        .resolve_home_split_with_size(disk_size, false)
+        .unwrap();
+    let available = disk_size - TABLE_RESERVED_SIZE;
+    assert_eq!(
+        options.data.as_ref().unwrap().root_size,
+        available - available / 100 * 50
+    );
+
+    // 根分区过小
+    let mut options = split(HomeSplit::FixedGiB(95));
+    options
+        .resolve_home_split_with_size(disk_size, true)
+        .unwrap();
+    assert!(options.check().is_err());
+
+    for home_split in [
+        HomeSplit::Percent(0),
+        HomeSplit::Percent(100),
+        HomeSplit::FixedGiB(0),
+        HomeSplit::FixedGiB(200),
+        HomeSplit::FixedGiB(u64::MAX),
+    ] {
+        assert!(split(home_split)
+            .resolve_home_split_with_size(disk_size, true)
+            .is_err());
+    }
+
+    let mut options = split(HomeSplit::Percent(50));
+    options.reuse_esp = true;
+    assert!(options
+        .resolve_home_split_with_size(disk_size, true)
+        .is_err());
+
+    let mut options = split(HomeSplit::Percent(50));
+    options.free_space_only = true;
+    assert!(options
+        .resolve_home_split_with_size(disk_size, true)
+        .is_err());
+}
+
+#[test]
+fn test_check_efi_size() {
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    assert!(check_efi_size(DEFAULT_EFI_SIZE, 32 * GIB).is_ok());
+    assert!(check_efi_size(GIB, 4 * GIB).is_ok());
+    assert!(check_efi_size(GIB + 1, 4 * GIB).is_err());
+    assert!(check_efi_size(DEFAULT_EFI_SIZE, GIB).is_err());
+}
+
+#[test]
+fn test_wipe_ranges() {
+    const MIB: u64 = 1024 * 1024;
+
+    assert_eq!(
+        wipe_ranges(512 * MIB),
+        vec![(0, 8 * MIB), (64 * MIB, 4096), (511 * MIB, MIB)]
+    );
+    assert_eq!(wipe_ranges(32 * MIB), vec![(0, 8 * MIB), (31 * MIB, MIB)]);
+    // BIOS boot 分区只有 1MiB
+    assert_eq!(wipe_ranges(MIB), vec![(0, MIB)]);
+    assert_eq!(
+        wipe_ranges(8 * MIB + 4096),
+        vec![(0, 8 * MIB), (8 * MIB, 4096)]
+    );
+}
+
+#[test]
+fn test_wipe_signatures() {
+    const MIB: u64 = 1024 * 1024;
+    let img = std::env::temp_dir().join(format!("dk-wipe-{}.img", std::process::id()));
+
+    let mut f = fs::File::create(&img).unwrap();
+    f.set_len(128 * MIB).unwrap();
+    let write_at = |f: &mut fs::File, offset: u64, data: &[u8]| {
+        f.seek(SeekFrom::Start(offset)).unwrap();
+        f.write_all(data).unwrap();
+    };
+    // ext4 magic
+    write_at(&mut f, 0x438, &[0x53, 0xef]);
+    // btrfs 超级块副本
+    write_at(&mut f, BTRFS_MIRROR_OFFSET + 0x40, b"_BHRfS_M");
+    // mdadm 1.0 超级块
+    write_at(&mut f, 128 * MIB - 8192, &[0xfc, 0x4e, 0x2b, 0xa9]);
+    // 不应被清除的数据
+    write_at(&mut f, 32 * MIB, b"data");
+    drop(f);
+
+    let res = wipe_signatures(&DkPartition {
+        path: Some(img.clone()),
+        size: 128 * MIB,
+        format: true,
+        ..Default::default()
+    });
+
+    let content = fs::read(&img).unwrap();
+    fs::remove_file(&img).unwrap();
+
+    res.unwrap();
+    assert_eq!(content.len() as u64, 128 * MIB);
+    assert_eq!(&content[0x438..0x43a], &[0, 0]);
+    let mirror = (BTRFS_MIRROR_OFFSET + 0x40) as usize;
+    assert_eq!(&content[mirror..mirror + 8], &[0; 8]);
+    let raid = (128 * MIB - 8192) as usize;
+    assert_eq!(&content[raid..raid + 4], &[0; 4]);
+    let data = (32 * MIB) as usize;
+    assert_eq!(&content[data..data + 4], b"data");
+}
+
+#[test]
+fn test_aligned_free_regions() {
+    // 不足 1MiB 的空隙被忽略
+    assert_eq!(
+        aligned_free_regions((34, 131038), &[(2048, 34815)], 512),
+        vec![FreeRegion {
+            start_lba: 34816,
+            end_lba: 129023,
+            size_bytes: 46 * 1024 * 1024,
+        }]
+    );
+    assert_eq!(
+        aligned_free_regions((34, 131038), &[(34, 131038)], 512),
+        vec![]
+    );
+    // 4K 扇区
+    assert_eq!(
+        aligned_free_regions((6, 16377), &[], 4096),
+        vec![FreeRegion {
+            start_lba: 256,
+            end_lba: 16127,
+            size_bytes: 62 * 1024 * 1024,
+        }]
+    );
+}
+
+#[test]
+fn test_get_free_space_gpt_image() {
+    let img = std::env::temp_dir().join(format!("dk-free-gpt-{}.img", std::process::id()));
+    let mut f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&img)
+        .unwrap();
+    f.set_len(64 * 1024 * 1024).unwrap();
+
+    let mut gpt = GPT::new_from(&mut f, 512, generate_gpt_random_uuid()).unwrap();
+    gpt[1] = gptman::GPTPartitionEntry {
+        partition_type_guid: LINUX_FS.to_bytes_le(),
+        unique_partition_guid: generate_gpt_random_uuid(),
+        starting_lba: 2048,
+        ending_lba: 2048 + 16 * 2048 - 1,
+        attribute_bits: 0,
+        partition_name: "".into(),
+    };
+    gpt.write_into(&mut f).unwrap();
+    drop(f);
+
+    let res = get_free_space(&img);
+    fs::remove_file(&img).unwrap();
+
+    // 末尾为备份 GPT 保留的扇区
+    assert_eq!(
+        res.unwrap(),
+        vec![FreeRegion {
+            start_lba: 34816,
+            end_lba: 129023,
+            size_bytes: 46 * 1024 * 1024,
+        }]
+    );
+}
+
+#[test]
+fn test_get_free_space_mbr_image() {
+    let img = std::env::temp_dir().join(format!("dk-free-mbr-{}.img", std::process::id()));
+    let mut f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&img)
+        .unwrap();
+    f.set_len(64 * 1024 * 1024).unwrap();
+
+    let mut mbr = MBR::new_from(&mut f, 512, [0xde, 0xad, 0xbe, 0xef]).unwrap();
+    mbr[1] = mbr_partition(0x83, 2048, 8 * 2048);
+    mbr[2] = mbr_partition(0x82, 32 * 2048, 8 * 2048);
+    mbr.write_into(&mut f).unwrap();
+    drop(f);
+
+    let res = get_free_space(&img);
+    fs::remove_file(&img).unwrap();
+
+    assert_eq!(
+        res.unwrap(),
+        vec![
+            FreeRegion {
+                start_lba: 9 * 2048,
+                end_lba: 32 * 2048 - 1,
+                size_bytes: 23 * 1024 * 1024,
+            },
+            FreeRegion {
+                start_lba: 40 * 2048,
+                end_lba: 64 * 2048 - 1,
+                size_bytes: 24 * 1024 * 1024,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_get_free_space_no_table() {
+    let img = std::env::temp_dir().join(format!("dk-free-none-{}.img", std::process::id()));
+    fs::File::create(&img)
+        .unwrap()
+        .set_len(4 * 1024 * 1024)
+        .unwrap();
+
+    let res = get_free_space(&img);
+    fs::remove_file(&img).unwrap();
+
+    assert!(matches!(res, Err(PartitionError::UnsupportedTable(_))));
+}
+
+#[test]
+fn test_largest_free_region() {
+    assert_eq!(largest_free_region(34, 1000, &[]), Some((34, 1000)));
+    assert_eq!(
+        largest_free_region(34, 1000, &[(100, 199), (34, 99)]),
+        Some((200, 1000))
+    );
+    assert_eq!(
+        largest_free_region(34, 1000, &[(34, 99), (500, 1000)]),
+        Some((100, 499))
+    );
+    assert_eq!(largest_free_region(34, 1000, &[(34, 1000)]), None);
+}
+
+#[test]
+#[ignore = "requires root to set up a loop device"]
+fn test_reuse_esp_loop_device() {
+    let dev = crate::loop_device::LoopDevice::partitioned("reuse-esp", 512 * 1024 * 1024);
+    let loop_dev = dev.path().to_path_buf();
+
+    // 只有一个 64MiB ESP 的磁盘
+    let res = create_gpt_table(&loop_dev, |gpt, _, starting_lba| {
+        gpt[1] = gptman::GPTPartitionEntry {
+            partition_type_guid: EFI.to_bytes_le(),
+            unique_partition_guid: generate_gpt_random_uuid(),
+            starting_lba,
+            ending_lba: starting_lba + 64 * 2048 - 1,
+            attribute_bits: 0,
+            partition_name: "".into(),
+        };
+
+        Ok(())
+    })
+    .and_then(|_| {
+        let mut esp = list_esp_partitions(&loop_dev)?.remove(0);
+        esp.fs_type = Some("vfat".to_string());
+        format_partition(&esp)?;
+
+        let esp = list_esp_partitions(&loop_dev)?.remove(0);
+        create_partitions_in_free_space(&loop_dev, &AutoPartitionOptions::default(), Some(esp), 0)
+    });
+
+    let res = res.unwrap();
+    let efi = res.efi.unwrap();
+    assert_eq!(efi.size, 64 * 1024 * 1024);
+    assert!(efi.path.unwrap().to_string_lossy().ends_with("p1"));
+    assert!(res.system.path.unwrap().to_string_lossy().ends_with("p2"));
+    assert_eq!(res.system.fs_type.as_deref(), Some("ext4"));
+    assert!(res.system.size > 400 * 1024 * 1024);
+}
+
+#[test]
+#[ignore = "requires root to set up a loop device"]
+fn test_free_space_too_small_loop_device() {
+    let dev = crate::loop_device::LoopDevice::partitioned("free-space", 512 * 1024 * 1024);
+    let loop_dev = dev.path().to_path_buf();
+
+    let res = create_gpt_table(&loop_dev, |_, _, _| Ok(())).and_then(|_| {
+        create_partitions_in_free_space(
+            &loop_dev,
+            &AutoPartitionOptions::default(),
+            None,
+            MIN_FREE_SPACE_SIZE,
+        )
+    });
+
+    let parts = list_partitions(loop_dev.clone());
+
+    match res {
+        Err(PartitionError::FreeSpaceTooSmall { size, min_size, .. }) => {
+            assert!(size < 512 * 1024 * 1024);
+            assert_eq!(min_size, MIN_FREE_SPACE_SIZE);
+        }
+        res => panic!("unexpected result: {res:?}"),
+    }
+
+    // 空闲空间不足时不修改分区表
+    assert!(parts.is_empty());
+}
+
+#[test]
+#[ignore = "requires root to set up a loop device"]
+fn test_gpt_bios_loop_device() {
+    let dev = crate::loop_device::LoopDevice::partitioned("gpt-bios", 512 * 1024 * 1024);
+    let loop_dev = dev.path().to_path_buf();
+
+    let res = auto_create_partitions_gpt_bios(&loop_dev, "ext4");
+    let has_bios_boot = has_bios_boot_partition(&loop_dev);
+    let table = get_partition_table_type(&loop_dev);
+
+    let system = res.unwrap();
+    assert!(has_bios_boot);
+    assert_eq!(table.unwrap(), "gpt");
+    // BIOS boot 分区在前
+    assert!(system.path.unwrap().to_string_lossy().ends_with("p2"));
+    assert_eq!(system.fs_type.as_deref(), Some("ext4"));
+}
+
+#[test]
+fn test_check_new_partition() {
+    assert!(check_new_partition("ext4", &[]).is_ok());
+    assert!(check_new_partition("vfat", &["esp".to_string()]).is_ok());
+    assert!(check_new_partition("swap", &[]).is_ok());
+    assert!(check_new_partition("ntfs", &[]).is_err());
+    assert!(check_new_partition("ext4", &["esp".to_string()]).is_err());
+    assert!(check_new_partition("ext4", &["hidden".to_string()]).is_err());
+}
+
+#[test]
+fn test_parse_proc_swaps() {
+    let content = b"Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/sda2                               partition\t8388604\t\t0\t\t-2
+/swapfile                               file\t\t2097148\t\t0\t\t-3
+";
+
+    assert_eq!(
+        parse_proc_swaps(content),
+        vec![
+            SwapEntry {
+                path: PathBuf::from("/dev/sda2"),
+                swap_type: "partition".to_string(),
+            },
+            SwapEntry {
+                path: PathBuf::from("/swapfile"),
+                swap_type: "file".to_string(),
+            },
+        ]
+    );
+
+    // 没有启用 swap 时只有表头
+    assert!(parse_proc_swaps(b"Filename\tType\tSize\tUsed\tPriority\n").is_empty());
+    assert!(parse_proc_swaps(b"").is_empty());
+}
+
+#[test]
+#[ignore = "requires root to set up a loop device"]
+fn test_partition_identity_loop_device() {
+    let dev = crate::loop_device::LoopDevice::partitioned("identity", 512 * 1024 * 1024);
+    let loop_dev = dev.path().to_path_buf();
+
+    let partuuid = generate_gpt_random_uuid();
+    let res = create_gpt_table(&loop_dev, |gpt, _, starting_lba| {
+        gpt[1] = gptman::GPTPartitionEntry {
+            partition_type_guid: EFI.to_bytes_le(),
+            unique_partition_guid: partuuid,
+            starting_lba,
+            ending_lba: starting_lba + 64 * 2048 - 1,
+            attribute_bits: 0,
+            partition_name: "".into(),
+        };
+
+        Ok(())
+    });
+
+    let part = dev.partition(1);
+    let mkfs = res.is_ok()
+        && Command::new("mkfs.vfat")
+            .args(["-n", "DKTEST"])
+            .arg(&part)
+            .status()
+            .is_ok_and(|x| x.success());
+    // 等待 udev 创建 /dev/disk/by-* 下的链接
+    Command::new("udevadm").arg("settle").status().ok();
+
+    let parts = list_partitions(loop_dev.clone());
+    let esps = list_esp_partitions(&loop_dev);
+
+    res.unwrap();
+    assert!(mkfs);
+
+    let esp = esps.unwrap().remove(0);
+    assert_eq!(parts.len(), 1);
+    assert_eq!(parts[0].part_number, Some(1));
+    assert_eq!(parts[0].label.as_deref(), Some("DKTEST"));
+    assert!(parts[0].uuid.is_some());
+    assert_eq!(
+        parts[0].partuuid.as_deref().map(|x| x.to_lowercase()),
+        Some(Uuid::from_bytes_le(partuuid).to_string())
+    );
+    assert_eq!(esp.uuid, parts[0].uuid);
+    assert_eq!(esp.partuuid, parts[0].partuuid);
+}
+
+#[test]
+fn test_table_backup_round_trip() {
+    let snapshot = TableSnapshot {
+        device_path: PathBuf::from("/dev/loop30"),
+        disk_size: 8 * 1024 * 1024,
+        head: vec![0x55; 4096],
+        tail: vec![0xaa; 4096],
+    };
+
+    let path = snapshot.save_backup().unwrap();
+    let loaded = TableSnapshot::load_backup(&path, Path::new("/dev/loop31"));
+    fs::remove_file(&path).unwrap();
+
+    assert!(path
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .starts_with("deploykit-table-backup-loop30-"));
+
+    let loaded = loaded.unwrap();
+    assert_eq!(loaded.device_path, Path::new("/dev/loop31"));
+    assert_eq!(loaded.disk_size, snapshot.disk_size);
+    assert_eq!(loaded.head, snapshot.head);
+    assert_eq!(loaded.tail, snapshot.tail);
+
+    // 截断或非备份文件
+    let bytes = snapshot.to_bytes();
+    assert!(
+        TableSnapshot::from_bytes(&bytes[..bytes.len() - 1], Path::new("/dev/loop30")).is_none()
+    );
+    assert!(TableSnapshot::from_bytes(b"DKTABLE0", Path::new("/dev/loop30")).is_none());
+}
+
+#[test]
+#[ignore = "requires root to set up a loop device"]
+fn test_restore_table_backup_loop_device() {
+    let dev = crate::loop_device::LoopDevice::partitioned("table-backup", 512 * 1024 * 1024);
+    let loop_dev = dev.path().to_path_buf();
+
+    let res = create_gpt_table(&loop_dev, |gpt, _, starting_lba| {
+        gpt[1] = gptman::GPTPartitionEntry {
+            partition_type_guid: EFI.to_bytes_le(),
+            unique_partition_guid: generate_gpt_random_uuid(),
+            starting_lba,
+            ending_lba: starting_lba + 64 * 2048 - 1,
+            attribute_bits: 0,
+            partition_name: "".into(),
+        };
+
+        Ok(())
+    })
+    .and_then(|_| TableSnapshot::capture(&loop_dev))
+    .and_then(|snapshot| snapshot.save_backup())
+    .and_then(|backup| {
+        let before = list_partitions(loop_dev.clone());
+
+        // 破坏分区表
+        let mut f = fs::OpenOptions::new().write(true).open(&loop_dev).unwrap();
+        f.write_all(&[0; 64 * 1024]).unwrap();
+        f.seek(SeekFrom::End(-64 * 1024)).unwrap();
+        f.write_all(&[0; 64 * 1024]).unwrap();
+        f.sync_all().unwrap();
+        gptman::linux::reread_partition_table(&mut f).unwrap();
+        drop(f);
+        let destroyed = list_partitions(loop_dev.clone());
+
+        TableSnapshot::load_backup(&backup, &loop_dev)?.restore()?;
+        let restored = list_partitions(loop_dev.clone());
+        fs::remove_file(&backup).ok();
+
+        Ok((before, destroyed, restored))
+    });
+
+    let (before, destroyed, restored) = res.unwrap();
+    assert_eq!(before.len(), 1);
+    assert!(destroyed.is_empty());
+    assert_eq!(restored.len(), 1);
+    assert_eq!(restored[0].path, before[0].path);
+    assert_eq!(restored[0].size, before[0].size);
+}
+
+#[test]
+fn test_find_mounted_points() {
+    let mounts = crate::mounts::parse_mounts(
+        b"/dev/sda2 / ext4 rw,relatime 0 0\n\
+          /dev/sda1 /efi vfat rw,relatime 0 0\n\
+          /dev/sdb1 /run/media/My\\040Disk vfat rw 0 0\n\
+          /dev/disk/by-label/data /data xfs rw 0 0\n\
+          proc /proc proc rw 0 0\n",
+    );
+    let swaps = parse_proc_swaps(
+        b"Filename\tType\tSize\tUsed\tPriority\n\
+          /dev/sda3\tpartition\t4194300\t0\t-2\n\
+          /swapfile\tfile\t1048572\t0\t-3\n",
+    );
+
+    let resolve = |p: &Path| {
+        let p = match p.to_str() {
+            Some("/dev/disk/by-label/data") => PathBuf::from("/dev/sda4"),
+            _ => p.to_path_buf(),
+        };
+        let parent = p
+            .to_str()
+            .filter(|s| s.starts_with("/dev/sd") && s.len() > "/dev/sda".len())
+            .map(|s| PathBuf::from(&s[.."/dev/sda".len()]));
+
+        (p, parent)
+    };
+
+    assert_eq!(
+        find_mounted_points(Path::new("/dev/sda"), &mounts, &swaps, resolve),
+        vec!["/", "/efi", "/data", "/dev/sda3 (swap)"]
+    );
+    assert_eq!(
+        find_mounted_points(Path::new("/dev/sdb"), &mounts, &swaps, resolve),
+        vec!["/run/media/My Disk"]
+    );
+    assert!(find_mounted_points(Path::new("/dev/sdc"), &mounts, &swaps, resolve).is_empty());
+}
+
+#[test]
+fn test_find_mounted_points_lvm() {
+    let mounts = crate::mounts::parse_mounts(
+        b"/dev/sda2 / ext4 rw,relatime 0 0\n\
+          /dev/vg0/home /home xfs rw 0 0\n",
+    );
+    let swaps = parse_proc_swaps(
+        b"Filename\tType\tSize\tUsed\tPriority\n\
+          /dev/dm-1\tpartition\t4194300\t0\t-2\n",
+    );
+
+    let resolve = |p: &Path| {
+        let p = match p.to_str() {
+            Some("/dev/vg0/home" | "/dev/mapper/vg0-home") => PathBuf::from("/dev/dm-0"),
+            Some("/dev/mapper/vg0-swap") => PathBuf::from("/dev/dm-1"),
+            _ => p.to_path_buf(),
+        };
+
+        (p, None)
+    };
+
+    assert_eq!(
+        find_mounted_points(Path::new("/dev/mapper/vg0-home"), &mounts, &swaps, resolve),
+        vec!["/home"]
+    );
+    assert_eq!(
+        find_mounted_points(Path::new("/dev/mapper/vg0-swap"), &mounts, &swaps, resolve),
+        vec!["/dev/dm-1 (swap)"]
+    );
+    assert!(
+        find_mounted_points(Path::new("/dev/mapper/vg1-data"), &mounts, &swaps, resolve).is_empty()
+    );
+}
+
+#[test]
+fn test_wait_for_path() {
+    let path = std::env::temp_dir().join(format!("dk-wait-node-{}", std::process::id()));
+
+    assert!(!wait_for_path(
+        &path,
+        Duration::from_millis(50),
+        Duration::from_millis(10)
+    ));
+
+    let creator = {
+        let path = path.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            fs::write(path, b"").unwrap();
+        })
+    };
+
+    let appeared = wait_for_path(&path, Duration::from_secs(5), Duration::from_millis(10));
+    creator.join().unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(appeared);
+}
+
+#[test]
+fn test_partition_path_by_name() {
+    assert_eq!(
+        partition_path_by_name(Path::new("/dev/sda"), 1),
+        Path::new("/dev/sda1")
+    );
+    assert_eq!(
+        partition_path_by_name(Path::new("/dev/nvme0n1"), 2),
+        Path::new("/dev/nvme0n1p2")
+    );
+    assert_eq!(
+        partition_path_by_name(Path::new("/dev/mmcblk0"), 10),
+        Path::new("/dev/mmcblk0p10")
+    );
+    assert_eq!(
+        partition_path_by_name(Path::new("/dev/loop30"), 3),
+        Path::new("/dev/loop30p3")
+    );
+    assert_eq!(
+        partition_path_by_name(Path::new("/dev/md127"), 1),
+        Path::new("/dev/md127p1")
+    );
+}
+
+#[test]
+fn test_find_created_partitions_image() {
+    let img = std::env::temp_dir().join(format!("dk-created-gpt-{}.img", std::process::id()));
+    let mut f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&img)
+        .unwrap();
+    f.set_len(64 * 1024 * 1024).unwrap();
+
+    let mut gpt = GPT::new_from(&mut f, 512, generate_gpt_random_uuid()).unwrap();
+    for (num, (start, sectors)) in [(1, (2048, 16 * 2048)), (3, (20 * 2048, 8 * 2048))] {
+        gpt[num] = gptman::GPTPartitionEntry {
+            partition_type_guid: LINUX_FS.to_bytes_le(),
+            unique_partition_guid: generate_gpt_random_uuid(),
+            starting_lba: start,
+            ending_lba: start + sectors - 1,
+            attribute_bits: 0,
+            partition_name: "".into(),
+        };
+    }
+    gpt.write_into(&mut f).unwrap();
+    drop(f);
+
+    let res = find_created_partitions(&img, 512);
+    fs::remove_file(&img).unwrap();
+
+    let res = res
+        .unwrap()
+        .into_iter()
+        .map(|(num, p)| (num, p.size, p.part_number))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        res,
+        vec![
+            (1, 16 * 1024 * 1024, Some(1)),
+            (3, 8 * 1024 * 1024, Some(3))
+        ]
+    );
+}
+
+#[test]
+#[ignore = "requires root to set up a loop device"]
+fn test_find_created_partitions_md_loop_devices() {
+    let devs =
+        [0, 1].map(|i| crate::loop_device::LoopDevice::new(&format!("md-{i}"), 256 * 1024 * 1024));
+    let mut loop_devs = devs
+        .iter()
+        .map(|x| x.path().to_path_buf())
+        .collect::<Vec<_>>();
+
+    // 元数据位于末尾的 RAID 1 阵列
+    let md_name = PathBuf::from(format!("/dev/md/dk-test-{}", std::process::id()));
+    let status = Command::new("mdadm")
+        .args([
+            "--create",
+            "--run",
+            "--level=1",
+            "--raid-devices=2",
+            "--metadata=1.0",
+        ])
+        .arg(&md_name)
+        .args(&loop_devs)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let md = fs::canonicalize(&md_name).unwrap();
+
+    let res = create_gpt_table(&md, |gpt, _, starting_lba| {
+        gpt[1] = gptman::GPTPartitionEntry {
+            partition_type_guid: LINUX_FS.to_bytes_le(),
+            unique_partition_guid: generate_gpt_random_uuid(),
+            starting_lba,
+            ending_lba: starting_lba + 64 * 2048 - 1,
+            attribute_bits: 0,
+            partition_name: "".into(),
+        };
+
+        Ok(())
+    })
+    .and_then(|_| find_created_partitions(&md, 512));
+
+    let mut members = crate::devices::slave_disks(&md);
+    members.sort();
+
+    Command::new("mdadm")
+        .arg("--stop")
+        .arg(&md)
+        .status()
+        .unwrap();
+
+    let res = res.unwrap();
+    assert_eq!(res.len(), 1);
+    assert_eq!(res[0].0, 1);
+    assert_eq!(res[0].1.path, Some(partition_path_by_name(&md, 1)));
+    assert!(res[0]
+        .1
+        .path
+        .as_ref()
+        .unwrap()
+        .to_string_lossy()
+        .ends_with("p1"));
+    loop_devs.sort();
+    assert_eq!(members, loop_devs);
+}
@@ -0,0 +1,127 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use rustix::mount::{self, MountFlags, UnmountFlags};
+use tracing::info;
+
+use crate::PartitionError;
+
+/// Subvolume of a btrfs system partition mounted at /
+pub const ROOT_SUBVOL: &str = "@";
+
+/// Other subvolumes of a btrfs system partition and their mount points
+pub const SUBVOLUMES: &[(&str, &str)] = &[("@home", "/home")];
+
+/// Create [`ROOT_SUBVOL`] and [`SUBVOLUMES`] on a btrfs filesystem, existing ones are kept
+pub fn create_btrfs_subvolumes(partition: &Path) -> Result<(), PartitionError> {
+    let err = |e: io::Error| PartitionError::BtrfsSubvolume {
+        path: partition.display().to_string(),
+        err: e,
+    };
+
+    let target = top_level_mount_point(partition);
+    fs::create_dir_all(&target).map_err(err)?;
+
+    // 挂载顶层子卷（subvolid=5）以创建子卷
+    if let Err(e) = mount::mount(
+        partition,
+        &target,
+        "btrfs",
+        MountFlags::empty(),
+        "subvolid=5",
+    ) {
+        fs::remove_dir(&target).ok();
+        return Err(err(e.into()));
+    }
+
+    let res = create_subvolumes_in(&target);
+
+    mount::unmount(&target, UnmountFlags::empty()).ok();
+    fs::remove_dir(&target).ok();
+
+    res.map_err(err)
+}
+
+fn create_subvolumes_in(top_level: &Path) -> io::Result<()> {
+    let names = std::iter::once(ROOT_SUBVOL).chain(SUBVOLUMES.iter().map(|(name, _)| *name));
+
+    for name in names {
+        let path = top_level.join(name);
+
+        if path.exists() {
+            info!("btrfs subvolume {name} already exists");
+            continue;
+        }
+
+        info!("Creating btrfs subvolume {name}");
+        let output = Command::new("btrfs")
+            .args(["subvolume", "create"])
+            .arg(&path)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "btrfs subvolume create {name} failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn top_level_mount_point(partition: &Path) -> PathBuf {
+    let name = partition
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    std::env::temp_dir().join(format!("dk-btrfs-{}-{name}", std::process::id()))
+}
+
+#[test]
+#[ignore = "requires root to set up a loop device"]
+fn test_create_btrfs_subvolumes_loop_device() {
+    let dev = crate::loop_device::LoopDevice::new("subvol", 512 * 1024 * 1024);
+    let loop_dev = dev.path().to_path_buf();
+
+    assert!(Command::new("mkfs.btrfs")
+        .arg("-f")
+        .arg(&loop_dev)
+        .output()
+        .unwrap()
+        .status
+        .success());
+
+    // 再次创建时保留已有的子卷
+    let first = create_btrfs_subvolumes(&loop_dev);
+    let second = create_btrfs_subvolumes(&loop_dev);
+
+    // 子卷根目录的 inode 号总是 256
+    let target = top_level_mount_point(&loop_dev);
+    fs::create_dir_all(&target).unwrap();
+    mount::mount(
+        &loop_dev,
+        &target,
+        "btrfs",
+        MountFlags::RDONLY,
+        "subvolid=5",
+    )
+    .unwrap();
+    let inodes = ["@", "@home"].map(|name| {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(target.join(name)).map(|m| m.ino()).ok()
+    });
+    mount::unmount(&target, UnmountFlags::empty()).unwrap();
+    fs::remove_dir(&target).unwrap();
+
+    first.unwrap();
+    second.unwrap();
+    assert_eq!(inodes, [Some(256), Some(256)]);
+}
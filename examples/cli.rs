@@ -46,7 +46,7 @@ trait Deploykit {
     async fn get_progress(&self) -> zResult<String>;
     async fn reset_config(&self) -> zResult<String>;
     async fn get_list_devices(&self) -> zResult<String>;
-    async fn auto_partition(&self, dev: &str) -> zResult<String>;
+    async fn auto_partition(&self, dev: &str, options: &str, discard: bool) -> zResult<String>;
     async fn start_install(&self) -> zResult<String>;
     async fn get_auto_partition_progress(&self) -> zResult<String>;
 }
@@ -62,6 +62,9 @@ struct Args {
     /// Set password for default user
     #[clap(long)]
     password: String,
+    /// Set password for root, the root account is locked if not set
+    #[clap(long)]
+    root_password: Option<String>,
     /// Set device hostname
     #[clap(long, default_value = "aosc")]
     hostname: String,
@@ -74,6 +77,9 @@ struct Args {
     /// Set install disk (will auto partition)
     #[clap(long)]
     disk_target: String,
+    /// Discard (TRIM) the whole install disk before partitioning
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    discard: bool,
     /// Toggle using RTC (real time clock) time as local time
     #[clap(long, action = clap::ArgAction::SetTrue)]
     rtc_as_localtime: bool,
@@ -100,8 +106,13 @@ impl Dbus {
         Ok(res)
     }
 
-    async fn auto_partition(proxy: &DeploykitProxy<'_>, dev: &str) -> Result<Self> {
-        let res = proxy.auto_partition(dev).await?;
+    async fn auto_partition(
+        proxy: &DeploykitProxy<'_>,
+        dev: &str,
+        options: &str,
+        discard: bool,
+    ) -> Result<Self> {
+        let res = proxy.auto_partition(dev, options, discard).await?;
         let res = Self::try_from(res)?;
 
         Ok(res)
@@ -135,11 +146,13 @@ async fn main() -> Result<()> {
     let Args {
         user,
         password,
+        root_password,
         hostname,
         timezone,
         locale,
         rtc_as_localtime,
         disk_target,
+        discard,
     } = args;
 
     let env_log = EnvFilter::try_from_default_env();
@@ -190,6 +203,7 @@ async fn main() -> Result<()> {
         &serde_json::json! {{
             "username": &user,
             "password": &password,
+            "root_password": &root_password,
         }}
         .to_string(),
     )
@@ -198,7 +212,8 @@ async fn main() -> Result<()> {
     Dbus::set_config(&proxy, "swapfile", "\"Disable\"").await?;
 
     info!("Auto partitioning {disk_target}...");
-    Dbus::auto_partition(&proxy, &disk_target).await?;
+    // 空选项表示使用默认分区方案
+    Dbus::auto_partition(&proxy, &disk_target, "", discard).await?;
 
     // 等待分区工作完成
     loop {
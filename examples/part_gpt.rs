@@ -1,7 +1,7 @@
 use std::path::Path;
 
-use disk::partition::auto_create_partitions_gpt;
+use disk::partition::{auto_create_partitions_gpt, DEFAULT_EFI_SIZE};
 
 fn main() {
-    auto_create_partitions_gpt(Path::new("/dev/loop30")).unwrap();
+    auto_create_partitions_gpt(Path::new("/dev/loop30"), "ext4", DEFAULT_EFI_SIZE).unwrap();
 }
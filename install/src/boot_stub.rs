@@ -0,0 +1,162 @@
+use std::{
+    cmp::Ordering,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use snafu::{OptionExt, ResultExt, Snafu};
+
+use crate::genfstab::find_fstab_spec;
+
+/// What an external bootloader needs to boot the installed system
+/// Paths are relative to the root partition of the installed system
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct BootStub {
+    pub kernel: PathBuf,
+    pub initrd: Option<PathBuf>,
+    /// fstab spec of the root partition, e.g. `UUID=...`
+    pub root: String,
+    pub cmdline: String,
+}
+
+#[derive(Debug, Snafu)]
+pub enum BootStubError {
+    #[snafu(display("Failed to read {}", path.display()))]
+    ReadBootFiles {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("No kernel found in /boot"))]
+    NoKernel,
+    #[snafu(display("No root entry in /etc/fstab"))]
+    NoRootEntry,
+}
+
+/// Collect the boot stub of the installed system
+/// Must be used in a chroot context
+pub(crate) fn boot_stub() -> Result<BootStub, BootStubError> {
+    let boot = Path::new("/boot");
+    let names = fs::read_dir(boot)
+        .context(ReadBootFilesSnafu { path: boot })?
+        .flatten()
+        .filter_map(|e| e.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+
+    let fstab = fs::read_to_string("/etc/fstab").context(ReadBootFilesSnafu {
+        path: PathBuf::from("/etc/fstab"),
+    })?;
+    let root = find_fstab_spec(&fstab, Path::new("/")).context(NoRootEntrySnafu)?;
+
+    boot_stub_from(boot, &names, root)
+}
+
+fn boot_stub_from(boot: &Path, names: &[String], root: &str) -> Result<BootStub, BootStubError> {
+    let version = names
+        .iter()
+        .filter_map(|x| x.strip_prefix("vmlinuz-"))
+        .max_by(|a, b| compare_versions(a, b))
+        .context(NoKernelSnafu)?;
+
+    // update-initramfs 生成 initramfs-<version>.img
+    let initrd = [
+        format!("initramfs-{version}.img"),
+        format!("initrd.img-{version}"),
+    ]
+    .into_iter()
+    .find(|x| names.contains(x))
+    .map(|x| boot.join(x));
+
+    Ok(BootStub {
+        kernel: boot.join(format!("vmlinuz-{version}")),
+        initrd,
+        root: root.to_string(),
+        cmdline: format!("root={root} rw"),
+    })
+}
+
+/// Compare kernel versions, numeric parts are compared as numbers
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let split = |s: &str| {
+        let mut res = vec![];
+        let mut cur = String::new();
+
+        for c in s.chars() {
+            if !cur.is_empty()
+                && cur.starts_with(|x: char| x.is_ascii_digit()) != c.is_ascii_digit()
+            {
+                res.push(std::mem::take(&mut cur));
+            }
+            cur.push(c);
+        }
+
+        res.push(cur);
+        res
+    };
+
+    for (x, y) in split(a).iter().zip(split(b).iter()) {
+        let ord = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            _ => x.cmp(y),
+        };
+
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+
+    a.len().cmp(&b.len())
+}
+
+#[test]
+fn test_compare_versions() {
+    assert_eq!(
+        compare_versions("6.10.2-aosc-main", "6.9.12-aosc-main"),
+        Ordering::Greater
+    );
+    assert_eq!(
+        compare_versions("6.6.1-aosc-lts", "6.6.10-aosc-lts"),
+        Ordering::Less
+    );
+    assert_eq!(
+        compare_versions("6.6.1-aosc-main", "6.6.1-aosc-main"),
+        Ordering::Equal
+    );
+}
+
+#[test]
+fn test_boot_stub_from() {
+    let names = [
+        "vmlinuz-6.9.12-aosc-main",
+        "vmlinuz-6.10.2-aosc-main",
+        "initramfs-6.9.12-aosc-main.img",
+        "initramfs-6.10.2-aosc-main.img",
+        "grub",
+    ]
+    .map(|x| x.to_string());
+
+    let stub = boot_stub_from(Path::new("/boot"), &names, "UUID=1234").unwrap();
+
+    assert_eq!(
+        stub,
+        BootStub {
+            kernel: PathBuf::from("/boot/vmlinuz-6.10.2-aosc-main"),
+            initrd: Some(PathBuf::from("/boot/initramfs-6.10.2-aosc-main.img")),
+            root: "UUID=1234".to_string(),
+            cmdline: "root=UUID=1234 rw".to_string(),
+        }
+    );
+
+    let stub = boot_stub_from(
+        Path::new("/boot"),
+        &["vmlinuz-6.6.1".to_string()],
+        "PARTUUID=abcd",
+    )
+    .unwrap();
+    assert_eq!(stub.initrd, None);
+
+    assert!(matches!(
+        boot_stub_from(Path::new("/boot"), &names[2..], "UUID=1234"),
+        Err(BootStubError::NoKernel)
+    ));
+}
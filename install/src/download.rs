@@ -1,20 +1,27 @@
-use std::io::BufReader;
+use std::io::{BufReader, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::Duration;
 use std::{fs, thread};
 
+use blake2::Blake2b512;
 use faster_hex::hex_string;
-use reqwest::header::HeaderValue;
+use reqwest::header::{HeaderValue, RANGE};
+use reqwest::StatusCode;
 use reqwest::{header::CONTENT_LENGTH, Client};
 use sha2::Digest;
-use sha2::Sha256;
+use sha2::{Sha256, Sha512};
 use snafu::{ensure, OptionExt, ResultExt, Snafu};
-use tokio::io::AsyncWriteExt;
-use tracing::debug;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, info, warn};
 
-use crate::DownloadType;
+use crate::extract::TarCompression;
+use crate::utils::{store_eta, VelocityMeter};
+use crate::{ChecksumKind, DownloadType};
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Snafu)]
 pub enum DownloadError {
@@ -41,19 +48,80 @@ pub enum DownloadError {
         source: std::io::Error,
         path: PathBuf,
     },
-    #[snafu(display("Checksum mismatch"))]
-    ChecksumMismatch,
+    #[snafu(display("Checksum mismatch, expected {expected}, got {actual}"))]
+    ChecksumMismatch { expected: String, actual: String },
     #[snafu(display("Failed to shutdown file"))]
     ShutdownFile {
         source: std::io::Error,
         path: PathBuf,
     },
+    #[snafu(display("Unsupported tarball compression: {}", path.display()))]
+    UnsupportedTarball { path: PathBuf },
+    #[snafu(display("No mirror URL is given"))]
+    NoMirrorUrl,
+    #[snafu(display("All mirrors failed, tried: {}", tried.join(", ")))]
+    AllMirrorsFailed {
+        source: Box<DownloadError>,
+        tried: Vec<String>,
+    },
+}
+
+impl DownloadError {
+    /// Whether the error is caused by the network and worth retrying
+    /// 4xx 等客户端错误重试也不会成功
+    fn is_retryable(&self) -> bool {
+        match self {
+            DownloadError::SendRequest { source } | DownloadError::DownloadFile { source, .. } => {
+                !source.status().is_some_and(|s| s.is_client_error())
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the mirror is unreachable, refused the request or served a corrupted file,
+    /// so the next one should be tried
+    fn is_mirror_failure(&self) -> bool {
+        matches!(
+            self,
+            DownloadError::SendRequest { .. }
+                | DownloadError::DownloadFile { .. }
+                | DownloadError::ChecksumMismatch { .. }
+        )
+    }
 }
 
 #[derive(Clone)]
 pub enum FilesType {
-    File { path: PathBuf, total: usize },
-    Dir { path: PathBuf, total: usize },
+    File {
+        path: PathBuf,
+        total: usize,
+    },
+    Dir {
+        path: PathBuf,
+        total: usize,
+    },
+    Tarball {
+        path: PathBuf,
+        total: usize,
+        compression: TarCompression,
+    },
+}
+
+impl FilesType {
+    /// The downloaded file is a tarball if the URL looks like one, otherwise a squashfs
+    fn downloaded(url: &str, path: &Path, total: usize) -> Self {
+        match TarCompression::from_name(url) {
+            Some(compression) => FilesType::Tarball {
+                path: path.to_path_buf(),
+                total,
+                compression,
+            },
+            None => FilesType::File {
+                path: path.to_path_buf(),
+                total,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -67,23 +135,80 @@ pub(crate) fn download_file(
     download_type: &DownloadType,
     progress: Arc<AtomicU8>,
     velocity: Arc<AtomicUsize>,
+    eta: Arc<AtomicUsize>,
     cancel_install: Arc<AtomicBool>,
-) -> Result<FilesType, DownloadError> {
+) -> Result<Option<FilesType>, DownloadError> {
     match download_type {
-        DownloadType::Http { url, hash, to_path } => {
+        DownloadType::Http {
+            url,
+            hash,
+            checksum,
+            to_path,
+            max_attempts,
+        } => {
             let to_path = to_path.as_ref().context(DownloadPathIsNotSetSnafu)?;
             let size = http_download_file(
                 url,
                 to_path,
                 hash,
+                *checksum,
+                max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
                 progress.clone(),
                 velocity.clone(),
+                eta.clone(),
                 cancel_install,
             )?;
-            Ok(FilesType::File {
-                path: to_path.clone(),
-                total: size,
-            })
+            Ok(size.map(|size| FilesType::downloaded(url, to_path, size)))
+        }
+        DownloadType::HttpMulti {
+            urls,
+            hash,
+            checksum,
+            to_path,
+            max_attempts,
+        } => {
+            let to_path = to_path.as_ref().context(DownloadPathIsNotSetSnafu)?;
+
+            let mut tried = vec![];
+            let mut last_err = None;
+
+            for url in urls {
+                tried.push(url.clone());
+
+                let res = http_download_file(
+                    url,
+                    to_path,
+                    hash,
+                    *checksum,
+                    max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
+                    progress.clone(),
+                    velocity.clone(),
+                    eta.clone(),
+                    cancel_install.clone(),
+                );
+
+                match res {
+                    Ok(size) => {
+                        return Ok(size.map(|size| FilesType::downloaded(url, to_path, size)))
+                    }
+                    Err(e) if e.is_mirror_failure() => {
+                        warn!("Mirror {url} failed: {e}, trying the next one");
+                        velocity.store(0, Ordering::SeqCst);
+                        eta.store(0, Ordering::SeqCst);
+                        progress.store(0, Ordering::SeqCst);
+                        last_err = Some(e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            match last_err {
+                Some(e) => Err(DownloadError::AllMirrorsFailed {
+                    source: Box::new(e),
+                    tried,
+                }),
+                None => NoMirrorUrlSnafu.fail(),
+            }
         }
         DownloadType::File(path) => {
             ensure!(
@@ -94,14 +219,36 @@ pub(crate) fn download_file(
             );
 
             velocity.store(0, Ordering::SeqCst);
+            eta.store(0, Ordering::SeqCst);
             progress.store(100, Ordering::SeqCst);
 
             let total = fs::metadata(path).map(|x| x.len()).unwrap_or(1) as usize;
 
-            Ok(FilesType::File {
+            Ok(Some(FilesType::File {
                 path: path.clone(),
                 total,
-            })
+            }))
+        }
+        DownloadType::Tarball(path) => {
+            ensure!(
+                path.exists(),
+                LocalFileNotFoundSnafu {
+                    path: path.to_owned()
+                }
+            );
+
+            let compression = TarCompression::from_name(&path.to_string_lossy())
+                .context(UnsupportedTarballSnafu { path: path.clone() })?;
+
+            velocity.store(0, Ordering::SeqCst);
+            eta.store(0, Ordering::SeqCst);
+            progress.store(100, Ordering::SeqCst);
+
+            Ok(Some(FilesType::Tarball {
+                path: path.clone(),
+                total: fs::metadata(path).map(|x| x.len()).unwrap_or(1) as usize,
+                compression,
+            }))
         }
         DownloadType::Dir(path) => {
             ensure!(
@@ -112,24 +259,30 @@ pub(crate) fn download_file(
             );
 
             velocity.store(0, Ordering::SeqCst);
+            eta.store(0, Ordering::SeqCst);
             progress.store(100, Ordering::SeqCst);
 
-            Ok(FilesType::Dir {
+            Ok(Some(FilesType::Dir {
                 path: path.clone(),
                 total: fs::metadata(path).map(|x| x.len()).unwrap_or(1) as usize,
-            })
+            }))
         }
     }
 }
 
+/// Download `url` to `path`, `None` if the installation is cancelled
+#[allow(clippy::too_many_arguments)]
 fn http_download_file(
     url: &str,
     path: &Path,
     hash: &str,
+    checksum: ChecksumKind,
+    max_attempts: u32,
     progress: Arc<AtomicU8>,
     velocity: Arc<AtomicUsize>,
+    eta: Arc<AtomicUsize>,
     cancel_install: Arc<AtomicBool>,
-) -> Result<usize, DownloadError> {
+) -> Result<Option<usize>, DownloadError> {
     let url = url.to_string();
     let hash = hash.to_string();
     let path = path.to_path_buf();
@@ -139,29 +292,147 @@ fn http_download_file(
             .build()
             .unwrap()
             .block_on(async move {
-                http_download_file_inner(url, path, hash, &progress, &velocity, &cancel_install)
-                    .await
+                http_download_file_inner(
+                    url,
+                    path,
+                    hash,
+                    checksum,
+                    max_attempts,
+                    &progress,
+                    &velocity,
+                    &eta,
+                    &cancel_install,
+                )
+                .await
             })
     })
     .join()
     .unwrap()
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn http_download_file_inner(
     url: String,
     path: PathBuf,
     hash: String,
+    checksum: ChecksumKind,
+    max_attempts: u32,
     progress: &AtomicU8,
     velocity: &AtomicUsize,
+    eta: &AtomicUsize,
     cancel_install: &AtomicBool,
-) -> Result<usize, DownloadError> {
+) -> Result<Option<usize>, DownloadError> {
     let client = Client::builder()
         .user_agent("deploykit")
         .build()
         .context(BuildDownloadClientSnafu)?;
 
+    let mut attempt = 1;
+
+    let total_size = loop {
+        match get_total_size(&client, &url).await {
+            Ok(total_size) => break total_size,
+            Err(e) if attempt < max_attempts && e.is_retryable() => {
+                let delay = retry_delay(attempt);
+                warn!("Failed to get size of {url}: {e}, retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    let mut file = tokio::fs::File::create(&path)
+        .await
+        .context(CreateFileSnafu { path: path.clone() })?;
+
+    loop {
+        if cancel_install.load(Ordering::Relaxed) {
+            return Ok(cancel_download(file, &path).await);
+        }
+
+        match download_range(
+            &client,
+            &url,
+            &path,
+            &mut file,
+            total_size,
+            progress,
+            velocity,
+            eta,
+            cancel_install,
+        )
+        .await
+        {
+            Ok(()) => break,
+            Err(e) if attempt < max_attempts && e.is_retryable() => {
+                let delay = retry_delay(attempt);
+                warn!(
+                    "Download attempt {attempt}/{max_attempts} of {url} failed: {e}, retrying in {delay:?}"
+                );
+                velocity.store(0, Ordering::SeqCst);
+                eta.store(0, Ordering::SeqCst);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    if cancel_install.load(Ordering::Relaxed) {
+        return Ok(cancel_download(file, &path).await);
+    }
+
+    let pc = path.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&pc).context(CreateFileSnafu { path: pc.clone() })?;
+        let mut buf = BufReader::new(file);
+
+        let checksum = match checksum {
+            ChecksumKind::Sha256 => file_digest::<Sha256>(&mut buf, &pc)?,
+            ChecksumKind::Sha512 => file_digest::<Sha512>(&mut buf, &pc)?,
+            ChecksumKind::Blake2b => file_digest::<Blake2b512>(&mut buf, &pc)?,
+        };
+
+        debug!("Right hash: {hash}");
+        debug!("Now checksum: {checksum}");
+        ensure!(
+            checksum.eq_ignore_ascii_case(&hash),
+            ChecksumMismatchSnafu {
+                expected: hash,
+                actual: checksum,
+            }
+        );
+        debug!("Checksum is ok");
+
+        Ok(())
+    })
+    .await
+    .unwrap()?;
+
+    file.shutdown()
+        .await
+        .context(ShutdownFileSnafu { path: path.clone() })?;
+
+    Ok(Some(total_size))
+}
+
+/// 取消安装时删除下载了一半的文件，以免被误当作完整的文件解压
+async fn cancel_download(file: tokio::fs::File, path: &Path) -> Option<usize> {
+    info!("Download of {} is cancelled", path.display());
+    drop(file);
+
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        warn!("Failed to remove {}: {e}", path.display());
+    }
+
+    None
+}
+
+async fn get_total_size(client: &Client, url: &str) -> Result<usize, DownloadError> {
     let head = client
-        .head(&url)
+        .head(url)
         .send()
         .await
         .and_then(|x| x.error_for_status())
@@ -179,74 +450,361 @@ async fn http_download_file_inner(
         .and_then(|x| x.parse::<usize>().ok())
         .unwrap_or(1);
 
-    let mut file = tokio::fs::File::create(&path)
-        .await
-        .context(CreateFileSnafu { path: path.clone() })?;
+    Ok(total_size)
+}
 
-    let mut resp = client
-        .get(url)
-        .send()
+/// Download the rest of the file, resuming from the bytes already written to `file`
+#[allow(clippy::too_many_arguments)]
+async fn download_range(
+    client: &Client,
+    url: &str,
+    path: &Path,
+    file: &mut tokio::fs::File,
+    total_size: usize,
+    progress: &AtomicU8,
+    velocity: &AtomicUsize,
+    eta: &AtomicUsize,
+    cancel_install: &AtomicBool,
+) -> Result<(), DownloadError> {
+    // 以磁盘上实际写入的大小为准，上次失败时可能有未写完的数据
+    file.flush().await.context(WriteFileSnafu {
+        path: path.to_path_buf(),
+    })?;
+    let mut download_len = file
+        .metadata()
         .await
-        .and_then(|x| x.error_for_status())
-        .context(SendRequestSnafu)?;
+        .context(WriteFileSnafu {
+            path: path.to_path_buf(),
+        })?
+        .len() as usize;
+
+    let mut req = client.get(url);
+    if download_len > 0 {
+        info!("Resuming download of {url} from {download_len} bytes");
+        req = req.header(RANGE, format!("bytes={download_len}-"));
+    }
+
+    let resp = req.send().await.context(SendRequestSnafu)?;
+
+    // 已下载完整个文件
+    if download_len > 0 && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        return Ok(());
+    }
 
-    let mut now = Instant::now();
-    let mut v_download_len = 0;
-    let mut download_len = 0;
+    let mut resp = resp.error_for_status().context(SendRequestSnafu)?;
 
-    while let Some(chunk) = resp
-        .chunk()
+    // 服务器不支持 Range 请求时返回整个文件，需从头写入
+    if download_len > 0 && resp.status() != StatusCode::PARTIAL_CONTENT {
+        warn!("{url} does not support resuming, restarting download");
+        download_len = 0;
+        file.set_len(0).await.context(WriteFileSnafu {
+            path: path.to_path_buf(),
+        })?;
+    }
+
+    file.seek(SeekFrom::Start(download_len as u64))
         .await
-        .context(DownloadFileSnafu { path: path.clone() })?
-    {
-        if now.elapsed().as_secs() >= 1 {
-            now = Instant::now();
-            velocity.store(v_download_len / 1024, Ordering::SeqCst);
-            v_download_len = 0;
-        }
+        .context(WriteFileSnafu {
+            path: path.to_path_buf(),
+        })?;
 
+    let mut meter = VelocityMeter::new();
+
+    while let Some(chunk) = resp.chunk().await.context(DownloadFileSnafu {
+        path: path.to_path_buf(),
+    })? {
         if cancel_install.load(Ordering::Relaxed) {
-            return Ok(0);
+            return Ok(());
         }
 
-        file.write_all(&chunk)
-            .await
-            .context(WriteFileSnafu { path: path.clone() })?;
+        file.write_all(&chunk).await.context(WriteFileSnafu {
+            path: path.to_path_buf(),
+        })?;
+
+        meter.add(chunk.len(), velocity);
+        download_len += chunk.len();
+        store_eta(eta, total_size.saturating_sub(download_len), velocity);
 
         progress.store(
-            (download_len as f64 / total_size as f64 * 100.0).round() as u8,
+            (download_len as f64 / total_size as f64 * 100.0)
+                .round()
+                .min(100.0) as u8,
             Ordering::SeqCst,
         );
-
-        v_download_len += chunk.len();
-        download_len += chunk.len();
     }
 
-    let pc = path.clone();
+    file.flush().await.context(WriteFileSnafu {
+        path: path.to_path_buf(),
+    })?;
 
-    tokio::task::spawn_blocking(move || {
-        let file = std::fs::File::open(&pc).context(CreateFileSnafu { path: pc.clone() })?;
-        let mut buf = BufReader::new(file);
+    Ok(())
+}
 
-        let mut sha256 = Sha256::new();
-        std::io::copy(&mut buf, &mut sha256).context(WriteFileSnafu { path: pc.clone() })?;
+/// Hex digest of everything read from `reader`
+fn file_digest<D: Digest + std::io::Write>(
+    reader: &mut impl std::io::Read,
+    path: &Path,
+) -> Result<String, DownloadError> {
+    let mut hasher = D::new();
+    std::io::copy(reader, &mut hasher).context(WriteFileSnafu {
+        path: path.to_path_buf(),
+    })?;
 
-        let download_hash = sha256.finalize().to_vec();
-        let checksum = hex_string(&download_hash);
+    Ok(hex_string(&hasher.finalize()))
+}
 
-        debug!("Right hash: {hash}");
-        debug!("Now checksum: {checksum}");
-        ensure!(checksum == hash, ChecksumMismatchSnafu);
-        debug!("Checksum is ok");
+/// Exponential backoff before retrying after `attempt` failed attempts: 1s, 2s, 4s ... at most 30s
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1u64 << attempt.saturating_sub(1).min(5)).min(MAX_RETRY_DELAY)
+}
 
-        Ok(())
-    })
-    .await
-    .unwrap()?;
+#[test]
+fn test_retry_delay() {
+    assert_eq!(retry_delay(1), Duration::from_secs(1));
+    assert_eq!(retry_delay(2), Duration::from_secs(2));
+    assert_eq!(retry_delay(3), Duration::from_secs(4));
+    assert_eq!(retry_delay(5), Duration::from_secs(16));
+    assert_eq!(retry_delay(6), Duration::from_secs(30));
+    assert_eq!(retry_delay(100), Duration::from_secs(30));
+}
 
-    file.shutdown()
-        .await
-        .context(ShutdownFileSnafu { path: path.clone() })?;
+#[test]
+fn test_file_digest() {
+    let path = Path::new("/tmp/abc");
+
+    assert_eq!(
+        file_digest::<Sha256>(&mut &b"abc"[..], path).unwrap(),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+        file_digest::<Sha512>(&mut &b"abc"[..], path).unwrap(),
+        "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+         2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+    );
+    assert_eq!(
+        file_digest::<Blake2b512>(&mut &b"abc"[..], path).unwrap(),
+        "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+         7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+    );
+}
 
-    Ok(total_size)
+#[test]
+fn test_cancel_http_download() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    const SIZE: usize = 1024 * 1024 * 1024;
+
+    // 响应 HEAD 后缓慢地发送数据，直到连接被关闭
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+                let n = stream.read(&mut buf).unwrap_or(0);
+                let is_head = buf[..n].starts_with(b"HEAD");
+
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {SIZE}\r\n\r\n");
+                if stream.write_all(header.as_bytes()).is_err() || is_head {
+                    return;
+                }
+
+                while stream.write_all(&[0; 4096]).is_ok() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            });
+        }
+    });
+
+    let to_path = std::env::temp_dir().join(format!(
+        "dk-cancel-download-{}.squashfs",
+        std::process::id()
+    ));
+    let download = DownloadType::Http {
+        url: format!("http://127.0.0.1:{port}/a.squashfs"),
+        hash: "".to_string(),
+        checksum: ChecksumKind::Sha256,
+        to_path: Some(to_path.clone()),
+        max_attempts: Some(1),
+    };
+
+    let progress = Arc::new(AtomicU8::new(0));
+    let velocity = Arc::new(AtomicUsize::new(0));
+    let cancel_install = Arc::new(AtomicBool::new(false));
+
+    // 开始接收数据后取消安装
+    let v = velocity.clone();
+    let c = cancel_install.clone();
+    thread::spawn(move || {
+        while v.load(Ordering::SeqCst) == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        c.store(true, Ordering::SeqCst);
+    });
+
+    let res = download_file(
+        &download,
+        progress,
+        velocity,
+        Arc::new(AtomicUsize::new(0)),
+        cancel_install,
+    );
+
+    assert!(matches!(res, Ok(None)));
+    assert!(!to_path.exists());
+}
+
+#[test]
+fn test_resume_http_download() {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener};
+    use std::sync::Mutex;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    const SIZE: usize = 64 * 1024;
+    let content = (0..SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let ranges = Arc::new(Mutex::new(vec![]));
+
+    // 第一次 GET 只发送一半数据就断开连接，之后按 Range 返回 206
+    let body = content.clone();
+    let r = ranges.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            let mut buf = [0; 4096];
+            let n = stream.read(&mut buf).unwrap_or(0);
+            let req = String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase();
+            let start = req
+                .lines()
+                .find_map(|x| x.strip_prefix("range: bytes="))
+                .and_then(|x| x.trim_end_matches('-').parse::<usize>().ok());
+
+            let ok =
+                format!("HTTP/1.1 200 OK\r\nContent-Length: {SIZE}\r\nConnection: close\r\n\r\n");
+            let res = if req.starts_with("head") {
+                stream.write_all(ok.as_bytes())
+            } else if let Some(start) = start {
+                r.lock().unwrap().push(start);
+                let partial = format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{}/{SIZE}\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    SIZE - 1,
+                    SIZE - start
+                );
+                stream
+                    .write_all(partial.as_bytes())
+                    .and_then(|_| stream.write_all(&body[start..]))
+            } else {
+                stream
+                    .write_all(ok.as_bytes())
+                    .and_then(|_| stream.write_all(&body[..SIZE / 2]))
+            };
+
+            if res.is_ok() {
+                let _ = stream.shutdown(Shutdown::Write);
+            }
+        }
+    });
+
+    let to_path = std::env::temp_dir().join(format!(
+        "dk-resume-download-{}.squashfs",
+        std::process::id()
+    ));
+
+    let res = http_download_file(
+        &format!("http://127.0.0.1:{port}/a.squashfs"),
+        &to_path,
+        &file_digest::<Sha256>(&mut &content[..], &to_path).unwrap(),
+        ChecksumKind::Sha256,
+        2,
+        Arc::new(AtomicU8::new(0)),
+        Arc::new(AtomicUsize::new(0)),
+        Arc::new(AtomicUsize::new(0)),
+        Arc::new(AtomicBool::new(false)),
+    );
+
+    let downloaded = fs::read(&to_path);
+    let _ = fs::remove_file(&to_path);
+
+    assert_eq!(res.unwrap(), Some(SIZE));
+    assert_eq!(*ranges.lock().unwrap(), [SIZE / 2]);
+    assert!(downloaded.unwrap() == content);
+}
+
+#[test]
+fn test_http_multi_checksum_mismatch() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // 返回固定内容的 HTTP 服务器
+    let serve = |body: &'static [u8]| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 4096];
+                let n = stream.read(&mut buf).unwrap_or(0);
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(header.as_bytes());
+                if !buf[..n].starts_with(b"HEAD") {
+                    let _ = stream.write_all(body);
+                }
+            }
+        });
+
+        format!("http://127.0.0.1:{port}/a.squashfs")
+    };
+
+    let bad = serve(b"corrupted");
+    let good = serve(b"abc");
+    let to_path =
+        std::env::temp_dir().join(format!("dk-multi-download-{}.squashfs", std::process::id()));
+
+    let download = |urls: Vec<String>| {
+        download_file(
+            &DownloadType::HttpMulti {
+                urls,
+                hash: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                    .to_string(),
+                checksum: ChecksumKind::Sha256,
+                to_path: Some(to_path.clone()),
+                max_attempts: Some(1),
+            },
+            Arc::new(AtomicU8::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicBool::new(false)),
+        )
+    };
+
+    // 校验和不匹配时尝试下一个镜像
+    let res = download(vec![bad.clone(), good]);
+    let downloaded = fs::read(&to_path);
+    assert!(matches!(res, Ok(Some(FilesType::File { total: 3, .. }))));
+    assert_eq!(downloaded.unwrap(), b"abc");
+
+    let res = download(vec![bad.clone(), bad]);
+    let _ = fs::remove_file(&to_path);
+    assert!(matches!(
+        res,
+        Err(DownloadError::AllMirrorsFailed { ref source, ref tried })
+            if matches!(**source, DownloadError::ChecksumMismatch { .. }) && tried.len() == 2
+    ));
+
+    assert!(matches!(download(vec![]), Err(DownloadError::NoMirrorUrl)));
 }
@@ -0,0 +1,250 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{InstallPlan, InstallationStage};
+
+// 未测速时使用的保守估计值
+const DEFAULT_BANDWIDTH: u64 = 5 * 1024 * 1024;
+const DEFAULT_WRITE_SPEED: u64 = 50 * 1024 * 1024;
+// squashfs (xz) 的典型压缩比
+const DEFAULT_COMPRESSION_RATIO: u64 = 3;
+// 未知安装大小时按桌面版的典型大小估算
+const DEFAULT_INSTALLED_SIZE: u64 = 8 * 1024 * 1024 * 1024;
+
+/// Known facts about the installation, sizes in bytes and speeds in bytes per second
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EstimateInput {
+    /// Size of the squashfs to download
+    pub download_size: Option<u64>,
+    /// Size of the installed system, 0 if unknown
+    pub installed_size: u64,
+    /// Measured mirror bandwidth
+    pub bandwidth: Option<u64>,
+    /// Measured write speed of the target disk
+    pub write_speed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct StageEstimate {
+    pub stage: InstallationStage,
+    pub seconds: u64,
+    pub confidence: Confidence,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct InstallEstimate {
+    pub stages: Vec<StageEstimate>,
+    pub total_seconds: u64,
+    /// Lowest confidence of all stages
+    pub confidence: Confidence,
+}
+
+/// Estimate the duration of every planned stage
+pub fn estimate_install_duration(plan: &InstallPlan, input: &EstimateInput) -> InstallEstimate {
+    let stages = plan
+        .stages
+        .iter()
+        .map(|stage| {
+            let (seconds, confidence) = estimate_stage(stage, input);
+
+            StageEstimate {
+                stage: stage.clone(),
+                seconds,
+                confidence,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    InstallEstimate {
+        total_seconds: stages.iter().map(|x| x.seconds).sum(),
+        confidence: stages
+            .iter()
+            .map(|x| x.confidence)
+            .min()
+            .unwrap_or(Confidence::Low),
+        stages,
+    }
+}
+
+fn estimate_stage(stage: &InstallationStage, input: &EstimateInput) -> (u64, Confidence) {
+    match stage {
+        InstallationStage::DownloadSquashfs => {
+            let (size, size_known) = match input.download_size {
+                Some(size) if size > 0 => (size, true),
+                _ => (installed_size(input).0 / DEFAULT_COMPRESSION_RATIO, false),
+            };
+
+            let (bandwidth, measured) = speed_or(input.bandwidth, DEFAULT_BANDWIDTH);
+
+            (
+                size.div_ceil(bandwidth),
+                if size_known && measured {
+                    Confidence::High
+                } else {
+                    Confidence::Low
+                },
+            )
+        }
+        InstallationStage::ExtractSquashfs => {
+            let (write_speed, measured) = speed_or(input.write_speed, DEFAULT_WRITE_SPEED);
+            let (size, size_known) = installed_size(input);
+
+            (
+                size.div_ceil(write_speed),
+                if size_known && measured {
+                    Confidence::High
+                } else {
+                    Confidence::Low
+                },
+            )
+        }
+        // 其余阶段耗时与安装源大小关系不大，使用固定开销
+        stage => (stage_overhead(stage), Confidence::Medium),
+    }
+}
+
+/// Installed size and whether it is known, estimated from the download size if not
+fn installed_size(input: &EstimateInput) -> (u64, bool) {
+    match (input.installed_size, input.download_size) {
+        (0, Some(size)) if size > 0 => (size * DEFAULT_COMPRESSION_RATIO, false),
+        (0, _) => (DEFAULT_INSTALLED_SIZE, false),
+        (size, _) => (size, true),
+    }
+}
+
+fn speed_or(measured: Option<u64>, default: u64) -> (u64, bool) {
+    match measured {
+        Some(speed) if speed > 0 => (speed, true),
+        _ => (default, false),
+    }
+}
+
+fn stage_overhead(stage: &InstallationStage) -> u64 {
+    match stage {
+        InstallationStage::SetupPartition => 10,
+        InstallationStage::GenerateFstab => 1,
+        InstallationStage::Dracut => 60,
+        InstallationStage::InstallGrub => 15,
+        InstallationStage::GenerateSshKey => 5,
+        InstallationStage::ConfigureSystem => 10,
+        _ => 0,
+    }
+}
+
+#[test]
+fn test_estimate_measured() {
+    const MIB: u64 = 1024 * 1024;
+
+    let plan = InstallPlan::new(None);
+    let input = EstimateInput {
+        download_size: Some(1024 * MIB),
+        installed_size: 4096 * MIB,
+        bandwidth: Some(10 * MIB),
+        write_speed: Some(100 * MIB),
+    };
+
+    let res = estimate_install_duration(&plan, &input);
+    let seconds_of = |stage: InstallationStage| {
+        res.stages
+            .iter()
+            .find(|x| x.stage == stage)
+            .map(|x| (x.seconds, x.confidence))
+    };
+
+    assert_eq!(
+        seconds_of(InstallationStage::DownloadSquashfs),
+        Some((103, Confidence::High))
+    );
+    assert_eq!(
+        seconds_of(InstallationStage::ExtractSquashfs),
+        Some((41, Confidence::High))
+    );
+    assert_eq!(res.total_seconds, 103 + 41 + 10 + 1 + 60 + 15 + 5 + 10);
+    assert_eq!(res.confidence, Confidence::Medium);
+}
+
+#[test]
+fn test_estimate_defaults() {
+    const MIB: u64 = 1024 * 1024;
+
+    let plan = InstallPlan::new(None);
+    let input = EstimateInput {
+        installed_size: 3000 * MIB,
+        bandwidth: Some(0),
+        ..Default::default()
+    };
+
+    let res = estimate_install_duration(&plan, &input);
+
+    // 未知下载大小按压缩比估算，未测速使用默认速度
+    assert_eq!(res.stages[1].seconds, 1000 * MIB / DEFAULT_BANDWIDTH);
+    assert_eq!(res.stages[1].confidence, Confidence::Low);
+    assert_eq!(res.stages[2].seconds, 3000 * MIB / DEFAULT_WRITE_SPEED);
+    assert_eq!(res.confidence, Confidence::Low);
+}
+
+#[test]
+fn test_estimate_local_source() {
+    use std::path::PathBuf;
+
+    let plan = InstallPlan::new(Some(&crate::DownloadType::File(PathBuf::from(
+        "/tmp/a.squashfs",
+    ))))
+    .with_stop_after(Some(&InstallationStage::ExtractSquashfs));
+
+    let res = estimate_install_duration(
+        &plan,
+        &EstimateInput {
+            installed_size: 100 * 1024 * 1024,
+            write_speed: Some(100 * 1024 * 1024),
+            ..Default::default()
+        },
+    );
+
+    assert_eq!(
+        res.stages
+            .iter()
+            .map(|x| x.stage.clone())
+            .collect::<Vec<_>>(),
+        [
+            InstallationStage::SetupPartition,
+            InstallationStage::ExtractSquashfs
+        ]
+    );
+    assert_eq!(res.total_seconds, 11);
+}
+
+#[test]
+fn test_estimate_unknown_installed_size() {
+    const MIB: u64 = 1024 * 1024;
+
+    let plan = InstallPlan::new(None).with_stop_after(Some(&InstallationStage::ExtractSquashfs));
+    let seconds_of = |input: &EstimateInput| {
+        let res = estimate_install_duration(&plan, input);
+        (res.stages[2].seconds, res.stages[2].confidence)
+    };
+
+    // 安装大小为 0 视为未知，而非耗时 0 秒
+    let (seconds, confidence) = seconds_of(&EstimateInput {
+        write_speed: Some(100 * MIB),
+        ..Default::default()
+    });
+    assert_eq!(seconds, DEFAULT_INSTALLED_SIZE / (100 * MIB));
+    assert_eq!(confidence, Confidence::Low);
+
+    // 已知下载大小时按压缩比推算
+    let (seconds, confidence) = seconds_of(&EstimateInput {
+        download_size: Some(1000 * MIB),
+        write_speed: Some(100 * MIB),
+        ..Default::default()
+    });
+    assert_eq!(seconds, 30);
+    assert_eq!(confidence, Confidence::Low);
+}
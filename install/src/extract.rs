@@ -1,19 +1,23 @@
 use std::{
-    io::{self, BufRead, BufReader},
+    fmt::Display,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::ffi::OsStrExt,
     path::Path,
     process::{Command, Stdio},
     sync::{
         atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
         Arc,
     },
-    time::Instant,
+    thread,
 };
 
+use rustix::{fs::statvfs, io::Errno};
 use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use sysinfo::System;
 use tracing::{debug, error, warn};
 
-use crate::utils::RunCmdError;
+use crate::utils::{store_eta, RunCmdError, VelocityMeter};
 
 /// Extract the .squashfs and callback download progress
 pub(crate) fn extract_squashfs<P>(
@@ -22,6 +26,7 @@ pub(crate) fn extract_squashfs<P>(
     path: P,
     progress: &AtomicU8,
     velocity: &AtomicUsize,
+    eta: &AtomicUsize,
     cancel_install: Arc<AtomicBool>,
 ) -> Result<(), io::Error>
 where
@@ -33,25 +38,25 @@ where
 
     let limit_thread = if total_memory <= 2 { Some(1) } else { None };
 
-    let mut now = Instant::now();
-    let mut v_download_len = 0.0;
+    let mut meter = VelocityMeter::new();
+    let mut last_count = 0;
 
     unsquashfs_wrapper::extract(
         archive,
         path,
         limit_thread,
         move |count| {
-            let elapsed = now.elapsed().as_secs();
-            if elapsed >= 1 {
-                now = Instant::now();
-                velocity.store(
-                    ((v_download_len / 1024.0) / elapsed as f64) as usize,
-                    Ordering::SeqCst,
-                );
-                v_download_len = 0.0;
-            }
             progress.store(count as u8, Ordering::SeqCst);
-            v_download_len += file_size * count as f64 / 100.0;
+            // 回调给出的是累计百分比，按增量换算为字节数
+            let count = count as usize;
+            let done = count.saturating_sub(last_count);
+            last_count = count;
+            meter.add((file_size * done as f64 / 100.0) as usize, velocity);
+            store_eta(
+                eta,
+                (file_size * 100usize.saturating_sub(count) as f64 / 100.0) as usize,
+                velocity,
+            );
         },
         cancel_install,
     )?;
@@ -59,6 +64,254 @@ where
     Ok(())
 }
 
+/// Compression of a rootfs tarball
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarCompression {
+    Xz,
+    Zstd,
+}
+
+impl TarCompression {
+    /// Detect the compression by the extension of a file name or URL
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name.ends_with(".tar.xz") || name.ends_with(".txz") {
+            Some(Self::Xz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    fn tar_arg(&self) -> &'static str {
+        match self {
+            Self::Xz => "--xz",
+            Self::Zstd => "--zstd",
+        }
+    }
+}
+
+/// Build the tar command line, the archive is read from stdin
+fn tar_command(compression: TarCompression, to: &Path) -> Command {
+    let mut cmd = Command::new("tar");
+    cmd.arg("-x")
+        .arg(compression.tar_arg())
+        .arg("-p")
+        .arg("--numeric-owner")
+        .arg("--acls")
+        .arg("--xattrs")
+        .arg("--xattrs-include=*")
+        .arg("-f")
+        .arg("-")
+        .arg("-C")
+        .arg(to)
+        .env("LANG", "C.UTF-8");
+
+    cmd
+}
+
+/// Extract the tarball and callback progress
+/// tar 本身不报告进度，故由此处向其标准输入写入归档，按已读取的字节数计算进度
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn extract_tarball<P>(
+    file_size: f64,
+    archive: P,
+    compression: TarCompression,
+    path: P,
+    progress: &AtomicU8,
+    velocity: &AtomicUsize,
+    eta: &AtomicUsize,
+    cancel_install: Arc<AtomicBool>,
+) -> Result<(), io::Error>
+where
+    P: AsRef<Path>,
+{
+    let mut archive = fs::File::open(archive)?;
+
+    let mut child = tar_command(compression, path.as_ref())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // 另开线程读取 stderr，避免管道写满导致 tar 阻塞
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_thread = thread::spawn(move || {
+        let mut s = String::new();
+        stderr.read_to_string(&mut s).ok();
+        s
+    });
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut buf = vec![0; 1024 * 1024];
+    let mut read_len = 0;
+    let mut meter = VelocityMeter::new();
+
+    let res = loop {
+        if cancel_install.load(Ordering::SeqCst) {
+            child.kill().ok();
+            break Ok(());
+        }
+
+        let n = match archive.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+
+        // tar 提前退出时写入会失败，具体原因以 tar 的输出为准
+        if stdin.write_all(&buf[..n]).is_err() {
+            break Ok(());
+        }
+
+        read_len += n;
+        meter.add(n, velocity);
+        store_eta(eta, (file_size as usize).saturating_sub(read_len), velocity);
+
+        progress.store(
+            ((read_len as f64 / file_size) * 100.0).min(100.0) as u8,
+            Ordering::SeqCst,
+        );
+    };
+
+    drop(stdin);
+
+    let status = child.wait()?;
+    let stderr = stderr_thread.join().unwrap_or_default();
+    res?;
+
+    if cancel_install.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("tar exited with {status}: {}", stderr.trim()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Cause of a failed squashfs or tarball extraction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtractErrorKind {
+    Corrupt,
+    NoSpace,
+    UnsupportedCompression,
+    Other,
+}
+
+// 剩余空间少于 1MiB 时视为空间不足
+const NO_SPACE_THRESHOLD: u64 = 1024 * 1024;
+
+impl ExtractErrorKind {
+    /// Classify an unsquashfs error by its errno and the unsquashfs output carried in it
+    /// Running out of space is detected by errno and the free space of `target` only,
+    /// as the strerror text in the output is translated by the locale
+    pub fn classify_squashfs(err: &io::Error, target: &Path) -> Self {
+        Self::classify(err, target, Self::classify_squashfs_message)
+    }
+
+    /// Classify a tar error by its errno and the tar output carried in it
+    pub fn classify_tarball(err: &io::Error, target: &Path) -> Self {
+        Self::classify(err, target, Self::classify_tarball_message)
+    }
+
+    fn classify(
+        err: &io::Error,
+        target: &Path,
+        classify_message: fn(&str) -> Option<Self>,
+    ) -> Self {
+        if err.raw_os_error() == Some(Errno::NOSPC.raw_os_error())
+            || err.kind() == io::ErrorKind::StorageFull
+        {
+            return Self::NoSpace;
+        }
+
+        if let Some(kind) = classify_message(&err.to_string()) {
+            return kind;
+        }
+
+        // 解包程序在写入失败时不一定会输出 ENOSPC，故检查目标分区的剩余空间
+        match statvfs(target) {
+            Ok(stat) if stat.f_bavail * stat.f_frsize < NO_SPACE_THRESHOLD => Self::NoSpace,
+            _ => Self::Other,
+        }
+    }
+
+    fn classify_tarball_message(msg: &str) -> Option<Self> {
+        // tar 以 LANG=C.UTF-8 运行，其输出不会被翻译
+        let msg = msg.to_lowercase();
+
+        // 找不到解压程序
+        if msg.contains("cannot exec") {
+            return Some(Self::UnsupportedCompression);
+        }
+
+        if msg.contains("does not look like a tar archive")
+            || msg.contains("unexpected eof in archive")
+            || msg.contains("file format not recognized")
+            || msg.contains("compressed data is corrupt")
+            || msg.contains("unexpected end of input")
+            || msg.contains("unknown frame descriptor")
+            || msg.contains("unsupported format")
+        {
+            return Some(Self::Corrupt);
+        }
+
+        None
+    }
+
+    fn classify_squashfs_message(msg: &str) -> Option<Self> {
+        // unsquashfs 自身的输出不会被翻译
+        let msg = msg.to_lowercase();
+
+        if msg.contains("compression, this is unsupported")
+            || msg.contains("compressor not supported")
+            || msg.contains("unknown compression")
+            || (msg.contains("decompressor") && msg.contains("not supported"))
+        {
+            return Some(Self::UnsupportedCompression);
+        }
+
+        if msg.contains("can't find a squashfs superblock")
+            || msg.contains("read_block: failed")
+            || msg.contains("uncompress failed")
+            || msg.contains("failed to read")
+            || msg.contains("corrupt")
+        {
+            return Some(Self::Corrupt);
+        }
+
+        None
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Corrupt => "corrupt",
+            Self::NoSpace => "nospace",
+            Self::UnsupportedCompression => "unsupported-compression",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl Display for ExtractErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Corrupt => "archive is corrupt",
+            Self::NoSpace => "no space left on target partition",
+            Self::UnsupportedCompression => "archive compression is not supported",
+            Self::Other => "unknown error",
+        };
+
+        write!(f, "{s}")
+    }
+}
+
 #[derive(Debug, Snafu)]
 pub enum RsyncError {
     #[snafu(transparent)]
@@ -75,25 +328,40 @@ pub enum RsyncError {
     RsyncFailed { status: i32 },
 }
 
-pub(crate) fn rsync_system(
-    progress: &AtomicU8,
-    velocity: &AtomicUsize,
-    from: &Path,
-    to: &Path,
-    cancel_install: &AtomicBool,
-    total: usize,
-) -> Result<(), RsyncError> {
-    let mut from = from.to_string_lossy().to_string();
-    let mut to = to.to_string_lossy().to_string();
-
-    for i in [&mut from, &mut to] {
-        if !i.ends_with('/') {
-            *i += "/";
+/// Bytes transferred so far, the first field of an `--info=progress2` line
+/// e.g. `  1,234,567  12%  1.23MB/s    0:00:10 (xfr#12, to-chk=100/200)`
+fn parse_rsync_transferred(line: &str) -> Option<usize> {
+    line.split_ascii_whitespace()
+        .next()?
+        .replace(',', "")
+        .parse()
+        .ok()
+}
+
+/// Remaining time in seconds, the fourth field of an `--info=progress2` line
+fn parse_rsync_eta(line: &str) -> Option<usize> {
+    let field = line.split_ascii_whitespace().nth(3)?;
+
+    field.split(':').try_fold(0, |acc, x| {
+        let x = x.parse::<usize>().ok()?;
+        Some(acc * 60 + x)
+    })
+}
+
+/// Build the rsync command line, paths are passed as-is so that spaces and non-UTF-8 bytes are kept
+fn rsync_command(from: &Path, to: &Path) -> Command {
+    // 以 / 结尾表示复制目录内容
+    let with_slash = |p: &Path| {
+        let mut p = p.as_os_str().to_os_string();
+        if !p.as_bytes().ends_with(b"/") {
+            p.push("/");
         }
-    }
 
-    let mut child = Command::new("rsync")
-        .arg("-a")
+        p
+    };
+
+    let mut cmd = Command::new("rsync");
+    cmd.arg("-a")
         .arg("-x")
         .arg("-H")
         .arg("-A")
@@ -103,26 +371,42 @@ pub(crate) fn rsync_system(
         .arg("--numeric-ids")
         .arg("--info=progress2")
         .arg("--no-i-r")
-        .arg(&from)
-        .arg(&to)
+        .arg(with_slash(from))
+        .arg(with_slash(to))
+        .env("LANG", "C.UTF-8");
+
+    cmd
+}
+
+/// Copy the system with rsync, `false` if the installation is cancelled
+pub(crate) fn rsync_system(
+    progress: &AtomicU8,
+    velocity: &AtomicUsize,
+    eta: &AtomicUsize,
+    from: &Path,
+    to: &Path,
+    cancel_install: &AtomicBool,
+) -> Result<bool, RsyncError> {
+    let mut cmd = rsync_command(from, to);
+
+    let mut child = cmd
         .stdout(Stdio::piped())
-        .env("LANG", "C.UTF-8")
         .spawn()
         .map_err(|e| RunCmdError::Exec {
-            cmd: format!(
-                "rsync -a -H -A -X -S -W --numeric-ids --info=progress2 --no-i-r {} {}",
-                from, to
-            ),
+            cmd: format!("{cmd:?}"),
             source: e,
         })?;
 
     let mut stdout = BufReader::new(child.stdout.take().context(GetStdoutSnafu)?);
 
-    let now = Instant::now();
+    let mut meter = VelocityMeter::new();
+    let mut last_transferred = 0;
+
     loop {
         if cancel_install.load(Ordering::SeqCst) {
             child.kill().ok();
-            return Ok(());
+            child.wait().ok();
+            return Ok(false);
         }
 
         let length = {
@@ -155,15 +439,13 @@ pub(crate) fn rsync_system(
                             (((total_files - uncheck) as f64 / total_files as f64) * 100.0) as u8,
                             Ordering::SeqCst,
                         );
-                        let elapsed = now.elapsed().as_secs();
-                        if elapsed >= 1 {
-                            velocity.store(
-                                total
-                                    * ((total_files - uncheck) as f64 / total_files as f64)
-                                        as usize
-                                    / elapsed as usize,
-                                Ordering::SeqCst,
-                            );
+                        if let Some(transferred) = parse_rsync_transferred(line) {
+                            meter.add(transferred.saturating_sub(last_transferred), velocity);
+                            last_transferred = transferred;
+                        }
+                        // rsync 自己估计了剩余时间
+                        if let Some(secs) = parse_rsync_eta(line) {
+                            eta.store(secs, Ordering::SeqCst);
                         }
                     } else {
                         warn!("rsync progress has except output: {}", line);
@@ -194,10 +476,7 @@ pub(crate) fn rsync_system(
     }
 
     let rsync_finish = child.wait().map_err(|e| RunCmdError::Exec {
-        cmd: format!(
-            "rsync -a -H -A -X -S -W --info=progress2 --numeric-ids --no-i-r {} {}",
-            from, to
-        ),
+        cmd: format!("{cmd:?}"),
         source: e,
     })?;
 
@@ -208,5 +487,141 @@ pub(crate) fn rsync_system(
         }
     );
 
-    Ok(())
+    Ok(true)
+}
+
+#[test]
+fn test_classify_unsquashfs_message() {
+    assert_eq!(
+        ExtractErrorKind::classify_squashfs_message(
+            "Can't find a SQUASHFS superblock on /tmp/a.squashfs"
+        ),
+        Some(ExtractErrorKind::Corrupt)
+    );
+    assert_eq!(
+        ExtractErrorKind::classify_squashfs_message("read_block: failed to read block @0x1f3a2"),
+        Some(ExtractErrorKind::Corrupt)
+    );
+    assert_eq!(
+        ExtractErrorKind::classify_squashfs_message(
+            "Filesystem uses zstd compression, this is unsupported by this version"
+        ),
+        Some(ExtractErrorKind::UnsupportedCompression)
+    );
+    // strerror 文本随语言环境变化，空间不足由 errno 与剩余空间判断
+    assert_eq!(
+        ExtractErrorKind::classify_squashfs_message(
+            "write_file: failed to create file /mnt/usr/bin/ls, because No space left on device"
+        ),
+        None
+    );
+    assert_eq!(ExtractErrorKind::classify_squashfs_message("Killed"), None);
+}
+
+#[test]
+fn test_classify_tar_message() {
+    assert_eq!(
+        ExtractErrorKind::classify_tarball_message(
+            "tar: This does not look like a tar archive\ntar: Exiting with failure status due to previous errors"
+        ),
+        Some(ExtractErrorKind::Corrupt)
+    );
+    assert_eq!(
+        ExtractErrorKind::classify_tarball_message("xz: (stdin): Compressed data is corrupt"),
+        Some(ExtractErrorKind::Corrupt)
+    );
+    assert_eq!(
+        ExtractErrorKind::classify_tarball_message(
+            "tar (child): zstd: Cannot exec: No such file or directory"
+        ),
+        Some(ExtractErrorKind::UnsupportedCompression)
+    );
+    // unsquashfs 的输出不应被当作 tar 的错误
+    assert_eq!(
+        ExtractErrorKind::classify_tarball_message(
+            "Filesystem uses zstd compression, this is unsupported by this version"
+        ),
+        None
+    );
+}
+
+#[test]
+fn test_classify_squashfs_error_by_errno() {
+    let target = std::env::temp_dir();
+
+    assert_eq!(
+        ExtractErrorKind::classify_squashfs(
+            &io::Error::from_raw_os_error(Errno::NOSPC.raw_os_error()),
+            &target
+        ),
+        ExtractErrorKind::NoSpace
+    );
+    assert_eq!(
+        ExtractErrorKind::classify_squashfs(
+            &io::Error::new(io::ErrorKind::Other, "Can't find a SQUASHFS superblock"),
+            &target
+        ),
+        ExtractErrorKind::Corrupt
+    );
+}
+
+#[test]
+fn test_rsync_command_keeps_paths() {
+    use std::ffi::{OsStr, OsString};
+    use std::os::unix::ffi::OsStringExt;
+    use std::path::PathBuf;
+
+    let from = PathBuf::from(OsString::from_vec(b"/run/media/My Disk/\xff".to_vec()));
+    let cmd = rsync_command(&from, Path::new("/tmp/target dir/"));
+    let args = cmd.get_args().collect::<Vec<_>>();
+
+    assert_eq!(args[args.len() - 2].as_bytes(), b"/run/media/My Disk/\xff/");
+    assert_eq!(args[args.len() - 1], OsStr::new("/tmp/target dir/"));
+}
+
+#[test]
+fn test_tar_compression_from_name() {
+    assert_eq!(
+        TarCompression::from_name("https://repo.aosc.io/aosc-os/base.tar.xz"),
+        Some(TarCompression::Xz)
+    );
+    assert_eq!(
+        TarCompression::from_name("/tmp/base.tar.zst"),
+        Some(TarCompression::Zstd)
+    );
+    assert_eq!(
+        TarCompression::from_name("base.tzst"),
+        Some(TarCompression::Zstd)
+    );
+    assert_eq!(TarCompression::from_name("/tmp/base.squashfs"), None);
+    assert_eq!(TarCompression::from_name("/tmp/squashfs"), None);
+}
+
+#[test]
+fn test_parse_rsync_transferred() {
+    assert_eq!(
+        parse_rsync_transferred("  1,234,567  12%  1.23MB/s    0:00:10 (xfr#12, to-chk=100/200)"),
+        Some(1234567)
+    );
+    assert_eq!(
+        parse_rsync_transferred("          0   0%    0.00kB/s    0:00:00 (xfr#0, to-chk=0/0)"),
+        Some(0)
+    );
+    assert_eq!(
+        parse_rsync_transferred("sending incremental file list"),
+        None
+    );
+}
+
+#[test]
+fn test_parse_rsync_eta() {
+    assert_eq!(
+        parse_rsync_eta("  1,234,567  12%  1.23MB/s    0:00:10 (xfr#12, to-chk=100/200)"),
+        Some(10)
+    );
+    assert_eq!(
+        parse_rsync_eta(" 53,687,091,200  50%  120.00MB/s    1:02:03 (xfr#1, to-chk=1/2)"),
+        Some(3723)
+    );
+    assert_eq!(parse_rsync_eta("sending incremental file list"), None);
 }
@@ -0,0 +1,103 @@
+use std::{fs, io, os::unix::fs::symlink, path::Path};
+
+/// Present until the first-boot setup tool has created the user account
+pub const FIRST_BOOT_MARKER: &str = "/etc/deploykit/first-boot";
+const FIRST_BOOT_UNIT: &str = "deploykit-first-boot.service";
+/// Setup tool launched on first boot, it removes [`FIRST_BOOT_MARKER`] when done
+const FIRST_BOOT_SETUP_COMMAND: &str = "/usr/bin/deploykit-first-boot";
+
+/// Let the end user create their account on first boot instead of at install time
+/// Must be used in a chroot context
+pub(crate) fn setup_first_boot() -> Result<(), io::Error> {
+    setup_first_boot_in(Path::new("/"))
+}
+
+fn setup_first_boot_in(root: &Path) -> Result<(), io::Error> {
+    // 没有设置工具时首次启动无法创建账户，系统将无法登录
+    if !root.join(&FIRST_BOOT_SETUP_COMMAND[1..]).is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{FIRST_BOOT_SETUP_COMMAND} is not installed"),
+        ));
+    }
+
+    let marker = root.join(&FIRST_BOOT_MARKER[1..]);
+    if let Some(parent) = marker.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&marker, "")?;
+
+    let unit_dir = root.join("etc/systemd/system");
+    let wants_dir = unit_dir.join("multi-user.target.wants");
+    fs::create_dir_all(&wants_dir)?;
+    fs::write(unit_dir.join(FIRST_BOOT_UNIT), first_boot_unit())?;
+
+    // 相当于 systemctl enable，chroot 中不一定能运行 systemctl
+    let link = wants_dir.join(FIRST_BOOT_UNIT);
+    if fs::symlink_metadata(&link).is_ok() {
+        fs::remove_file(&link)?;
+    }
+    symlink(format!("/etc/systemd/system/{FIRST_BOOT_UNIT}"), link)?;
+
+    Ok(())
+}
+
+fn first_boot_unit() -> String {
+    format!(
+        "[Unit]\n\
+        Description=Create the user account on first boot\n\
+        ConditionPathExists={FIRST_BOOT_MARKER}\n\
+        After=systemd-user-sessions.service\n\
+        Before=display-manager.service getty@tty1.service\n\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        ExecStart={FIRST_BOOT_SETUP_COMMAND}\n\
+        StandardInput=tty\n\
+        StandardOutput=tty\n\
+        TTYPath=/dev/tty1\n\
+        \n\
+        [Install]\n\
+        WantedBy=multi-user.target\n"
+    )
+}
+
+#[test]
+fn test_setup_first_boot_in() {
+    let root = std::env::temp_dir().join(format!("dk-first-boot-{}", std::process::id()));
+    fs::create_dir_all(root.join("usr/bin")).unwrap();
+    fs::write(root.join("usr/bin/deploykit-first-boot"), "").unwrap();
+
+    setup_first_boot_in(&root).unwrap();
+    // 重复执行不会因链接已存在而失败
+    setup_first_boot_in(&root).unwrap();
+
+    let marker = root.join("etc/deploykit/first-boot").exists();
+    let unit = fs::read_to_string(root.join("etc/systemd/system/deploykit-first-boot.service"));
+    let link = fs::read_link(
+        root.join("etc/systemd/system/multi-user.target.wants/deploykit-first-boot.service"),
+    );
+    fs::remove_dir_all(&root).unwrap();
+
+    assert!(marker);
+    let unit = unit.unwrap();
+    assert!(unit.contains("ConditionPathExists=/etc/deploykit/first-boot\n"));
+    assert!(unit.contains("ExecStart=/usr/bin/deploykit-first-boot\n"));
+    assert_eq!(
+        link.unwrap(),
+        Path::new("/etc/systemd/system/deploykit-first-boot.service")
+    );
+}
+
+#[test]
+fn test_setup_first_boot_in_without_setup_command() {
+    let root = std::env::temp_dir().join(format!("dk-first-boot-missing-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+
+    let res = setup_first_boot_in(&root);
+    let marker = root.join("etc/deploykit/first-boot").exists();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert!(!marker);
+}
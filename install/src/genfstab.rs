@@ -1,11 +1,12 @@
 use std::{
     ffi::OsString,
-    io::Write,
+    io::{self, Write},
     os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
+    process::Command,
 };
 
-use disk::disk_types::FileSystem;
+use disk::{disk_types::FileSystem, is_dev_mode, mounts::decode_octal_escapes};
 use fstab_generate::BlockInfo;
 use snafu::{OptionExt, ResultExt, Snafu};
 
@@ -17,6 +18,22 @@ pub enum GenfstabError {
     UUID { path: PathBuf },
     #[snafu(display("Failed to operate /etc/fstab"))]
     OperateFstabFile { source: std::io::Error },
+    #[snafu(display("Failed to operate /etc/crypttab"))]
+    OperateCrypttabFile { source: std::io::Error },
+    #[snafu(display("Failed to write md arrays to /etc/mdadm.conf"))]
+    MdadmConf { source: std::io::Error },
+    #[snafu(display("Failed to run blkid"))]
+    Blkid { source: std::io::Error },
+    #[snafu(display("Partition {} has no PARTUUID", path.display()))]
+    PartUUID { path: PathBuf },
+    #[snafu(display("fstab has no entry for {}", mount_path.display()))]
+    MissingEntry { mount_path: PathBuf },
+    #[snafu(display("fstab entry {spec} for {} does not match partition {}", mount_path.display(), path.display()))]
+    MismatchEntry {
+        spec: String,
+        mount_path: PathBuf,
+        path: PathBuf,
+    },
 }
 
 /// Gen fstab to /etc/fstab
@@ -26,7 +43,7 @@ pub(crate) fn genfstab_to_file(
     root_path: &Path,
     mount_path: &Path,
 ) -> Result<(), GenfstabError> {
-    if cfg!(debug_assertions) {
+    if is_dev_mode() {
         return Ok(());
     }
 
@@ -41,6 +58,174 @@ pub(crate) fn genfstab_to_file(
     Ok(())
 }
 
+/// Gen fstab entry of a btrfs subvolume to /etc/fstab
+pub(crate) fn genfstab_btrfs_subvol_to_file(
+    partition_path: &Path,
+    root_path: &Path,
+    mount_path: &Path,
+    subvol: &str,
+) -> Result<(), GenfstabError> {
+    if is_dev_mode() {
+        return Ok(());
+    }
+
+    let uuid = blkid_probe(partition_path, "UUID")?.context(UUIDSnafu {
+        path: partition_path,
+    })?;
+
+    let s = btrfs_subvol_fstab_entry(&uuid, mount_path, subvol);
+    let mut f = std::fs::OpenOptions::new()
+        .append(true)
+        .open(root_path.join("etc/fstab"))
+        .context(OperateFstabFileSnafu)?;
+
+    f.write_all(s.as_bytes()).context(OperateFstabFileSnafu)?;
+
+    Ok(())
+}
+
+// btrfs 不需要开机时 fsck
+fn btrfs_subvol_fstab_entry(uuid: &str, mount_path: &Path, subvol: &str) -> String {
+    format!(
+        "UUID={uuid}  {}  btrfs  defaults,subvol={subvol}  0  0\n",
+        mount_path.display()
+    )
+}
+
+/// Gen ESP fstab entry to /etc/fstab
+/// ESP 重新格式化后文件系统 UUID 会改变，故使用 PARTUUID
+pub(crate) fn genfstab_esp_to_file(
+    partition_path: &Path,
+    root_path: &Path,
+    mount_path: &Path,
+) -> Result<(), GenfstabError> {
+    if is_dev_mode() {
+        return Ok(());
+    }
+
+    let s = esp_fstab_entry(partition_path, mount_path)?;
+    let mut f = std::fs::OpenOptions::new()
+        .append(true)
+        .open(root_path.join("etc/fstab"))
+        .context(OperateFstabFileSnafu)?;
+
+    f.write_all(s.as_bytes()).context(OperateFstabFileSnafu)?;
+
+    Ok(())
+}
+
+/// Gen swap partition fstab entry to /etc/fstab
+pub(crate) fn genfstab_swap_to_file(
+    partition_path: &Path,
+    root_path: &Path,
+) -> Result<(), GenfstabError> {
+    if is_dev_mode() {
+        return Ok(());
+    }
+
+    let s = fstab_entries(partition_path, "swap", None)?;
+    let mut f = std::fs::OpenOptions::new()
+        .append(true)
+        .open(root_path.join("etc/fstab"))
+        .context(OperateFstabFileSnafu)?;
+
+    f.write_all(s.as_bytes()).context(OperateFstabFileSnafu)?;
+
+    Ok(())
+}
+
+/// Gen /etc/crypttab entry of the LUKS container to unlock at boot
+pub(crate) fn gencrypttab_to_file(
+    name: &str,
+    luks_uuid: &str,
+    root_path: &Path,
+) -> Result<(), GenfstabError> {
+    if is_dev_mode() {
+        return Ok(());
+    }
+
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(root_path.join("etc/crypttab"))
+        .context(OperateCrypttabFileSnafu)?;
+
+    f.write_all(crypttab_entry(name, luks_uuid).as_bytes())
+        .context(OperateCrypttabFileSnafu)?;
+
+    Ok(())
+}
+
+/// Write the running md arrays to /etc/mdadm.conf, so that the initramfs assembles them
+/// ARRAY entries already in the file are replaced, other settings are kept
+pub(crate) fn genmdadm_conf_to_file(root_path: &Path) -> Result<(), GenfstabError> {
+    if is_dev_mode() {
+        return Ok(());
+    }
+
+    let output = Command::new("mdadm")
+        .args(["--detail", "--scan"])
+        .output()
+        .context(MdadmConfSnafu)?;
+
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "mdadm --detail --scan failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ))
+        .context(MdadmConfSnafu);
+    }
+
+    let path = root_path.join("etc/mdadm.conf");
+    let existing = match std::fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context(MdadmConfSnafu),
+    };
+
+    std::fs::write(
+        &path,
+        merge_mdadm_conf(&existing, &String::from_utf8_lossy(&output.stdout)),
+    )
+    .context(MdadmConfSnafu)?;
+
+    Ok(())
+}
+
+/// Replace the ARRAY entries of an mdadm.conf with `scan`, the output of `mdadm --detail --scan`
+fn merge_mdadm_conf(existing: &str, scan: &str) -> String {
+    let mut res = String::new();
+    let mut in_array = false;
+
+    for line in existing.lines() {
+        // 以空白开头的行是上一行的续行
+        if line.starts_with(char::is_whitespace) && !line.trim().is_empty() {
+            if !in_array {
+                res.push_str(line);
+                res.push('\n');
+            }
+            continue;
+        }
+
+        in_array = line.starts_with("ARRAY");
+        if !in_array {
+            res.push_str(line);
+            res.push('\n');
+        }
+    }
+
+    res.push_str(scan);
+
+    res
+}
+
+fn crypttab_entry(name: &str, luks_uuid: &str) -> String {
+    format!("{name}  UUID={luks_uuid}  none  luks\n")
+}
+
 /// Must be used in a chroot context
 pub(crate) fn write_swap_entry_to_fstab() -> Result<(), GenfstabError> {
     let s = "/swapfile none swap defaults,nofail 0 0\n";
@@ -56,25 +241,34 @@ pub(crate) fn write_swap_entry_to_fstab() -> Result<(), GenfstabError> {
     Ok(())
 }
 
-fn fstab_entries(
-    device_path: &Path,
-    fs_type: &str,
-    mount_path: Option<&Path>,
-) -> Result<OsString, GenfstabError> {
-    let (fs_type, option) = match fs_type {
+/// Filesystem and mount options of a fstab entry
+fn fstab_fs_type(fs_type: &str) -> Option<(FileSystem, &'static str)> {
+    let res = match fs_type {
         "vfat" | "fat16" | "fat32" => (FileSystem::Fat32, "defaults,nofail"),
         "ext4" => (FileSystem::Ext4, "defaults"),
         "btrfs" => (FileSystem::Btrfs, "defaults"),
         "xfs" => (FileSystem::Xfs, "defaults"),
         "f2fs" => (FileSystem::F2fs, "defaults"),
         "swap" => (FileSystem::Swap, "sw"),
-        _ => {
-            return Err(GenfstabError::UnsupportedFileSystem {
-                fs_type: fs_type.to_string(),
-            });
-        }
+        _ => return None,
     };
 
+    Some(res)
+}
+
+/// Whether a fstab entry can be generated for the filesystem
+pub(crate) fn is_fstab_supported(fs_type: &str) -> bool {
+    fstab_fs_type(fs_type).is_some()
+}
+
+fn fstab_entries(
+    device_path: &Path,
+    fs_type: &str,
+    mount_path: Option<&Path>,
+) -> Result<OsString, GenfstabError> {
+    let (fs_type, option) =
+        fstab_fs_type(fs_type).context(UnsupportedFileSystemSnafu { fs_type })?;
+
     let root_id = BlockInfo::get_partition_id(device_path, fs_type)
         .context(UUIDSnafu { path: device_path })?;
 
@@ -84,3 +278,215 @@ fn fstab_entries(
 
     Ok(fstab.to_owned())
 }
+
+fn esp_fstab_entry(device_path: &Path, mount_path: &Path) -> Result<String, GenfstabError> {
+    let partuuid = get_partuuid(device_path)?;
+
+    Ok(format!(
+        "PARTUUID={partuuid}  {}  vfat  defaults,nofail  0  2\n",
+        mount_path.display()
+    ))
+}
+
+fn get_partuuid(device_path: &Path) -> Result<String, GenfstabError> {
+    blkid_probe(device_path, "PART_ENTRY_UUID")?.context(PartUUIDSnafu { path: device_path })
+}
+
+/// Probe a tag (UUID, PART_ENTRY_UUID, ...) of a partition
+/// 使用 -p 直接读取设备，避免读到 blkid 缓存中格式化之前的信息
+pub(crate) fn blkid_probe(device_path: &Path, tag: &str) -> Result<Option<String>, GenfstabError> {
+    let output = Command::new("blkid")
+        .arg("-p")
+        .arg("-s")
+        .arg(tag)
+        .arg("-o")
+        .arg("value")
+        .arg(device_path)
+        .output()
+        .context(BlkidSnafu)?;
+
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    if !output.status.success() || value.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(value))
+}
+
+/// Check that the fstab of the installed system has an entry for every `(device, mount path)`,
+/// and the entry points to the current UUID/PARTUUID of the device
+pub(crate) fn verify_fstab(
+    root_path: &Path,
+    entries: &[(&Path, &Path)],
+) -> Result<(), GenfstabError> {
+    let fstab =
+        std::fs::read_to_string(root_path.join("etc/fstab")).context(OperateFstabFileSnafu)?;
+
+    for (device_path, mount_path) in entries {
+        let spec = find_fstab_spec(&fstab, mount_path).context(MissingEntrySnafu {
+            mount_path: mount_path.to_path_buf(),
+        })?;
+
+        let current = match spec.split_once('=') {
+            Some(("UUID", _)) => blkid_probe(device_path, "UUID")?,
+            Some(("PARTUUID", _)) => blkid_probe(device_path, "PART_ENTRY_UUID")?,
+            // 设备路径或其他形式的条目，无法比较
+            _ => continue,
+        };
+
+        let matched = current
+            .zip(spec.split_once('='))
+            .is_some_and(|(current, (_, v))| current.eq_ignore_ascii_case(v));
+
+        if !matched {
+            return Err(GenfstabError::MismatchEntry {
+                spec: spec.to_string(),
+                mount_path: mount_path.to_path_buf(),
+                path: device_path.to_path_buf(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the source (first field) of the fstab entry mounted at `mount_path`
+pub(crate) fn find_fstab_spec<'a>(fstab: &'a str, mount_path: &Path) -> Option<&'a str> {
+    fstab
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let spec = fields.next()?;
+            let target = fields.next()?;
+
+            // 挂载点中的空格等字符以八进制转义
+            (Path::new(&decode_octal_escapes(target.as_bytes())) == mount_path).then_some(spec)
+        })
+}
+
+#[test]
+fn test_find_fstab_spec() {
+    let fstab = "# /etc/fstab: static file system information.\n\
+                 \n\
+                 UUID=3c6a2a1e-5d6f-4a8e-9a2b-6f0f3c2d1e0a  /  ext4  defaults  0  1\n\
+                 PARTUUID=8a2f3c1d-0b4e-4f6a-9c7d-1e2f3a4b5c6d  /efi  vfat  defaults,nofail  0  2\n\
+                 /swapfile none swap defaults,nofail 0 0\n";
+
+    assert_eq!(
+        find_fstab_spec(fstab, Path::new("/")),
+        Some("UUID=3c6a2a1e-5d6f-4a8e-9a2b-6f0f3c2d1e0a")
+    );
+    assert_eq!(
+        find_fstab_spec(fstab, Path::new("/efi")),
+        Some("PARTUUID=8a2f3c1d-0b4e-4f6a-9c7d-1e2f3a4b5c6d")
+    );
+    assert_eq!(find_fstab_spec(fstab, Path::new("/data")), None);
+    assert_eq!(find_fstab_spec("# / ext4\n", Path::new("/")), None);
+    assert_eq!(
+        find_fstab_spec(
+            "/dev/sdb1  /media/My\\040Disk  vfat  defaults  0  0\n",
+            Path::new("/media/My Disk")
+        ),
+        Some("/dev/sdb1")
+    );
+}
+
+#[test]
+fn test_crypttab_entry() {
+    assert_eq!(
+        crypttab_entry(
+            "luks-0b4e4f6a-9c7d-1e2f-3a4b-5c6d8a2f3c1d",
+            "0b4e4f6a-9c7d-1e2f-3a4b-5c6d8a2f3c1d"
+        ),
+        "luks-0b4e4f6a-9c7d-1e2f-3a4b-5c6d8a2f3c1d  UUID=0b4e4f6a-9c7d-1e2f-3a4b-5c6d8a2f3c1d  none  luks\n"
+    );
+}
+
+#[test]
+#[ignore = "requires root to set up a loop device"]
+fn test_esp_fstab_entry_after_reformat() {
+    fn run(cmd: &mut Command) -> String {
+        let output = cmd.output().unwrap();
+        assert!(output.status.success(), "{cmd:?} failed");
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    fn fs_uuid(p: &Path) -> String {
+        run(Command::new("blkid")
+            .args(["-p", "-s", "UUID", "-o", "value"])
+            .arg(p))
+    }
+
+    let dev = disk::loop_device::LoopDevice::partitioned("esp-test", 64 * 1024 * 1024);
+
+    let mut sfdisk = Command::new("sfdisk")
+        .arg(dev.path())
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    sfdisk
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"label: gpt\n,,U\n")
+        .unwrap();
+    assert!(sfdisk.wait().unwrap().success());
+    // 等待内核与 udev 处理新的分区表
+    Command::new("udevadm").arg("settle").status().ok();
+
+    let part = dev.partition(1);
+
+    run(Command::new("mkfs.vfat").arg("-F32").arg(&part));
+    let first_uuid = fs_uuid(&part);
+    let first = esp_fstab_entry(&part, Path::new("/efi"));
+
+    run(Command::new("mkfs.vfat").arg("-F32").arg(&part));
+    let second_uuid = fs_uuid(&part);
+    let second = esp_fstab_entry(&part, Path::new("/efi"));
+
+    let partuuid = run(Command::new("blkid")
+        .args(["-p", "-s", "PART_ENTRY_UUID", "-o", "value"])
+        .arg(&part));
+
+    // 文件系统 UUID 会改变，但 fstab 条目应一直指向当前的分区
+    assert_ne!(first_uuid, second_uuid);
+    assert_eq!(first.unwrap(), second.as_ref().unwrap().clone());
+    assert!(second
+        .unwrap()
+        .starts_with(&format!("PARTUUID={partuuid}  /efi  vfat")));
+}
+
+#[test]
+fn test_btrfs_subvol_fstab_entry() {
+    let uuid = "3c6a2a1e-5d6f-4a8e-9a2b-6f0f3c2d1e0a";
+    let fstab = btrfs_subvol_fstab_entry(uuid, Path::new("/"), "@")
+        + &btrfs_subvol_fstab_entry(uuid, Path::new("/home"), "@home");
+
+    assert_eq!(
+        fstab,
+        "UUID=3c6a2a1e-5d6f-4a8e-9a2b-6f0f3c2d1e0a  /  btrfs  defaults,subvol=@  0  0\n\
+         UUID=3c6a2a1e-5d6f-4a8e-9a2b-6f0f3c2d1e0a  /home  btrfs  defaults,subvol=@home  0  0\n"
+    );
+    assert_eq!(
+        find_fstab_spec(&fstab, Path::new("/home")),
+        Some("UUID=3c6a2a1e-5d6f-4a8e-9a2b-6f0f3c2d1e0a")
+    );
+}
+
+#[test]
+fn test_merge_mdadm_conf() {
+    let existing = "# mdadm.conf\nMAILADDR root\nARRAY /dev/md/old metadata=1.2 UUID=aaaa\n   devices=/dev/sda1,/dev/sdb1\nDEVICE partitions\n";
+    let scan = "ARRAY /dev/md/root metadata=1.2 name=aosc:root UUID=bbbb\n";
+
+    let res = merge_mdadm_conf(existing, scan);
+    assert_eq!(
+        res,
+        "# mdadm.conf\nMAILADDR root\nDEVICE partitions\nARRAY /dev/md/root metadata=1.2 name=aosc:root UUID=bbbb\n"
+    );
+    // 重复生成不会产生重复的 ARRAY
+    assert_eq!(merge_mdadm_conf(&res, scan), res);
+    assert_eq!(merge_mdadm_conf("", scan), scan);
+}
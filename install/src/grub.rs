@@ -1,15 +1,34 @@
+use disk::PartitionError;
 use snafu::Snafu;
-use tracing::info;
+use tracing::{debug, info, warn};
 
 use crate::utils::RunCmdError;
 use crate::utils::{get_arch_name, run_command};
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const GRUB_CFG_PATH: &str = "/boot/grub/grub.cfg";
+const OS_PROBER_BEGIN: &str = "### BEGIN /etc/grub.d/30_os-prober ###";
+const OS_PROBER_END: &str = "### END /etc/grub.d/30_os-prober ###";
+const GRUB_PLATFORM_DIR: &str = "/usr/lib/grub";
+const GRUB_BOOTLOADER_ID: &str = "AOSC OS";
+const GRUB_DEFAULT_PATH: &str = "/etc/default/grub";
 
 #[cfg(not(target_arch = "powerpc64"))]
 #[derive(Debug, Snafu)]
 pub enum RunGrubError {
     #[snafu(transparent)]
     RunCommand { source: RunCmdError },
+    #[snafu(display("GRUB platform {platform} is not installed"))]
+    MissingPlatform { platform: &'static str },
+    #[snafu(display("Failed to enable GRUB cryptodisk in {GRUB_DEFAULT_PATH}"))]
+    EnableCryptodisk { source: std::io::Error },
+    #[snafu(display("Failed to set kernel command line in {GRUB_DEFAULT_PATH}"))]
+    SetKernelCmdline { source: std::io::Error },
+    #[snafu(display("Failed to get the resume offset of the swapfile"))]
+    ResumeOffset { source: std::io::Error },
+    #[snafu(display("Failed to get the UUID of the LUKS container"))]
+    LuksUuid { source: PartitionError },
 }
 
 #[cfg(target_arch = "powerpc64")]
@@ -19,20 +38,149 @@ pub enum RunGrubError {
     RunCommand { source: RunCmdError },
     #[snafu(display("Failed to open /proc/cpuinfo"))]
     OpenCpuInfo { source: std::io::Error },
+    #[snafu(display("GRUB platform {platform} is not installed"))]
+    MissingPlatform { platform: &'static str },
+    #[snafu(display("Failed to enable GRUB cryptodisk in {GRUB_DEFAULT_PATH}"))]
+    EnableCryptodisk { source: std::io::Error },
+    #[snafu(display("Failed to set kernel command line in {GRUB_DEFAULT_PATH}"))]
+    SetKernelCmdline { source: std::io::Error },
+    #[snafu(display("Failed to get the resume offset of the swapfile"))]
+    ResumeOffset { source: std::io::Error },
+    #[snafu(display("Failed to get the UUID of the LUKS container"))]
+    LuksUuid { source: PartitionError },
 }
 
-/// Runs grub-install and grub-mkconfig
+/// GRUB platform of the architecture and boot mode
+fn grub_platform(arch: &str, is_efi: bool) -> Option<&'static str> {
+    let platform = match (arch, is_efi) {
+        ("amd64", true) => "x86_64-efi",
+        ("amd64", false) => "i386-pc",
+        ("i486", true) => "i386-efi",
+        ("i486", false) => "i386-pc",
+        ("arm64", true) => "arm64-efi",
+        ("riscv64", true) => "riscv64-efi",
+        ("loongarch64", true) => "loongarch64-efi",
+        ("loongson3", true) => "mips64el-efi",
+        ("ppc64el" | "ppc64" | "powerpc", _) => "powerpc-ieee1275",
+        _ => return None,
+    };
+
+    Some(platform)
+}
+
+/// Suffix of EFI executables of the architecture, as used by grub-install
+fn efi_suffix(arch: &str) -> Option<&'static str> {
+    let suffix = match arch {
+        "amd64" => "x64",
+        "i486" => "ia32",
+        "arm64" => "aa64",
+        "riscv64" => "riscv64",
+        "loongarch64" => "loongarch64",
+        "loongson3" => "mips64el",
+        _ => return None,
+    };
+
+    Some(suffix)
+}
+
+/// Default loader path (relative to the ESP) used by the firmware when no boot entry works
+/// e.g. `EFI/BOOT/BOOTAA64.EFI` on arm64
+fn default_boot_file(arch: &str) -> Option<PathBuf> {
+    efi_suffix(arch).map(|s| PathBuf::from(format!("EFI/BOOT/BOOT{}.EFI", s.to_ascii_uppercase())))
+}
+
+/// Default loader path of the running architecture, relative to the ESP
+pub fn firmware_boot_file() -> Option<PathBuf> {
+    get_arch_name().and_then(default_boot_file)
+}
+
+/// Find the loader path the firmware looks for on the ESP mounted at `esp`
+/// FAT 不区分大小写，沿用 ESP 上已有的目录与文件名（如 EFI/Boot/bootaa64.efi）
+#[cfg(not(target_arch = "powerpc64"))]
+fn detect_firmware_boot_file(esp: &Path, arch: &str) -> Option<PathBuf> {
+    let default = default_boot_file(arch)?;
+    let mut res = esp.to_path_buf();
+
+    for c in default.components() {
+        let name = c.as_os_str();
+        let found = fs::read_dir(&res).ok().and_then(|dir| {
+            dir.flatten()
+                .map(|e| e.file_name())
+                .find(|x| x.eq_ignore_ascii_case(name))
+        });
+
+        res.push(found.as_deref().unwrap_or(name));
+    }
+
+    Some(res)
+}
+
+/// Make sure the GRUB loader exists at the path the firmware looks for
+/// Returns the loader path
 /// Must be used in a chroot context
 #[cfg(not(target_arch = "powerpc64"))]
-pub(crate) fn execute_grub_install(mbr_dev: Option<&Path>, lang: &str) -> Result<(), RunCmdError> {
-    use tracing::warn;
+fn place_firmware_boot_file(esp: &Path, arch: &str) -> std::io::Result<Option<PathBuf>> {
+    let (suffix, boot_file) = match (efi_suffix(arch), detect_firmware_boot_file(esp, arch)) {
+        (Some(suffix), Some(boot_file)) => (suffix, boot_file),
+        _ => return Ok(None),
+    };
+
+    if boot_file.is_file() {
+        return Ok(Some(boot_file));
+    }
+
+    let loader = esp
+        .join("EFI")
+        .join(GRUB_BOOTLOADER_ID)
+        .join(format!("grub{suffix}.efi"));
+
+    info!("Copying {} to {}", loader.display(), boot_file.display());
+
+    if let Some(parent) = boot_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
 
-    let mut grub_install_args = vec![];
+    fs::copy(&loader, &boot_file)?;
+
+    Ok(Some(boot_file))
+}
+
+/// Check that the GRUB platform files needed by grub-install exist
+/// Must be used in a chroot context
+pub(crate) fn check_grub_platform(is_efi: bool) -> Result<(), RunGrubError> {
+    let platform = match get_arch_name().and_then(|arch| grub_platform(arch, is_efi)) {
+        Some(v) => v,
+        // 不支持 GRUB 的架构，由 execute_grub_install 跳过
+        None => return Ok(()),
+    };
+
+    if Path::new(GRUB_PLATFORM_DIR).join(platform).is_dir() {
+        return Ok(());
+    }
+
+    Err(RunGrubError::MissingPlatform { platform })
+}
 
-    if let Some(mbr_dev) = mbr_dev {
-        grub_install_args.push("--target=i386-pc".to_string());
-        let path = mbr_dev.display().to_string();
-        grub_install_args.push(path);
+/// Runs grub-install and grub-mkconfig
+/// `mbr_devs` are the disks to install BIOS GRUB onto, UEFI GRUB is installed if empty
+/// Must be used in a chroot context
+#[cfg(not(target_arch = "powerpc64"))]
+pub(crate) fn execute_grub_install(
+    mbr_devs: &[PathBuf],
+    lang: &str,
+    live_devices: &[PathBuf],
+) -> Result<(), RunCmdError> {
+    if !mbr_devs.is_empty() {
+        for mbr_dev in mbr_devs {
+            run_command(
+                "grub-install",
+                [
+                    "--target=i386-pc".to_string(),
+                    mbr_dev.display().to_string(),
+                ],
+                vec![("LANG", lang.to_string())],
+            )?;
+        }
     } else {
         let (target, is_efi) = match get_arch_name() {
             Some("amd64") => (&[][..], true),
@@ -49,31 +197,46 @@ pub(crate) fn execute_grub_install(mbr_dev: Option<&Path>, lang: &str) -> Result
                 return Ok(());
             }
         };
-        grub_install_args.push("--bootloader-id=AOSC OS".to_string());
+        let mut grub_install_args = vec![format!("--bootloader-id={GRUB_BOOTLOADER_ID}")];
         grub_install_args.extend(target.iter().map(|x| x.to_string()));
         if is_efi {
             grub_install_args.push("--efi-directory=/efi".to_string());
         }
+
+        run_command(
+            "grub-install",
+            grub_install_args,
+            vec![("LANG", lang.to_string())],
+        )?;
     };
 
-    run_command(
-        "grub-install",
-        grub_install_args,
-        vec![("LANG", lang.to_string())],
-    )?;
+    // 部分非 amd64 固件只认默认路径下的引导器
+    if let Some(arch @ ("arm64" | "riscv64" | "loongarch64" | "loongson3")) =
+        get_arch_name().filter(|_| mbr_devs.is_empty())
+    {
+        match place_firmware_boot_file(Path::new("/efi"), arch) {
+            Ok(Some(path)) => info!("Firmware boot file: {}", path.display()),
+            Ok(None) => {}
+            Err(e) => warn!("Failed to place firmware boot file: {e}"),
+        }
+    }
+
     run_command(
         "grub-mkconfig",
-        ["-o", "/boot/grub/grub.cfg"],
+        ["-o", GRUB_CFG_PATH],
         vec![("LANG", lang.to_string())],
     )?;
 
+    remove_live_menuentries(Path::new(GRUB_CFG_PATH), live_devices);
+
     Ok(())
 }
 
 #[cfg(target_arch = "powerpc64")]
 pub(crate) fn execute_grub_install(
-    _mbr_dev: Option<&Path>,
+    _mbr_devs: &[PathBuf],
     lang: &str,
+    live_devices: &[PathBuf],
 ) -> Result<(), RunGrubError> {
     use snafu::ResultExt;
     use std::io::BufRead;
@@ -111,9 +274,405 @@ pub(crate) fn execute_grub_install(
 
     run_command(
         "grub-mkconfig",
-        ["-o", "/boot/grub/grub.cfg"],
+        ["-o", GRUB_CFG_PATH],
         vec![("LANG", lang.to_string())],
     )?;
 
+    remove_live_menuentries(Path::new(GRUB_CFG_PATH), live_devices);
+
     Ok(())
 }
+
+/// Set `GRUB_ENABLE_CRYPTODISK=y` so that GRUB can unlock the encrypted system partition
+/// Must be used in a chroot context
+pub(crate) fn enable_cryptodisk() -> Result<(), RunGrubError> {
+    let path = Path::new(GRUB_DEFAULT_PATH);
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(RunGrubError::EnableCryptodisk { source: e }),
+    };
+
+    fs::write(
+        path,
+        set_grub_default(&content, "GRUB_ENABLE_CRYPTODISK", "y"),
+    )
+    .map_err(|e| RunGrubError::EnableCryptodisk { source: e })
+}
+
+/// Whether `param` can be put in GRUB_CMDLINE_LINUX_DEFAULT, e.g. `amd_iommu=on`
+pub fn is_valid_kernel_param(param: &str) -> bool {
+    // /etc/default/grub 会被 shell 读取，不允许引号与 shell 元字符
+    !param.is_empty()
+        && param
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"\"'`$\\;&|<>(){}[]*?!#~".contains(c))
+}
+
+/// Append `params` to GRUB_CMDLINE_LINUX_DEFAULT in /etc/default/grub
+/// Existing parameters of the same name are replaced
+/// Must be used in a chroot context
+pub(crate) fn add_kernel_cmdline(params: &[String]) -> Result<(), RunGrubError> {
+    let path = Path::new(GRUB_DEFAULT_PATH);
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(RunGrubError::SetKernelCmdline { source: e }),
+    };
+
+    fs::write(path, append_grub_cmdline(&content, params))
+        .map_err(|e| RunGrubError::SetKernelCmdline { source: e })
+}
+
+fn append_grub_cmdline(content: &str, params: &[String]) -> String {
+    const KEY: &str = "GRUB_CMDLINE_LINUX_DEFAULT";
+
+    // 以最后一处未注释的赋值为准
+    let current = content
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix(KEY)?.strip_prefix('='))
+        .last()
+        .map(|v| v.trim().trim_matches(|c| c == '"' || c == '\''))
+        .unwrap_or_default();
+
+    let name = |param: &str| param.split('=').next().unwrap_or_default().to_string();
+    let new_names = params.iter().map(|p| name(p)).collect::<Vec<_>>();
+
+    let cmdline = current
+        .split_whitespace()
+        .filter(|p| !new_names.contains(&name(p)))
+        .map(|p| p.to_string())
+        .chain(params.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    set_grub_default(content, KEY, &format!("\"{cmdline}\""))
+}
+
+/// Set `key=value` in /etc/default/grub, replacing existing (including commented out) assignments
+fn set_grub_default(content: &str, key: &str, value: &str) -> String {
+    let entry = format!("{key}={value}");
+    let mut found = false;
+
+    let mut lines = content
+        .lines()
+        .filter_map(|line| {
+            let assignment = line.trim_start().trim_start_matches('#').trim_start();
+            if assignment
+                .strip_prefix(key)
+                .is_some_and(|x| x.starts_with('='))
+            {
+                if found {
+                    return None;
+                }
+                found = true;
+                return Some(entry.clone());
+            }
+
+            Some(line.to_string())
+        })
+        .collect::<Vec<_>>();
+
+    if !found {
+        lines.push(entry);
+    }
+
+    let mut res = lines.join("\n");
+    res.push('\n');
+
+    res
+}
+
+/// Remove os-prober menu entries which point to the live medium from grub.cfg
+/// Must be used in a chroot context
+fn remove_live_menuentries(cfg_path: &Path, live_devices: &[PathBuf]) {
+    if live_devices.is_empty() {
+        return;
+    }
+
+    let cfg = match fs::read_to_string(cfg_path) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            warn!("Failed to read {}: {e}", cfg_path.display());
+            return;
+        }
+    };
+
+    let filtered = match filter_live_menuentries(&cfg, live_devices) {
+        Some(filtered) => filtered,
+        None => {
+            debug!("No live medium menu entry in {}", cfg_path.display());
+            return;
+        }
+    };
+
+    info!(
+        "Removing live medium menu entries from {}",
+        cfg_path.display()
+    );
+    if let Err(e) = fs::write(cfg_path, filtered) {
+        warn!("Failed to write {}: {e}", cfg_path.display());
+    }
+}
+
+/// Drop top-level menu entries generated by os-prober whose device is one of `live_devices`
+/// Returns `None` if nothing changed or the config can not be parsed safely
+fn filter_live_menuentries(cfg: &str, live_devices: &[PathBuf]) -> Option<String> {
+    // os-prober 生成的菜单项标题形如 'AOSC OS Livekit (on /dev/sdb1)'
+    let suffixes = live_devices
+        .iter()
+        .map(|d| format!("(on {})'", d.display()))
+        .collect::<Vec<_>>();
+
+    let mut res = String::with_capacity(cfg.len());
+    let mut in_os_prober = false;
+    let mut depth = 0;
+    let mut skip = false;
+    let mut changed = false;
+
+    for line in cfg.split_inclusive('\n') {
+        let trimmed = line.trim();
+
+        if trimmed == OS_PROBER_BEGIN {
+            in_os_prober = true;
+        } else if trimmed == OS_PROBER_END {
+            if depth != 0 {
+                return None;
+            }
+            in_os_prober = false;
+        }
+
+        let is_open = trimmed.ends_with('{');
+        let is_close = trimmed == "}";
+
+        if in_os_prober
+            && depth == 0
+            && is_open
+            && (trimmed.starts_with("menuentry ") || trimmed.starts_with("submenu "))
+            && suffixes.iter().any(|s| trimmed.contains(s.as_str()))
+        {
+            debug!("Removing grub menu entry: {trimmed}");
+            skip = true;
+            changed = true;
+        }
+
+        let is_skip_line = skip;
+
+        if is_open {
+            depth += 1;
+        } else if is_close {
+            if depth == 0 {
+                return None;
+            }
+            depth -= 1;
+            if depth == 0 {
+                skip = false;
+            }
+        }
+
+        if !is_skip_line {
+            res.push_str(line);
+        }
+    }
+
+    if depth != 0 || !changed {
+        return None;
+    }
+
+    Some(res)
+}
+
+#[test]
+fn test_filter_live_menuentries() {
+    let cfg = include_str!("../testdata/grub_os_prober.cfg");
+    let expected = include_str!("../testdata/grub_os_prober_filtered.cfg");
+
+    let res = filter_live_menuentries(
+        cfg,
+        &[PathBuf::from("/dev/sdb"), PathBuf::from("/dev/sdb1")],
+    );
+    assert_eq!(res.as_deref(), Some(expected));
+
+    // 其他设备上的系统和非 os-prober 生成的菜单项不应被删除
+    assert!(filter_live_menuentries(cfg, &[PathBuf::from("/dev/sdb10")]).is_none());
+    assert!(filter_live_menuentries(cfg, &[PathBuf::from("/dev/nvme0n1p2")]).is_none());
+
+    // 括号不匹配时不修改
+    let broken = cfg.replacen("}\n", "", 1);
+    assert!(filter_live_menuentries(&broken, &[PathBuf::from("/dev/sdb1")]).is_none());
+}
+
+#[test]
+fn test_set_grub_default() {
+    assert_eq!(
+        set_grub_default(
+            "GRUB_TIMEOUT=5\n#GRUB_ENABLE_CRYPTODISK=y\n",
+            "GRUB_ENABLE_CRYPTODISK",
+            "y"
+        ),
+        "GRUB_TIMEOUT=5\nGRUB_ENABLE_CRYPTODISK=y\n"
+    );
+    assert_eq!(
+        set_grub_default(
+            "GRUB_ENABLE_CRYPTODISK=n\nGRUB_ENABLE_CRYPTODISK=n\n",
+            "GRUB_ENABLE_CRYPTODISK",
+            "y"
+        ),
+        "GRUB_ENABLE_CRYPTODISK=y\n"
+    );
+    assert_eq!(
+        set_grub_default("GRUB_TIMEOUT=5", "GRUB_ENABLE_CRYPTODISK", "y"),
+        "GRUB_TIMEOUT=5\nGRUB_ENABLE_CRYPTODISK=y\n"
+    );
+    assert_eq!(
+        set_grub_default("", "GRUB_ENABLE_CRYPTODISK", "y"),
+        "GRUB_ENABLE_CRYPTODISK=y\n"
+    );
+    // 前缀相同的其他变量不受影响
+    assert_eq!(
+        set_grub_default(
+            "GRUB_ENABLE_CRYPTODISK_X=1\n",
+            "GRUB_ENABLE_CRYPTODISK",
+            "y"
+        ),
+        "GRUB_ENABLE_CRYPTODISK_X=1\nGRUB_ENABLE_CRYPTODISK=y\n"
+    );
+}
+
+#[test]
+fn test_grub_platform() {
+    assert_eq!(grub_platform("amd64", true), Some("x86_64-efi"));
+    assert_eq!(grub_platform("amd64", false), Some("i386-pc"));
+    assert_eq!(grub_platform("arm64", true), Some("arm64-efi"));
+    assert_eq!(grub_platform("arm64", false), None);
+    assert_eq!(grub_platform("riscv64", true), Some("riscv64-efi"));
+    assert_eq!(grub_platform("loongarch64", true), Some("loongarch64-efi"));
+    assert_eq!(grub_platform("loongson3", true), Some("mips64el-efi"));
+    assert_eq!(grub_platform("ppc64el", false), Some("powerpc-ieee1275"));
+    assert_eq!(grub_platform("sparc64", true), None);
+}
+
+#[test]
+fn test_default_boot_file() {
+    assert_eq!(
+        default_boot_file("amd64"),
+        Some(PathBuf::from("EFI/BOOT/BOOTX64.EFI"))
+    );
+    assert_eq!(
+        default_boot_file("arm64"),
+        Some(PathBuf::from("EFI/BOOT/BOOTAA64.EFI"))
+    );
+    assert_eq!(
+        default_boot_file("riscv64"),
+        Some(PathBuf::from("EFI/BOOT/BOOTRISCV64.EFI"))
+    );
+    assert_eq!(
+        default_boot_file("loongarch64"),
+        Some(PathBuf::from("EFI/BOOT/BOOTLOONGARCH64.EFI"))
+    );
+    assert_eq!(
+        default_boot_file("loongson3"),
+        Some(PathBuf::from("EFI/BOOT/BOOTMIPS64EL.EFI"))
+    );
+    assert_eq!(default_boot_file("ppc64el"), None);
+}
+
+#[cfg(not(target_arch = "powerpc64"))]
+#[test]
+fn test_place_firmware_boot_file() {
+    let esp = std::env::temp_dir().join(format!("dk-esp-boot-{}", std::process::id()));
+    fs::remove_dir_all(&esp).ok();
+    fs::create_dir_all(esp.join("EFI/Boot")).unwrap();
+
+    // 沿用已有目录的大小写
+    assert_eq!(
+        detect_firmware_boot_file(&esp, "arm64"),
+        Some(esp.join("EFI/Boot/BOOTAA64.EFI"))
+    );
+
+    // grub-install 未生成引导器
+    assert!(place_firmware_boot_file(&esp, "arm64").is_err());
+
+    fs::create_dir_all(esp.join("EFI/AOSC OS")).unwrap();
+    fs::write(esp.join("EFI/AOSC OS/grubaa64.efi"), "grub").unwrap();
+
+    assert_eq!(
+        place_firmware_boot_file(&esp, "arm64").unwrap(),
+        Some(esp.join("EFI/Boot/BOOTAA64.EFI"))
+    );
+    assert_eq!(
+        fs::read_to_string(esp.join("EFI/Boot/BOOTAA64.EFI")).unwrap(),
+        "grub"
+    );
+
+    // 已存在的引导器（大小写不同）不被覆盖
+    fs::remove_file(esp.join("EFI/Boot/BOOTAA64.EFI")).unwrap();
+    fs::write(esp.join("EFI/Boot/bootaa64.efi"), "vendor").unwrap();
+    assert_eq!(
+        place_firmware_boot_file(&esp, "arm64").unwrap(),
+        Some(esp.join("EFI/Boot/bootaa64.efi"))
+    );
+    assert_eq!(
+        fs::read_to_string(esp.join("EFI/Boot/bootaa64.efi")).unwrap(),
+        "vendor"
+    );
+
+    assert_eq!(place_firmware_boot_file(&esp, "ppc64el").unwrap(), None);
+
+    fs::remove_dir_all(&esp).unwrap();
+}
+
+#[test]
+fn test_is_valid_kernel_param() {
+    for param in [
+        "i915.enable_psr=0",
+        "amd_iommu=on",
+        "quiet",
+        "console=ttyS0,115200n8",
+    ] {
+        assert!(is_valid_kernel_param(param), "{param}");
+    }
+
+    for param in [
+        "",
+        "quiet splash",
+        "a=1\nGRUB_TIMEOUT=0",
+        "a=\"1\"",
+        "a=$(reboot)",
+        "a=`id`",
+        "a=1;b",
+        "a=1&",
+    ] {
+        assert!(!is_valid_kernel_param(param), "{param}");
+    }
+}
+
+#[test]
+fn test_append_grub_cmdline() {
+    let resume = [
+        "resume=UUID=1234".to_string(),
+        "resume_offset=5678".to_string(),
+    ];
+
+    assert_eq!(
+        append_grub_cmdline(
+            "GRUB_TIMEOUT=5\nGRUB_CMDLINE_LINUX_DEFAULT=\"quiet splash\"\n",
+            &resume
+        ),
+        "GRUB_TIMEOUT=5\nGRUB_CMDLINE_LINUX_DEFAULT=\"quiet splash resume=UUID=1234 resume_offset=5678\"\n"
+    );
+    // 替换同名参数
+    assert_eq!(
+        append_grub_cmdline(
+            "GRUB_CMDLINE_LINUX_DEFAULT='quiet resume=UUID=0000 resume_offset=1'\n",
+            &resume
+        ),
+        "GRUB_CMDLINE_LINUX_DEFAULT=\"quiet resume=UUID=1234 resume_offset=5678\"\n"
+    );
+    assert_eq!(
+        append_grub_cmdline("#GRUB_CMDLINE_LINUX_DEFAULT=\"quiet\"\n", &resume),
+        "GRUB_CMDLINE_LINUX_DEFAULT=\"resume=UUID=1234 resume_offset=5678\"\n"
+    );
+}
@@ -1,13 +1,152 @@
 use std::{
-    fs::File,
-    io::{self, Write},
+    fs, io,
+    path::{Path, PathBuf},
 };
 
-/// Sets hostname in the guest environment
+use snafu::{ensure, ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum SetHostnameError {
+    #[snafu(display("Invalid hostname: {hostname}"))]
+    InvalidHostname { hostname: String },
+    #[snafu(display("Failed to write {}", path.display()))]
+    WriteFile { path: PathBuf, source: io::Error },
+}
+
+/// Whether `name` is a valid hostname as of RFC 1123
+/// Dot separated labels of 1 to 63 letters, digits and hyphens, not starting or ending with a hyphen
+pub fn is_valid_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Sets hostname and /etc/hosts in the guest environment
 /// Must be used in a chroot context
-pub fn set_hostname(name: &str) -> Result<(), io::Error> {
-    let mut f = File::create("/etc/hostname")?;
-    f.write_all(name.as_bytes())?;
+pub fn set_hostname(name: &str) -> Result<(), SetHostnameError> {
+    set_hostname_in(Path::new("/"), name)
+}
+
+fn set_hostname_in(root: &Path, name: &str) -> Result<(), SetHostnameError> {
+    ensure!(
+        is_valid_hostname(name),
+        InvalidHostnameSnafu {
+            hostname: name.to_string()
+        }
+    );
+
+    let path = root.join("etc/hostname");
+    fs::write(&path, name).context(WriteFileSnafu { path })?;
+
+    // 缺少本机主机名的解析记录时，sudo 等程序会等待 DNS 超时
+    let path = root.join("etc/hosts");
+    let existing = match fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context(WriteFileSnafu { path }),
+    };
+
+    fs::write(&path, hosts_content(&existing, name)).context(WriteFileSnafu { path })?;
 
     Ok(())
 }
+
+/// Keep the existing entries, replace the 127.0.1.1 entry with `hostname`
+/// A fully qualified `hostname` is listed with its short name, otherwise with `.localdomain` appended
+fn hosts_content(existing: &str, hostname: &str) -> String {
+    let mut lines = existing
+        .lines()
+        .filter(|line| line.split_whitespace().next() != Some("127.0.1.1"))
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+
+    if !lines
+        .iter()
+        .any(|line| line.split_whitespace().next() == Some("127.0.0.1"))
+    {
+        lines.insert(0, "127.0.0.1\tlocalhost".to_string());
+    }
+
+    match hostname.split_once('.') {
+        Some((short, _)) => lines.push(format!("127.0.1.1\t{hostname} {short}")),
+        None => lines.push(format!("127.0.1.1\t{hostname}.localdomain {hostname}")),
+    }
+
+    let mut res = lines.join("\n");
+    res.push('\n');
+
+    res
+}
+
+#[test]
+fn test_is_valid_hostname() {
+    for name in [
+        "aosc",
+        "aosc-os",
+        "node01.example.com",
+        "0day",
+        &"a".repeat(63),
+    ] {
+        assert!(is_valid_hostname(name), "{name}");
+    }
+
+    for name in [
+        "",
+        "-aosc",
+        "aosc-",
+        "aosc_os",
+        "aosc os",
+        "aosc..os",
+        ".aosc",
+        "aosc/os",
+        &"a".repeat(64),
+        &["a"; 128].join("."),
+    ] {
+        assert!(!is_valid_hostname(name), "{name}");
+    }
+}
+
+#[test]
+fn test_hosts_content() {
+    assert_eq!(
+        hosts_content("", "aosc"),
+        "127.0.0.1\tlocalhost\n127.0.1.1\taosc.localdomain aosc\n"
+    );
+    assert_eq!(
+        hosts_content("", "node01.example.com"),
+        "127.0.0.1\tlocalhost\n127.0.1.1\tnode01.example.com node01\n"
+    );
+    assert_eq!(
+        hosts_content(
+            "# Static table lookup for hostnames.\n127.0.0.1 localhost\n::1 localhost\n127.0.1.1 old\n",
+            "aosc"
+        ),
+        "# Static table lookup for hostnames.\n127.0.0.1 localhost\n::1 localhost\n127.0.1.1\taosc.localdomain aosc\n"
+    );
+}
+
+#[test]
+fn test_set_hostname_in() {
+    let root = std::env::temp_dir().join(format!("dk-hostname-{}", std::process::id()));
+    fs::create_dir_all(root.join("etc")).unwrap();
+
+    assert!(matches!(
+        set_hostname_in(&root, "-aosc"),
+        Err(SetHostnameError::InvalidHostname { .. })
+    ));
+    assert!(!root.join("etc/hostname").exists());
+
+    set_hostname_in(&root, "aosc").unwrap();
+    let hostname = fs::read_to_string(root.join("etc/hostname")).unwrap();
+    let hosts = fs::read_to_string(root.join("etc/hosts")).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(hostname, "aosc");
+    assert!(hosts.ends_with("127.0.1.1\taosc.localdomain aosc\n"));
+}
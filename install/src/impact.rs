@@ -0,0 +1,258 @@
+use std::path::{Path, PathBuf};
+
+use disk::partition::DkPartition;
+use serde::Serialize;
+
+use crate::InstallConfigPrepare;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Impact {
+    /// Will be formatted, data on it will be lost
+    Format,
+    /// Will be mounted read-write without formatting
+    Mount,
+    Untouched,
+}
+
+/// What will happen to a partition when installing with the current config
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DiskImpact {
+    pub path: Option<PathBuf>,
+    pub parent_path: Option<PathBuf>,
+    pub impact: Impact,
+    /// Mount point in the installed system
+    pub mount_point: Option<PathBuf>,
+    pub size: u64,
+    /// Current filesystem
+    pub fs_type: Option<String>,
+    /// Filesystem after installation, only set if the partition will be formatted
+    pub new_fs_type: Option<String>,
+}
+
+/// Classify every partition in `partitions` (usually from `list_partitions` of the target disks)
+/// Selected partitions missing from `partitions` are appended to the end
+pub fn disk_impact(config: &InstallConfigPrepare, partitions: &[DkPartition]) -> Vec<DiskImpact> {
+    let mut selected = vec![];
+
+    {
+        let target = config
+            .target_partition
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        if let Some(ref p) = *target {
+            selected.push(affected(
+                p,
+                format_impact(p),
+                Some(Path::new("/")),
+                p.fs_type.as_deref(),
+            ));
+        }
+    }
+
+    {
+        let efi = config
+            .efi_partition
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        // 未设置 fs_type 的 ESP 会被格式化，见 format_partitions
+        if let Some(ref p) = *efi {
+            let (impact, new_fs_type) = match p.fs_type {
+                Some(_) => (Impact::Mount, None),
+                None => (Impact::Format, Some("vfat")),
+            };
+
+            selected.push(affected(p, impact, Some(Path::new("/efi")), new_fs_type));
+        }
+    }
+
+    if let Some(ref layout) = config.data_layout {
+        let data = config
+            .data_partition
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        if let Some(ref p) = *data {
+            selected.push(affected(
+                p,
+                format_impact(p),
+                Some(layout.mount_point.as_path()),
+                Some(layout.fs_type.as_str()),
+            ));
+        }
+    }
+
+    {
+        let swap = config
+            .swap_partition
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        if let Some(ref p) = *swap {
+            selected.push(affected(p, format_impact(p), None, Some("swap")));
+        }
+    }
+
+    let mut res = vec![];
+
+    for p in partitions {
+        match selected
+            .iter()
+            .position(|s| s.path.is_some() && s.path == p.path)
+        {
+            Some(i) => {
+                let mut s = selected.remove(i);
+                // 以磁盘上的实际信息为准
+                s.size = p.size;
+                s.fs_type.clone_from(&p.fs_type);
+                res.push(s);
+            }
+            None => res.push(DiskImpact {
+                path: p.path.clone(),
+                parent_path: p.parent_path.clone(),
+                impact: Impact::Untouched,
+                mount_point: None,
+                size: p.size,
+                fs_type: p.fs_type.clone(),
+                new_fs_type: None,
+            }),
+        }
+    }
+
+    res.extend(selected);
+
+    res
+}
+
+/// 设置为不格式化的分区只挂载，见 format_partitions
+fn format_impact(p: &DkPartition) -> Impact {
+    if p.format {
+        Impact::Format
+    } else {
+        Impact::Mount
+    }
+}
+
+fn affected(
+    p: &DkPartition,
+    impact: Impact,
+    mount_point: Option<&Path>,
+    new_fs_type: Option<&str>,
+) -> DiskImpact {
+    DiskImpact {
+        path: p.path.clone(),
+        parent_path: p.parent_path.clone(),
+        impact,
+        mount_point: mount_point.map(|x| x.to_path_buf()),
+        size: p.size,
+        // 分区信息来自配置时无法得知当前文件系统
+        fs_type: None,
+        new_fs_type: match impact {
+            Impact::Format => new_fs_type.map(|x| x.to_string()),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+fn partition(path: &str, fs_type: Option<&str>) -> DkPartition {
+    DkPartition {
+        path: Some(PathBuf::from(path)),
+        parent_path: Some(PathBuf::from("/dev/sda")),
+        fs_type: fs_type.map(|x| x.to_string()),
+        size: 1024,
+        format: true,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_disk_impact_auto_partition() {
+    let config = InstallConfigPrepare::default();
+    *config.target_partition.lock().unwrap() = Some(partition("/dev/sda2", Some("ext4")));
+    *config.efi_partition.lock().unwrap() = Some(partition("/dev/sda1", Some("vfat")));
+
+    let on_disk = [
+        partition("/dev/sda1", Some("fat32")),
+        partition("/dev/sda2", Some("ntfs")),
+        partition("/dev/sda3", Some("ext4")),
+    ];
+
+    let res = disk_impact(&config, &on_disk);
+
+    assert_eq!(res.len(), 3);
+    // 已有文件系统的 ESP 只挂载
+    assert_eq!(res[0].impact, Impact::Mount);
+    assert_eq!(res[0].mount_point, Some(PathBuf::from("/efi")));
+    assert_eq!(res[0].new_fs_type, None);
+    assert_eq!(res[1].impact, Impact::Format);
+    assert_eq!(res[1].mount_point, Some(PathBuf::from("/")));
+    assert_eq!(res[1].fs_type.as_deref(), Some("ntfs"));
+    assert_eq!(res[1].new_fs_type.as_deref(), Some("ext4"));
+    assert_eq!(res[2].impact, Impact::Untouched);
+    assert_eq!(res[2].fs_type.as_deref(), Some("ext4"));
+}
+
+#[test]
+fn test_disk_impact_format_efi_data_swap() {
+    let config = InstallConfigPrepare {
+        data_layout: Some(disk::partition::DataLayout {
+            root_size: 32 * 1024 * 1024 * 1024,
+            fs_type: "xfs".to_string(),
+            mount_point: PathBuf::from("/data"),
+        }),
+        ..Default::default()
+    };
+
+    *config.target_partition.lock().unwrap() = Some(partition("/dev/sda2", Some("btrfs")));
+    *config.efi_partition.lock().unwrap() = Some(partition("/dev/sda1", None));
+    *config.data_partition.lock().unwrap() = Some(partition("/dev/sda3", None));
+    *config.swap_partition.lock().unwrap() = Some(partition("/dev/sda4", None));
+
+    let res = disk_impact(&config, &[partition("/dev/sda3", Some("ext4"))]);
+
+    assert_eq!(res.len(), 4);
+    assert_eq!(res[0].path, Some(PathBuf::from("/dev/sda3")));
+    assert_eq!(res[0].impact, Impact::Format);
+    assert_eq!(res[0].mount_point, Some(PathBuf::from("/data")));
+    assert_eq!(res[0].new_fs_type.as_deref(), Some("xfs"));
+
+    // 不在磁盘分区列表中的已选分区放在最后
+    assert_eq!(res[1].mount_point, Some(PathBuf::from("/")));
+    assert_eq!(res[1].fs_type, None);
+    assert_eq!(res[2].impact, Impact::Format);
+    assert_eq!(res[2].new_fs_type.as_deref(), Some("vfat"));
+    assert_eq!(res[3].impact, Impact::Format);
+    assert_eq!(res[3].mount_point, None);
+    assert_eq!(res[3].new_fs_type.as_deref(), Some("swap"));
+}
+
+#[test]
+fn test_disk_impact_keep_existing() {
+    let config = InstallConfigPrepare::default();
+    *config.target_partition.lock().unwrap() = Some(DkPartition {
+        format: false,
+        ..partition("/dev/sda2", Some("ext4"))
+    });
+
+    let res = disk_impact(&config, &[partition("/dev/sda2", Some("ext4"))]);
+
+    assert_eq!(res.len(), 1);
+    assert_eq!(res[0].impact, Impact::Mount);
+    assert_eq!(res[0].mount_point, Some(PathBuf::from("/")));
+    assert_eq!(res[0].new_fs_type, None);
+}
+
+#[test]
+fn test_disk_impact_data_partition_without_layout() {
+    let config = InstallConfigPrepare::default();
+    *config.data_partition.lock().unwrap() = Some(partition("/dev/sda3", Some("ext4")));
+
+    // 未设置数据分区布局时不会使用数据分区
+    let res = disk_impact(&config, &[partition("/dev/sda3", Some("ext4"))]);
+
+    assert_eq!(res.len(), 1);
+    assert_eq!(res[0].impact, Impact::Untouched);
+}
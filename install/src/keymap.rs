@@ -0,0 +1,70 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+const XORG_KEYBOARD_CONF: &str = "/etc/X11/xorg.conf.d/00-keyboard.conf";
+
+/// Whether `keymap` looks like a console keymap name, e.g. `us` or `de-latin1`
+pub fn is_valid_keymap(keymap: &str) -> bool {
+    !keymap.is_empty()
+        && keymap
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Sets console keymap and X11 keyboard layout in the guest environment
+/// Must be used in a chroot context
+pub(crate) fn set_keymap(keymap: &str) -> Result<(), io::Error> {
+    let mut f = File::create("/etc/vconsole.conf")?;
+    f.write_all(vconsole_conf(keymap).as_bytes())?;
+
+    // 未安装 X11 的系统不需要键盘布局配置
+    if Path::new("/etc/X11").is_dir() {
+        let path = Path::new(XORG_KEYBOARD_CONF);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(path, xorg_keyboard_conf(keymap))?;
+    }
+
+    Ok(())
+}
+
+fn vconsole_conf(keymap: &str) -> String {
+    format!("KEYMAP={keymap}\n")
+}
+
+fn xorg_keyboard_conf(keymap: &str) -> String {
+    // 控制台键盘映射如 de-latin1 对应 XKB 布局 de
+    let layout = keymap.split(['-', '_']).next().unwrap_or(keymap);
+
+    format!(
+        "Section \"InputClass\"\n\
+        \x20       Identifier \"system-keyboard\"\n\
+        \x20       MatchIsKeyboard \"on\"\n\
+        \x20       Option \"XkbLayout\" \"{layout}\"\n\
+        EndSection\n"
+    )
+}
+
+#[test]
+fn test_is_valid_keymap() {
+    assert!(is_valid_keymap("us"));
+    assert!(is_valid_keymap("de-latin1"));
+    assert!(is_valid_keymap("fr_CH"));
+    assert!(!is_valid_keymap(""));
+    assert!(!is_valid_keymap("us\nFONT=x"));
+    assert!(!is_valid_keymap("../us"));
+}
+
+#[test]
+fn test_keymap_conf() {
+    assert_eq!(vconsole_conf("de-latin1"), "KEYMAP=de-latin1\n");
+    assert_eq!(
+        xorg_keyboard_conf("de-latin1"),
+        "Section \"InputClass\"\n        Identifier \"system-keyboard\"\n        MatchIsKeyboard \"on\"\n        Option \"XkbLayout\" \"de\"\nEndSection\n"
+    );
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     fmt::{Display, Formatter},
     fs::{self, create_dir_all, read_dir},
     io::{self, Write},
@@ -12,19 +13,31 @@ use std::{
     time::Duration,
 };
 
+use boot_stub::BootStub;
 use chroot::ChrootError;
 use disk::{
-    is_efi_booted,
-    partition::{format_partition, DkPartition},
+    devices::{is_md_device, live_device_paths, slave_disks},
+    flush::{flush_device, FlushReport},
+    is_dev_mode, is_efi_booted,
+    luks::{is_luks_open, luks_close, luks_format, luks_open, luks_uuid, mapper_path},
+    partition::{format_partition, swapoff_active_swaps, DataLayout, DkPartition},
+    subvol::{create_btrfs_subvolumes, ROOT_SUBVOL, SUBVOLUMES},
     PartitionError,
 };
 
 use download::{download_file, DownloadError, FilesType};
-use extract::{extract_squashfs, rsync_system, RsyncError};
-use genfstab::{genfstab_to_file, GenfstabError};
+use extract::{extract_squashfs, extract_tarball, rsync_system, ExtractErrorKind, RsyncError};
+use genfstab::{
+    blkid_probe, gencrypttab_to_file, genfstab_btrfs_subvol_to_file, genfstab_esp_to_file,
+    genfstab_swap_to_file, genfstab_to_file, genmdadm_conf_to_file, is_fstab_supported,
+    verify_fstab, GenfstabError,
+};
 use grub::RunGrubError;
-use locale::SetHwclockError;
-use mount::{mount_root_path, UmountError};
+use hostname::SetHostnameError;
+use identity::{Identity, IdentityError};
+use locale::{SetHwclockError, SetLocaleError};
+use locale_extras::{default_locale_extras, LocaleExtra, LocaleExtrasError};
+use mount::{check_mountable, mount_btrfs_subvol, mount_root_path, UmountError};
 use num_enum::IntoPrimitive;
 use rustix::{
     fs::sync,
@@ -32,43 +45,66 @@ use rustix::{
     system::{reboot, RebootCommand},
 };
 use serde::{Deserialize, Serialize};
-use snafu::{OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use swap::SwapFileError;
 use sysinfo::System;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use user::{AddUserError, SetFullNameError};
 use utils::RunCmdError;
 use zoneinfo::SetZoneinfoError;
 
 use crate::{
+    boot_stub::boot_stub as collect_boot_stub,
     chroot::{dive_into_guest, escape_chroot, get_dir_fd},
     dracut::execute_dracut,
+    first_boot::setup_first_boot,
     genfstab::write_swap_entry_to_fstab,
-    grub::execute_grub_install,
+    grub::{add_kernel_cmdline, check_grub_platform, enable_cryptodisk, execute_grub_install},
     hostname::set_hostname,
+    identity::apply_identity,
+    keymap::set_keymap,
     locale::{set_hwclock_tc, set_locale},
-    mount::{remove_files_mounts, umount_root_path},
+    locale_extras::install_locale_extras,
+    mount::{remove_files_mounts, syncfs_path, umount_root_path},
+    os_release::set_os_release_branding,
     ssh::gen_ssh_key,
-    swap::{create_swapfile, get_recommend_swap_size, swapoff},
-    user::{add_new_user, passwd_set_fullname},
+    swap::{
+        create_swapfile, get_hibernate_swap_size, get_recommend_swap_size, swapfile_resume_offset,
+        swapoff, write_dracut_resume_config, write_zram_generator_config,
+    },
+    user::{
+        add_new_user, check_new_user, check_unique_uids, check_unique_usernames,
+        passwd_set_fullname, set_passwordless_sudo, set_root_password,
+    },
     zoneinfo::set_zoneinfo,
 };
 
+pub mod boot_stub;
 pub mod chroot;
 pub mod download;
 mod dracut;
+pub mod estimate;
 mod extract;
+pub mod first_boot;
 pub mod genfstab;
 pub mod grub;
-mod hostname;
+pub mod hostname;
+pub mod identity;
+pub mod impact;
+pub mod keymap;
 pub mod locale;
+pub mod locale_extras;
 pub mod mount;
+pub mod os_release;
 mod ssh;
 pub mod swap;
 pub mod user;
 pub mod utils;
 pub mod zoneinfo;
 
+/// Name of the LUKS mapped device of the system partition during installation
+const LUKS_ROOT_NAME: &str = "dk-root";
+
 #[derive(Debug, Snafu)]
 pub enum MountError {
     #[snafu(display("Failed to create dir {}", path.display()))]
@@ -90,6 +126,8 @@ pub enum SetupPartitionError {
     Mount { source: MountError },
     #[snafu(display("Failed to create swap file"))]
     SwapFile { source: SwapFileError },
+    #[snafu(display("Partition {} can not be used without formatting: {reason}", path.display()))]
+    ReusePartition { path: PathBuf, reason: String },
 }
 
 #[derive(Debug, Snafu)]
@@ -100,6 +138,8 @@ pub enum InstallErr {
     CreateTempDir { source: std::io::Error },
     #[snafu(display("Value is not set: {v:?}"))]
     ValueNotSet { v: NotSetValue },
+    #[snafu(display("Extra users can not be created when the user is created on first boot"))]
+    ExtraUsersOnFirstBoot,
     #[snafu(display("Failed to get root dir fd"))]
     GetDirFd { source: Errno },
     #[snafu(display("Failed to setup partition"))]
@@ -136,6 +176,8 @@ pub enum PostInstallationError {
 pub enum ConfigureSystemError {
     #[snafu(display("Failed to append swap config to fstab"))]
     SwapToGenfstab { source: GenfstabError },
+    #[snafu(display("Failed to write zram-generator config"))]
+    ZramConfig { source: std::io::Error },
     #[snafu(display("Failed to set zoneinfo: {zone}"))]
     SetZoneinfo {
         source: SetZoneinfoError,
@@ -148,11 +190,22 @@ pub enum ConfigureSystemError {
     },
     #[snafu(display("Failed to set hostname: {hostname}"))]
     SetHostname {
-        source: std::io::Error,
+        source: SetHostnameError,
+        hostname: String,
+    },
+    #[snafu(display("Failed to apply machine identity: {hostname}"))]
+    ApplyIdentity {
+        source: IdentityError,
         hostname: String,
     },
     #[snafu(display("Failed to add new user"))]
     AddNewUser { source: AddUserError },
+    #[snafu(display("Failed to set root password"))]
+    SetRootPassword { source: AddUserError },
+    #[snafu(display("Failed to set up user creation on first boot"))]
+    SetupFirstBoot { source: std::io::Error },
+    #[snafu(display("Failed to enable passwordless sudo"))]
+    SetPasswordlessSudo { source: AddUserError },
     #[snafu(display("Failed to set fullname: {fullname}"))]
     SetFullName {
         source: SetFullNameError,
@@ -160,26 +213,90 @@ pub enum ConfigureSystemError {
     },
     #[snafu(display("Failed to set locale: {locale}"))]
     SetLocale {
+        source: SetLocaleError,
+        locale: String,
+    },
+    #[snafu(display("Failed to set keymap: {keymap}"))]
+    SetKeymap {
         source: std::io::Error,
+        keymap: String,
+    },
+    #[snafu(display("Failed to set os-release branding"))]
+    SetBranding { source: std::io::Error },
+    #[snafu(display("Failed to install extra packages for locale {locale}"))]
+    InstallLocaleExtras {
+        source: LocaleExtrasError,
         locale: String,
     },
 }
 
+impl ConfigureSystemError {
+    /// Whether the installation must be aborted on this error
+    /// 非关键步骤失败不影响系统启动与登录，可以警告后继续安装
+    pub fn is_critical(&self) -> bool {
+        !matches!(
+            self,
+            Self::SetFullName { .. }
+                | Self::SetHwclock { .. }
+                | Self::SetLocale { .. }
+                | Self::SetKeymap { .. }
+                | Self::SetPasswordlessSudo { .. }
+                | Self::SetBranding { .. }
+                | Self::InstallLocaleExtras { .. }
+                | Self::ZramConfig { .. }
+        )
+    }
+}
+
+/// Non-fatal problem occurred during installation
+#[derive(Debug, Clone, Serialize)]
+pub struct InstallWarning {
+    pub stage: String,
+    pub message: String,
+}
+
+impl InstallWarning {
+    fn from_error(stage: &InstallationStage, err: &dyn std::error::Error) -> Self {
+        let mut message = err.to_string();
+        let mut source = err.source();
+
+        while let Some(e) = source {
+            message.push_str(&format!(": {e}"));
+            source = e.source();
+        }
+
+        Self {
+            stage: stage.to_string(),
+            message,
+        }
+    }
+}
+
 #[derive(Debug, Snafu)]
 pub enum SetupGenfstabError {
     #[snafu(transparent)]
     Genfstab { source: GenfstabError },
     #[snafu(display("value is not set: {t}"))]
     ValueNotSetGenfstab { t: &'static str },
+    #[snafu(display("Failed to get LUKS UUID of system partition"))]
+    LuksUuid { source: PartitionError },
 }
 
 #[derive(Debug, Snafu)]
 pub enum InstallSquashfsError {
-    #[snafu(display("Failed to extract squashfs {} to {}", from.display(), to.display()))]
+    #[snafu(display("Failed to extract squashfs {} to {}: {kind}", from.display(), to.display()))]
     Extract {
         source: std::io::Error,
         from: PathBuf,
         to: PathBuf,
+        kind: ExtractErrorKind,
+    },
+    #[snafu(display("Failed to extract tarball {} to {}: {kind}", from.display(), to.display()))]
+    ExtractTarball {
+        source: std::io::Error,
+        from: PathBuf,
+        to: PathBuf,
+        kind: ExtractErrorKind,
     },
     #[snafu(display("Failed to remove downloaded squashfs file"))]
     RemoveDownloadedFile { source: std::io::Error },
@@ -193,7 +310,7 @@ pub enum PartitionNotSetValue {
     FsType,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum NotSetValue {
     Locale,
     Timezone,
@@ -202,6 +319,8 @@ pub enum NotSetValue {
     User,
     Hostname,
     TargetPartition,
+    /// `SwapFile::Partition` is set but `auto_partition` has not created the swap partition
+    SwapPartition,
 }
 
 impl Display for NotSetValue {
@@ -214,6 +333,7 @@ impl Display for NotSetValue {
             NotSetValue::User => write!(f, "user"),
             NotSetValue::Hostname => write!(f, "hostname"),
             NotSetValue::TargetPartition => write!(f, "target partition"),
+            NotSetValue::SwapPartition => write!(f, "swap partition"),
         }
     }
 }
@@ -235,36 +355,167 @@ pub enum GenFstabErrorKind {
     UUID,
 }
 
+/// Algorithm of the `hash` of a download
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumKind {
+    #[default]
+    Sha256,
+    Sha512,
+    /// BLAKE2b-512, as `b2sum` outputs
+    Blake2b,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum DownloadType {
     Http {
         url: String,
         hash: String,
+        /// 旧配置中没有此项，默认为 SHA256
+        #[serde(default)]
+        checksum: ChecksumKind,
         to_path: Option<PathBuf>,
+        /// Attempts before giving up on a flaky connection, 5 if not set
+        max_attempts: Option<u32>,
+    },
+    /// Try the mirrors in order, moving to the next one if a mirror is unreachable
+    HttpMulti {
+        urls: Vec<String>,
+        hash: String,
+        #[serde(default)]
+        checksum: ChecksumKind,
+        to_path: Option<PathBuf>,
+        /// Attempts of every mirror, 5 if not set
+        max_attempts: Option<u32>,
     },
     File(PathBuf),
     Dir(PathBuf),
+    /// A local `.tar.xz` or `.tar.zst` rootfs tarball
+    Tarball(PathBuf),
+}
+
+/// Weighted overall progress (0..=100) of all steps
+pub fn overall_progress(weights: &[u8], step: u8, progress: u8) -> u8 {
+    let total: u32 = weights.iter().map(|x| *x as u32).sum();
+
+    if total == 0 || step == 0 {
+        return 0;
+    }
+
+    let step = (step as usize).min(weights.len());
+    let done: u32 = weights[..step - 1].iter().map(|x| *x as u32).sum();
+    let current = weights[step - 1] as u32 * progress.min(100) as u32 / 100;
+
+    ((done + current) * 100 / total) as u8
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+// 导入旧版本导出的配置时，缺少的项使用默认值
+#[serde(default)]
 pub struct InstallConfigPrepare {
     pub locale: Option<String>,
     pub timezone: Option<String>,
     pub download: Option<DownloadType>,
     pub user: Option<User>,
+    /// Accounts created in addition to `user`
+    pub extra_users: Vec<User>,
     pub rtc_as_localtime: bool,
     pub hostname: Option<String>,
+    /// Console keymap, e.g. `us`, the system default is kept if not set
+    pub keymap: Option<String>,
+    /// Extra kernel parameters appended to GRUB_CMDLINE_LINUX_DEFAULT
+    pub kernel_cmdline: Vec<String>,
     pub swapfile: SwapFile,
+    #[serde(with = "shared_partition")]
     pub target_partition: Arc<Mutex<Option<DkPartition>>>,
+    #[serde(with = "shared_partition")]
     pub efi_partition: Arc<Mutex<Option<DkPartition>>>,
+    pub data_layout: Option<DataLayout>,
+    /// Filesystem of the system partition created by auto partitioning, ext4 if not set
+    /// Set by either `root_fs` or `auto_partition_fs` over D-Bus
+    pub root_fs: Option<String>,
+    /// Size in bytes of the ESP created by auto partitioning, 512MiB if not set
+    pub efi_size: Option<u64>,
+    #[serde(with = "shared_partition")]
+    pub data_partition: Arc<Mutex<Option<DkPartition>>>,
+    #[serde(with = "shared_partition")]
+    pub swap_partition: Arc<Mutex<Option<DkPartition>>>,
+    pub strict_configure: bool,
+    pub allow_no_bootloader: bool,
+    /// Do not install GRUB, report the boot stub for an external bootloader instead
+    pub skip_bootloader: bool,
+    /// Extra entries appended to /etc/os-release of the installed system
+    pub branding: BTreeMap<String, String>,
+    /// Stop after this stage and leave the target mounted, see [`InstallationStage::can_stop_after`]
+    pub stop_after: Option<InstallationStage>,
+    /// Locales generated in addition to `locale`, e.g. for users of other languages
+    pub extra_locales: Vec<String>,
+    /// Install fonts and input methods needed by the selected locale
+    pub install_locale_extras: bool,
+    pub locale_extras: BTreeMap<String, LocaleExtra>,
+    /// Host SSH keys and network profile to provision, replaces the generated host keys
+    pub identity: Option<Identity>,
+    /// Passphrase of the LUKS container holding the system partition, not encrypted if not set
+    pub encrypt: Option<String>,
+    /// Also list mdadm RAID arrays (/dev/mdN) in `get_list_devices`
+    pub list_md_devices: bool,
+    /// Let users in the wheel group (admin users) use sudo without password
+    pub passwordless_sudo: bool,
+    /// Only simulate the installation, no disk is touched, for testing front-ends
+    pub dry_run: bool,
+    /// Let the end user create their account on first boot (OEM mode), `user` is not required
+    pub user_on_first_boot: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
     pub username: String,
     pub password: String,
+    /// Only used for the main user
     pub root_password: Option<String>,
     pub full_name: Option<String>,
+    /// Whether the user can use sudo, true if not set
+    #[serde(default = "default_admin")]
+    pub admin: bool,
+    /// Login shell, e.g. `/usr/bin/zsh`, must be listed in /etc/shells of the installed system
+    /// bash if not set
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// Fixed UID, e.g. for NFS home directories, must be at least 1000 and unused
+    /// Allocated by useradd if not set
+    #[serde(default)]
+    pub uid: Option<u32>,
+}
+
+// 旧配置中没有此项，沿用之前所有用户都在 wheel 组的行为
+fn default_admin() -> bool {
+    true
+}
+
+/// (De)serialize the partition inside `Arc<Mutex<_>>` as a plain `Option<DkPartition>`
+mod shared_partition {
+    use std::sync::{Arc, Mutex};
+
+    use disk::partition::DkPartition;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S>(
+        value: &Arc<Mutex<Option<DkPartition>>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // 自动分区线程崩溃时锁可能中毒，此时分区信息仍然可用
+        let lock = value.lock().unwrap_or_else(|e| e.into_inner());
+        lock.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Arc<Mutex<Option<DkPartition>>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Arc::new(Mutex::new(Option::deserialize(deserializer)?)))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -272,6 +523,30 @@ pub enum SwapFile {
     Automatic,
     Custom(u64),
     Disable,
+    /// Swapfile large enough to hibernate, see [`swap::get_hibernate_swap_size`]
+    /// `resume=` and `resume_offset=` pointing at the swapfile are added to the kernel command line
+    Hibernate,
+    /// Compressed swap in RAM set up by zram-generator at boot, no swapfile is created
+    Zram {
+        size_mb: u64,
+    },
+    /// Swap partition of this many bytes created by `auto_partition`, no swapfile is created
+    Partition(u64),
+}
+
+impl SwapFile {
+    /// `Custom(0)` means no swapfile, the same as `Disable`
+    fn normalize(self) -> Self {
+        match self {
+            Self::Custom(0) => Self::Disable,
+            x => x,
+        }
+    }
+
+    /// Whether a swapfile is created (and swapped on) in the target system
+    fn creates_swapfile(&self) -> bool {
+        matches!(self, Self::Automatic | Self::Custom(1..) | Self::Hibernate)
+    }
 }
 
 impl Default for InstallConfigPrepare {
@@ -281,62 +556,240 @@ impl Default for InstallConfigPrepare {
             timezone: None,
             download: None,
             user: None,
+            extra_users: vec![],
             rtc_as_localtime: false,
             hostname: None,
+            keymap: None,
+            kernel_cmdline: vec![],
             swapfile: SwapFile::Automatic,
             target_partition: Arc::new(Mutex::new(None)),
             efi_partition: Arc::new(Mutex::new(None)),
+            data_layout: None,
+            root_fs: None,
+            efi_size: None,
+            data_partition: Arc::new(Mutex::new(None)),
+            swap_partition: Arc::new(Mutex::new(None)),
+            strict_configure: false,
+            allow_no_bootloader: false,
+            skip_bootloader: false,
+            branding: BTreeMap::new(),
+            stop_after: None,
+            extra_locales: vec![],
+            install_locale_extras: false,
+            locale_extras: default_locale_extras(),
+            identity: None,
+            encrypt: None,
+            list_md_devices: false,
+            passwordless_sudo: false,
+            dry_run: false,
+            user_on_first_boot: false,
+        }
+    }
+}
+
+/// Values that must be set before installing
+struct RequiredValues {
+    locale: String,
+    timezone: String,
+    download: DownloadType,
+    /// `None` if the user is created on first boot
+    user: Option<User>,
+    hostname: String,
+    target_partition: DkPartition,
+}
+
+impl InstallConfigPrepare {
+    /// Every required value that is not set yet, empty if the config is complete
+    pub fn missing_values(&self) -> Vec<NotSetValue> {
+        self.required_values().err().unwrap_or_default()
+    }
+
+    /// Required values, or every one of them that is not set yet
+    /// 与 missing_values 共用，保证二者检查的项一致
+    fn required_values(&self) -> Result<RequiredValues, Vec<NotSetValue>> {
+        let mut target_partition = self
+            .target_partition
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let mut download = self.download.clone();
+
+        // 模拟安装不读写磁盘也不下载，无需目标分区与安装源
+        if self.dry_run {
+            target_partition.get_or_insert_with(dry_run_partition);
+            download.get_or_insert_with(dry_run_download);
+        }
+        let has_swap_partition = self
+            .swap_partition
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some();
+
+        let mut missing = vec![];
+        let mut check = |is_set: bool, v: NotSetValue| {
+            if !is_set {
+                missing.push(v);
+            }
+        };
+
+        check(self.locale.is_some(), NotSetValue::Locale);
+        check(self.timezone.is_some(), NotSetValue::Timezone);
+        check(download.is_some(), NotSetValue::Download);
+        // OEM 模式下由最终用户在首次启动时创建账户
+        check(
+            self.user.is_some() || self.user_on_first_boot,
+            NotSetValue::User,
+        );
+        check(self.hostname.is_some(), NotSetValue::Hostname);
+        check(target_partition.is_some(), NotSetValue::TargetPartition);
+        // 选择了交换分区但尚未由 auto_partition 创建，否则安装后没有任何交换空间
+        check(
+            self.dry_run || !matches!(self.swapfile, SwapFile::Partition(_)) || has_swap_partition,
+            NotSetValue::SwapPartition,
+        );
+
+        match (
+            self.locale.clone(),
+            self.timezone.clone(),
+            download,
+            self.hostname.clone(),
+            target_partition,
+        ) {
+            (
+                Some(locale),
+                Some(timezone),
+                Some(download),
+                Some(hostname),
+                Some(target_partition),
+            ) if missing.is_empty() => Ok(RequiredValues {
+                locale,
+                timezone,
+                download,
+                user: if self.user_on_first_boot {
+                    None
+                } else {
+                    self.user.clone()
+                },
+                hostname,
+                target_partition,
+            }),
+            _ => Err(missing),
         }
     }
 }
 
+/// Placeholder target partition of a dry run, never touched
+fn dry_run_partition() -> DkPartition {
+    DkPartition::default()
+}
+
+/// Placeholder download of a dry run, the plan still contains the download stage
+fn dry_run_download() -> DownloadType {
+    DownloadType::Http {
+        url: String::new(),
+        hash: String::new(),
+        checksum: ChecksumKind::default(),
+        to_path: None,
+        max_attempts: None,
+    }
+}
+
 #[derive(Debug)]
 pub struct InstallConfig {
     local: String,
     timezone: String,
     pub download: DownloadType,
-    user: User,
+    /// `None` if the user is created on first boot
+    user: Option<User>,
+    extra_users: Vec<User>,
     rtc_as_localtime: bool,
     hostname: String,
+    keymap: Option<String>,
+    kernel_cmdline: Vec<String>,
     swapfile: SwapFile,
     pub target_partition: DkPartition,
     efi_partition: Option<DkPartition>,
+    data_partition: Option<(DkPartition, DataLayout)>,
+    swap_partition: Option<DkPartition>,
+    strict_configure: bool,
+    allow_no_bootloader: bool,
+    skip_bootloader: bool,
+    branding: BTreeMap<String, String>,
+    stop_after: Option<InstallationStage>,
+    extra_locales: Vec<String>,
+    install_locale_extras: bool,
+    locale_extras: BTreeMap<String, LocaleExtra>,
+    identity: Option<Identity>,
+    encrypt: Option<String>,
+    passwordless_sudo: bool,
+    dry_run: bool,
 }
 
 impl TryFrom<InstallConfigPrepare> for InstallConfig {
     type Error = InstallErr;
 
     fn try_from(value: InstallConfigPrepare) -> Result<Self, Self::Error> {
+        let required = value.required_values().map_err(|mut missing| {
+            ValueNotSetSnafu {
+                v: missing.remove(0),
+            }
+            .build()
+        })?;
+
+        ensure!(
+            !value.user_on_first_boot || value.extra_users.is_empty(),
+            ExtraUsersOnFirstBootSnafu
+        );
+
         Ok(Self {
-            local: value.locale.context(ValueNotSetSnafu {
-                v: NotSetValue::Locale,
-            })?,
-            timezone: value.timezone.context(ValueNotSetSnafu {
-                v: NotSetValue::Timezone,
-            })?,
-            download: value.download.context(ValueNotSetSnafu {
-                v: NotSetValue::Download,
-            })?,
-            user: value.user.context(ValueNotSetSnafu {
-                v: NotSetValue::User,
-            })?,
+            local: required.locale,
+            timezone: required.timezone,
+            download: required.download,
+            user: required.user,
+            extra_users: value.extra_users,
             rtc_as_localtime: value.rtc_as_localtime,
-            hostname: value.hostname.context(ValueNotSetSnafu {
-                v: NotSetValue::Hostname,
-            })?,
-            swapfile: value.swapfile,
-            target_partition: {
-                let lock = value.target_partition.lock().unwrap();
-
-                lock.clone().context(ValueNotSetSnafu {
-                    v: NotSetValue::TargetPartition,
-                })?
-            },
+            hostname: required.hostname,
+            keymap: value.keymap,
+            kernel_cmdline: value.kernel_cmdline,
+            swapfile: value.swapfile.normalize(),
+            target_partition: required.target_partition,
             efi_partition: {
-                let lock = value.efi_partition.lock().unwrap();
+                let lock = value
+                    .efi_partition
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner());
 
                 lock.clone()
             },
+            data_partition: {
+                let lock = value
+                    .data_partition
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner());
+
+                // 只有设置了数据分区布局时才挂载数据分区
+                value.data_layout.and_then(|l| lock.clone().map(|p| (p, l)))
+            },
+            swap_partition: {
+                let lock = value
+                    .swap_partition
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner());
+
+                lock.clone()
+            },
+            strict_configure: value.strict_configure,
+            allow_no_bootloader: value.allow_no_bootloader,
+            skip_bootloader: value.skip_bootloader,
+            branding: value.branding,
+            stop_after: value.stop_after,
+            extra_locales: value.extra_locales,
+            install_locale_extras: value.install_locale_extras,
+            locale_extras: value.locale_extras,
+            identity: value.identity,
+            encrypt: value.encrypt,
+            passwordless_sudo: value.passwordless_sudo,
+            dry_run: value.dry_run,
         })
     }
 }
@@ -349,9 +802,9 @@ macro_rules! cancel_install_exit {
     };
 }
 
-#[derive(Clone, IntoPrimitive)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, IntoPrimitive)]
 #[repr(u8)]
-enum InstallationStage {
+pub enum InstallationStage {
     SetupPartition = 1,
     DownloadSquashfs,
     ExtractSquashfs,
@@ -366,6 +819,7 @@ enum InstallationStage {
     CopyLog,
     UmountInnerPath,
     UmountEFIPath,
+    UmountDataPath,
     UmountRootPath,
     Done,
 }
@@ -393,6 +847,7 @@ impl Display for InstallationStage {
             Self::CopyLog => "copy log",
             Self::UmountInnerPath => "umount inner path",
             Self::UmountEFIPath => "umount EFI path",
+            Self::UmountDataPath => "umount data path",
             Self::UmountRootPath => "umount root path",
             Self::Done => "done",
         };
@@ -401,7 +856,122 @@ impl Display for InstallationStage {
     }
 }
 
+/// Stages shown to the user, in order
+/// 其余阶段（如 Chroot、卸载等）归入前一个可见阶段
+#[derive(Debug, Clone, Serialize)]
+pub struct InstallPlan {
+    pub stages: Vec<InstallationStage>,
+}
+
+impl InstallPlan {
+    pub fn new(download: Option<&DownloadType>) -> Self {
+        let mut stages = vec![InstallationStage::SetupPartition];
+
+        // 本地安装源无需下载
+        if !matches!(
+            download,
+            Some(DownloadType::File(_) | DownloadType::Dir(_) | DownloadType::Tarball(_))
+        ) {
+            stages.push(InstallationStage::DownloadSquashfs);
+        }
+
+        stages.extend([
+            InstallationStage::ExtractSquashfs,
+            InstallationStage::GenerateFstab,
+            InstallationStage::Dracut,
+            InstallationStage::InstallGrub,
+            InstallationStage::GenerateSshKey,
+            InstallationStage::ConfigureSystem,
+        ]);
+
+        Self { stages }
+    }
+
+    /// Index in `stages` of the stage running at GUI `step`, see [`InstallationStage::step`]
+    pub fn index_of_step(&self, step: u8) -> usize {
+        self.stages
+            .iter()
+            .filter(|x| x.step() <= step)
+            .count()
+            .saturating_sub(1)
+    }
+
+    /// Drop stages after `stop_after`
+    pub fn with_stop_after(mut self, stop_after: Option<&InstallationStage>) -> Self {
+        if let Some(stop_after) = stop_after {
+            let order = u8::from(stop_after.clone());
+            self.stages.retain(|x| u8::from(x.clone()) <= order);
+        }
+
+        self
+    }
+
+    /// Weight of each GUI step (1..=8) in the overall progress, 0 for steps not in the plan
+    pub fn weights(&self) -> Vec<u8> {
+        (1..=InstallationStage::Done.step())
+            .map(|step| {
+                self.stages
+                    .iter()
+                    .find(|x| x.step() == step)
+                    .map(|x| x.weight())
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+}
+
 impl InstallationStage {
+    /// Whether the installation can stop after this stage with the target left mounted
+    /// 进入 chroot 之后停止会使后端停留在目标系统中，故只允许在此之前停止
+    pub fn can_stop_after(&self) -> bool {
+        matches!(
+            self,
+            Self::SetupPartition
+                | Self::DownloadSquashfs
+                | Self::ExtractSquashfs
+                | Self::GenerateFstab
+        )
+    }
+
+    /// GUI step (1..=8) of the stage, fixed for clients that key on step numbers
+    /// 不可见的阶段（如 Chroot、卸载等）沿用前一个可见阶段的 step
+    pub fn step(&self) -> u8 {
+        match self {
+            Self::SetupPartition => 1,
+            Self::DownloadSquashfs => 2,
+            Self::ExtractSquashfs => 3,
+            Self::GenerateFstab | Self::Chroot => 4,
+            Self::Dracut => 5,
+            Self::InstallGrub => 6,
+            Self::GenerateSshKey => 7,
+            Self::ConfigureSystem
+            | Self::EscapeChroot
+            | Self::SwapOff
+            | Self::CopyLog
+            | Self::UmountInnerPath
+            | Self::UmountEFIPath
+            | Self::UmountDataPath
+            | Self::UmountRootPath
+            | Self::Done => 8,
+        }
+    }
+
+    /// Weight of the stage in the overall progress
+    /// 下载与解压占据绝大部分安装时间
+    fn weight(&self) -> u8 {
+        match self {
+            Self::SetupPartition => 2,
+            Self::DownloadSquashfs => 35,
+            Self::ExtractSquashfs => 40,
+            Self::GenerateFstab => 1,
+            Self::Dracut => 10,
+            Self::InstallGrub => 5,
+            Self::GenerateSshKey => 2,
+            Self::ConfigureSystem => 5,
+            _ => 0,
+        }
+    }
+
     fn get_next_stage(&self) -> Self {
         match self {
             Self::SetupPartition => Self::DownloadSquashfs,
@@ -417,7 +987,8 @@ impl InstallationStage {
             Self::SwapOff => Self::CopyLog,
             Self::CopyLog => Self::UmountInnerPath,
             Self::UmountInnerPath => Self::UmountEFIPath,
-            Self::UmountEFIPath => Self::UmountRootPath,
+            Self::UmountEFIPath => Self::UmountDataPath,
+            Self::UmountDataPath => Self::UmountRootPath,
             Self::UmountRootPath => Self::Done,
             Self::Done => Self::Done,
         }
@@ -425,16 +996,34 @@ impl InstallationStage {
 }
 
 impl InstallConfig {
+    /// `velocity` is in bytes per second, `eta` is the estimated remaining seconds of the current step
+    /// The installation waits before the next stage while `pause_install` is set
+    #[allow(clippy::too_many_arguments)]
     pub fn start_install(
         &self,
         step: Arc<AtomicU8>,
         progress: Arc<AtomicU8>,
         velocity: Arc<AtomicUsize>,
+        eta: Arc<AtomicUsize>,
         tmp_mount_path: Arc<PathBuf>,
         cancel_install: Arc<AtomicBool>,
+        pause_install: Arc<AtomicBool>,
+        warnings: Arc<Mutex<Vec<InstallWarning>>>,
+        boot_stub: Arc<Mutex<Option<BootStub>>>,
     ) -> Result<bool, InstallErr> {
         debug!("Install config: {:#?}", self);
 
+        if self.dry_run {
+            return self.simulate_install(
+                &step,
+                &progress,
+                &velocity,
+                &eta,
+                &cancel_install,
+                &pause_install,
+            );
+        }
+
         let root_fd = get_dir_fd(Path::new("/")).context(GetDirFdSnafu)?;
 
         let mut stage = InstallationStage::default();
@@ -443,39 +1032,41 @@ impl InstallConfig {
 
         let mut error_retry = 1;
 
+        // 进入 chroot 后无法再读取 Livekit 的挂载信息，故需要提前获取
+        let live_devices = live_device_paths().unwrap_or_else(|e| {
+            warn!("Failed to get live devices: {e}");
+            vec![]
+        });
+
+        let plan = InstallPlan::new(Some(&self.download)).with_stop_after(self.stop_after.as_ref());
+
         loop {
             debug!("Current stage: {stage}");
 
-            // GUI 用户体验需求，一些步骤不应该执行 step 回掉
-            let num = match stage {
-                InstallationStage::SetupPartition => 1,
-                InstallationStage::DownloadSquashfs => 2,
-                InstallationStage::ExtractSquashfs => 3,
-                InstallationStage::GenerateFstab => 4,
-                InstallationStage::Chroot => 4,
-                InstallationStage::Dracut => 5,
-                InstallationStage::InstallGrub => 6,
-                InstallationStage::GenerateSshKey => 7,
-                InstallationStage::ConfigureSystem => 8,
-                InstallationStage::EscapeChroot => 8,
-                InstallationStage::SwapOff => 8,
-                InstallationStage::CopyLog => 8,
-                InstallationStage::UmountInnerPath => 8,
-                InstallationStage::UmountEFIPath => 8,
-                InstallationStage::UmountRootPath => 8,
-                InstallationStage::Done => 8,
-            };
+            // 暂停时在步骤之间等待，取消安装优先于暂停
+            if pause_install.load(Ordering::SeqCst) {
+                info!("Installation paused before {stage}");
+
+                while pause_install.load(Ordering::SeqCst) && !cancel_install.load(Ordering::SeqCst)
+                {
+                    std::thread::sleep(Duration::from_millis(200));
+                }
+
+                info!("Installation resumed");
+            }
 
-            step.store(num, Ordering::SeqCst);
+            // GUI 用户体验需求，step 编号固定，跳过的步骤由 plan 体现
+            step.store(stage.step(), Ordering::SeqCst);
 
             let res = match stage {
                 InstallationStage::SetupPartition => self
-                    .setup_partition(&progress, &tmp_mount_path, &cancel_install)
+                    .setup_partition(&progress, &tmp_mount_path, &cancel_install, &warnings)
                     .context(SetupPartitionSnafu),
                 InstallationStage::DownloadSquashfs => self
                     .download_squashfs(
                         progress.clone(),
                         velocity.clone(),
+                        eta.clone(),
                         Arc::clone(&cancel_install),
                         &mut files_type,
                     )
@@ -484,6 +1075,7 @@ impl InstallConfig {
                     .extract_squashfs(
                         &progress,
                         &velocity,
+                        &eta,
                         &tmp_mount_path,
                         cancel_install.clone(),
                         // 若能进行到这一步，则 squashfs_total_size 一定有值，故 unwrap 安全
@@ -497,16 +1089,23 @@ impl InstallConfig {
                     .chroot(&progress, &tmp_mount_path, &cancel_install)
                     .context(ChrootSnafu),
                 InstallationStage::Dracut => {
+                    self.enable_dracut_resume(&warnings);
                     run_dracut(&cancel_install, &progress).context(DracutSnafu)
                 }
                 InstallationStage::InstallGrub => self
-                    .install_grub(&progress, &cancel_install)
+                    .install_grub(
+                        &progress,
+                        &cancel_install,
+                        &live_devices,
+                        &warnings,
+                        &boot_stub,
+                    )
                     .context(GrubSnafu),
                 InstallationStage::GenerateSshKey => self
                     .generate_ssh_key(&progress, &cancel_install)
                     .context(GenerateSshKeySnafu),
                 InstallationStage::ConfigureSystem => self
-                    .configure_system(&progress, &cancel_install)
+                    .configure_system(&progress, &cancel_install, &warnings)
                     .context(ConfigureSystemSnafu),
                 InstallationStage::EscapeChroot => self
                     .escape_chroot(&progress, &cancel_install, &root_fd)
@@ -521,7 +1120,10 @@ impl InstallConfig {
                 InstallationStage::UmountInnerPath => remove_files_mounts(&tmp_mount_path)
                     .context(UmountSnafu)
                     .context(PostInstallationSnafu)
-                    .map(|_| true),
+                    .map(|_| {
+                        self.syncfs_target(&tmp_mount_path);
+                        true
+                    }),
                 InstallationStage::UmountEFIPath => {
                     if is_efi_booted() {
                         let path = tmp_mount_path.join("efi");
@@ -533,16 +1135,33 @@ impl InstallConfig {
                         Ok(true)
                     }
                 }
-                InstallationStage::UmountRootPath => umount_root_path(&tmp_mount_path)
+                InstallationStage::UmountDataPath => self
+                    .umount_data_paths(&tmp_mount_path)
                     .context(UmountSnafu)
                     .context(PostInstallationSnafu)
                     .map(|_| true),
+                InstallationStage::UmountRootPath => umount_root_path(&tmp_mount_path)
+                    .context(UmountSnafu)
+                    .context(PostInstallationSnafu)
+                    .map(|_| {
+                        self.flush_target_devices(&warnings);
+                        self.close_encrypted_root(&warnings);
+                        true
+                    }),
                 InstallationStage::Done => break,
             };
 
             stage = match res {
+                Ok(true) if self.stop_after.as_ref() == Some(&stage) => {
+                    info!(
+                        "Stopped after {stage}, target is mounted at {}",
+                        tmp_mount_path.display()
+                    );
+                    return Ok(true);
+                }
                 Ok(v) if v => stage.get_next_stage(),
-                Ok(_) => break,
+                // 安装被取消
+                Ok(_) => return Ok(false),
                 Err(e) => {
                     error!("Error occured in step {stage}: {e:?}");
 
@@ -551,6 +1170,7 @@ impl InstallConfig {
                     if error_retry == 3 {
                         if matches!(stage, InstallationStage::UmountRootPath)
                             || matches!(stage, InstallationStage::UmountEFIPath)
+                            || matches!(stage, InstallationStage::UmountDataPath)
                             || matches!(stage, InstallationStage::UmountInnerPath)
                         {
                             umount_all(&tmp_mount_path);
@@ -572,6 +1192,55 @@ impl InstallConfig {
         Ok(true)
     }
 
+    /// Walk the planned stages and drive the progress without touching any disk
+    fn simulate_install(
+        &self,
+        step: &AtomicU8,
+        progress: &AtomicU8,
+        velocity: &AtomicUsize,
+        eta: &AtomicUsize,
+        cancel_install: &AtomicBool,
+        pause_install: &AtomicBool,
+    ) -> Result<bool, InstallErr> {
+        let plan = InstallPlan::new(Some(&self.download)).with_stop_after(self.stop_after.as_ref());
+
+        for stage in &plan.stages {
+            info!("Simulating {stage} (dry run)");
+            step.store(stage.step(), Ordering::SeqCst);
+
+            // 下载与解压阶段报告速度与剩余时间
+            let is_transfer = matches!(
+                stage,
+                InstallationStage::DownloadSquashfs | InstallationStage::ExtractSquashfs
+            );
+
+            for p in (0..=100).step_by(5) {
+                while pause_install.load(Ordering::SeqCst) && !cancel_install.load(Ordering::SeqCst)
+                {
+                    std::thread::sleep(Duration::from_millis(200));
+                }
+
+                cancel_install_exit!(cancel_install);
+
+                progress.store(p, Ordering::SeqCst);
+
+                if is_transfer {
+                    velocity.store(50 * 1024 * 1024, Ordering::SeqCst);
+                    eta.store((100 - p as usize) / 20, Ordering::SeqCst);
+                }
+
+                std::thread::sleep(Duration::from_millis(50));
+            }
+
+            velocity.store(0, Ordering::SeqCst);
+            eta.store(0, Ordering::SeqCst);
+        }
+
+        info!("Dry run finished");
+
+        Ok(true)
+    }
+
     fn chroot(
         &self,
         progress: &AtomicU8,
@@ -614,10 +1283,21 @@ impl InstallConfig {
         progress: &AtomicU8,
         tmp_mount_path: &Path,
         cancel_install: &AtomicBool,
+        warnings: &Mutex<Vec<InstallWarning>>,
     ) -> Result<bool, SetupPartitionError> {
         progress.store(0, Ordering::SeqCst);
 
-        self.format_partitions().context(FormatSnafu)?;
+        self.check_reused_partitions(tmp_mount_path, warnings)?;
+        cancel_install_exit!(cancel_install);
+
+        self.format_partitions(warnings).context(FormatSnafu)?;
+        cancel_install_exit!(cancel_install);
+
+        if self.use_btrfs_subvolumes() {
+            if let Some(ref path) = self.root_partition().path {
+                create_btrfs_subvolumes(path).context(FormatSnafu)?;
+            }
+        }
         cancel_install_exit!(cancel_install);
 
         self.mount_partitions(tmp_mount_path).context(MountSnafu)?;
@@ -625,20 +1305,30 @@ impl InstallConfig {
 
         progress.store(50, Ordering::SeqCst);
 
-        match self.swapfile {
+        let fs_type = self.target_partition.fs_type.as_deref();
+
+        match self.swapfile() {
             SwapFile::Automatic => {
                 let mut sys = System::new_all();
                 sys.refresh_memory();
                 let total_memory = sys.total_memory();
                 let size = get_recommend_swap_size(total_memory);
                 cancel_install_exit!(cancel_install);
-                create_swapfile(size, tmp_mount_path).context(SwapFileSnafu)?;
+                create_swapfile(size, tmp_mount_path, fs_type).context(SwapFileSnafu)?;
             }
             SwapFile::Custom(size) => {
                 cancel_install_exit!(cancel_install);
-                create_swapfile(size as f64, tmp_mount_path).context(SwapFileSnafu)?;
+                create_swapfile(*size as f64, tmp_mount_path, fs_type).context(SwapFileSnafu)?;
+            }
+            SwapFile::Hibernate => {
+                let mut sys = System::new_all();
+                sys.refresh_memory();
+                let size = get_hibernate_swap_size(sys.total_memory());
+                cancel_install_exit!(cancel_install);
+                create_swapfile(size, tmp_mount_path, fs_type).context(SwapFileSnafu)?;
             }
-            SwapFile::Disable => {}
+            // zram 在配置系统时写入 zram-generator 配置
+            SwapFile::Disable | SwapFile::Zram { .. } | SwapFile::Partition(_) => {}
         }
 
         progress.store(100, Ordering::SeqCst);
@@ -650,6 +1340,7 @@ impl InstallConfig {
         &self,
         progress: Arc<AtomicU8>,
         velocity: Arc<AtomicUsize>,
+        eta: Arc<AtomicUsize>,
         cancel_install: Arc<AtomicBool>,
         res: &mut Option<FilesType>,
     ) -> Result<bool, DownloadError> {
@@ -657,7 +1348,20 @@ impl InstallConfig {
 
         cancel_install_exit!(cancel_install);
 
-        let f = download_file(&self.download, progress, velocity, cancel_install)?;
+        let f = download_file(
+            &self.download,
+            progress,
+            velocity,
+            eta.clone(),
+            cancel_install,
+        )?;
+        eta.store(0, Ordering::SeqCst);
+
+        // 下载途中取消安装
+        let f = match f {
+            Some(f) => f,
+            None => return Ok(false),
+        };
 
         *res = Some(f);
 
@@ -668,6 +1372,7 @@ impl InstallConfig {
         &self,
         progress: &AtomicU8,
         velocity: &AtomicUsize,
+        eta: &AtomicUsize,
         tmp_mount_path: &Path,
         cancel_install: Arc<AtomicBool>,
         files_type: &FilesType,
@@ -687,16 +1392,19 @@ impl InstallConfig {
                     tmp_mount_path.to_path_buf(),
                     progress,
                     velocity,
+                    eta,
                     cancel_install.clone(),
                 )
-                .context(ExtractSnafu {
+                .map_err(|e| InstallSquashfsError::Extract {
+                    kind: ExtractErrorKind::classify_squashfs(&e, tmp_mount_path),
+                    source: e,
                     from: squashfs_path.clone(),
                     to: tmp_mount_path.to_path_buf(),
                 })?;
 
                 cancel_install_exit!(cancel_install);
 
-                if let DownloadType::Http { .. } = self.download {
+                if let DownloadType::Http { .. } | DownloadType::HttpMulti { .. } = self.download {
                     debug!(
                         "Removing downloaded squashfs file {}",
                         squashfs_path.display()
@@ -704,23 +1412,53 @@ impl InstallConfig {
                     fs::remove_file(squashfs_path).context(RemoveDownloadedFileSnafu)?;
                 }
             }
-            FilesType::Dir { path, total } => {
+            FilesType::Tarball {
+                path: tarball_path,
+                total: total_size,
+                compression,
+            } => {
+                extract_tarball(
+                    *total_size as f64,
+                    tarball_path.clone(),
+                    *compression,
+                    tmp_mount_path.to_path_buf(),
+                    progress,
+                    velocity,
+                    eta,
+                    cancel_install.clone(),
+                )
+                .map_err(|e| InstallSquashfsError::ExtractTarball {
+                    kind: ExtractErrorKind::classify_tarball(&e, tmp_mount_path),
+                    source: e,
+                    from: tarball_path.clone(),
+                    to: tmp_mount_path.to_path_buf(),
+                })?;
+
+                cancel_install_exit!(cancel_install);
+
+                if let DownloadType::Http { .. } | DownloadType::HttpMulti { .. } = self.download {
+                    debug!("Removing downloaded tarball {}", tarball_path.display());
+                    fs::remove_file(tarball_path).context(RemoveDownloadedFileSnafu)?;
+                }
+            }
+            FilesType::Dir { path, .. } => {
                 cancel_install_exit!(cancel_install);
 
-                rsync_system(
+                if !rsync_system(
                     progress,
                     velocity,
+                    eta,
                     path,
                     tmp_mount_path,
                     &cancel_install,
-                    *total,
-                )?;
-
-                cancel_install_exit!(cancel_install);
+                )? {
+                    return Ok(false);
+                }
             }
         }
 
         velocity.store(0, Ordering::SeqCst);
+        eta.store(0, Ordering::SeqCst);
 
         Ok(true)
     }
@@ -729,12 +1467,49 @@ impl InstallConfig {
         &self,
         progress: &AtomicU8,
         cancel_install: &AtomicBool,
+        live_devices: &[PathBuf],
+        warnings: &Mutex<Vec<InstallWarning>>,
+        boot_stub: &Mutex<Option<BootStub>>,
     ) -> Result<bool, RunGrubError> {
         progress.store(0, Ordering::SeqCst);
         cancel_install_exit!(cancel_install);
 
+        // 由外部引导器启动，只报告内核、initrd 与内核参数
+        if self.skip_bootloader {
+            info!("Skipping bootloader installation, collecting boot stub ...");
+            match collect_boot_stub() {
+                Ok(stub) => {
+                    info!("Boot stub: {stub:?}");
+                    *boot_stub.lock().unwrap_or_else(|e| e.into_inner()) = Some(stub);
+                }
+                Err(e) => {
+                    warn!("Failed to collect boot stub: {e}");
+                    warnings.lock().unwrap_or_else(|e| e.into_inner()).push(
+                        InstallWarning::from_error(&InstallationStage::InstallGrub, &e),
+                    );
+                }
+            }
+            progress.store(100, Ordering::SeqCst);
+
+            return Ok(true);
+        }
+
+        // 尽早检查 GRUB 平台文件，避免 grub-install 报错 platform directory not found
+        match check_grub_platform(self.efi_partition.is_some()) {
+            Err(e @ RunGrubError::MissingPlatform { .. }) if self.allow_no_bootloader => {
+                warn!("{e}, skipping bootloader installation, installed system will NOT boot by itself");
+                warnings.lock().unwrap_or_else(|e| e.into_inner()).push(
+                    InstallWarning::from_error(&InstallationStage::InstallGrub, &e),
+                );
+                progress.store(100, Ordering::SeqCst);
+
+                return Ok(true);
+            }
+            res => res?,
+        }
+
         info!("Installing grub ...");
-        self.install_grub_impl()?;
+        self.install_grub_impl(live_devices)?;
 
         cancel_install_exit!(cancel_install);
         progress.store(100, Ordering::SeqCst);
@@ -781,13 +1556,36 @@ impl InstallConfig {
         &self,
         progress: &AtomicU8,
         cancel_install: &AtomicBool,
+        warnings: &Mutex<Vec<InstallWarning>>,
     ) -> Result<bool, ConfigureSystemError> {
+        // 非关键步骤失败时记录警告并继续
+        let check = |res: Result<(), ConfigureSystemError>| -> Result<(), ConfigureSystemError> {
+            match res {
+                Err(e) if !self.strict_configure && !e.is_critical() => {
+                    warn!("Non-critical step failed, continuing: {e:?}");
+                    warnings.lock().unwrap_or_else(|e| e.into_inner()).push(
+                        InstallWarning::from_error(&InstallationStage::ConfigureSystem, &e),
+                    );
+
+                    Ok(())
+                }
+                res => res,
+            }
+        };
+
         progress.store(0, Ordering::SeqCst);
 
         cancel_install_exit!(cancel_install);
 
-        if self.swapfile != SwapFile::Disable {
-            write_swap_entry_to_fstab().context(SwapToGenfstabSnafu)?;
+        match self.swapfile() {
+            // swap 分区的 fstab 条目在 genfstab 阶段写入
+            SwapFile::Disable | SwapFile::Partition(_) => {}
+            SwapFile::Zram { size_mb } => {
+                check(write_zram_generator_config(*size_mb).context(ZramConfigSnafu))?;
+            }
+            SwapFile::Automatic | SwapFile::Custom(_) | SwapFile::Hibernate => {
+                write_swap_entry_to_fstab().context(SwapToGenfstabSnafu)?;
+            }
         }
 
         cancel_install_exit!(cancel_install);
@@ -804,9 +1602,11 @@ impl InstallConfig {
         cancel_install_exit!(cancel_install);
 
         info!("Setting rtc_as_localtime ...");
-        set_hwclock_tc(!self.rtc_as_localtime).context(SetHwclockSnafu {
-            is_rtc: self.rtc_as_localtime,
-        })?;
+        check(
+            set_hwclock_tc(!self.rtc_as_localtime).context(SetHwclockSnafu {
+                is_rtc: self.rtc_as_localtime,
+            }),
+        )?;
         progress.store(50, Ordering::SeqCst);
 
         cancel_install_exit!(cancel_install);
@@ -815,19 +1615,62 @@ impl InstallConfig {
         set_hostname(&self.hostname).context(SetHostnameSnafu {
             hostname: self.hostname.to_string(),
         })?;
+
+        if let Some(identity) = &self.identity {
+            info!("Applying machine identity of {}", identity.hostname);
+            apply_identity(identity).context(ApplyIdentitySnafu {
+                hostname: identity.hostname.to_string(),
+            })?;
+        }
         progress.store(75, Ordering::SeqCst);
 
         cancel_install_exit!(cancel_install);
 
-        info!("Setting User ...");
-        add_new_user(&self.user.username, &self.user.password).context(AddNewUserSnafu)?;
+        if self.user.is_none() {
+            info!("Setting up user creation on first boot ...");
+            setup_first_boot().context(SetupFirstBootSnafu)?;
+        }
+
+        // 在创建任何用户之前检查所有用户，以免只创建了一部分用户
+        let users = self.user.iter().chain(&self.extra_users);
+        check_unique_usernames(users.clone().map(|u| u.username.as_str()))
+            .context(AddNewUserSnafu)?;
+        check_unique_uids(users.clone().filter_map(|u| u.uid)).context(AddNewUserSnafu)?;
 
-        cancel_install_exit!(cancel_install);
+        for user in users.clone() {
+            check_new_user(&user.username, user.shell.as_deref(), user.uid)
+                .context(AddNewUserSnafu)?;
+        }
 
-        if let Some(full_name) = &self.user.full_name {
-            passwd_set_fullname(full_name, &self.user.username).context(SetFullNameSnafu {
-                fullname: full_name.to_string(),
-            })?;
+        for user in users {
+            info!("Setting User {} ...", user.username);
+            add_new_user(
+                &user.username,
+                &user.password,
+                user.admin,
+                user.shell.as_deref(),
+                user.uid,
+            )
+            .context(AddNewUserSnafu)?;
+
+            cancel_install_exit!(cancel_install);
+
+            if let Some(full_name) = &user.full_name {
+                check(
+                    passwd_set_fullname(full_name, &user.username).context(SetFullNameSnafu {
+                        fullname: full_name.to_string(),
+                    }),
+                )?;
+            }
+        }
+
+        info!("Setting root password ...");
+        set_root_password(self.user.as_ref().and_then(|u| u.root_password.as_deref()))
+            .context(SetRootPasswordSnafu)?;
+
+        if self.passwordless_sudo {
+            info!("Enabling passwordless sudo ...");
+            check(set_passwordless_sudo().context(SetPasswordlessSudoSnafu))?;
         }
 
         cancel_install_exit!(cancel_install);
@@ -835,9 +1678,36 @@ impl InstallConfig {
         progress.store(80, Ordering::SeqCst);
 
         info!("Setting locale ...");
-        set_locale(&self.local).context(SetLocaleSnafu {
-            locale: self.local.to_string(),
-        })?;
+        check(
+            set_locale(&self.local, &self.extra_locales).context(SetLocaleSnafu {
+                locale: self.local.to_string(),
+            }),
+        )?;
+
+        if let Some(keymap) = &self.keymap {
+            info!("Setting keymap as {keymap} ...");
+            check(set_keymap(keymap).context(SetKeymapSnafu {
+                keymap: keymap.to_string(),
+            }))?;
+        }
+
+        cancel_install_exit!(cancel_install);
+
+        info!("Setting os-release branding ...");
+        check(set_os_release_branding(&self.branding).context(SetBrandingSnafu))?;
+
+        cancel_install_exit!(cancel_install);
+
+        if self.install_locale_extras {
+            info!("Installing extra packages for locale ...");
+            check(
+                install_locale_extras(&self.locale_extras, &self.local).context(
+                    InstallLocaleExtrasSnafu {
+                        locale: self.local.to_string(),
+                    },
+                ),
+            )?;
+        }
 
         progress.store(100, Ordering::SeqCst);
 
@@ -845,102 +1715,486 @@ impl InstallConfig {
     }
 
     fn swapoff_impl(&self, tmp_mount_path: &Path) -> Result<bool, PostInstallationError> {
-        if self.swapfile != SwapFile::Disable || self.swapfile != SwapFile::Custom(0) {
-            let mut retry = 1;
-            while let Err(e) = swapoff(tmp_mount_path) {
-                debug!("swapoff has error: {e:?}, retry {} times", retry);
+        // 未创建 swapfile 时（包括 zram 与 swap 分区）无需 swapoff
+        if !self.swapfile().creates_swapfile() {
+            return Ok(true);
+        }
 
-                if retry == 5 {
-                    break;
-                }
+        let mut retry = 1;
+        while let Err(e) = swapoff(tmp_mount_path) {
+            debug!("swapoff has error: {e:?}, retry {} times", retry);
 
-                retry += 1;
-                std::thread::sleep(Duration::from_millis(500));
+            if retry == 5 {
+                break;
             }
+
+            retry += 1;
+            std::thread::sleep(Duration::from_millis(500));
         }
 
         Ok(true)
     }
 
-    fn install_grub_impl(&self) -> Result<bool, RunGrubError> {
+    /// syncfs the target root, ESP and data partition while they are still mounted
+    fn syncfs_target(&self, tmp_mount_path: &Path) {
+        let mut paths = vec![tmp_mount_path.to_path_buf()];
+
+        if self.efi_partition.is_some() {
+            paths.push(tmp_mount_path.join("efi"));
+        }
+
+        if let Some((_, ref layout)) = self.data_partition {
+            paths.push(data_mount_path(tmp_mount_path, layout));
+        }
+
+        for path in paths {
+            info!("Syncing filesystem at {}", path.display());
+            if let Err(e) = syncfs_path(&path) {
+                warn!("Failed to sync filesystem at {}: {e}", path.display());
+            }
+        }
+    }
+
+    /// Flush the write cache of the target disks before reporting success
+    /// 防止安装完成后立即断电导致文件损坏
+    fn flush_target_devices(&self, warnings: &Mutex<Vec<InstallWarning>>) {
+        let mut devices = vec![];
+
+        for p in [
+            Some(&self.target_partition),
+            self.efi_partition.as_ref(),
+            self.data_partition.as_ref().map(|(p, _)| p),
+            self.swap_partition.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let Some(ref parent) = p.parent_path {
+                if !devices.contains(parent) {
+                    devices.push(parent.clone());
+                }
+            }
+        }
+
+        for dev in devices {
+            match flush_device(&dev) {
+                Ok(FlushReport {
+                    cache_flushed: true,
+                    ..
+                }) => {}
+                Ok(_) => warnings
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(InstallWarning {
+                        stage: InstallationStage::UmountRootPath.to_string(),
+                        message: format!("Failed to flush write cache of {}", dev.display()),
+                    }),
+                Err(e) => {
+                    warn!("Failed to open {} to flush: {e}", dev.display());
+                    warnings.lock().unwrap_or_else(|e| e.into_inner()).push(
+                        InstallWarning::from_error(&InstallationStage::UmountRootPath, &e),
+                    );
+                }
+            }
+        }
+    }
+
+    fn install_grub_impl(&self, live_devices: &[PathBuf]) -> Result<bool, RunGrubError> {
+        if self.encrypt.is_some() {
+            info!("Enabling GRUB cryptodisk support ...");
+            enable_cryptodisk()?;
+
+            // 由 initramfs 在启动时解锁系统分区
+            let uuid = luks_uuid(&self.target_partition)
+                .map_err(|e| RunGrubError::LuksUuid { source: e })?;
+            add_kernel_cmdline(&[format!("rd.luks.uuid={uuid}")])?;
+        }
+
+        // 需在 grub-mkconfig 之前写入 /etc/default/grub
+        if self.resumes_from_swap() {
+            info!("Adding resume parameters ...");
+            let params = self
+                .resume_cmdline()
+                .map_err(|e| RunGrubError::ResumeOffset { source: e })?;
+            add_kernel_cmdline(&params)?;
+        }
+
+        if !self.kernel_cmdline.is_empty() {
+            info!(
+                "Adding kernel parameters: {} ...",
+                self.kernel_cmdline.join(" ")
+            );
+            add_kernel_cmdline(&self.kernel_cmdline)?;
+        }
+
         if self.efi_partition.is_some() {
             info!("Installing grub to UEFI partition ...");
-            execute_grub_install(None, &self.local)?;
+            execute_grub_install(&[], &self.local, live_devices)?;
         } else {
-            info!("Installing grub to MBR partition ...");
-            execute_grub_install(
-                Some(self.target_partition.parent_path.as_ref().unwrap()),
-                &self.local,
-            )?;
+            // GPT 磁盘由 grub-install 写入 BIOS boot 分区
+            info!("Installing grub to MBR or BIOS boot partition ...");
+            let parent = self.target_partition.parent_path.as_ref().unwrap();
+
+            // md 阵列需要写入每个成员磁盘，以便从任一磁盘启动
+            let mut disks = slave_disks(parent);
+            if disks.is_empty() {
+                disks.push(parent.clone());
+            }
+
+            execute_grub_install(&disks, &self.local, live_devices)?;
         }
 
         Ok(true)
     }
 
+    /// Include the dracut resume module for hibernation, the system still boots without it
+    /// Must be used in a chroot context
+    fn enable_dracut_resume(&self, warnings: &Mutex<Vec<InstallWarning>>) {
+        if !self.resumes_from_swap() {
+            return;
+        }
+
+        info!("Enabling dracut resume module ...");
+        if let Err(e) = write_dracut_resume_config() {
+            warn!("Failed to enable dracut resume module: {e}");
+            warnings
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(InstallWarning::from_error(&InstallationStage::Dracut, &e));
+        }
+    }
+
+    /// Whether the installed system resumes from the swap partition or the hibernation swapfile
+    fn resumes_from_swap(&self) -> bool {
+        self.swap_partition.is_some() || *self.swapfile() == SwapFile::Hibernate
+    }
+
+    /// `resume=` kernel parameter of the swap partition,
+    /// or `resume=` and `resume_offset=` of the swapfile
+    /// Must be used in a chroot context
+    fn resume_cmdline(&self) -> io::Result<Vec<String>> {
+        if let Some(ref swap) = self.swap_partition {
+            let path = swap.path.as_ref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "swap partition path is not set")
+            })?;
+
+            let uuid = blkid_probe(path, "UUID").ok().flatten().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no UUID", path.display()),
+                )
+            })?;
+
+            return Ok(vec![format!("resume=UUID={uuid}")]);
+        }
+
+        let root = self.root_partition();
+        let path = root.path.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "system partition path is not set")
+        })?;
+
+        let uuid = blkid_probe(path, "UUID").ok().flatten().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no UUID", path.display()),
+            )
+        })?;
+
+        let offset = swapfile_resume_offset(Path::new("/swapfile"), root.fs_type.as_deref())?;
+
+        Ok(vec![
+            format!("resume=UUID={uuid}"),
+            format!("resume_offset={offset}"),
+        ])
+    }
+
     fn genfatab(&self, tmp_mount_path: &Path) -> Result<bool, SetupGenfstabError> {
-        genfstab_to_file(
-            self.target_partition
-                .path
-                .as_ref()
-                .context(ValueNotSetGenfstabSnafu {
-                    t: "system partition path",
-                })?,
-            self.target_partition
-                .fs_type
-                .as_ref()
-                .context(ValueNotSetGenfstabSnafu {
+        let root = self.root_partition();
+        let root_path = root.path.as_ref().context(ValueNotSetGenfstabSnafu {
+            t: "system partition path",
+        })?;
+
+        if self.use_btrfs_subvolumes() {
+            genfstab_btrfs_subvol_to_file(root_path, tmp_mount_path, Path::new("/"), ROOT_SUBVOL)?;
+
+            for (subvol, mount_point) in self.btrfs_subvolume_mounts() {
+                genfstab_btrfs_subvol_to_file(root_path, tmp_mount_path, mount_point, subvol)?;
+            }
+        } else {
+            genfstab_to_file(
+                root_path,
+                root.fs_type.as_ref().context(ValueNotSetGenfstabSnafu {
                     t: "system partition fstype",
                 })?,
-            tmp_mount_path,
-            Path::new("/"),
-        )?;
+                tmp_mount_path,
+                Path::new("/"),
+            )?;
+        }
+
+        if self.encrypt.is_some() {
+            let uuid = luks_uuid(&self.target_partition).context(LuksUuidSnafu)?;
+            gencrypttab_to_file(&format!("luks-{uuid}"), &uuid, tmp_mount_path)?;
+        }
+
+        if self.uses_md_device() {
+            genmdadm_conf_to_file(tmp_mount_path)?;
+        }
 
         if let Some(ref efi_partition) = self.efi_partition {
-            genfstab_to_file(
+            // 不使用缓存的 UUID 与文件系统信息，ESP 可能已被重新格式化
+            genfstab_esp_to_file(
                 efi_partition
                     .path
                     .as_ref()
                     .context(ValueNotSetGenfstabSnafu {
                         t: "efi partition path",
                     })?,
-                efi_partition
+                tmp_mount_path,
+                Path::new("/efi"),
+            )?;
+        }
+
+        if let Some((ref data_partition, ref layout)) = self.data_partition {
+            genfstab_to_file(
+                data_partition
+                    .path
+                    .as_ref()
+                    .context(ValueNotSetGenfstabSnafu {
+                        t: "data partition path",
+                    })?,
+                data_partition
                     .fs_type
                     .as_ref()
                     .context(ValueNotSetGenfstabSnafu {
-                        t: "efi partition fstype",
+                        t: "data partition fstype",
                     })?,
                 tmp_mount_path,
-                Path::new("/efi"),
+                &layout.mount_point,
             )?;
         }
 
+        if let Some(ref swap) = self.swap_partition {
+            genfstab_swap_to_file(
+                swap.path.as_ref().context(ValueNotSetGenfstabSnafu {
+                    t: "swap partition path",
+                })?,
+                tmp_mount_path,
+            )?;
+        }
+
+        self.verify_fstab(tmp_mount_path)?;
+
         Ok(true)
     }
 
-    fn mount_partitions(&self, tmp_mount_path: &Path) -> Result<bool, MountError> {
-        let fs_type = self
-            .target_partition
-            .fs_type
-            .as_ref()
-            .context(ValueNotSetMountSnafu {
-                t: "system partition fstype",
-            })?;
+    /// Whether any partition to install onto is on an mdadm RAID array
+    fn uses_md_device(&self) -> bool {
+        [
+            Some(&self.target_partition),
+            self.efi_partition.as_ref(),
+            self.data_partition.as_ref().map(|(p, _)| p),
+            self.swap_partition.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|p| p.parent_path.as_ref())
+        .any(|parent| is_md_device(parent))
+    }
 
-        mount_root_path(
-            self.target_partition.path.as_deref(),
-            tmp_mount_path,
-            fs_type,
-        )
-        .context(MountRootSnafu {
-            path: self
-                .target_partition
-                .path
-                .as_ref()
-                .context(ValueNotSetMountSnafu {
-                    t: "system mount path",
-                })?,
+    /// The device holding the root filesystem, the LUKS mapped device if encrypted
+    fn root_partition(&self) -> DkPartition {
+        match self.encrypt {
+            Some(_) => DkPartition {
+                path: Some(mapper_path(LUKS_ROOT_NAME)),
+                ..self.target_partition.clone()
+            },
+            None => self.target_partition.clone(),
+        }
+    }
+
+    /// Check that the partitions kept without formatting can be mounted and put in fstab
+    /// 非空的分区仍可使用，但其中的文件可能被覆盖，故只给出警告
+    fn check_reused_partitions(
+        &self,
+        tmp_mount_path: &Path,
+        warnings: &Mutex<Vec<InstallWarning>>,
+    ) -> Result<(), SetupPartitionError> {
+        let mut reused = vec![&self.target_partition];
+        if let Some((ref data, _)) = self.data_partition {
+            reused.push(data);
+        }
+
+        for p in reused.into_iter().filter(|p| !p.format) {
+            let path = match p.path {
+                Some(ref path) => path,
+                None => continue,
+            };
+
+            let err = |reason: &str| SetupPartitionError::ReusePartition {
+                path: path.to_path_buf(),
+                reason: reason.to_string(),
+            };
+
+            // 加密需要重新格式化系统分区
+            if self.encrypt.is_some() && p.path == self.target_partition.path {
+                return Err(err("encryption requires formatting"));
+            }
+
+            let fs_type = match p.fs_type {
+                Some(ref fs_type) => fs_type,
+                None => return Err(err("no filesystem found")),
+            };
+
+            if !is_fstab_supported(fs_type) {
+                return Err(err(&format!("unsupported filesystem {fs_type}")));
+            }
+
+            match check_mountable(path, tmp_mount_path, fs_type) {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("{} is not empty", path.display());
+                    warnings
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(InstallWarning {
+                            stage: InstallationStage::SetupPartition.to_string(),
+                            message: format!(
+                                "{} is not empty, existing files may be overwritten",
+                                path.display()
+                            ),
+                        });
+                }
+                Err(e) => return Err(err(&format!("failed to mount as {fs_type}: {e}"))),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 卸载完成后关闭 LUKS 映射设备
+    fn close_encrypted_root(&self, warnings: &Mutex<Vec<InstallWarning>>) {
+        if self.encrypt.is_none() || !is_luks_open(LUKS_ROOT_NAME) {
+            return;
+        }
+
+        if let Err(e) = luks_close(LUKS_ROOT_NAME) {
+            warn!("Failed to close LUKS container {LUKS_ROOT_NAME}: {e}");
+            warnings
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(InstallWarning::from_error(
+                    &InstallationStage::UmountRootPath,
+                    &e,
+                ));
+        }
+    }
+
+    /// Mount the subvolumes of a btrfs system partition instead of its top-level volume,
+    /// only if the system partition is formatted by the installer, see [`ROOT_SUBVOL`]
+    fn use_btrfs_subvolumes(&self) -> bool {
+        self.target_partition.format && self.target_partition.fs_type.as_deref() == Some("btrfs")
+    }
+
+    /// Subvolumes other than [`ROOT_SUBVOL`] to mount and their mount points
+    /// 数据分区挂载到相同位置时不再挂载对应的子卷
+    fn btrfs_subvolume_mounts(&self) -> Vec<(&'static str, &'static Path)> {
+        if !self.use_btrfs_subvolumes() {
+            return vec![];
+        }
+
+        SUBVOLUMES
+            .iter()
+            .map(|(subvol, mount_point)| (*subvol, Path::new(*mount_point)))
+            .filter(|(_, mount_point)| {
+                self.data_partition
+                    .as_ref()
+                    .map_or(true, |(_, layout)| layout.mount_point != *mount_point)
+            })
+            .collect()
+    }
+
+    /// Umount the data partition and the btrfs subvolumes mounted under the system partition
+    fn umount_data_paths(&self, tmp_mount_path: &Path) -> Result<(), UmountError> {
+        if let Some((_, ref layout)) = self.data_partition {
+            umount_root_path(&data_mount_path(tmp_mount_path, layout))?;
+        }
+
+        for (_, mount_point) in self.btrfs_subvolume_mounts().into_iter().rev() {
+            umount_root_path(
+                &tmp_mount_path.join(mount_point.strip_prefix("/").unwrap_or(mount_point)),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// 已有 swap 分区时不再创建 swapfile
+    fn swapfile(&self) -> &SwapFile {
+        if self.swap_partition.is_some() {
+            return &SwapFile::Disable;
+        }
+
+        &self.swapfile
+    }
+
+    fn verify_fstab(&self, tmp_mount_path: &Path) -> Result<(), SetupGenfstabError> {
+        let mut entries = vec![];
+
+        let root = self.root_partition();
+        if let Some(ref p) = root.path {
+            entries.push((p.as_path(), Path::new("/")));
+        }
+
+        if let Some(ref p) = self.efi_partition.as_ref().and_then(|x| x.path.as_ref()) {
+            entries.push((p.as_path(), Path::new("/efi")));
+        }
+
+        if let Some((ref data, ref layout)) = self.data_partition {
+            if let Some(ref p) = data.path {
+                entries.push((p.as_path(), layout.mount_point.as_path()));
+            }
+        }
+
+        if is_dev_mode() {
+            // 开发模式不会生成 fstab，此时安装的系统无法启动，需明确告知
+            if let Err(e) = verify_fstab(tmp_mount_path, &entries) {
+                warn!("Dev mode does not generate fstab, installed system will NOT boot: {e}");
+            }
+
+            return Ok(());
+        }
+
+        verify_fstab(tmp_mount_path, &entries)?;
+
+        Ok(())
+    }
+
+    fn mount_partitions(&self, tmp_mount_path: &Path) -> Result<bool, MountError> {
+        let root = self.root_partition();
+        let fs_type = root.fs_type.as_ref().context(ValueNotSetMountSnafu {
+            t: "system partition fstype",
+        })?;
+        let root_path = root.path.as_ref().context(ValueNotSetMountSnafu {
+            t: "system mount path",
         })?;
 
+        if self.use_btrfs_subvolumes() {
+            mount_btrfs_subvol(root_path, tmp_mount_path, ROOT_SUBVOL)
+                .context(MountRootSnafu { path: root_path })?;
+
+            for (subvol, mount_point) in self.btrfs_subvolume_mounts() {
+                let path =
+                    tmp_mount_path.join(mount_point.strip_prefix("/").unwrap_or(mount_point));
+                fs::create_dir_all(&path).context(CreateDirSnafu { path: path.clone() })?;
+
+                mount_btrfs_subvol(root_path, &path, subvol)
+                    .context(MountRootSnafu { path: root_path })?;
+            }
+        } else {
+            mount_root_path(Some(root_path), tmp_mount_path, fs_type)
+                .context(MountRootSnafu { path: root_path })?;
+        }
+
         if let Some(ref efi) = self.efi_partition {
             let efi_mount_path = tmp_mount_path.join("efi");
             fs::create_dir_all(&efi_mount_path).context(CreateDirSnafu {
@@ -962,20 +2216,98 @@ impl InstallConfig {
             })?;
         }
 
+        if let Some((ref data, ref layout)) = self.data_partition {
+            let data_mount_path = data_mount_path(tmp_mount_path, layout);
+            fs::create_dir_all(&data_mount_path).context(CreateDirSnafu {
+                path: data_mount_path.to_path_buf(),
+            })?;
+
+            mount_root_path(
+                data.path.as_deref(),
+                &data_mount_path,
+                data.fs_type.as_ref().context(ValueNotSetMountSnafu {
+                    t: "data partition fstype",
+                })?,
+            )
+            .context(MountRootSnafu {
+                path: data
+                    .path
+                    .as_ref()
+                    .context(ValueNotSetMountSnafu { t: "data path" })?,
+            })?;
+        }
+
         Ok(true)
     }
 
-    fn format_partitions(&self) -> Result<bool, PartitionError> {
-        format_partition(&self.target_partition)?;
+    fn format_partitions(
+        &self,
+        warnings: &Mutex<Vec<InstallWarning>>,
+    ) -> Result<bool, PartitionError> {
+        // 格式化正在使用的 swap 分区会失败，甚至破坏正在运行的 swap
+        let release_swap = |p: &DkPartition| -> Result<(), PartitionError> {
+            let path = match p.path {
+                Some(ref path) => path,
+                None => return Ok(()),
+            };
+
+            for swap in swapoff_active_swaps(path)? {
+                warnings
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(InstallWarning {
+                        stage: InstallationStage::SetupPartition.to_string(),
+                        message: format!("Turned off active swap {}", swap.display()),
+                    });
+            }
+
+            Ok(())
+        };
+
+        if self.target_partition.format {
+            release_swap(&self.target_partition)?;
+
+            if let Some(ref passphrase) = self.encrypt {
+                // 重试时映射设备可能仍处于打开状态，需先关闭才能重新格式化
+                if is_luks_open(LUKS_ROOT_NAME) {
+                    luks_close(LUKS_ROOT_NAME)?;
+                }
+
+                luks_format(&self.target_partition, passphrase)?;
+                luks_open(&self.target_partition, LUKS_ROOT_NAME, passphrase)?;
+            }
+
+            format_partition(&self.root_partition())?;
+        } else {
+            info!(
+                "Keeping the existing filesystem of system partition {:?}",
+                self.target_partition.path
+            );
+        }
 
         if let Some(ref efi) = self.efi_partition {
             let mut efi = efi.clone();
             if efi.fs_type.is_none() {
+                release_swap(&efi)?;
                 efi.fs_type = Some("vfat".to_string());
                 format_partition(&efi)?;
             }
         }
 
+        if let Some((data, layout)) = self.data_partition.as_ref().filter(|(p, _)| p.format) {
+            let mut data = data.clone();
+            release_swap(&data)?;
+            data.fs_type = Some(layout.fs_type.clone());
+            format_partition(&data)?;
+        }
+
+        if let Some(swap) = self.swap_partition.as_ref().filter(|p| p.format) {
+            let mut swap = swap.clone();
+            release_swap(&swap)?;
+            swap.fs_type = Some("swap".to_string());
+            format_partition(&swap)?;
+        }
+
         Ok(true)
     }
 
@@ -1020,6 +2352,16 @@ pub fn sync_and_reboot() -> io::Result<()> {
     Ok(())
 }
 
+fn data_mount_path(tmp_mount_path: &Path, layout: &DataLayout) -> PathBuf {
+    // mount_point 为绝对路径，直接 join 会覆盖 tmp_mount_path
+    tmp_mount_path.join(
+        layout
+            .mount_point
+            .strip_prefix("/")
+            .unwrap_or(&layout.mount_point),
+    )
+}
+
 pub fn umount_all(tmp_mount_path: &Path) {
     debug!(
         "Try to use umount -R {} to umount",
@@ -1053,3 +2395,303 @@ fn sysrq_reboot() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_configure_system_error_is_critical() {
+    let io_err = || io::Error::new(io::ErrorKind::Other, "test");
+
+    // 非关键步骤
+    assert!(!ConfigureSystemError::SetFullName {
+        source: SetFullNameError::BrokenPassswd,
+        fullname: "Foo".to_string(),
+    }
+    .is_critical());
+    assert!(!ConfigureSystemError::SetHwclock {
+        source: SetHwclockError::OperateAdjtimeFile { source: io_err() },
+        is_rtc: true,
+    }
+    .is_critical());
+    assert!(!ConfigureSystemError::SetLocale {
+        source: SetLocaleError::WriteLocaleConf { source: io_err() },
+        locale: "en_US.UTF-8".to_string(),
+    }
+    .is_critical());
+    assert!(!ConfigureSystemError::SetBranding { source: io_err() }.is_critical());
+
+    // 关键步骤
+    assert!(ConfigureSystemError::AddNewUser {
+        source: AddUserError::ChpasswdStdin,
+    }
+    .is_critical());
+    assert!(ConfigureSystemError::SetHostname {
+        source: SetHostnameError::InvalidHostname {
+            hostname: "-aosc".to_string(),
+        },
+        hostname: "-aosc".to_string(),
+    }
+    .is_critical());
+}
+
+#[test]
+fn test_install_plan() {
+    use InstallationStage::*;
+
+    let http = DownloadType::Http {
+        url: "https://example.com/a.squashfs".to_string(),
+        hash: "".to_string(),
+        checksum: ChecksumKind::Sha256,
+        to_path: None,
+        max_attempts: None,
+    };
+    let plan = InstallPlan::new(Some(&http));
+    assert_eq!(
+        plan.stages,
+        [
+            SetupPartition,
+            DownloadSquashfs,
+            ExtractSquashfs,
+            GenerateFstab,
+            Dracut,
+            InstallGrub,
+            GenerateSshKey,
+            ConfigureSystem
+        ]
+    );
+    assert_eq!(DownloadSquashfs.step(), 2);
+    assert_eq!(Chroot.step(), 4);
+    assert_eq!(UmountRootPath.step(), 8);
+    assert_eq!(Done.step(), 8);
+    assert_eq!(plan.index_of_step(DownloadSquashfs.step()), 1);
+    assert_eq!(plan.index_of_step(Done.step()), 7);
+
+    for download in [
+        DownloadType::File(PathBuf::from("/tmp/a.squashfs")),
+        DownloadType::Dir(PathBuf::from("/run/livekit/sysroot")),
+        DownloadType::Tarball(PathBuf::from("/tmp/a.tar.xz")),
+    ] {
+        let plan = InstallPlan::new(Some(&download));
+        assert!(!plan.stages.contains(&DownloadSquashfs));
+        assert_eq!(plan.stages.len(), 7);
+        // step 编号不变，跳过下载步骤只影响 plan 中的位置
+        assert_eq!(plan.index_of_step(SetupPartition.step()), 0);
+        assert_eq!(plan.index_of_step(ExtractSquashfs.step()), 1);
+        assert_eq!(plan.index_of_step(Done.step()), 6);
+    }
+
+    let plan = InstallPlan::new(Some(&http)).with_stop_after(Some(&ExtractSquashfs));
+    assert_eq!(
+        plan.stages,
+        [SetupPartition, DownloadSquashfs, ExtractSquashfs]
+    );
+    assert_eq!(plan.index_of_step(ExtractSquashfs.step()), 2);
+    assert_eq!(plan.weights(), [2, 35, 40, 0, 0, 0, 0, 0]);
+
+    let plan = InstallPlan::new(Some(&http)).with_stop_after(None);
+    assert_eq!(plan.stages.len(), 8);
+
+    assert!(ExtractSquashfs.can_stop_after());
+    assert!(GenerateFstab.can_stop_after());
+    assert!(!Chroot.can_stop_after());
+    assert!(!ConfigureSystem.can_stop_after());
+}
+
+#[test]
+fn test_swapfile_normalize() {
+    assert_eq!(SwapFile::Custom(0).normalize(), SwapFile::Disable);
+    assert_eq!(SwapFile::Custom(1024).normalize(), SwapFile::Custom(1024));
+    assert_eq!(SwapFile::Disable.normalize(), SwapFile::Disable);
+    assert_eq!(SwapFile::Automatic.normalize(), SwapFile::Automatic);
+}
+
+#[test]
+fn test_swapfile_creates_swapfile() {
+    assert!(!SwapFile::Disable.creates_swapfile());
+    assert!(!SwapFile::Custom(0).creates_swapfile());
+    assert!(SwapFile::Custom(1024).creates_swapfile());
+    assert!(SwapFile::Automatic.creates_swapfile());
+    assert!(SwapFile::Hibernate.creates_swapfile());
+    assert!(!SwapFile::Zram { size_mb: 4096 }.creates_swapfile());
+    assert!(!SwapFile::Partition(1024).creates_swapfile());
+}
+
+#[test]
+fn test_overall_progress() {
+    let weights = InstallPlan::new(None).weights();
+    assert_eq!(weights.iter().map(|x| *x as u32).sum::<u32>(), 100);
+
+    assert_eq!(overall_progress(&weights, 0, 0), 0);
+    assert_eq!(overall_progress(&weights, 1, 0), 0);
+    assert_eq!(overall_progress(&weights, 1, 100), 2);
+    assert_eq!(overall_progress(&weights, 2, 50), 19);
+    assert_eq!(overall_progress(&weights, 3, 0), 37);
+    assert_eq!(overall_progress(&weights, 8, 100), 100);
+
+    // 本地安装源不需要下载，解压占据的比例更大
+    let weights =
+        InstallPlan::new(Some(&DownloadType::File(PathBuf::from("/tmp/a.squashfs")))).weights();
+    assert_eq!(weights[1], 0);
+    assert_eq!(overall_progress(&weights, 3, 50), 33);
+    assert_eq!(overall_progress(&weights, 8, 100), 100);
+}
+
+#[cfg(test)]
+fn test_partition(part_number: u32, fs_type: &str, size: u64) -> DkPartition {
+    DkPartition {
+        path: Some(PathBuf::from(format!("/dev/sda{part_number}"))),
+        parent_path: Some(PathBuf::from("/dev/sda")),
+        fs_type: Some(fs_type.to_string()),
+        size,
+        format: true,
+        part_number: Some(part_number),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_install_config_prepare_round_trip() {
+    let config = InstallConfigPrepare {
+        hostname: Some("aosc".to_string()),
+        swapfile: SwapFile::Custom(1024),
+        ..Default::default()
+    };
+    *config.target_partition.lock().unwrap() =
+        Some(test_partition(2, "ext4", 10 * 1024 * 1024 * 1024));
+
+    let json = serde_json::to_string(&config).unwrap();
+    let config: InstallConfigPrepare = serde_json::from_str(&json).unwrap();
+
+    assert_eq!(config.hostname.as_deref(), Some("aosc"));
+    assert_eq!(config.swapfile, SwapFile::Custom(1024));
+    assert_eq!(
+        config
+            .target_partition
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|p| p.path.clone()),
+        Some(PathBuf::from("/dev/sda2"))
+    );
+    assert!(config.efi_partition.lock().unwrap().is_none());
+
+    // 缺少的项使用默认值
+    let config: InstallConfigPrepare = serde_json::from_str(r#"{"locale":"en_US.UTF-8"}"#).unwrap();
+    assert_eq!(config.locale.as_deref(), Some("en_US.UTF-8"));
+    assert_eq!(config.swapfile, SwapFile::Automatic);
+}
+
+#[test]
+fn test_install_config_try_from_user_on_first_boot() {
+    let prepare = || {
+        let config = InstallConfigPrepare {
+            locale: Some("en_US.UTF-8".to_string()),
+            timezone: Some("UTC".to_string()),
+            download: Some(DownloadType::Dir(PathBuf::from("/run/rootfs"))),
+            hostname: Some("aosc".to_string()),
+            ..Default::default()
+        };
+        *config.target_partition.lock().unwrap() =
+            Some(test_partition(2, "ext4", 10 * 1024 * 1024 * 1024));
+
+        config
+    };
+
+    let config = prepare();
+    assert_eq!(config.missing_values().len(), 1);
+    assert!(matches!(
+        InstallConfig::try_from(config),
+        Err(InstallErr::ValueNotSet {
+            v: NotSetValue::User
+        })
+    ));
+
+    let mut config = prepare();
+    config.user_on_first_boot = true;
+    config.extra_users = vec![User {
+        username: "alice".to_string(),
+        password: "a".to_string(),
+        root_password: None,
+        full_name: None,
+        admin: true,
+        shell: None,
+        uid: None,
+    }];
+    assert!(config.missing_values().is_empty());
+    // 首次启动创建账户时不能同时预设其他用户
+    assert!(matches!(
+        InstallConfig::try_from(config),
+        Err(InstallErr::ExtraUsersOnFirstBoot)
+    ));
+
+    let mut config = prepare();
+    config.user_on_first_boot = true;
+    let config = InstallConfig::try_from(config).unwrap();
+    assert!(config.user.is_none());
+    assert!(config.extra_users.is_empty());
+}
+
+#[test]
+fn test_install_config_swap_partition_not_set() {
+    let config = InstallConfigPrepare {
+        swapfile: SwapFile::Partition(1024 * 1024 * 1024),
+        ..Default::default()
+    };
+    assert!(config
+        .missing_values()
+        .contains(&NotSetValue::SwapPartition));
+
+    *config.swap_partition.lock().unwrap() = Some(test_partition(3, "swap", 1024 * 1024 * 1024));
+    assert!(!config
+        .missing_values()
+        .contains(&NotSetValue::SwapPartition));
+
+    // 其他交换方式不需要交换分区
+    let config = InstallConfigPrepare::default();
+    assert!(!config
+        .missing_values()
+        .contains(&NotSetValue::SwapPartition));
+}
+
+#[test]
+fn test_simulate_install() {
+    let prepare = InstallConfigPrepare {
+        locale: Some("en_US.UTF-8".to_string()),
+        timezone: Some("UTC".to_string()),
+        hostname: Some("aosc".to_string()),
+        user_on_first_boot: true,
+        dry_run: true,
+        ..Default::default()
+    };
+    // 模拟安装不需要目标分区与安装源
+    assert!(prepare.missing_values().is_empty());
+    let config = InstallConfig::try_from(prepare).unwrap();
+
+    let step = Arc::new(AtomicU8::new(0));
+    let progress = Arc::new(AtomicU8::new(0));
+    let run = |cancel: bool| {
+        config.start_install(
+            step.clone(),
+            progress.clone(),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(PathBuf::from("/nonexistent")),
+            Arc::new(AtomicBool::new(cancel)),
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(Mutex::new(vec![])),
+            Arc::new(Mutex::new(None)),
+        )
+    };
+
+    assert!(matches!(run(false), Ok(true)));
+    assert_eq!(
+        step.load(Ordering::SeqCst),
+        InstallationStage::ConfigureSystem.step()
+    );
+    assert_eq!(progress.load(Ordering::SeqCst), 100);
+
+    step.store(0, Ordering::SeqCst);
+    assert!(matches!(run(true), Ok(false)));
+    assert_eq!(
+        step.load(Ordering::SeqCst),
+        InstallationStage::SetupPartition.step()
+    );
+}
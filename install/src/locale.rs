@@ -1,6 +1,8 @@
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{self, BufRead, BufReader, Write},
+    path::Path,
+    process::Command,
 };
 
 use snafu::{ResultExt, Snafu};
@@ -16,16 +18,152 @@ pub enum SetHwclockError {
     RunCommand { source: RunCmdError },
 }
 
-/// Sets locale in the guest environment
+#[derive(Debug, Snafu)]
+pub enum SetLocaleError {
+    #[snafu(display("Failed to write /etc/locale.conf"))]
+    WriteLocaleConf { source: std::io::Error },
+    #[snafu(display("Failed to operate /etc/locale.gen"))]
+    OperateLocaleGen { source: std::io::Error },
+    #[snafu(transparent)]
+    RunCommand { source: RunCmdError },
+}
+
+/// Whether `locale` looks like a locale name, e.g. `en_US.UTF-8` or `sr_RS.UTF-8@latin`
+pub fn is_valid_locale_name(locale: &str) -> bool {
+    !locale.is_empty()
+        && locale
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '@'))
+}
+
+// glibc 内置的 locale，无需生成
+const BUILTIN_LOCALES: &[&str] = &["C", "C.UTF-8", "C.utf8", "POSIX"];
+
+/// Sets locale in the guest environment, `locale` and `extra_locales` are generated by locale-gen
 /// Must be used in a chroot context
-pub(crate) fn set_locale(locale: &str) -> Result<(), io::Error> {
-    let mut f = File::create("/etc/locale.conf")?;
-    f.write_all(b"LANG=")?;
-    f.write_all(format!("{locale}\n").as_bytes())?;
+pub(crate) fn set_locale(locale: &str, extra_locales: &[String]) -> Result<(), SetLocaleError> {
+    let mut f = File::create("/etc/locale.conf").context(WriteLocaleConfSnafu)?;
+    f.write_all(b"LANG=").context(WriteLocaleConfSnafu)?;
+    f.write_all(format!("{locale}\n").as_bytes())
+        .context(WriteLocaleConfSnafu)?;
+
+    let locales = std::iter::once(locale)
+        .chain(extra_locales.iter().map(|x| x.as_str()))
+        .collect::<Vec<_>>();
+
+    if enable_locales_in(Path::new("/"), &locales)? {
+        info!("Running locale-gen ...");
+        run_command(
+            "locale-gen",
+            &[] as &[&str],
+            vec![] as Vec<(String, String)>,
+        )?;
+    }
 
     Ok(())
 }
 
+/// Uncomment `locales` in /etc/locale.gen, returns `false` if the system has no /etc/locale.gen
+/// and ships its locales prebuilt
+fn enable_locales_in(root: &Path, locales: &[&str]) -> Result<bool, SetLocaleError> {
+    let path = root.join("etc/locale.gen");
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(SetLocaleError::OperateLocaleGen { source: e }),
+    };
+
+    fs::write(&path, locale_gen_content(&content, locales)).context(OperateLocaleGenSnafu)?;
+
+    Ok(true)
+}
+
+/// Uncomment the entries of `locales`, entries not in the file are appended
+fn locale_gen_content(content: &str, locales: &[&str]) -> String {
+    let locales = locales
+        .iter()
+        .filter(|x| !BUILTIN_LOCALES.contains(x))
+        .collect::<Vec<_>>();
+
+    let mut found = vec![];
+    let mut res = String::new();
+
+    for line in content.lines() {
+        let entry = line
+            .trim_start_matches(|c: char| c == '#' || c.is_whitespace())
+            .trim_end();
+
+        let name = entry.split_whitespace().next().unwrap_or_default();
+        // 形如 `en_US.UTF-8 UTF-8` 的条目恰有两列
+        let is_entry = entry.split_whitespace().count() == 2;
+
+        if is_entry && locales.contains(&&name) {
+            found.push(name.to_string());
+            res.push_str(entry);
+        } else {
+            res.push_str(line);
+        }
+
+        res.push('\n');
+    }
+
+    for locale in locales {
+        if found.iter().any(|x| x == *locale) {
+            continue;
+        }
+
+        // en_US.UTF-8 的字符集为 UTF-8，无后缀时按 glibc 默认使用 ISO-8859-1
+        let charset = locale
+            .split_once('.')
+            .map(|(_, charset)| charset.split('@').next().unwrap_or(charset))
+            .unwrap_or("ISO-8859-1");
+
+        res.push_str(&format!("{locale} {charset}\n"));
+        found.push(locale.to_string());
+    }
+
+    res
+}
+
+const SUPPORTED_LOCALES_PATH: &str = "/usr/share/i18n/SUPPORTED";
+
+/// Locales of the running system which can be set as `locale`, e.g. `en_US.UTF-8`
+/// Falls back to `locale -a` if /usr/share/i18n/SUPPORTED does not exist
+pub fn list_locales() -> io::Result<Vec<String>> {
+    match fs::read_to_string(SUPPORTED_LOCALES_PATH) {
+        Ok(content) => Ok(parse_supported_locales(&content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let output = Command::new("locale").arg("-a").output()?;
+
+            Ok(parse_supported_locales(&String::from_utf8_lossy(
+                &output.stdout,
+            )))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Parse lines like `en_US.UTF-8 UTF-8`, the glibc source tree uses `en_US.UTF-8/UTF-8 \`
+fn parse_supported_locales(content: &str) -> Vec<String> {
+    let mut res = vec![];
+
+    for line in content.lines() {
+        let name = match line.split_whitespace().next() {
+            Some(name) => name.split('/').next().unwrap_or(name),
+            None => continue,
+        };
+
+        if name.starts_with('#') || name.contains('=') || res.iter().any(|x| x == name) {
+            continue;
+        }
+
+        res.push(name.to_string());
+    }
+
+    res
+}
+
 /// Sets utc/rtc time in the guest environment
 /// Must be used in a chroot context
 pub(crate) fn set_hwclock_tc(utc: bool) -> Result<(), SetHwclockError> {
@@ -60,3 +198,60 @@ pub(crate) fn set_hwclock_tc(utc: bool) -> Result<(), SetHwclockError> {
 
     Ok(())
 }
+
+#[test]
+fn test_parse_supported_locales() {
+    assert_eq!(
+        parse_supported_locales("en_US.UTF-8 UTF-8\nen_US ISO-8859-1\nzh_CN.UTF-8 UTF-8\n\n"),
+        ["en_US.UTF-8", "en_US", "zh_CN.UTF-8"]
+    );
+    assert_eq!(
+        parse_supported_locales(
+            "# See localedata/README\nSUPPORTED-LOCALES=\\\nC.UTF-8/UTF-8 \\\naa_DJ.UTF-8/UTF-8 \\\n"
+        ),
+        ["C.UTF-8", "aa_DJ.UTF-8"]
+    );
+    assert_eq!(
+        parse_supported_locales("C\nC.utf8\nPOSIX\nC\n"),
+        ["C", "C.utf8", "POSIX"]
+    );
+}
+
+#[test]
+fn test_is_valid_locale_name() {
+    assert!(is_valid_locale_name("en_US.UTF-8"));
+    assert!(is_valid_locale_name("sr_RS.UTF-8@latin"));
+    assert!(!is_valid_locale_name(""));
+    assert!(!is_valid_locale_name("en_US.UTF-8 UTF-8"));
+    assert!(!is_valid_locale_name("en_US\nLC_ALL=C"));
+}
+
+#[test]
+fn test_locale_gen_content() {
+    let content = "# Configuration file for locale-gen\n#\n#en_US ISO-8859-1\n#en_US.UTF-8 UTF-8\n#  zh_CN.UTF-8 UTF-8\nde_DE.UTF-8 UTF-8\n";
+
+    assert_eq!(
+        locale_gen_content(content, &["en_US.UTF-8", "zh_CN.UTF-8", "C.UTF-8"]),
+        "# Configuration file for locale-gen\n#\n#en_US ISO-8859-1\nen_US.UTF-8 UTF-8\nzh_CN.UTF-8 UTF-8\nde_DE.UTF-8 UTF-8\n"
+    );
+    assert_eq!(
+        locale_gen_content("", &["ja_JP.UTF-8", "sr_RS.UTF-8@latin", "fr_FR", "fr_FR"]),
+        "ja_JP.UTF-8 UTF-8\nsr_RS.UTF-8@latin UTF-8\nfr_FR ISO-8859-1\n"
+    );
+}
+
+#[test]
+fn test_enable_locales_in() {
+    let root = std::env::temp_dir().join(format!("dk-locale-gen-{}", std::process::id()));
+    fs::create_dir_all(root.join("etc")).unwrap();
+
+    // 没有 /etc/locale.gen 时不运行 locale-gen
+    assert!(!enable_locales_in(&root, &["en_US.UTF-8"]).unwrap());
+
+    fs::write(root.join("etc/locale.gen"), "#en_US.UTF-8 UTF-8\n").unwrap();
+    assert!(enable_locales_in(&root, &["en_US.UTF-8"]).unwrap());
+    let content = fs::read_to_string(root.join("etc/locale.gen")).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(content, "en_US.UTF-8 UTF-8\n");
+}
@@ -0,0 +1,217 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use tracing::{info, warn};
+
+use crate::utils::{run_command, RunCmdError};
+
+const DPKG_STATUS_PATH: &str = "/var/lib/dpkg/status";
+const APPLICATIONS_DIR: &str = "/usr/share/applications";
+const AUTOSTART_DIR: &str = "/etc/xdg/autostart";
+
+/// Packages (and autostart entries) needed by a locale, e.g. fonts and input methods for CJK
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct LocaleExtra {
+    pub packages: Vec<String>,
+    /// Desktop entries under /usr/share/applications to start on login
+    pub autostart: Vec<String>,
+}
+
+#[derive(Debug, Snafu)]
+pub enum LocaleExtrasError {
+    #[snafu(display("Failed to read dpkg status"))]
+    ReadDpkgStatus { source: std::io::Error },
+    #[snafu(display("Failed to install locale packages"))]
+    InstallPackages { source: RunCmdError },
+    #[snafu(display("Failed to enable autostart entry {}", path.display()))]
+    Autostart {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+/// Default locale -> extras table, keys are `language` or `language_TERRITORY`
+pub fn default_locale_extras() -> BTreeMap<String, LocaleExtra> {
+    let fcitx5 = |addon: &str| LocaleExtra {
+        packages: vec![
+            "fcitx5".to_string(),
+            addon.to_string(),
+            "noto-cjk-fonts".to_string(),
+        ],
+        autostart: vec!["org.fcitx.Fcitx5.desktop".to_string()],
+    };
+
+    BTreeMap::from([
+        ("zh".to_string(), fcitx5("fcitx5-chinese-addons")),
+        ("ja".to_string(), fcitx5("fcitx5-anthy")),
+        ("ko".to_string(), fcitx5("fcitx5-hangul")),
+    ])
+}
+
+/// Find the extras of `locale` (e.g. `zh_CN.UTF-8`), the most specific key wins
+pub fn extras_for_locale<'a>(
+    table: &'a BTreeMap<String, LocaleExtra>,
+    locale: &str,
+) -> Option<&'a LocaleExtra> {
+    // zh_CN.UTF-8@modifier -> zh_CN -> zh
+    let name = locale.split(['.', '@']).next().unwrap_or(locale);
+    let lang = name.split('_').next().unwrap_or(name);
+
+    table.get(name).or_else(|| table.get(lang))
+}
+
+/// Names of installed packages in the content of dpkg status
+fn installed_packages(status: &str) -> HashSet<&str> {
+    let mut res = HashSet::new();
+
+    for stanza in status.split("\n\n") {
+        let mut name = None;
+        let mut installed = false;
+
+        for line in stanza.lines() {
+            if let Some(v) = line.strip_prefix("Package:") {
+                name = Some(v.trim());
+            } else if let Some(v) = line.strip_prefix("Status:") {
+                // Status: want flag status，只有最后一项为 installed 时才算已安装
+                installed = v.split_whitespace().last() == Some("installed");
+            }
+        }
+
+        if let (Some(name), true) = (name, installed) {
+            res.insert(name);
+        }
+    }
+
+    res
+}
+
+/// Packages of `extra` absent in the content of dpkg status
+fn missing_packages<'a>(status: &str, extra: &'a LocaleExtra) -> Vec<&'a str> {
+    let installed = installed_packages(status);
+
+    extra
+        .packages
+        .iter()
+        .map(|x| x.as_str())
+        .filter(|x| !installed.contains(x))
+        .collect()
+}
+
+/// Install the missing locale packages and enable autostart entries
+/// Must be used in a chroot context
+pub(crate) fn install_locale_extras(
+    table: &BTreeMap<String, LocaleExtra>,
+    locale: &str,
+) -> Result<(), LocaleExtrasError> {
+    let extra = match extras_for_locale(table, locale) {
+        Some(extra) => extra,
+        None => {
+            info!("No extra packages for locale {locale}");
+            return Ok(());
+        }
+    };
+
+    let status = fs::read_to_string(DPKG_STATUS_PATH).context(ReadDpkgStatusSnafu)?;
+    let missing = missing_packages(&status, extra);
+
+    if !missing.is_empty() {
+        info!("Installing locale packages: {missing:?}");
+
+        let mut args = vec!["install", "--yes", "--no-check-dbus"];
+        args.extend(missing);
+
+        run_command("oma", args, vec![("LANG", locale)]).context(InstallPackagesSnafu)?;
+    }
+
+    for name in &extra.autostart {
+        let src = Path::new(APPLICATIONS_DIR).join(name);
+        let dst = Path::new(AUTOSTART_DIR).join(name);
+
+        if !src.exists() {
+            warn!("Desktop entry {} does not exist, skipping", src.display());
+            continue;
+        }
+
+        if fs::symlink_metadata(&dst).is_ok() {
+            continue;
+        }
+
+        fs::create_dir_all(AUTOSTART_DIR)
+            .and_then(|_| symlink(&src, &dst))
+            .context(AutostartSnafu { path: dst })?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_extras_for_locale() {
+    let mut table = default_locale_extras();
+
+    assert_eq!(
+        extras_for_locale(&table, "zh_CN.UTF-8").map(|x| x.packages[1].as_str()),
+        Some("fcitx5-chinese-addons")
+    );
+    assert_eq!(
+        extras_for_locale(&table, "ja_JP.UTF-8").map(|x| x.packages[1].as_str()),
+        Some("fcitx5-anthy")
+    );
+    assert_eq!(
+        extras_for_locale(&table, "ko_KR").map(|x| x.packages[1].as_str()),
+        Some("fcitx5-hangul")
+    );
+    assert!(extras_for_locale(&table, "en_US.UTF-8").is_none());
+    assert!(extras_for_locale(&table, "C.UTF-8").is_none());
+
+    // 更具体的地区优先
+    table.insert(
+        "zh_TW".to_string(),
+        LocaleExtra {
+            packages: vec!["fcitx5-chewing".to_string()],
+            autostart: vec![],
+        },
+    );
+    assert_eq!(
+        extras_for_locale(&table, "zh_TW.UTF-8").map(|x| x.packages.clone()),
+        Some(vec!["fcitx5-chewing".to_string()])
+    );
+    assert_eq!(
+        extras_for_locale(&table, "zh_HK.UTF-8@stroke").map(|x| x.packages[1].as_str()),
+        Some("fcitx5-chinese-addons")
+    );
+}
+
+#[test]
+fn test_missing_packages() {
+    let status = "Package: fcitx5\n\
+                  Status: install ok installed\n\
+                  Version: 5.1.10\n\
+                  \n\
+                  Package: noto-cjk-fonts\n\
+                  Status: deinstall ok config-files\n\
+                  \n\
+                  Package: fcitx5-anthy\n\
+                  Status: install ok half-installed\n";
+
+    let installed = installed_packages(status);
+    assert!(installed.contains("fcitx5"));
+    assert!(!installed.contains("noto-cjk-fonts"));
+    assert!(!installed.contains("fcitx5-anthy"));
+
+    let table = default_locale_extras();
+    assert_eq!(
+        missing_packages(status, extras_for_locale(&table, "zh_CN").unwrap()),
+        vec!["fcitx5-chinese-addons", "noto-cjk-fonts"]
+    );
+    assert_eq!(
+        missing_packages("", extras_for_locale(&table, "ko_KR").unwrap()),
+        vec!["fcitx5", "fcitx5-hangul", "noto-cjk-fonts"]
+    );
+}
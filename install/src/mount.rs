@@ -22,6 +22,25 @@ pub struct UmountError {
     pub point: String,
 }
 
+/// Mount the filesystem read-only at `target` to check that it is usable, then umount it
+/// Returns whether the filesystem is empty (ignoring lost+found)
+pub(crate) fn check_mountable(partition: &Path, target: &Path, fs_type: &str) -> io::Result<bool> {
+    let fs_type = if fs_type.starts_with("fat") {
+        "vfat"
+    } else {
+        fs_type
+    };
+
+    mount_inner(Some(partition), target, Some(fs_type), MountFlags::RDONLY)?;
+
+    let is_empty = std::fs::read_dir(target)
+        .map(|dir| dir.flatten().all(|entry| entry.file_name() == "lost+found"));
+
+    mount::unmount(target, mount::UnmountFlags::empty())?;
+
+    is_empty
+}
+
 /// Mount the filesystem
 pub(crate) fn mount_root_path(
     partition: Option<&Path>,
@@ -38,6 +57,21 @@ pub(crate) fn mount_root_path(
     Ok(())
 }
 
+/// Mount a subvolume of the btrfs filesystem
+pub(crate) fn mount_btrfs_subvol(
+    partition: &Path,
+    target: &Path,
+    subvol: &str,
+) -> Result<(), Errno> {
+    mount::mount(
+        partition,
+        target,
+        "btrfs",
+        MountFlags::empty(),
+        format!("subvol={subvol}").as_str(),
+    )
+}
+
 fn mount_inner<P: AsRef<Path>>(
     partition: Option<P>,
     target: &Path,
@@ -68,6 +102,14 @@ pub fn sync_disk() {
     rustix::fs::sync();
 }
 
+/// Sync the filesystem mounted at `path`
+pub fn syncfs_path(path: &Path) -> io::Result<()> {
+    let f = std::fs::File::open(path)?;
+    rustix::fs::syncfs(&f)?;
+
+    Ok(())
+}
+
 #[derive(Debug, Snafu)]
 pub enum MountInnerError {
     #[snafu(display("failed to mount {point}"))]
@@ -80,8 +122,18 @@ pub enum MountInnerError {
     CreateDir { dir: PathBuf, source: io::Error },
 }
 
+/// Create the mount point if the extracted system does not have it
+/// 精简的基础系统中可能缺少 dev/pts 等目录
+fn create_mount_point(dir: &Path) -> Result<(), MountInnerError> {
+    create_dir_all(dir).context(CreateDirSnafu {
+        dir: dir.to_path_buf(),
+    })
+}
+
 /// Setup all the necessary bind mounts
+/// 挂载点目录在其父目录挂载完成后再创建，否则会被挂载覆盖
 pub fn setup_files_mounts(root: &Path) -> Result<(), MountInnerError> {
+    create_mount_point(&root.join("proc"))?;
     mount_inner(
         Some("proc"),
         &root.join("proc"),
@@ -93,6 +145,7 @@ pub fn setup_files_mounts(root: &Path) -> Result<(), MountInnerError> {
         umount: false,
     })?;
 
+    create_mount_point(&root.join("sys"))?;
     mount_inner(
         Some("sys"),
         &root.join("sys"),
@@ -105,6 +158,7 @@ pub fn setup_files_mounts(root: &Path) -> Result<(), MountInnerError> {
     })?;
 
     if is_efi_booted() && !cfg!(target_arch = "mips64") {
+        create_mount_point(&root.join(EFIVARS_PATH))?;
         mount_inner(
             Some("efivarfs"),
             &root.join(EFIVARS_PATH),
@@ -117,6 +171,7 @@ pub fn setup_files_mounts(root: &Path) -> Result<(), MountInnerError> {
         })?;
     }
 
+    create_mount_point(&root.join("dev"))?;
     mount_inner(
         Some("udev"),
         &root.join("dev"),
@@ -128,6 +183,7 @@ pub fn setup_files_mounts(root: &Path) -> Result<(), MountInnerError> {
         umount: false,
     })?;
 
+    create_mount_point(&root.join("dev").join("pts"))?;
     mount_inner(
         Some("devpts"),
         &root.join("dev").join("pts"),
@@ -139,6 +195,7 @@ pub fn setup_files_mounts(root: &Path) -> Result<(), MountInnerError> {
         umount: false,
     })?;
 
+    create_mount_point(&root.join("dev").join("shm"))?;
     mount_inner(
         Some("shm"),
         &root.join("dev").join("shm"),
@@ -151,9 +208,7 @@ pub fn setup_files_mounts(root: &Path) -> Result<(), MountInnerError> {
     })?;
 
     let run_dev = root.join("run").join("udev");
-    create_dir_all(&run_dev).context(CreateDirSnafu {
-        dir: run_dev.to_path_buf(),
-    })?;
+    create_mount_point(&run_dev)?;
 
     mount_inner(Some("/run/udev"), &run_dev, Some("tmpfs"), MountFlags::BIND).context(
         MountInnerSnafu {
@@ -189,14 +244,11 @@ pub fn remove_files_mounts(system_path: &Path) -> Result<(), UmountError> {
         let mount_point = system_path.join(i);
 
         debug!("umounting point {}", mount_point.display());
-        run_command(
-            "umount",
-            [&mount_point.to_string_lossy().to_string()],
-            vec![] as Vec<(String, String)>,
-        )
-        .context(UmountSnafu {
-            point: mount_point.display().to_string(),
-        })?;
+        run_command("umount", [&mount_point], vec![] as Vec<(String, String)>).context(
+            UmountSnafu {
+                point: mount_point.display().to_string(),
+            },
+        )?;
     }
 
     Ok(())
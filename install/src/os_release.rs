@@ -0,0 +1,140 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use snafu::Snafu;
+
+// 基础 os-release 中的必要字段，品牌定制不能覆盖
+const PROTECTED_KEYS: &[&str] = &[
+    "NAME",
+    "ID",
+    "ID_LIKE",
+    "VERSION",
+    "VERSION_ID",
+    "VERSION_CODENAME",
+    "PRETTY_NAME",
+];
+
+#[derive(Debug, Snafu)]
+pub enum BrandingError {
+    #[snafu(display("Invalid os-release key: {key}"))]
+    InvalidKey { key: String },
+    #[snafu(display("os-release key {key} can not be overridden"))]
+    ProtectedKey { key: String },
+    #[snafu(display("Value of os-release key {key} contains control characters"))]
+    InvalidValue { key: String },
+}
+
+/// Check that branding entries are valid os-release assignments
+pub fn check_branding(branding: &BTreeMap<String, String>) -> Result<(), BrandingError> {
+    for (key, value) in branding {
+        let mut chars = key.chars();
+        let is_valid_key = chars.next().is_some_and(|c| c.is_ascii_uppercase())
+            && chars.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+
+        if !is_valid_key {
+            return Err(BrandingError::InvalidKey { key: key.clone() });
+        }
+
+        if PROTECTED_KEYS.contains(&key.as_str()) {
+            return Err(BrandingError::ProtectedKey { key: key.clone() });
+        }
+
+        // 换行会破坏 os-release 格式
+        if value.chars().any(|c| c.is_control()) {
+            return Err(BrandingError::InvalidValue { key: key.clone() });
+        }
+    }
+
+    Ok(())
+}
+
+/// Append branding entries to /etc/os-release, existing entries with the same key are replaced
+/// Must be used in a chroot context
+pub fn set_os_release_branding(branding: &BTreeMap<String, String>) -> io::Result<()> {
+    if branding.is_empty() {
+        return Ok(());
+    }
+
+    check_branding(branding).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+    let path = Path::new("/etc/os-release");
+    let base = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == ErrorKind::NotFound => fs::read_to_string("/usr/lib/os-release")?,
+        Err(e) => return Err(e),
+    };
+
+    // /etc/os-release 可能是指向 /usr/lib/os-release 的符号链接，不修改链接目标
+    if path.is_symlink() {
+        fs::remove_file(path)?;
+    }
+
+    fs::write(path, merge_os_release(&base, branding))?;
+
+    Ok(())
+}
+
+fn merge_os_release(base: &str, branding: &BTreeMap<String, String>) -> String {
+    let mut res = String::new();
+
+    for line in base.lines() {
+        let key = line.split_once('=').map(|(k, _)| k.trim());
+        if key.is_some_and(|k| branding.contains_key(k)) {
+            continue;
+        }
+
+        res.push_str(line);
+        res.push('\n');
+    }
+
+    for (key, value) in branding {
+        res.push_str(&format!("{key}=\"{}\"\n", escape_value(value)));
+    }
+
+    res
+}
+
+fn escape_value(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '$' | '`') {
+            res.push('\\');
+        }
+
+        res.push(c);
+    }
+
+    res
+}
+
+#[test]
+fn test_check_branding() {
+    let branding = |key: &str, value: &str| BTreeMap::from([(key.to_string(), value.to_string())]);
+
+    assert!(check_branding(&branding("VARIANT", "OEM Edition")).is_ok());
+    assert!(check_branding(&branding("SUPPORT_URL", "https://example.com")).is_ok());
+    assert!(check_branding(&branding("variant", "OEM")).is_err());
+    assert!(check_branding(&branding("1VARIANT", "OEM")).is_err());
+    assert!(check_branding(&branding("", "OEM")).is_err());
+    assert!(check_branding(&branding("ID", "oem")).is_err());
+    assert!(check_branding(&branding("VARIANT", "OEM\nID=oem")).is_err());
+}
+
+#[test]
+fn test_merge_os_release() {
+    let base = "NAME=\"AOSC OS\"\nID=aosc\nVARIANT=\"Desktop\"\n";
+    let branding = BTreeMap::from([
+        ("VARIANT".to_string(), "OEM \"Edition\"".to_string()),
+        ("LOGO".to_string(), "oem-logo".to_string()),
+    ]);
+
+    assert_eq!(
+        merge_os_release(base, &branding),
+        "NAME=\"AOSC OS\"\nID=aosc\nLOGO=\"oem-logo\"\nVARIANT=\"OEM \\\"Edition\\\"\"\n"
+    );
+}
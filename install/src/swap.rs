@@ -1,13 +1,18 @@
 use std::{
     fs::File,
-    io::{self},
+    io::{self, Write},
     os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
+    process::Command,
 };
 
-use rustix::{fd::AsRawFd, fs::FallocateFlags};
+use rustix::{
+    fd::AsRawFd,
+    fs::{fallocate, FallocateFlags},
+    io::Errno,
+};
 use snafu::{ResultExt, Snafu};
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::utils::{run_command, RunCmdError};
 
@@ -37,6 +42,11 @@ pub enum SwapFileError {
     },
     #[snafu(display("Failed to run mkswap {}", path.display()))]
     Mkswap { path: PathBuf, source: RunCmdError },
+    #[snafu(display(
+        "Failed to disable copy-on-write of swap file {}, swap files on btrfs require Linux 5.0 or later",
+        path.display()
+    ))]
+    BtrfsNocow { path: PathBuf, source: RunCmdError },
 }
 
 pub fn get_recommend_swap_size(mem: u64) -> f64 {
@@ -55,30 +65,172 @@ pub fn get_recommend_swap_size(mem: u64) -> f64 {
     }
 }
 
-/// Create swapfile
-pub(crate) fn create_swapfile(size: f64, tempdir: &Path) -> Result<(), SwapFileError> {
+/// Swap size for hibernation, the size of RAM plus a margin for the rest of the swapped out pages
+/// Unlike [`get_recommend_swap_size`], it is not capped at 32GiB, as the whole RAM must fit
+pub fn get_hibernate_swap_size(mem: u64) -> f64 {
+    let mem: f64 = mem as f64 / 1024.0 / 1024.0 / 1024.0;
+
+    let margin = if mem <= 1.0 { mem } else { mem.sqrt().round() };
+
+    (mem + margin) * 1024.0_f32.powi(3) as f64
+}
+
+/// _IOWR('f', 11, struct fiemap), see linux/fs.h
+const FS_IOC_FIEMAP: u64 = 0xC020660B;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+
+#[repr(C)]
+#[derive(Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapExtent; 1],
+}
+
+/// Physical offset of the swapfile in pages, used by the `resume_offset=` kernel parameter
+/// `fs_type` is the filesystem of the system partition
+pub(crate) fn swapfile_resume_offset(path: &Path, fs_type: Option<&str>) -> io::Result<u64> {
+    // btrfs 的 fiemap 返回的是逻辑地址而非物理地址，需由 btrfs-progs 计算
+    if fs_type == Some("btrfs") {
+        return btrfs_swapfile_resume_offset(path);
+    }
+
+    let f = File::open(path)?;
+
+    let mut fiemap = Fiemap {
+        fm_length: u64::MAX,
+        fm_flags: FIEMAP_FLAG_SYNC,
+        fm_extent_count: 1,
+        ..Default::default()
+    };
+
+    // SAFETY: fiemap 的布局与内核的 struct fiemap 一致，且只能容纳一个 extent
+    let ret = unsafe { libc::ioctl(f.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fiemap) };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if fiemap.fm_mapped_extents == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} has no extent", path.display()),
+        ));
+    }
+
+    // SAFETY: sysconf 没有副作用
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+
+    Ok(fiemap.fm_extents[0].fe_physical / page_size as u64)
+}
+
+fn btrfs_swapfile_resume_offset(path: &Path) -> io::Result<u64> {
+    let output = Command::new("btrfs")
+        .args(["inspect-internal", "map-swapfile", "-r"])
+        .arg(path)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "btrfs inspect-internal map-swapfile failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+
+    parse_map_swapfile_offset(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to get resume offset of {}", path.display()),
+        )
+    })
+}
+
+/// `btrfs inspect-internal map-swapfile -r` prints only the resume offset
+fn parse_map_swapfile_offset(output: &str) -> Option<u64> {
+    output.trim().parse().ok()
+}
+
+/// Create swapfile, `fs_type` is the filesystem of the system partition
+pub(crate) fn create_swapfile(
+    size: f64,
+    tempdir: &Path,
+    fs_type: Option<&str>,
+) -> Result<(), SwapFileError> {
     let swap_path = tempdir.join("swapfile");
+    let is_btrfs = fs_type == Some("btrfs");
+
+    // btrfs-progs 6.1 起可直接创建可用的 swapfile
+    if is_btrfs {
+        info!("Creating swapfile with btrfs filesystem mkswapfile");
+        match run_command(
+            "btrfs",
+            [
+                "filesystem".to_string(),
+                "mkswapfile".to_string(),
+                "--size".to_string(),
+                (size as u64).to_string(),
+                swap_path.display().to_string(),
+            ],
+            vec![] as Vec<(String, String)>,
+        ) {
+            Ok(_) => {
+                run_command("swapon", [swap_path], vec![] as Vec<(String, String)>).ok();
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("btrfs filesystem mkswapfile failed: {e}, falling back to chattr +C");
+                std::fs::remove_file(&swap_path).ok();
+            }
+        }
+    }
 
     info!("Creating swapfile");
     let swapfile = File::create(&swap_path).context(CreateFileSnafu {
         path: swap_path.to_path_buf(),
     })?;
 
-    let res = unsafe {
-        libc::fallocate64(
-            swapfile.as_raw_fd(),
-            FallocateFlags::empty().bits() as i32,
-            0,
-            size as i64,
+    // btrfs 上的 swapfile 不能写时复制或压缩，且须在文件为空时设置
+    if is_btrfs {
+        run_command(
+            "chattr",
+            ["+C".to_string(), swap_path.display().to_string()],
+            vec![] as Vec<(String, String)>,
         )
+        .context(BtrfsNocowSnafu {
+            path: swap_path.clone(),
+        })?;
+    }
+
+    let res = match fallocate(&swapfile, FallocateFlags::empty(), 0, size as u64) {
+        // 不支持 fallocate 的文件系统（如 vfat）只能写入零
+        Err(Errno::OPNOTSUPP) => {
+            info!("fallocate is not supported, filling swapfile with zeros");
+            fill_zeros(&swapfile, size as u64)
+        }
+        res => res.map_err(io::Error::from),
     };
 
-    if res != 0 {
-        return Err(SwapFileError::Fallocate {
-            path: swap_path.to_path_buf(),
-            source: io::Error::from_raw_os_error(res),
-        });
-    }
+    res.context(FallocateSnafu {
+        path: swap_path.to_path_buf(),
+    })?;
 
     swapfile.sync_all().context(FlushSwapFileSnafu {
         path: swap_path.to_path_buf(),
@@ -99,6 +251,19 @@ pub(crate) fn create_swapfile(size: f64, tempdir: &Path) -> Result<(), SwapFileE
     Ok(())
 }
 
+fn fill_zeros(mut f: &File, size: u64) -> io::Result<()> {
+    let buf = vec![0; 1024 * 1024];
+    let mut written = 0;
+
+    while written < size {
+        let len = (size - written).min(buf.len() as u64) as usize;
+        f.write_all(&buf[..len])?;
+        written += len as u64;
+    }
+
+    Ok(())
+}
+
 pub fn swapoff(tempdir: &Path) -> Result<(), RunCmdError> {
     let swapfile_path = tempdir.join("swapfile");
 
@@ -110,3 +275,151 @@ pub fn swapoff(tempdir: &Path) -> Result<(), RunCmdError> {
 
     Ok(())
 }
+
+/// Write the zram-generator config of a zram swap of `size_mb` MiB
+/// Must be used in a chroot context
+pub(crate) fn write_zram_generator_config(size_mb: u64) -> io::Result<()> {
+    info!("Writing zram-generator config, zram size: {size_mb}MiB");
+    std::fs::create_dir_all("/etc/systemd")?;
+    std::fs::write(
+        "/etc/systemd/zram-generator.conf",
+        zram_generator_config(size_mb),
+    )?;
+
+    Ok(())
+}
+
+/// Make dracut include the resume module, so that the initramfs resumes from the swapfile
+/// Must be used in a chroot context
+pub(crate) fn write_dracut_resume_config() -> io::Result<()> {
+    std::fs::create_dir_all("/etc/dracut.conf.d")?;
+    std::fs::write(
+        "/etc/dracut.conf.d/90-deploykit-resume.conf",
+        "add_dracutmodules+=\" resume \"\n",
+    )?;
+
+    Ok(())
+}
+
+fn zram_generator_config(size_mb: u64) -> String {
+    format!("[zram0]\nzram-size = {size_mb}\ncompression-algorithm = zstd\n")
+}
+
+#[test]
+fn test_zram_generator_config() {
+    assert_eq!(
+        zram_generator_config(4096),
+        "[zram0]\nzram-size = 4096\ncompression-algorithm = zstd\n"
+    );
+}
+
+#[test]
+fn test_get_hibernate_swap_size() {
+    const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+    assert_eq!(get_hibernate_swap_size(1024 * 1024 * 1024), 2.0 * GIB);
+    assert_eq!(get_hibernate_swap_size(16 * 1024 * 1024 * 1024), 20.0 * GIB);
+    // 不受 32GiB 上限限制
+    assert_eq!(get_hibernate_swap_size(64 * 1024 * 1024 * 1024), 72.0 * GIB);
+    assert!(
+        get_hibernate_swap_size(64 * 1024 * 1024 * 1024)
+            > get_recommend_swap_size(64 * 1024 * 1024 * 1024)
+    );
+}
+
+#[test]
+#[ignore = "requires root to set up a loop device"]
+fn test_create_swapfile_btrfs_loop_device() {
+    let dev = disk::loop_device::LoopDevice::new("swap-btrfs", 512 * 1024 * 1024);
+    let mount_point =
+        std::env::temp_dir().join(format!("dk-swap-btrfs-{}.mnt", std::process::id()));
+    std::fs::create_dir_all(&mount_point).unwrap();
+
+    assert!(Command::new("mkfs.btrfs")
+        .arg("-f")
+        .arg(dev.path())
+        .status()
+        .unwrap()
+        .success());
+    assert!(Command::new("mount")
+        .arg(dev.path())
+        .arg(&mount_point)
+        .status()
+        .unwrap()
+        .success());
+
+    let res = create_swapfile(64.0 * 1024.0 * 1024.0, &mount_point, Some("btrfs"));
+    let attrs = Command::new("lsattr")
+        .arg(mount_point.join("swapfile"))
+        .output()
+        .unwrap();
+    let offset = swapfile_resume_offset(&mount_point.join("swapfile"), Some("btrfs"));
+
+    swapoff(&mount_point).ok();
+    Command::new("umount").arg(&mount_point).status().unwrap();
+    std::fs::remove_dir(&mount_point).unwrap();
+
+    res.unwrap();
+    let attrs = String::from_utf8_lossy(&attrs.stdout);
+    let flags = attrs.split_whitespace().next().unwrap();
+    assert!(flags.contains('C'), "{attrs}");
+    assert!(offset.unwrap() > 0);
+}
+
+#[test]
+fn test_parse_map_swapfile_offset() {
+    assert_eq!(parse_map_swapfile_offset("198122980\n"), Some(198122980));
+    assert_eq!(parse_map_swapfile_offset(""), None);
+    assert_eq!(parse_map_swapfile_offset("ERROR: not a swapfile\n"), None);
+}
+
+#[test]
+fn test_fill_zeros() {
+    let path = std::env::temp_dir().join(format!("dk-fill-zeros-{}", std::process::id()));
+    let f = File::create(&path).unwrap();
+
+    fill_zeros(&f, 3 * 1024 * 1024 + 512).unwrap();
+    let content = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(content.len(), 3 * 1024 * 1024 + 512);
+    assert!(content.iter().all(|x| *x == 0));
+}
+
+#[test]
+#[ignore = "requires root to set up a loop device"]
+fn test_create_swapfile_no_space_loop_device() {
+    let dev = disk::loop_device::LoopDevice::new("swap-enospc", 16 * 1024 * 1024);
+    let mount_point =
+        std::env::temp_dir().join(format!("dk-swap-enospc-{}.mnt", std::process::id()));
+    std::fs::create_dir_all(&mount_point).unwrap();
+
+    assert!(Command::new("mkfs.ext4")
+        .arg("-F")
+        .arg(dev.path())
+        .status()
+        .unwrap()
+        .success());
+    assert!(Command::new("mount")
+        .arg(dev.path())
+        .arg(&mount_point)
+        .status()
+        .unwrap()
+        .success());
+
+    let res = create_swapfile(64.0 * 1024.0 * 1024.0, &mount_point, Some("ext4"));
+
+    Command::new("umount").arg(&mount_point).status().unwrap();
+    std::fs::remove_dir(&mount_point).unwrap();
+
+    match res.unwrap_err() {
+        SwapFileError::Fallocate { source, .. } => {
+            assert_eq!(
+                source.raw_os_error(),
+                Some(Errno::NOSPC.raw_os_error()),
+                "{source:?}"
+            )
+        }
+        err => panic!("{err:?}"),
+    }
+}
@@ -1,5 +1,8 @@
 use std::{
+    fs,
     io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::Path,
     process::{Command, Stdio},
 };
 
@@ -32,8 +35,25 @@ pub enum AddUserError {
     WriteChpasswdStdin { source: std::io::Error },
     #[snafu(display("Failed to flush chpasswd stdin"))]
     FlushChpasswdStdin { source: std::io::Error },
+    #[snafu(display("Invalid username {username}: {reason}"))]
+    InvalidUsername { username: String, reason: String },
+    #[snafu(display("Invalid UID {uid}: {reason}"))]
+    InvalidUid { uid: u32, reason: String },
+    #[snafu(display("Username is used more than once: {username}"))]
+    DuplicateUsername { username: String },
+    #[snafu(display("UID is used more than once: {uid}"))]
+    DuplicateUid { uid: u32 },
+    #[snafu(display("Login shell is not installed or not listed in /etc/shells: {shell}"))]
+    InvalidShell { shell: String },
+    #[snafu(display("Failed to write {WHEEL_SUDOERS_PATH}"))]
+    WriteSudoers { source: std::io::Error },
 }
 
+const DEFAULT_SHELL: &str = "/bin/bash";
+const MAX_USERNAME_LEN: usize = 32;
+const MIN_UID: u32 = 1000;
+const WHEEL_SUDOERS_PATH: &str = "/etc/sudoers.d/90-deploykit-wheel";
+
 /// Sets Fullname
 /// Must be used in a chroot context
 pub(crate) fn passwd_set_fullname(full_name: &str, username: &str) -> Result<(), SetFullNameError> {
@@ -100,17 +120,177 @@ fn set_full_name(
     Ok(())
 }
 
-/// Adds a new normal user to the guest environment
+/// Check that every username is used only once, including root
+pub fn check_unique_usernames<'a>(
+    usernames: impl IntoIterator<Item = &'a str>,
+) -> Result<(), AddUserError> {
+    let mut seen = vec!["root"];
+
+    for username in usernames {
+        ensure!(
+            !seen.contains(&username),
+            DuplicateUsernameSnafu {
+                username: username.to_string()
+            }
+        );
+        seen.push(username);
+    }
+
+    Ok(())
+}
+
+/// Check that every given UID is used only once
+pub fn check_unique_uids(uids: impl IntoIterator<Item = u32>) -> Result<(), AddUserError> {
+    let mut seen = vec![];
+
+    for uid in uids {
+        ensure!(!seen.contains(&uid), DuplicateUidSnafu { uid });
+        seen.push(uid);
+    }
+
+    Ok(())
+}
+
+/// Check that `username` follows the POSIX portable username rules accepted by useradd:
+/// lowercase letters, digits, `-` and `_`, not starting with a digit or `-`, at most 32 characters
+pub fn check_username(username: &str) -> Result<(), AddUserError> {
+    let reason = if username.is_empty() {
+        Some("must not be empty".to_string())
+    } else if username.len() > MAX_USERNAME_LEN {
+        Some(format!(
+            "must not be longer than {MAX_USERNAME_LEN} characters"
+        ))
+    } else if username.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+        Some("must not start with a digit or '-'".to_string())
+    } else if let Some(c) = username
+        .chars()
+        .find(|c| !matches!(c, 'a'..='z' | '0'..='9' | '-' | '_'))
+    {
+        Some(format!("contains invalid character {c:?}"))
+    } else if username == "root" {
+        Some("is reserved for the system".to_string())
+    } else {
+        None
+    };
+
+    match reason {
+        Some(reason) => InvalidUsernameSnafu { username, reason }.fail(),
+        None => Ok(()),
+    }
+}
+
+/// [`check_username`], and check that `username` is not a system user in /etc/passwd under `root`
+fn check_username_in(root: &Path, username: &str) -> Result<(), AddUserError> {
+    check_username(username)?;
+
+    // 新解压的系统中 /etc/passwd 只有系统用户
+    let passwd = fs::read_to_string(root.join("etc/passwd")).unwrap_or_default();
+    let is_system_user = passwd
+        .lines()
+        .filter_map(|line| line.split(':').next())
+        .any(|name| name == username);
+
+    ensure!(
+        !is_system_user,
+        InvalidUsernameSnafu {
+            username,
+            reason: "is already used by a system user",
+        }
+    );
+
+    Ok(())
+}
+
+/// Check that `uid` is not reserved for system users
+pub fn check_uid(uid: u32) -> Result<(), AddUserError> {
+    ensure!(
+        uid >= MIN_UID,
+        InvalidUidSnafu {
+            uid,
+            reason: format!("must be at least {MIN_UID}"),
+        }
+    );
+
+    Ok(())
+}
+
+/// [`check_uid`], and check that `uid` is not used in /etc/passwd under `root`
+fn check_uid_in(root: &Path, uid: u32) -> Result<(), AddUserError> {
+    check_uid(uid)?;
+
+    let passwd = fs::read_to_string(root.join("etc/passwd")).unwrap_or_default();
+    let used_by = passwd.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let line_uid = fields.nth(1)?.parse::<u32>().ok()?;
+
+        (line_uid == uid).then_some(name)
+    });
+
+    match used_by {
+        Some(name) => InvalidUidSnafu {
+            uid,
+            reason: format!("is already used by {name}"),
+        }
+        .fail(),
+        None => Ok(()),
+    }
+}
+
+/// Check that a user can be added to the guest environment, see [`add_new_user`]
+/// Check every user before adding any, so that no user is left half created
 /// Must be used in a chroot context
-pub(crate) fn add_new_user(name: &str, password: &str) -> Result<(), AddUserError> {
-    run_command(
-        "useradd",
-        ["-m", "-s", "/bin/bash", name],
-        vec![] as Vec<(String, String)>,
-    )?;
+pub(crate) fn check_new_user(
+    name: &str,
+    shell: Option<&str>,
+    uid: Option<u32>,
+) -> Result<(), AddUserError> {
+    check_new_user_in(Path::new("/"), name, shell, uid)
+}
+
+fn check_new_user_in(
+    root: &Path,
+    name: &str,
+    shell: Option<&str>,
+    uid: Option<u32>,
+) -> Result<(), AddUserError> {
+    check_username_in(root, name)?;
+
+    if let Some(uid) = uid {
+        check_uid_in(root, uid)?;
+    }
+
+    if let Some(shell) = shell {
+        check_shell_in(root, shell)?;
+    }
+
+    Ok(())
+}
+
+/// Adds a new normal user to the guest environment, `admin` users are in the wheel group
+/// The login shell is bash if `shell` is not set, the UID is allocated by useradd if `uid` is not set
+/// The user must be checked by [`check_new_user`] first
+/// Must be used in a chroot context
+pub(crate) fn add_new_user(
+    name: &str,
+    password: &str,
+    admin: bool,
+    shell: Option<&str>,
+    uid: Option<u32>,
+) -> Result<(), AddUserError> {
+    let shell = shell.unwrap_or(DEFAULT_SHELL);
+
+    let mut args = vec!["-m", "-s", shell];
+    let uid = uid.map(|uid| uid.to_string());
+    if let Some(uid) = &uid {
+        args.extend(["-u", uid.as_str()]);
+    }
+    args.push(name);
+
+    run_command("useradd", args, vec![] as Vec<(String, String)>)?;
     run_command(
         "usermod",
-        ["-aG", "audio,cdrom,video,wheel,plugdev", name],
+        ["-aG", &user_groups(admin), name],
         vec![] as Vec<(String, String)>,
     )?;
 
@@ -119,6 +299,85 @@ pub(crate) fn add_new_user(name: &str, password: &str) -> Result<(), AddUserErro
     Ok(())
 }
 
+/// Check that `shell` is listed in /etc/shells and installed under `root`
+fn check_shell_in(root: &Path, shell: &str) -> Result<(), AddUserError> {
+    let shells = fs::read_to_string(root.join("etc/shells")).unwrap_or_default();
+
+    let is_listed = shells
+        .lines()
+        .map(|line| line.trim())
+        .any(|line| !line.starts_with('#') && line == shell);
+
+    ensure!(
+        is_listed && shell.starts_with('/') && root.join(&shell[1..]).is_file(),
+        InvalidShellSnafu { shell }
+    );
+
+    Ok(())
+}
+
+// wheel 组的用户可以使用 sudo
+fn user_groups(admin: bool) -> String {
+    let mut groups = "audio,cdrom,video,plugdev".to_string();
+    if admin {
+        groups.push_str(",wheel");
+    }
+
+    groups
+}
+
+/// Let users in the wheel group use sudo without password
+/// Must be used in a chroot context
+pub(crate) fn set_passwordless_sudo() -> Result<(), AddUserError> {
+    let path = Path::new(WHEEL_SUDOERS_PATH);
+    write_sudoers(path).context(WriteSudoersSnafu)?;
+
+    // 有语法错误的 sudoers 会导致 sudo 完全无法使用，检查失败时删除
+    if let Err(e) = run_command(
+        "visudo",
+        ["-cf", WHEEL_SUDOERS_PATH],
+        vec![] as Vec<(String, String)>,
+    ) {
+        fs::remove_file(path).ok();
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+fn write_sudoers(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o440)
+        .open(path)?;
+    f.write_all(b"%wheel ALL=(ALL) NOPASSWD: ALL\n")?;
+
+    // 已存在的文件不受 mode 影响
+    fs::set_permissions(path, fs::Permissions::from_mode(0o440))?;
+
+    Ok(())
+}
+
+/// Sets the password of root, or locks the root account if `password` is `None`
+/// Must be used in a chroot context
+pub(crate) fn set_root_password(password: Option<&str>) -> Result<(), AddUserError> {
+    match password {
+        Some(password) => chpasswd("root", password)?,
+        None => {
+            // AOSC OS 默认锁定 root 账户，通过 sudo 提权
+            run_command("passwd", ["-l", "root"], vec![] as Vec<(String, String)>)?;
+        }
+    }
+
+    Ok(())
+}
+
 pub(crate) fn chpasswd(name: &str, password: &str) -> Result<(), AddUserError> {
     info!("Running chpasswd ...");
     let command = Command::new("chpasswd")
@@ -189,3 +448,187 @@ _apt:x:976:976::/var/lib/apt:/sbin/nologin
     assert!(set_full_name("Mag Mell\n", "saki", &mut passwd_2).is_err());
     assert!(set_full_name("Mag Mell:", "saki", &mut passwd_3).is_err());
 }
+
+#[test]
+fn test_check_unique_usernames() {
+    assert!(check_unique_usernames(["aosc", "alice", "bob"]).is_ok());
+    assert!(check_unique_usernames([]).is_ok());
+
+    for usernames in [&["aosc", "alice", "aosc"][..], &["root"][..]] {
+        let username = match check_unique_usernames(usernames.iter().copied()) {
+            Err(AddUserError::DuplicateUsername { username }) => username,
+            res => panic!("{res:?}"),
+        };
+        assert_eq!(username, usernames[usernames.len() - 1]);
+    }
+}
+
+#[test]
+fn test_check_unique_uids() {
+    assert!(check_unique_uids([1000, 1001, 1500]).is_ok());
+    assert!(check_unique_uids([]).is_ok());
+    assert!(matches!(
+        check_unique_uids([1000, 1500, 1000]),
+        Err(AddUserError::DuplicateUid { uid: 1000 })
+    ));
+}
+
+#[test]
+fn test_check_new_user_in() {
+    let root = std::env::temp_dir().join(format!("dk-new-user-{}", std::process::id()));
+    fs::create_dir_all(root.join("etc")).unwrap();
+    fs::write(
+        root.join("etc/passwd"),
+        "root:x:0:0:root:/root:/bin/bash
+saki:x:1000:1001::/home/saki:/bin/bash
+",
+    )
+    .unwrap();
+    fs::write(
+        root.join("etc/shells"),
+        "/bin/bash
+/usr/bin/zsh
+",
+    )
+    .unwrap();
+
+    let res = [
+        check_new_user_in(&root, "aosc", None, Some(1001)).is_ok(),
+        // 系统中已有同名用户
+        check_new_user_in(&root, "saki", None, None).is_ok(),
+        // UID 已被占用
+        check_new_user_in(&root, "aosc", None, Some(1000)).is_ok(),
+        // 登录 shell 未安装
+        check_new_user_in(&root, "aosc", Some("/usr/bin/zsh"), None).is_ok(),
+    ];
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(res, [true, false, false, false]);
+}
+
+#[test]
+fn test_user_groups() {
+    assert_eq!(user_groups(true), "audio,cdrom,video,plugdev,wheel");
+    assert_eq!(user_groups(false), "audio,cdrom,video,plugdev");
+}
+
+#[test]
+fn test_check_shell_in() {
+    let root = std::env::temp_dir().join(format!("dk-shells-{}", std::process::id()));
+    fs::create_dir_all(root.join("etc")).unwrap();
+    fs::create_dir_all(root.join("usr/bin")).unwrap();
+    fs::write(
+        root.join("etc/shells"),
+        "# Pathnames of valid login shells.\n/bin/bash\n/usr/bin/zsh\n/usr/bin/fish\n",
+    )
+    .unwrap();
+    fs::write(root.join("usr/bin/zsh"), "").unwrap();
+    fs::write(root.join("usr/bin/nologin"), "").unwrap();
+
+    let res = [
+        check_shell_in(&root, "/usr/bin/zsh").is_ok(),
+        // 未安装
+        check_shell_in(&root, "/usr/bin/fish").is_ok(),
+        // 未列在 /etc/shells 中
+        check_shell_in(&root, "/usr/bin/nologin").is_ok(),
+        check_shell_in(&root, "zsh").is_ok(),
+    ];
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(res, [true, false, false, false]);
+}
+
+#[test]
+fn test_write_sudoers() {
+    let root = std::env::temp_dir().join(format!("dk-sudoers-{}", std::process::id()));
+    let path = root.join("etc/sudoers.d/90-deploykit-wheel");
+
+    write_sudoers(&path).unwrap();
+    let content = fs::read_to_string(&path).unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(content, "%wheel ALL=(ALL) NOPASSWD: ALL\n");
+    assert_eq!(mode & 0o777, 0o440);
+}
+
+#[test]
+fn test_check_username() {
+    let names = [
+        ("aosc", true),
+        ("alice", true),
+        ("bob-2", true),
+        ("_apt_user", true),
+        ("user_01", true),
+        ("a", true),
+        (&"a".repeat(32), true),
+        ("", false),
+        (&"a".repeat(33), false),
+        ("root", false),
+        ("-f", false),
+        ("1user", false),
+        ("Alice", false),
+        ("ALICE", false),
+        ("foo bar", false),
+        ("foo:bar", false),
+        ("foo/bar", false),
+        ("foo.bar", false),
+        ("用户", false),
+        ("foo\n", false),
+    ];
+
+    for (name, is_valid) in names {
+        let res = check_username(name);
+        assert_eq!(res.is_ok(), is_valid, "{name:?}: {res:?}");
+        if let Err(e) = res {
+            assert!(matches!(e, AddUserError::InvalidUsername { .. }), "{e:?}");
+        }
+    }
+}
+
+#[test]
+fn test_check_username_in() {
+    let root = std::env::temp_dir().join(format!("dk-passwd-{}", std::process::id()));
+    fs::create_dir_all(root.join("etc")).unwrap();
+    fs::write(
+        root.join("etc/passwd"),
+        "root:x:0:0:root:/root:/bin/bash\nbin:x:1:1:bin:/dev/null:/bin/false\nsystemd-network:x:992:992:systemd Network Management:/:/sbin/nologin\n",
+    )
+    .unwrap();
+
+    let res = [
+        check_username_in(&root, "aosc").is_ok(),
+        check_username_in(&root, "bin").is_ok(),
+        check_username_in(&root, "systemd-network").is_ok(),
+        check_username_in(&root, "-f").is_ok(),
+    ];
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(res, [true, false, false, false]);
+}
+
+#[test]
+fn test_check_uid_in() {
+    let root = std::env::temp_dir().join(format!("dk-passwd-uid-{}", std::process::id()));
+    fs::create_dir_all(root.join("etc")).unwrap();
+    fs::write(
+        root.join("etc/passwd"),
+        "root:x:0:0:root:/root:/bin/bash\nsystemd-oom:x:980:980:systemd Userspace OOM Killer:/:/usr/bin/nologin\nnobody:x:65534:65534:Unprivileged User:/dev/null:/bin/false\nsaki:x:1000:1001:Mag Mell:/home/saki:/bin/bash\n",
+    )
+    .unwrap();
+
+    let res = [0, 980, 999, 1000, 1001, 2000, 65534].map(|uid| check_uid_in(&root, uid));
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(
+        res.iter().map(|r| r.is_ok()).collect::<Vec<_>>(),
+        [false, false, false, false, true, true, false]
+    );
+    match &res[3] {
+        Err(AddUserError::InvalidUid { uid, reason }) => {
+            assert_eq!(*uid, 1000);
+            assert_eq!(reason, "is already used by saki");
+        }
+        res => panic!("{res:?}"),
+    }
+}
@@ -1,4 +1,6 @@
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use std::{ffi::OsStr, process::Command};
 
 use snafu::{ensure, ResultExt, Snafu};
@@ -92,3 +94,93 @@ pub(crate) fn no_need_to_run_info(s: &str, str_is_retro: bool) {
         info!("Non retro system no need to run {}", s);
     }
 }
+
+/// Measure the velocity in bytes per second, sampled about every second
+pub(crate) struct VelocityMeter {
+    since: Instant,
+    len: usize,
+}
+
+impl VelocityMeter {
+    pub(crate) fn new() -> Self {
+        Self::starting_at(Instant::now())
+    }
+
+    fn starting_at(now: Instant) -> Self {
+        Self { since: now, len: 0 }
+    }
+
+    /// Count `len` more bytes done, and store the velocity once a second has passed
+    pub(crate) fn add(&mut self, len: usize, velocity: &AtomicUsize) {
+        if let Some(v) = self.add_at(len, Instant::now()) {
+            velocity.store(v, Ordering::SeqCst);
+        }
+    }
+
+    fn add_at(&mut self, len: usize, now: Instant) -> Option<usize> {
+        self.len += len;
+
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed < Duration::from_secs(1) {
+            return None;
+        }
+
+        let v = (self.len as f64 / elapsed.as_secs_f64()) as usize;
+        self.since = now;
+        self.len = 0;
+
+        Some(v)
+    }
+}
+
+/// Seconds to finish `remaining` bytes at `velocity` bytes per second, 0 if unknown
+fn eta_secs(remaining: usize, velocity: usize) -> usize {
+    if velocity == 0 {
+        return 0;
+    }
+
+    remaining.div_ceil(velocity)
+}
+
+/// Store the estimated remaining seconds of the current step
+pub(crate) fn store_eta(eta: &AtomicUsize, remaining: usize, velocity: &AtomicUsize) {
+    eta.store(
+        eta_secs(remaining, velocity.load(Ordering::SeqCst)),
+        Ordering::SeqCst,
+    );
+}
+
+#[test]
+fn test_eta_secs() {
+    assert_eq!(eta_secs(10 * 1024 * 1024, 1024 * 1024), 10);
+    assert_eq!(eta_secs(1024 * 1024 + 1, 1024 * 1024), 2);
+    assert_eq!(eta_secs(0, 1024), 0);
+    // 速度未知时不作估计
+    assert_eq!(eta_secs(1024, 0), 0);
+}
+
+#[test]
+fn test_velocity_meter() {
+    let start = Instant::now();
+    let mut meter = VelocityMeter::starting_at(start);
+
+    // 不足一秒时不更新速度
+    assert_eq!(
+        meter.add_at(300 * 1024, start + Duration::from_millis(400)),
+        None
+    );
+    assert_eq!(
+        meter.add_at(700 * 1024, start + Duration::from_secs(1)),
+        Some(1000 * 1024)
+    );
+
+    // 按实际经过的时间计算，而非整秒
+    assert_eq!(
+        meter.add_at(3 * 1024 * 1024, start + Duration::from_millis(2500)),
+        Some(2 * 1024 * 1024)
+    );
+    assert_eq!(
+        meter.add_at(0, start + Duration::from_millis(3500)),
+        Some(0)
+    );
+}
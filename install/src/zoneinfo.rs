@@ -1,4 +1,6 @@
 use std::{
+    fs::{self, File},
+    io::{self, ErrorKind, Read},
     os::unix::fs::symlink,
     path::{Path, PathBuf},
 };
@@ -9,19 +11,34 @@ use snafu::{ResultExt, Snafu};
 pub enum SetZoneinfoError {
     #[snafu(display("Failed to remove /etc/localtime"))]
     RemoveLocaltimeFile { source: std::io::Error },
+    #[snafu(display("/etc/localtime is a {file_type}, refusing to replace it"))]
+    UnexpectedLocaltime { file_type: String },
     #[snafu(display("Failed to symlink {} to /etc/localtime", path.display()))]
     Symlink {
         path: PathBuf,
         source: std::io::Error,
     },
+    #[snafu(display("/etc/localtime ({}) does not resolve to a zoneinfo file", path.display()))]
+    ResolveLocaltime {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("/etc/localtime ({}) resolves outside the target system", path.display()))]
+    LocaltimeOutsideRoot { path: PathBuf },
+    #[snafu(display("Unknown timezone: {zone}"))]
+    UnknownZone { zone: String },
 }
 
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
 /// Sets zoneinfo in the guest environment
 /// Must be used in a chroot context
 pub(crate) fn set_zoneinfo(zone: &str) -> Result<(), SetZoneinfoError> {
-    if Path::new("/etc/localtime").exists() {
-        std::fs::remove_file("/etc/localtime").context(RemoveLocaltimeFileSnafu)?;
-    }
+    set_zoneinfo_in(Path::new("/"), zone)
+}
+
+fn set_zoneinfo_in(root: &Path, zone: &str) -> Result<(), SetZoneinfoError> {
+    let localtime = root.join("etc/localtime");
 
     let zone = if zone == "Asia/Beijing" {
         "Asia/Shanghai"
@@ -29,8 +46,285 @@ pub(crate) fn set_zoneinfo(zone: &str) -> Result<(), SetZoneinfoError> {
         zone
     };
 
-    let zone_path = PathBuf::from("/usr/share/zoneinfo").join(zone);
-    symlink(&zone_path, "/etc/localtime").context(SymlinkSnafu { path: zone_path })?;
+    // 在移除原有的 /etc/localtime 之前检查，避免留下失效的链接
+    if !root.join("usr/share/zoneinfo").join(zone).is_file() {
+        return Err(SetZoneinfoError::UnknownZone {
+            zone: zone.to_string(),
+        });
+    }
+
+    remove_localtime(&localtime)?;
+
+    // systemd 期望 /etc/localtime 为指向 zoneinfo 的相对链接
+    let zone_path = PathBuf::from("../usr/share/zoneinfo").join(zone);
+    symlink(&zone_path, &localtime).context(SymlinkSnafu {
+        path: zone_path.clone(),
+    })?;
+
+    if let Err(e) = check_localtime(root, &localtime, &zone_path) {
+        // 不留下无效的链接
+        fs::remove_file(&localtime).ok();
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn remove_localtime(localtime: &Path) -> Result<(), SetZoneinfoError> {
+    // 不跟随链接，以便处理失效的链接
+    let file_type = match fs::symlink_metadata(localtime) {
+        Ok(m) => m.file_type(),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(SetZoneinfoError::RemoveLocaltimeFile { source: e }),
+    };
+
+    if file_type.is_dir() {
+        return Err(SetZoneinfoError::UnexpectedLocaltime {
+            file_type: "directory".to_string(),
+        });
+    }
+
+    // 普通文件（包括硬链接）只移除这一个目录项，不改写其内容
+    match fs::remove_file(localtime) {
+        Ok(()) => Ok(()),
+        // 绑定挂载的目标无法被移除
+        Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+            Err(SetZoneinfoError::UnexpectedLocaltime {
+                file_type: "mount point".to_string(),
+            })
+        }
+        Err(e) => Err(SetZoneinfoError::RemoveLocaltimeFile { source: e }),
+    }
+}
+
+fn check_localtime(
+    root: &Path,
+    localtime: &Path,
+    zone_path: &Path,
+) -> Result<(), SetZoneinfoError> {
+    let resolve = |path: &Path| {
+        fs::canonicalize(path).context(ResolveLocaltimeSnafu {
+            path: zone_path.to_path_buf(),
+        })
+    };
+
+    let root = resolve(root)?;
+    let target = resolve(localtime)?;
+
+    if !target.starts_with(root) {
+        return Err(SetZoneinfoError::LocaltimeOutsideRoot {
+            path: zone_path.to_path_buf(),
+        });
+    }
+
+    if !target.is_file() {
+        return Err(SetZoneinfoError::ResolveLocaltime {
+            path: zone_path.to_path_buf(),
+            source: io::Error::new(ErrorKind::InvalidInput, "not a regular file"),
+        });
+    }
+
+    Ok(())
+}
+
+/// Names of the timezones in /usr/share/zoneinfo of the running system, e.g. `Asia/Shanghai`
+pub fn list_timezones() -> io::Result<Vec<String>> {
+    list_timezones_in(Path::new(ZONEINFO_DIR))
+}
+
+fn list_timezones_in(zoneinfo: &Path) -> io::Result<Vec<String>> {
+    let mut res = vec![];
+    collect_timezones(zoneinfo, zoneinfo, &mut res)?;
+    res.sort();
+
+    Ok(res)
+}
+
+fn collect_timezones(zoneinfo: &Path, dir: &Path, res: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.strip_prefix(zoneinfo) {
+            Ok(name) => name.to_string_lossy().to_string(),
+            Err(_) => continue,
+        };
+
+        // posix/ 与 right/ 是同一组时区的副本
+        if path.is_dir() {
+            if name != "posix" && name != "right" {
+                collect_timezones(zoneinfo, &path, res)?;
+            }
+            continue;
+        }
+
+        // 跳过 zone.tab、tzdata.zi 等非时区文件
+        if name != "posixrules" && name != "localtime" && is_tzif(&path) {
+            res.push(name);
+        }
+    }
 
     Ok(())
 }
+
+fn is_tzif(path: &Path) -> bool {
+    let mut magic = [0; 4];
+
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok_and(|_| &magic == b"TZif")
+}
+
+#[cfg(test)]
+fn test_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("dk-zoneinfo-{name}-{}", std::process::id()));
+
+    fs::remove_dir_all(&root).ok();
+    fs::create_dir_all(root.join("etc")).unwrap();
+    fs::create_dir_all(root.join("usr/share/zoneinfo/Asia")).unwrap();
+    fs::write(root.join("usr/share/zoneinfo/Asia/Shanghai"), "TZif").unwrap();
+    fs::write(root.join("usr/share/zoneinfo/UTC"), "TZif").unwrap();
+
+    root
+}
+
+#[cfg(test)]
+fn assert_zone_link(root: &Path, zone: &str) {
+    let localtime = root.join("etc/localtime");
+
+    assert_eq!(
+        fs::read_link(&localtime).unwrap(),
+        Path::new("../usr/share/zoneinfo").join(zone)
+    );
+    assert_eq!(
+        fs::canonicalize(&localtime).unwrap(),
+        fs::canonicalize(root.join("usr/share/zoneinfo").join(zone)).unwrap()
+    );
+}
+
+#[test]
+fn test_set_zoneinfo_missing() {
+    let root = test_root("missing");
+
+    set_zoneinfo_in(&root, "Asia/Beijing").unwrap();
+    assert_zone_link(&root, "Asia/Shanghai");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_set_zoneinfo_regular_file() {
+    let root = test_root("regular");
+    let localtime = root.join("etc/localtime");
+    let other = root.join("etc/localtime.orig");
+
+    fs::write(&localtime, "old").unwrap();
+    fs::hard_link(&localtime, &other).unwrap();
+
+    set_zoneinfo_in(&root, "UTC").unwrap();
+    assert_zone_link(&root, "UTC");
+    // 硬链接的另一端不受影响
+    assert_eq!(fs::read_to_string(&other).unwrap(), "old");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_set_zoneinfo_symlinks() {
+    let root = test_root("symlinks");
+    let localtime = root.join("etc/localtime");
+
+    symlink("/usr/share/zoneinfo/Nowhere", &localtime).unwrap();
+    set_zoneinfo_in(&root, "UTC").unwrap();
+    assert_zone_link(&root, "UTC");
+
+    set_zoneinfo_in(&root, "Asia/Shanghai").unwrap();
+    assert_zone_link(&root, "Asia/Shanghai");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_set_zoneinfo_directory() {
+    let root = test_root("directory");
+    let localtime = root.join("etc/localtime");
+
+    fs::create_dir(&localtime).unwrap();
+
+    assert!(matches!(
+        set_zoneinfo_in(&root, "UTC"),
+        Err(SetZoneinfoError::UnexpectedLocaltime { file_type }) if file_type == "directory"
+    ));
+    assert!(localtime.is_dir());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_set_zoneinfo_bad_zone() {
+    let root = test_root("bad-zone");
+    let localtime = root.join("etc/localtime");
+
+    assert!(matches!(
+        set_zoneinfo_in(&root, "Mars/Olympus"),
+        Err(SetZoneinfoError::UnknownZone { zone }) if zone == "Mars/Olympus"
+    ));
+    assert!(matches!(
+        set_zoneinfo_in(&root, "Asia"),
+        Err(SetZoneinfoError::UnknownZone { .. })
+    ));
+
+    // usr/share/zoneinfo/../../../.. 即 root 的上级目录
+    let outside = root.with_extension("outside");
+    fs::write(&outside, "TZif").unwrap();
+    let escape = format!(
+        "../../../../{}",
+        outside.file_name().unwrap().to_string_lossy()
+    );
+
+    assert!(matches!(
+        set_zoneinfo_in(&root, &escape),
+        Err(SetZoneinfoError::LocaltimeOutsideRoot { .. })
+    ));
+
+    // 失败时不留下链接
+    assert!(fs::symlink_metadata(&localtime).is_err());
+
+    fs::remove_dir_all(&root).unwrap();
+    fs::remove_file(&outside).unwrap();
+}
+
+#[test]
+fn test_set_zoneinfo_unknown_zone_keeps_localtime() {
+    let root = test_root("unknown");
+    let localtime = root.join("etc/localtime");
+
+    set_zoneinfo_in(&root, "UTC").unwrap();
+
+    assert!(matches!(
+        set_zoneinfo_in(&root, "Mars/Olympus"),
+        Err(SetZoneinfoError::UnknownZone { .. })
+    ));
+    assert_zone_link(&root, "UTC");
+    assert!(localtime.exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_list_timezones() {
+    let root = test_root("list");
+    let zoneinfo = root.join("usr/share/zoneinfo");
+
+    fs::create_dir_all(zoneinfo.join("right/Asia")).unwrap();
+    fs::write(zoneinfo.join("right/Asia/Shanghai"), "TZif").unwrap();
+    fs::write(zoneinfo.join("posixrules"), "TZif").unwrap();
+    fs::write(
+        zoneinfo.join("zone.tab"),
+        "CN\t+3114+12128\tAsia/Shanghai\n",
+    )
+    .unwrap();
+
+    let res = list_timezones_in(&zoneinfo).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(res, ["Asia/Shanghai", "UTC"]);
+}
@@ -1,12 +1,15 @@
-use std::fmt::Display;
+use std::{fmt::Display, io};
 
-use disk::CombineError;
+use disk::{CombineError, PartitionError};
 use install::{
     chroot::ChrootError,
     download::DownloadError,
     genfstab::GenfstabError,
     grub::RunGrubError,
-    locale::SetHwclockError,
+    hostname::SetHostnameError,
+    identity::IdentityError,
+    locale::{SetHwclockError, SetLocaleError},
+    locale_extras::LocaleExtrasError,
     mount::MountInnerError,
     swap::SwapFileError,
     user::{AddUserError, SetFullNameError},
@@ -76,8 +79,58 @@ impl From<&CombineError> for DkError {
 #[cfg(not(target_arch = "powerpc64"))]
 impl From<&RunGrubError> for DkError {
     fn from(value: &RunGrubError) -> Self {
-        let RunGrubError::RunCommand { source } = value;
-        DkError::from(source)
+        match value {
+            RunGrubError::RunCommand { source } => DkError::from(source),
+            RunGrubError::MissingPlatform { platform } => Self {
+                message: value.to_string(),
+                t: "MissingGrubPlatform".to_string(),
+                data: {
+                    json!({
+                        "platform": platform.to_string(),
+                    })
+                },
+            },
+            RunGrubError::EnableCryptodisk { source } => Self {
+                message: value.to_string(),
+                t: "EnableCryptodisk".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            RunGrubError::SetKernelCmdline { source } => Self {
+                message: value.to_string(),
+                t: "SetKernelCmdline".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            RunGrubError::ResumeOffset { source } => Self {
+                message: value.to_string(),
+                t: "ResumeOffset".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            RunGrubError::LuksUuid { source } => Self {
+                message: value.to_string(),
+                t: "LuksUuid".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "data": DkError::from(source)
+                    })
+                },
+            },
+        }
     }
 }
 
@@ -96,6 +149,55 @@ impl From<&RunGrubError> for DkError {
                 },
             },
             RunGrubError::RunCommand { source } => DkError::from(source),
+            RunGrubError::MissingPlatform { platform } => Self {
+                message: value.to_string(),
+                t: "MissingGrubPlatform".to_string(),
+                data: {
+                    json!({
+                        "platform": platform.to_string(),
+                    })
+                },
+            },
+            RunGrubError::EnableCryptodisk { source } => Self {
+                message: value.to_string(),
+                t: "EnableCryptodisk".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            RunGrubError::SetKernelCmdline { source } => Self {
+                message: value.to_string(),
+                t: "SetKernelCmdline".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            RunGrubError::ResumeOffset { source } => Self {
+                message: value.to_string(),
+                t: "ResumeOffset".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            RunGrubError::LuksUuid { source } => Self {
+                message: value.to_string(),
+                t: "LuksUuid".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "data": DkError::from(source)
+                    })
+                },
+            },
         }
     }
 }
@@ -103,12 +205,36 @@ impl From<&RunGrubError> for DkError {
 impl From<&InstallSquashfsError> for DkError {
     fn from(value: &InstallSquashfsError) -> Self {
         match value {
-            InstallSquashfsError::Extract { source, from, to } => Self {
+            InstallSquashfsError::Extract {
+                source,
+                from,
+                to,
+                kind,
+            } => Self {
                 message: value.to_string(),
                 t: "ExtractSquashfs".to_string(),
                 data: {
                     json!({
                         "stage": 3,
+                        "kind": kind.as_str(),
+                        "message": source.to_string(),
+                        "from": from.display().to_string(),
+                        "to": to.display().to_string(),
+                    })
+                },
+            },
+            InstallSquashfsError::ExtractTarball {
+                source,
+                from,
+                to,
+                kind,
+            } => Self {
+                message: value.to_string(),
+                t: "ExtractTarball".to_string(),
+                data: {
+                    json!({
+                        "stage": 3,
+                        "kind": kind.as_str(),
                         "message": source.to_string(),
                         "from": from.display().to_string(),
                         "to": to.display().to_string(),
@@ -152,6 +278,15 @@ impl From<&InstallErr> for DkError {
                     })
                 },
             },
+            InstallErr::ExtraUsersOnFirstBoot => Self {
+                message: value.to_string(),
+                t: "ExtraUsersOnFirstBoot".to_string(),
+                data: {
+                    json!({
+                        "stage": 0,
+                    })
+                },
+            },
             InstallErr::GetDirFd { source } => Self {
                 message: value.to_string(),
                 t: "GetDirFd".to_string(),
@@ -336,6 +471,16 @@ impl From<&ConfigureSystemError> for DkError {
                     })
                 },
             },
+            ConfigureSystemError::ZramConfig { source } => Self {
+                message: value.to_string(),
+                t: "ZramConfig".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
             ConfigureSystemError::SetZoneinfo { source, zone } => Self {
                 message: value.to_string(),
                 t: "SetZoneinfo".to_string(),
@@ -362,10 +507,28 @@ impl From<&ConfigureSystemError> for DkError {
                 message: value.to_string(),
                 t: "SetHostname".to_string(),
                 data: {
+                    // 保留旧版本的 kind 字段，以兼容按其判断错误的前端
+                    let kind = match source {
+                        SetHostnameError::WriteFile { source, .. } => source.kind(),
+                        SetHostnameError::InvalidHostname { .. } => io::ErrorKind::InvalidInput,
+                    };
+
                     json!({
                         "hostname": hostname.to_string(),
                         "message": source.to_string(),
-                        "kind": source.kind().to_string(),
+                        "kind": kind.to_string(),
+                        "data": DkError::from(source)
+                    })
+                },
+            },
+            ConfigureSystemError::ApplyIdentity { source, hostname } => Self {
+                message: value.to_string(),
+                t: "ApplyIdentity".to_string(),
+                data: {
+                    json!({
+                        "hostname": hostname.to_string(),
+                        "message": source.to_string(),
+                        "data": DkError::from(source)
                     })
                 },
             },
@@ -379,6 +542,36 @@ impl From<&ConfigureSystemError> for DkError {
                     })
                 },
             },
+            ConfigureSystemError::SetRootPassword { source } => Self {
+                message: value.to_string(),
+                t: "SetRootPassword".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "data": DkError::from(source)
+                    })
+                },
+            },
+            ConfigureSystemError::SetupFirstBoot { source } => Self {
+                message: value.to_string(),
+                t: "SetupFirstBoot".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            ConfigureSystemError::SetPasswordlessSudo { source } => Self {
+                message: value.to_string(),
+                t: "SetPasswordlessSudo".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "data": DkError::from(source)
+                    })
+                },
+            },
             ConfigureSystemError::SetFullName { source, fullname } => Self {
                 message: value.to_string(),
                 t: "SetFullName".to_string(),
@@ -390,6 +583,17 @@ impl From<&ConfigureSystemError> for DkError {
                     })
                 },
             },
+            ConfigureSystemError::SetKeymap { source, keymap } => Self {
+                message: value.to_string(),
+                t: "SetKeymap".to_string(),
+                data: {
+                    json!({
+                        "keymap": keymap.to_string(),
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
             ConfigureSystemError::SetLocale { source, locale } => Self {
                 message: value.to_string(),
                 t: "SetLocale".to_string(),
@@ -399,11 +603,133 @@ impl From<&ConfigureSystemError> for DkError {
                         "message": source.to_string(),
                         "data": {
                             "message": source.to_string(),
-                            "kind": source.kind().to_string(),
+                            "kind": set_locale_error_kind(source).to_string(),
+                            "error": DkError::from(source),
                         }
                     })
                 },
             },
+            ConfigureSystemError::SetBranding { source } => Self {
+                message: value.to_string(),
+                t: "SetBranding".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            ConfigureSystemError::InstallLocaleExtras { source, locale } => Self {
+                message: value.to_string(),
+                t: "InstallLocaleExtras".to_string(),
+                data: {
+                    json!({
+                        "locale": locale.to_string(),
+                        "message": source.to_string(),
+                        "data": DkError::from(source)
+                    })
+                },
+            },
+        }
+    }
+}
+
+impl From<&LocaleExtrasError> for DkError {
+    fn from(value: &LocaleExtrasError) -> Self {
+        match value {
+            LocaleExtrasError::ReadDpkgStatus { source } => Self {
+                message: value.to_string(),
+                t: "ReadDpkgStatus".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            LocaleExtrasError::InstallPackages { source } => Self {
+                message: value.to_string(),
+                t: "InstallPackages".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "data": DkError::from(source)
+                    })
+                },
+            },
+            LocaleExtrasError::Autostart { path, source } => Self {
+                message: value.to_string(),
+                t: "Autostart".to_string(),
+                data: {
+                    json!({
+                        "path": path.display().to_string(),
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+        }
+    }
+}
+
+impl From<&IdentityError> for DkError {
+    fn from(value: &IdentityError) -> Self {
+        match value {
+            IdentityError::InvalidHostname { hostname } => Self {
+                message: value.to_string(),
+                t: "InvalidHostname".to_string(),
+                data: json!({
+                    "hostname": hostname.to_string(),
+                }),
+            },
+            IdentityError::InvalidKeyType { key_type } => Self {
+                message: value.to_string(),
+                t: "InvalidKeyType".to_string(),
+                data: json!({
+                    "key_type": key_type.to_string(),
+                }),
+            },
+            IdentityError::InvalidHostKey { key_type } => Self {
+                message: value.to_string(),
+                t: "InvalidHostKey".to_string(),
+                data: json!({
+                    "key_type": key_type.to_string(),
+                }),
+            },
+            IdentityError::CheckHostKey { key_type, source } => Self {
+                message: value.to_string(),
+                t: "CheckHostKey".to_string(),
+                data: json!({
+                    "key_type": key_type.to_string(),
+                    "message": source.to_string(),
+                    "kind": source.kind().to_string(),
+                }),
+            },
+            IdentityError::InvalidAddress { value: v } => Self {
+                message: value.to_string(),
+                t: "InvalidAddress".to_string(),
+                data: json!({
+                    "value": v.to_string(),
+                }),
+            },
+            IdentityError::InvalidInterface { value: v } => Self {
+                message: value.to_string(),
+                t: "InvalidInterface".to_string(),
+                data: json!({
+                    "value": v.to_string(),
+                }),
+            },
+            IdentityError::WriteIdentityFile { path, source } => Self {
+                message: value.to_string(),
+                t: "WriteIdentityFile".to_string(),
+                data: {
+                    json!({
+                        "path": path.display().to_string(),
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
         }
     }
 }
@@ -473,27 +799,87 @@ impl From<&AddUserError> for DkError {
                     })
                 },
             },
-            AddUserError::ChpasswdStdin => Self {
+            AddUserError::InvalidUsername { username, reason } => Self {
                 message: value.to_string(),
-                t: "ChpasswdStdin".to_string(),
-                data: { json!({}) },
+                t: "InvalidUsername".to_string(),
+                data: {
+                    json!({
+                        "username": username.to_string(),
+                        "reason": reason.to_string(),
+                    })
+                },
             },
-            AddUserError::WriteChpasswdStdin { source } => Self {
+            AddUserError::InvalidUid { uid, reason } => Self {
                 message: value.to_string(),
-                t: "WriteChpasswdStdin".to_string(),
+                t: "InvalidUid".to_string(),
                 data: {
                     json!({
-                        "message": source.to_string(),
-                        "data": {
-                            "message": source.to_string(),
-                            "kind": source.kind().to_string(),
-                        }
+                        "uid": uid,
+                        "reason": reason.to_string(),
                     })
                 },
             },
-            AddUserError::FlushChpasswdStdin { source } => Self {
+            AddUserError::DuplicateUsername { username } => Self {
                 message: value.to_string(),
-                t: "FlushChpasswdStdin".to_string(),
+                t: "DuplicateUsername".to_string(),
+                data: {
+                    json!({
+                        "username": username.to_string(),
+                    })
+                },
+            },
+            AddUserError::DuplicateUid { uid } => Self {
+                message: value.to_string(),
+                t: "DuplicateUid".to_string(),
+                data: {
+                    json!({
+                        "uid": uid,
+                    })
+                },
+            },
+            AddUserError::InvalidShell { shell } => Self {
+                message: value.to_string(),
+                t: "InvalidShell".to_string(),
+                data: {
+                    json!({
+                        "shell": shell.to_string(),
+                    })
+                },
+            },
+            AddUserError::WriteSudoers { source } => Self {
+                message: value.to_string(),
+                t: "WriteSudoers".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "data": {
+                            "message": source.to_string(),
+                            "kind": source.kind().to_string(),
+                        }
+                    })
+                },
+            },
+            AddUserError::ChpasswdStdin => Self {
+                message: value.to_string(),
+                t: "ChpasswdStdin".to_string(),
+                data: { json!({}) },
+            },
+            AddUserError::WriteChpasswdStdin { source } => Self {
+                message: value.to_string(),
+                t: "WriteChpasswdStdin".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "data": {
+                            "message": source.to_string(),
+                            "kind": source.kind().to_string(),
+                        }
+                    })
+                },
+            },
+            AddUserError::FlushChpasswdStdin { source } => Self {
+                message: value.to_string(),
+                t: "FlushChpasswdStdin".to_string(),
                 data: {
                     json!({
                         "message": source.to_string(),
@@ -537,6 +923,60 @@ impl From<&SetHwclockError> for DkError {
     }
 }
 
+/// 保留旧版 SetLocale 错误中的 kind 字段
+fn set_locale_error_kind(err: &SetLocaleError) -> io::ErrorKind {
+    match err {
+        SetLocaleError::WriteLocaleConf { source }
+        | SetLocaleError::OperateLocaleGen { source } => source.kind(),
+        SetLocaleError::RunCommand {
+            source: RunCmdError::Exec { source, .. },
+        } => source.kind(),
+        SetLocaleError::RunCommand { .. } => io::ErrorKind::Other,
+    }
+}
+
+impl From<&SetLocaleError> for DkError {
+    fn from(value: &SetLocaleError) -> Self {
+        match value {
+            SetLocaleError::WriteLocaleConf { source } => Self {
+                message: value.to_string(),
+                t: "WriteLocaleConf".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "data": {
+                            "message": source.to_string(),
+                            "kind": source.kind().to_string(),
+                        }
+                    })
+                },
+            },
+            SetLocaleError::OperateLocaleGen { source } => Self {
+                message: value.to_string(),
+                t: "OperateLocaleGen".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "data": {
+                            "message": source.to_string(),
+                            "kind": source.kind().to_string(),
+                        }
+                    })
+                },
+            },
+            SetLocaleError::RunCommand { source } => Self {
+                message: value.to_string(),
+                t: "RunCommand".to_string(),
+                data: serde_json::to_value(DkError::from(source)).unwrap_or_else(|e| {
+                    json!({
+                        "message": format!("Failed to ser error message: {e}"),
+                    })
+                }),
+            },
+        }
+    }
+}
+
 impl From<&SetZoneinfoError> for DkError {
     fn from(value: &SetZoneinfoError) -> Self {
         match value {
@@ -553,6 +993,47 @@ impl From<&SetZoneinfoError> for DkError {
                     })
                 },
             },
+            SetZoneinfoError::UnexpectedLocaltime { file_type } => Self {
+                message: value.to_string(),
+                t: "UnexpectedLocaltime".to_string(),
+                data: {
+                    json!({
+                        "file_type": file_type,
+                    })
+                },
+            },
+            SetZoneinfoError::ResolveLocaltime { path, source } => Self {
+                message: value.to_string(),
+                t: "ResolveLocaltime".to_string(),
+                data: {
+                    json!({
+                        "path": path.display().to_string(),
+                        "message": source.to_string(),
+                        "data": {
+                            "message": source.to_string(),
+                            "kind": source.kind().to_string(),
+                        }
+                    })
+                },
+            },
+            SetZoneinfoError::UnknownZone { zone } => Self {
+                message: value.to_string(),
+                t: "UnknownZone".to_string(),
+                data: {
+                    json!({
+                        "zone": zone.to_string(),
+                    })
+                },
+            },
+            SetZoneinfoError::LocaltimeOutsideRoot { path } => Self {
+                message: value.to_string(),
+                t: "LocaltimeOutsideRoot".to_string(),
+                data: {
+                    json!({
+                        "path": path.display().to_string(),
+                    })
+                },
+            },
             SetZoneinfoError::Symlink { path, source } => Self {
                 message: value.to_string(),
                 t: "Symlink".to_string(),
@@ -571,6 +1052,36 @@ impl From<&SetZoneinfoError> for DkError {
     }
 }
 
+impl From<&SetHostnameError> for DkError {
+    fn from(value: &SetHostnameError) -> Self {
+        match value {
+            SetHostnameError::InvalidHostname { hostname } => Self {
+                message: value.to_string(),
+                t: "InvalidHostname".to_string(),
+                data: {
+                    json!({
+                        "hostname": hostname.to_string(),
+                    })
+                },
+            },
+            SetHostnameError::WriteFile { path, source } => Self {
+                message: value.to_string(),
+                t: "WriteFile".to_string(),
+                data: {
+                    json!({
+                        "path": path.display().to_string(),
+                        "message": source.to_string(),
+                        "data": {
+                            "message": source.to_string(),
+                            "kind": source.kind().to_string(),
+                        }
+                    })
+                },
+            },
+        }
+    }
+}
+
 impl From<&ChrootError> for DkError {
     fn from(value: &ChrootError) -> Self {
         match value {
@@ -664,6 +1175,16 @@ impl From<&SetupGenfstabError> for DkError {
                     })
                 },
             },
+            SetupGenfstabError::LuksUuid { source } => Self {
+                message: value.to_string(),
+                t: "LuksUuid".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "data": DkError::from(source)
+                    })
+                },
+            },
         }
     }
 }
@@ -699,6 +1220,69 @@ impl From<&GenfstabError> for DkError {
                     })
                 },
             },
+            GenfstabError::OperateCrypttabFile { source } => Self {
+                message: value.to_string(),
+                t: "OperateCrypttabFile".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            GenfstabError::MdadmConf { source } => Self {
+                message: value.to_string(),
+                t: "MdadmConf".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            GenfstabError::Blkid { source } => Self {
+                message: value.to_string(),
+                t: "Blkid".to_string(),
+                data: {
+                    json!({
+                        "message": source.to_string(),
+                        "kind": source.kind().to_string(),
+                    })
+                },
+            },
+            GenfstabError::PartUUID { path } => Self {
+                message: value.to_string(),
+                t: "PartUUID".to_string(),
+                data: {
+                    json!({
+                        "path": path.display().to_string()
+                    })
+                },
+            },
+            GenfstabError::MissingEntry { mount_path } => Self {
+                message: value.to_string(),
+                t: "MissingFstabEntry".to_string(),
+                data: {
+                    json!({
+                        "mount_path": mount_path.display().to_string()
+                    })
+                },
+            },
+            GenfstabError::MismatchEntry {
+                spec,
+                mount_path,
+                path,
+            } => Self {
+                message: value.to_string(),
+                t: "MismatchFstabEntry".to_string(),
+                data: {
+                    json!({
+                        "spec": spec.to_string(),
+                        "mount_path": mount_path.display().to_string(),
+                        "path": path.display().to_string()
+                    })
+                },
+            },
         }
     }
 }
@@ -720,6 +1304,15 @@ impl From<&DownloadError> for DkError {
                     })
                 },
             },
+            DownloadError::UnsupportedTarball { path } => Self {
+                message: value.to_string(),
+                t: "UnsupportedTarball".to_string(),
+                data: {
+                    json!({
+                        "path": path.display().to_string()
+                    })
+                },
+            },
             DownloadError::BuildDownloadClient { source } => Self {
                 message: value.to_string(),
                 t: "BuildDownloadClient".to_string(),
@@ -768,10 +1361,15 @@ impl From<&DownloadError> for DkError {
                     })
                 },
             },
-            DownloadError::ChecksumMismatch => Self {
+            DownloadError::ChecksumMismatch { expected, actual } => Self {
                 message: value.to_string(),
                 t: "ChecksumMismatch".to_string(),
-                data: json!({}),
+                data: {
+                    json!({
+                        "expected": expected,
+                        "actual": actual,
+                    })
+                },
             },
             DownloadError::ShutdownFile { source, path } => Self {
                 message: value.to_string(),
@@ -783,6 +1381,217 @@ impl From<&DownloadError> for DkError {
                     })
                 },
             },
+            DownloadError::NoMirrorUrl => Self {
+                message: value.to_string(),
+                t: "NoMirrorUrl".to_string(),
+                data: json!({}),
+            },
+            DownloadError::AllMirrorsFailed { source, tried } => Self {
+                message: value.to_string(),
+                t: "AllMirrorsFailed".to_string(),
+                data: {
+                    json!({
+                        "tried": tried,
+                        "message": source.to_string(),
+                        "data": DkError::from(source.as_ref())
+                    })
+                },
+            },
+        }
+    }
+}
+
+impl From<&PartitionError> for DkError {
+    fn from(value: &PartitionError) -> Self {
+        match value {
+            PartitionError::NoFreeSpace { path, start, size } => Self {
+                message: value.to_string(),
+                t: "NoFreeSpace".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "start": start,
+                    "size": size,
+                }),
+            },
+            PartitionError::PartitionOverlap { path, num } => Self {
+                message: value.to_string(),
+                t: "PartitionOverlap".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "num": num,
+                }),
+            },
+            PartitionError::NoFreeSlot { path } => Self {
+                message: value.to_string(),
+                t: "NoFreeSlot".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                }),
+            },
+            PartitionError::PartitionNotFound { path, num } => Self {
+                message: value.to_string(),
+                t: "PartitionNotFound".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "num": num,
+                }),
+            },
+            PartitionError::PartitionInUse { path, used_by } => Self {
+                message: value.to_string(),
+                t: "PartitionInUse".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "used_by": used_by.to_string(),
+                }),
+            },
+            PartitionError::ShrinkPartition { path, reason } => Self {
+                message: value.to_string(),
+                t: "ShrinkPartition".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "reason": reason.to_string(),
+                }),
+            },
+            PartitionError::ResizeFilesystem { path, err } => Self {
+                message: value.to_string(),
+                t: "ResizeFilesystem".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "message": err.to_string(),
+                    "kind": err.kind().to_string(),
+                }),
+            },
+            PartitionError::FreeSpaceTooSmall {
+                path,
+                size,
+                min_size,
+            } => Self {
+                message: value.to_string(),
+                t: "FreeSpaceTooSmall".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "size": size,
+                    "min_size": min_size,
+                }),
+            },
+            PartitionError::Cryptsetup { path, err } => Self {
+                message: value.to_string(),
+                t: "Cryptsetup".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "message": err.to_string(),
+                    "kind": err.kind().to_string(),
+                }),
+            },
+            PartitionError::WipeSignatures { path, err } => Self {
+                message: value.to_string(),
+                t: "WipeSignatures".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "message": err.to_string(),
+                    "kind": err.kind().to_string(),
+                }),
+            },
+            PartitionError::DeviceInUse {
+                path,
+                mounted_points,
+            } => Self {
+                message: value.to_string(),
+                t: "DeviceInUse".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "mounted_points": mounted_points,
+                }),
+            },
+            PartitionError::EncryptedPartitions { path, partitions } => Self {
+                message: value.to_string(),
+                t: "EncryptedPartitions".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "partitions": partitions,
+                }),
+            },
+            PartitionError::DeviceNodeTimeout { path, secs } => Self {
+                message: value.to_string(),
+                t: "DeviceNodeTimeout".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "secs": secs,
+                }),
+            },
+            PartitionError::RestoreTable { path, err } => Self {
+                message: value.to_string(),
+                t: "RestoreTable".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "message": err.to_string(),
+                    "kind": err.kind().to_string(),
+                }),
+            },
+            PartitionError::TableBackup { path, err } => Self {
+                message: value.to_string(),
+                t: "TableBackup".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "message": err.to_string(),
+                    "kind": err.kind().to_string(),
+                }),
+            },
+            PartitionError::InvalidPartitionOptions(reason) => Self {
+                message: value.to_string(),
+                t: "InvalidPartitionOptions".to_string(),
+                data: json!({
+                    "reason": reason.to_string(),
+                }),
+            },
+            PartitionError::OpenDevice { path, err } => Self {
+                message: value.to_string(),
+                t: "OpenDevice".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "message": err.to_string(),
+                    "kind": err.kind().to_string(),
+                }),
+            },
+            PartitionError::OpenDisk { path, err } => Self {
+                message: value.to_string(),
+                t: "OpenDisk".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "message": err.to_string(),
+                    "kind": err.kind().to_string(),
+                }),
+            },
+            PartitionError::GetPartitionType { path, err } => Self {
+                message: value.to_string(),
+                t: "GetPartitionType".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "message": err.to_string(),
+                    "kind": err.kind().to_string(),
+                }),
+            },
+            PartitionError::CreatePartition { path, err } => Self {
+                message: value.to_string(),
+                t: "CreatePartition".to_string(),
+                data: json!({
+                    "path": path.to_string(),
+                    "message": err.to_string(),
+                    "kind": err.kind().to_string(),
+                }),
+            },
+            PartitionError::UnsupportedTable(table) => Self {
+                message: value.to_string(),
+                t: "UnsupportedTable".to_string(),
+                data: json!({
+                    "table": table.to_string(),
+                }),
+            },
+            _ => Self {
+                message: value.to_string(),
+                t: "Partition".to_string(),
+                data: json!({}),
+            },
         }
     }
 }
@@ -790,6 +1599,25 @@ impl From<&DownloadError> for DkError {
 impl From<&SetupPartitionError> for DkError {
     fn from(value: &SetupPartitionError) -> Self {
         match value {
+            SetupPartitionError::Format {
+                source: PartitionError::ActiveSwap { path },
+            } => Self {
+                message: value.to_string(),
+                t: "ActiveSwap".to_string(),
+                data: json!({
+                    "path": path,
+                }),
+            },
+            SetupPartitionError::Format {
+                source: source @ PartitionError::Cryptsetup { .. },
+            } => Self {
+                message: value.to_string(),
+                t: "Cryptsetup".to_string(),
+                data: json!({
+                    "message": source.to_string(),
+                    "data": DkError::from(source)
+                }),
+            },
             SetupPartitionError::Format { .. } => Self {
                 message: value.to_string(),
                 t: "Format".to_string(),
@@ -805,6 +1633,14 @@ impl From<&SetupPartitionError> for DkError {
                     })
                 }),
             },
+            SetupPartitionError::ReusePartition { path, reason } => Self {
+                message: value.to_string(),
+                t: "ReusePartition".to_string(),
+                data: json!({
+                    "path": path,
+                    "reason": reason.to_string(),
+                }),
+            },
             SetupPartitionError::SwapFile { source } => Self {
                 message: value.to_string(),
                 t: "SwapFile".to_string(),
@@ -914,6 +1750,17 @@ impl From<&SwapFileError> for DkError {
                     })
                 },
             },
+            SwapFileError::BtrfsNocow { path, source } => Self {
+                message: value.to_string(),
+                t: "BtrfsNocow".to_string(),
+                data: {
+                    json!({
+                        "path": path.display().to_string(),
+                        "message": source.to_string(),
+                        "data": DkError::from(source)
+                    })
+                },
+            },
         }
     }
 }
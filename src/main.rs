@@ -2,12 +2,11 @@ use std::future::pending;
 
 use crate::server::DeploykitServer;
 use eyre::Result;
-use take_wake_lock::take_wake_lock;
 use tracing::level_filters::LevelFilter;
 use tracing::{debug, info};
 use tracing_subscriber::fmt;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
-use zbus::{connection, Connection};
+use zbus::connection;
 
 mod error;
 mod server;
@@ -36,10 +35,8 @@ async fn main() -> Result<()> {
 
     info!("Deploykit version: {}", env!("VERGEN_GIT_DESCRIBE"));
 
-    let conn = Connection::system().await?;
-    let fds = take_wake_lock(&conn).await?;
-
     let deploykit_server = DeploykitServer::default();
+    deploykit_server.set_exit_handler()?;
 
     let _conn = connection::Builder::system()?
         .name("io.aosc.Deploykit")?
@@ -50,7 +47,5 @@ async fn main() -> Result<()> {
     debug!("zbus session created");
     pending::<()>().await;
 
-    drop(fds);
-
     Ok(())
 }
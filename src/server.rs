@@ -1,4 +1,6 @@
 use std::{
+    collections::BTreeMap,
+    io::Write,
     os::unix::prelude::OwnedFd,
     path::{Path, PathBuf},
     process::exit,
@@ -11,28 +13,48 @@ use std::{
 };
 
 use disk::{
-    devices::{is_root_device, list_devices},
-    is_efi_booted,
+    devices::{
+        device_traits, is_md_device, is_on_live_medium, is_root_device, list_devices_with_md,
+        live_dm_parents, slave_disks, DeviceTraits,
+    },
+    is_dev_mode, is_efi_booted,
     partition::{
-        self, all_esp_partitions, auto_create_partitions, find_root_mount_point, is_lvm_device,
-        list_partitions, DkPartition,
+        self, all_esp_partitions, auto_create_partitions_with_progress, find_root_mount_point,
+        is_lvm_device, list_partitions, resize_partition, swapoff_active_swaps,
+        umount_partitions_on, AutoPartitionOptions, AutoPartitionStage, DataLayout, DkPartition,
+        TableSnapshot,
     },
     PartitionError,
 };
 use install::{
+    boot_stub::BootStub,
     chroot::{escape_chroot, get_dir_fd},
+    estimate::{estimate_install_duration, EstimateInput},
+    grub::{firmware_boot_file, is_valid_kernel_param},
+    hostname::{is_valid_hostname, SetHostnameError},
+    identity::Identity,
+    impact::{disk_impact, DiskImpact},
+    keymap::is_valid_keymap,
+    locale::{is_valid_locale_name, list_locales},
+    locale_extras::{default_locale_extras, LocaleExtra},
     mount::{remove_files_mounts, sync_disk, umount_root_path},
-    swap::{get_recommend_swap_size, swapoff},
-    sync_and_reboot, umount_all, DownloadType, InstallConfig, InstallConfigPrepare, InstallErr,
-    SwapFile, User,
+    os_release::check_branding,
+    overall_progress,
+    swap::{get_hibernate_swap_size, get_recommend_swap_size, swapoff},
+    sync_and_reboot, umount_all,
+    user::{check_uid, check_unique_uids, check_unique_usernames, check_username},
+    zoneinfo::list_timezones,
+    DownloadType, InstallConfig, InstallConfigPrepare, InstallErr, InstallPlan, InstallWarning,
+    InstallationStage, SwapFile, User,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use sysinfo::System;
+use tokio::{runtime::Handle, sync::watch};
 use tracing::{error, info, warn};
-use zbus::interface;
+use zbus::{fdo, interface, object_server::SignalEmitter, zvariant, Connection};
 
-use crate::error::DkError;
+use crate::{error::DkError, take_wake_lock::take_wake_lock};
 
 #[derive(Debug)]
 pub struct DeploykitServer {
@@ -41,10 +63,23 @@ pub struct DeploykitServer {
     progress_num: Arc<AtomicU8>,
     step: Arc<AtomicU8>,
     v: Arc<AtomicUsize>,
+    eta: Arc<AtomicUsize>,
     install_thread: Option<JoinHandle<()>>,
     partition_thread: Option<JoinHandle<()>>,
     cancel_run_install: Arc<AtomicBool>,
+    pause_install: Arc<AtomicBool>,
     auto_partition_progress: Arc<Mutex<AutoPartitionProgress>>,
+    resize_partition_progress: Arc<Mutex<ResizePartitionProgress>>,
+    install_env: Arc<Mutex<Option<InstallEnv>>>,
+    warnings: Arc<Mutex<Vec<InstallWarning>>>,
+    table_snapshot: Arc<Mutex<Option<TableSnapshot>>>,
+}
+
+/// Environment of the running installation, used to clean up on exit
+#[derive(Debug)]
+struct InstallEnv {
+    root_fd: OwnedFd,
+    tmp_dir: Arc<PathBuf>,
 }
 
 impl Default for DeploykitServer {
@@ -53,6 +88,7 @@ impl Default for DeploykitServer {
         let progress_num = Arc::new(AtomicU8::new(0));
         let step = Arc::new(AtomicU8::new(0));
         let v = Arc::new(AtomicUsize::new(0));
+        let eta = Arc::new(AtomicUsize::new(0));
 
         Self {
             config: InstallConfigPrepare::default(),
@@ -60,25 +96,164 @@ impl Default for DeploykitServer {
             progress_num: progress_num.clone(),
             step: step.clone(),
             v: v.clone(),
+            eta: eta.clone(),
             install_thread: None,
             partition_thread: None,
             cancel_run_install: Arc::new(AtomicBool::new(false)),
+            pause_install: Arc::new(AtomicBool::new(false)),
             auto_partition_progress: Arc::new(Mutex::new(AutoPartitionProgress::Pending)),
+            resize_partition_progress: Arc::new(Mutex::new(ResizePartitionProgress::Pending)),
+            install_env: Arc::new(Mutex::new(None)),
+            warnings: Arc::new(Mutex::new(vec![])),
+            table_snapshot: Arc::new(Mutex::new(None)),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl DeploykitServer {
+    /// Set SIGINT/SIGTERM handler
+    /// Cancel the running installation and clean up the environment before exit
+    pub fn set_exit_handler(&self) -> Result<(), ctrlc::Error> {
+        let cancel_install = self.cancel_run_install.clone();
+        let ps = self.progress.clone();
+        let install_env = self.install_env.clone();
+
+        ctrlc::set_handler(move || {
+            info!("Received exit signal");
+
+            let is_working = {
+                let ps = ps.lock().unwrap_or_else(|e| e.into_inner());
+                matches!(*ps, ProgressStatus::Working { .. })
+            };
+
+            if is_working {
+                info!("Cancelling running installation ...");
+                cancel_install.store(true, Ordering::SeqCst);
+
+                // 等待安装线程自行退出环境
+                for _ in 0..EXIT_WAIT_TIMES {
+                    if install_env
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .is_none()
+                    {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+
+            let env = install_env.lock().unwrap_or_else(|e| e.into_inner()).take();
+            if let Some(InstallEnv { root_fd, tmp_dir }) = env {
+                warn!("Installation environment is still alive, cleaning up ...");
+                exit_env(root_fd, tmp_dir);
+            }
+
+            exit(0);
+        })
+    }
+}
+
+/// 收到退出信号后等待安装线程退出的次数（每次 100ms）
+const EXIT_WAIT_TIMES: usize = 600;
+
+const OBJECT_PATH: &str = "/io/aosc/Deploykit";
+
+/// `(step, progress, velocity)` carried by the `ProgressChanged` signal, velocity is in bytes per second
+type ProgressSignal = (u8, u8, u64);
+
+/// Whether the progress changed enough to emit a `ProgressChanged` signal
+/// 速度每 10ms 都可能抖动，只有变化超过 10% 时才通知
+fn is_progress_changed(last: ProgressSignal, current: ProgressSignal) -> bool {
+    let (last_step, last_progress, last_v) = last;
+    let (step, progress, v) = current;
+
+    if step != last_step || progress != last_progress {
+        return true;
+    }
+
+    if (last_v == 0) != (v == 0) {
+        return true;
+    }
+
+    last_v.abs_diff(v) > last_v / 10
+}
+
+#[derive(Debug, Serialize)]
 #[serde(tag = "status")]
 pub enum ProgressStatus {
     Pending,
     Working {
         step: Arc<AtomicU8>,
         progress: Arc<AtomicU8>,
+        /// Velocity of downloading or extracting in bytes per second
         v: Arc<AtomicUsize>,
+        /// Estimated remaining seconds of downloading or extracting, 0 if unknown
+        eta: Arc<AtomicUsize>,
+        overall: OverallProgress,
+        plan: PlanProgress,
     },
     Error(DkError),
-    Finish,
+    Finish {
+        /// Only set if `skip_bootloader` is set
+        #[serde(skip_serializing_if = "Option::is_none")]
+        boot_stub: Option<BootStub>,
+    },
+    /// Stopped after `stop_after` stage, the target system is left mounted
+    Prepared {
+        mount_path: PathBuf,
+    },
+}
+
+/// Weighted overall progress of all steps, computed from `step` and `progress` when read
+#[derive(Debug, Clone)]
+pub struct OverallProgress {
+    step: Arc<AtomicU8>,
+    progress: Arc<AtomicU8>,
+    weights: Vec<u8>,
+}
+
+impl OverallProgress {
+    fn get(&self) -> u8 {
+        overall_progress(
+            &self.weights,
+            self.step.load(Ordering::SeqCst),
+            self.progress.load(Ordering::SeqCst),
+        )
+    }
+}
+
+impl Serialize for OverallProgress {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u8(self.get())
+    }
+}
+
+/// Planned stages and the index of the current one
+#[derive(Debug, Clone)]
+pub struct PlanProgress {
+    step: Arc<AtomicU8>,
+    plan: InstallPlan,
+}
+
+impl Serialize for PlanProgress {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("PlanProgress", 2)?;
+        s.serialize_field("stages", &self.plan.stages)?;
+        s.serialize_field(
+            "current_index",
+            &self.plan.index_of_step(self.step.load(Ordering::SeqCst)),
+        )?;
+        s.end()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -86,6 +261,290 @@ struct DkDevice {
     path: String,
     model: String,
     size: u64,
+    /// `removable`, `rotational` and `transport`, to warn before installing onto a USB drive
+    #[serde(flatten)]
+    traits: DeviceTraits,
+}
+
+/// A disk left out of the device list and why
+#[derive(Debug, Serialize)]
+struct FilteredDevice {
+    path: String,
+    model: String,
+    #[serde(flatten)]
+    reason: FilterReason,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "reason")]
+enum FilterReason {
+    /// The disk holds the partition mounted as `/`, i.e. the live medium
+    RootDevice {
+        root: String,
+    },
+    /// The disk is under the live-rw/live-base device-mapper devices of the live system
+    LiveMedium,
+    /// The disk is a member of a listed md RAID array
+    MdMember {
+        array: String,
+    },
+    ImplausibleGeometry {
+        sector_size: u64,
+        length: u64,
+    },
+}
+
+/// Why `get_list_devices` returned what it returned
+#[derive(Debug, Serialize)]
+struct DevicesDiagnostic {
+    devices: Vec<DkDevice>,
+    filtered: Vec<FilteredDevice>,
+    /// Human readable explanation if no disk can be used
+    message: Option<String>,
+}
+
+impl From<DkDevice> for types::Device {
+    fn from(value: DkDevice) -> Self {
+        Self {
+            version: types::TYPES_VERSION,
+            path: value.path,
+            model: value.model,
+            size: value.size,
+        }
+    }
+}
+
+impl From<&ProgressStatus> for types::Progress {
+    fn from(value: &ProgressStatus) -> Self {
+        match value {
+            ProgressStatus::Pending => Self::pending(),
+            ProgressStatus::Working {
+                step,
+                progress,
+                v,
+                eta,
+                overall,
+                ..
+            } => Self::working(
+                step.load(Ordering::SeqCst),
+                progress.load(Ordering::SeqCst),
+                v.load(Ordering::SeqCst) as u64,
+                eta.load(Ordering::SeqCst) as u64,
+                overall.get(),
+            ),
+            ProgressStatus::Error(e) => Self::error(e.t.clone(), e.message.clone()),
+            ProgressStatus::Finish { boot_stub: None } => Self::finish(),
+            ProgressStatus::Finish {
+                boot_stub: Some(stub),
+            } => Self::finish_with_boot_stub(
+                stub.kernel.display().to_string(),
+                stub.initrd.as_ref().map(|x| x.display().to_string()),
+                stub.cmdline.clone(),
+            ),
+            ProgressStatus::Prepared { mount_path } => {
+                Self::prepared(mount_path.display().to_string())
+            }
+        }
+    }
+}
+
+fn dk_partition_to_typed(p: &DkPartition) -> types::Partition {
+    types::Partition {
+        version: types::TYPES_VERSION,
+        path: p.path.as_ref().map(|x| x.display().to_string()),
+        parent_path: p.parent_path.as_ref().map(|x| x.display().to_string()),
+        fs_type: p.fs_type.clone(),
+        size: p.size,
+    }
+}
+
+/// Impact of the current config on every partition of the selected disks
+fn disk_impact_inner(config: &InstallConfigPrepare) -> Vec<DiskImpact> {
+    let mut disks: Vec<PathBuf> = vec![];
+
+    for p in [
+        &config.target_partition,
+        &config.efi_partition,
+        &config.data_partition,
+        &config.swap_partition,
+    ] {
+        let lock = p.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(parent) = lock.as_ref().and_then(|x| x.parent_path.as_ref()) {
+            if !disks.contains(parent) {
+                disks.push(parent.clone());
+            }
+        }
+    }
+
+    let partitions = disks
+        .into_iter()
+        .flat_map(list_partitions)
+        .collect::<Vec<_>>();
+
+    disk_impact(config, &partitions)
+}
+
+fn list_devices_inner(include_md: bool) -> Result<Vec<DkDevice>, PartitionError> {
+    Ok(scan_devices(include_md)?.0)
+}
+
+/// Usable disks and the disks filtered out
+/// `include_md` also lists mdadm RAID arrays
+fn scan_devices(include_md: bool) -> Result<(Vec<DkDevice>, Vec<FilteredDevice>), PartitionError> {
+    let mut res = vec![];
+    let mut filtered = vec![];
+    let root = find_root_mount_point().inspect_err(|e| {
+        error!("Failed to get root device: {e}");
+    })?;
+
+    // 从 U 盘启动时 livemnt 可能经由 device-mapper 挂载，需要另外排除其下的物理磁盘
+    let live_parents = live_dm_parents();
+
+    let devices = list_devices_with_md(include_md).collect::<Vec<_>>();
+
+    // 列出的 RAID 阵列的成员盘不能再单独使用
+    let md_members = devices
+        .iter()
+        .filter(|x| is_md_device(x.path()))
+        .flat_map(|x| {
+            slave_disks(x.path())
+                .into_iter()
+                .map(|member| (member, x.path().to_path_buf()))
+        })
+        .collect::<Vec<_>>();
+
+    for mut i in devices {
+        let is_root_device = is_root_device(&root, &mut i).inspect_err(|e| {
+            error!("Failed to get root device: {e}");
+        })?;
+
+        if is_root_device {
+            filtered.push(FilteredDevice {
+                path: i.path().display().to_string(),
+                model: i.model().to_string(),
+                reason: FilterReason::RootDevice { root: root.clone() },
+            });
+            continue;
+        }
+
+        if live_parents.iter().any(|x| x == i.path()) {
+            filtered.push(FilteredDevice {
+                path: i.path().display().to_string(),
+                model: i.model().to_string(),
+                reason: FilterReason::LiveMedium,
+            });
+            continue;
+        }
+
+        if let Some((_, array)) = md_members.iter().find(|(member, _)| member == i.path()) {
+            filtered.push(FilteredDevice {
+                path: i.path().display().to_string(),
+                model: i.model().to_string(),
+                reason: FilterReason::MdMember {
+                    array: array.display().to_string(),
+                },
+            });
+            continue;
+        }
+
+        let size = match device_size(i.sector_size(), i.length()) {
+            Some(size) => size,
+            None => {
+                warn!(
+                    "Skipping device {} with implausible geometry: sector size {}, length {}",
+                    i.path().display(),
+                    i.sector_size(),
+                    i.length()
+                );
+                filtered.push(FilteredDevice {
+                    path: i.path().display().to_string(),
+                    model: i.model().to_string(),
+                    reason: FilterReason::ImplausibleGeometry {
+                        sector_size: i.sector_size(),
+                        length: i.length(),
+                    },
+                });
+                continue;
+            }
+        };
+
+        res.push(DkDevice {
+            path: i.path().display().to_string(),
+            model: i.model().to_string(),
+            size,
+            traits: device_traits(i.path()),
+        });
+    }
+
+    Ok((res, filtered))
+}
+
+/// Refuse the live medium, which is filtered out of the device list but can still be passed in
+fn check_not_live_medium(path: &Path) -> Result<(), DkError> {
+    match is_on_live_medium(path) {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(DkError {
+            message: format!("{} is on the live medium", path.display()),
+            t: "LiveMedium".to_string(),
+            data: json!({
+                "path": path.display().to_string(),
+            }),
+        }),
+        Err(e) => Err(DkError::from(&e)),
+    }
+}
+
+/// Explain an empty device list
+fn explain_empty_devices(devices: &[DkDevice], filtered: &[FilteredDevice]) -> Option<String> {
+    if !devices.is_empty() {
+        return None;
+    }
+
+    let res = match filtered {
+        [] => "No SATA, NVMe or SD card disk is found".to_string(),
+        [FilteredDevice {
+            path,
+            reason: FilterReason::RootDevice { root },
+            ..
+        }] => format!(
+            "The only disk {path} is the boot device of the live system ({root} is mounted as /), \
+            boot the installer from another medium to install onto it"
+        ),
+        _ => {
+            let reasons = filtered
+                .iter()
+                .map(|x| match &x.reason {
+                    FilterReason::RootDevice { .. } | FilterReason::LiveMedium => {
+                        format!("{} is the boot device", x.path)
+                    }
+                    FilterReason::MdMember { array } => {
+                        format!("{} is a member of {array}", x.path)
+                    }
+                    FilterReason::ImplausibleGeometry { .. } => {
+                        format!("{} reports an implausible size", x.path)
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            format!("No disk can be used: {}", reasons.join(", "))
+        }
+    };
+
+    Some(res)
+}
+
+/// 大于此值的磁盘大小视为 libparted 返回了错误的几何信息
+const MAX_DEVICE_SIZE: u64 = 1 << 60;
+
+/// Size of a device in bytes, `None` if the geometry is implausible
+fn device_size(sector_size: u64, length: u64) -> Option<u64> {
+    if !sector_size.is_power_of_two() || !(512..=65536).contains(&sector_size) || length == 0 {
+        return None;
+    }
+
+    sector_size
+        .checked_mul(length)
+        .filter(|size| *size <= MAX_DEVICE_SIZE)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -131,9 +590,25 @@ impl Message {
 #[serde(tag = "status")]
 pub enum AutoPartitionProgress {
     Pending,
+    /// Discarding the whole disk before partitioning, see `discard` of `auto_partition`
+    Discarding,
     Working,
     Finish {
         res: Result<(Option<DkPartition>, DkPartition), PartitionError>,
+        /// Backup of the previous partition table, see `restore_partition_table`
+        backup: Option<PathBuf>,
+        /// LVM logical volumes on the disk removed before repartitioning
+        removed_lvm: Vec<String>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status")]
+pub enum ResizePartitionProgress {
+    Pending,
+    Working,
+    Finish {
+        res: Result<DkPartition, PartitionError>,
     },
 }
 
@@ -148,19 +623,73 @@ impl DeploykitServer {
                 "timezone" => Message::check_is_set(field, &self.config.timezone),
                 "download" => Message::check_is_set(field, &self.config.download),
                 "user" => Message::check_is_set(field, &self.config.user),
+                "extra_users" => Message::ok(&self.config.extra_users),
                 "hostname" => Message::check_is_set(field, &self.config.hostname),
+                "keymap" => Message::check_is_set(field, &self.config.keymap),
+                "kernel_cmdline" => Message::ok(&self.config.kernel_cmdline),
                 "rtc_as_localtime" => Message::ok(&self.config.rtc_as_localtime.to_string()),
+                "strict_configure" => Message::ok(&self.config.strict_configure.to_string()),
+                "list_md_devices" => Message::ok(&self.config.list_md_devices.to_string()),
+                "passwordless_sudo" => Message::ok(&self.config.passwordless_sudo.to_string()),
+                "dry_run" => Message::ok(&self.config.dry_run.to_string()),
+                "user_on_first_boot" => Message::ok(&self.config.user_on_first_boot.to_string()),
+                "allow_no_bootloader" => Message::ok(&self.config.allow_no_bootloader.to_string()),
+                "skip_bootloader" => Message::ok(&self.config.skip_bootloader.to_string()),
+                "extra_locales" => Message::ok(&self.config.extra_locales),
+                "install_locale_extras" => {
+                    Message::ok(&self.config.install_locale_extras.to_string())
+                }
+                "locale_extras" => Message::ok(&self.config.locale_extras),
+                "identity" => Message::check_is_set(field, &self.config.identity),
+                // 不回显口令，只报告是否启用加密
+                "encrypt" => Message::ok(&self.config.encrypt.is_some().to_string()),
                 "target_partition" => Message::check_is_set(field, {
-                    let lock = self.config.target_partition.lock().unwrap();
+                    let lock = self
+                        .config
+                        .target_partition
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner());
 
                     &lock.clone()
                 }),
                 "efi_partition" => {
-                    let lock = self.config.efi_partition.lock().unwrap();
+                    let lock = self
+                        .config
+                        .efi_partition
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner());
 
                     Message::check_is_set(field, &lock.clone())
                 }
                 "swapfile" => Message::ok(&self.config.swapfile),
+                "hibernation" => {
+                    Message::ok(&(self.config.swapfile == SwapFile::Hibernate).to_string())
+                }
+                "branding" => Message::ok(&self.config.branding),
+                "stop_after" => Message::check_is_set(field, &self.config.stop_after),
+                "data_layout" => Message::check_is_set(field, &self.config.data_layout),
+                "root_fs" | "auto_partition_fs" => {
+                    Message::check_is_set(field, &self.config.root_fs)
+                }
+                "efi_size" => Message::check_is_set(field, &self.config.efi_size),
+                "data_partition" => {
+                    let lock = self
+                        .config
+                        .data_partition
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner());
+
+                    Message::check_is_set(field, &lock.clone())
+                }
+                "swap_partition" => {
+                    let lock = self
+                        .config
+                        .swap_partition
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner());
+
+                    Message::check_is_set(field, &lock.clone())
+                }
                 _ => {
                     error!("Unknown field: {field}");
                     Message::err(format!("Unknown field: {field}"))
@@ -179,8 +708,21 @@ impl DeploykitServer {
         }
     }
 
+    /// Emitted when the installation advances a stage or its progress or velocity changes
+    /// `velocity` is in bytes per second
+    #[zbus(signal)]
+    async fn progress_changed(
+        emitter: &SignalEmitter<'_>,
+        step: u8,
+        progress: u8,
+        velocity: u64,
+    ) -> zbus::Result<()>;
+
+    /// Prefer subscribing to the `ProgressChanged` signal over polling this method
+    /// `v` of the `Working` status is the velocity in bytes per second,
+    /// `eta` is the estimated remaining seconds of the current step, 0 if unknown
     fn get_progress(&self) -> String {
-        let ps = self.progress.lock().unwrap();
+        let ps = self.progress.lock().unwrap_or_else(|e| e.into_inner());
         Message::ok(&*ps)
     }
 
@@ -189,35 +731,138 @@ impl DeploykitServer {
         Message::ok(&"")
     }
 
-    fn get_list_devices(&self) -> String {
-        let mut res = vec![];
-        let root = match find_root_mount_point() {
-            Ok(v) => v,
+    /// Save the whole config as JSON to `path`, to replay it with `import_config` later
+    /// The file contains user passwords and the encryption passphrase, it is only readable by root
+    fn export_config(&self, path: &str) -> String {
+        match export_config_inner(&self.config, Path::new(path)) {
+            Ok(()) => Message::ok(&""),
             Err(e) => {
-                error!("Failed to get root device: {e}");
-                return Message::err(e);
+                error!("Failed to export config: {e}");
+                Message::err(e)
+            }
+        }
+    }
+
+    /// Replace the config with one saved by `export_config`, missing values are reset to default
+    /// Returns the partition fields cleared because the partitions are not on the disks any more,
+    /// they need to be chosen again
+    fn import_config(&mut self, path: &str) -> String {
+        if self
+            .install_env
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+        {
+            return Message::err(DkError {
+                message: "Installation is running".to_string(),
+                t: "InstallInProgress".to_string(),
+                data: json!({}),
+            });
+        }
+
+        match import_config_inner(Path::new(path)) {
+            Ok((config, cleared)) => {
+                self.config = config;
+                Message::ok(&cleared)
+            }
+            Err(e) => {
+                error!("Failed to import config: {e}");
+                Message::err(e)
+            }
+        }
+    }
+
+    /// Check that every value required by `start_install` is set
+    /// Returns an error listing all missing values, without starting the installation
+    fn validate_config(&self) -> String {
+        let missing = self.config.missing_values();
+
+        if missing.is_empty() {
+            return Message::ok(&"");
+        }
+
+        let missing = missing.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+
+        Message::err(DkError {
+            message: format!("Value is not set: {}", missing.join(", ")),
+            t: "ValueNotSet".to_string(),
+            data: json!({
+                "stage": 0,
+                "missing": missing,
+            }),
+        })
+    }
+
+    fn get_install_plan(&self) -> String {
+        Message::ok(
+            &InstallPlan::new(self.config.download.as_ref())
+                .with_stop_after(self.config.stop_after.as_ref()),
+        )
+    }
+
+    fn get_disk_impact(&self) -> String {
+        Message::ok(&disk_impact_inner(&self.config))
+    }
+
+    fn estimate_install_duration(&self, input: &str) -> String {
+        let input = match serde_json::from_str::<EstimateInput>(input) {
+            Ok(input) => input,
+            Err(e) => {
+                return Message::err(DkError {
+                    message: e.to_string(),
+                    t: "InvalidEstimateInput".to_string(),
+                    data: json!({
+                        "input": input.to_string(),
+                    }),
+                })
             }
         };
 
-        for mut i in list_devices() {
-            let is_root_device = match is_root_device(&root, &mut i) {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("Failed to get root device: {e}");
-                    return Message::err(e);
-                }
-            };
+        let plan = InstallPlan::new(self.config.download.as_ref())
+            .with_stop_after(self.config.stop_after.as_ref());
 
-            if !is_root_device {
-                res.push(DkDevice {
-                    path: i.path().display().to_string(),
-                    model: i.model().to_string(),
-                    size: i.sector_size() * i.length(),
-                });
+        Message::ok(&estimate_install_duration(&plan, &input))
+    }
+
+    fn get_warnings(&self) -> String {
+        let warnings = self.warnings.lock().unwrap_or_else(|e| e.into_inner());
+        Message::ok(&*warnings)
+    }
+
+    /// Prefer subscribing to the `ProgressChanged` signal over polling this method
+    fn get_progress2(&self) -> types::Progress {
+        let ps = self.progress.lock().unwrap_or_else(|e| e.into_inner());
+        types::Progress::from(&*ps)
+    }
+
+    fn get_list_devices(&self) -> String {
+        match list_devices_inner(self.config.list_md_devices) {
+            Ok(res) => Message::ok(&res),
+            Err(e) => Message::err(e),
+        }
+    }
+
+    /// Devices filtered out of `get_list_devices` and why, useful when the list is empty
+    fn get_list_devices_diagnostic(&self) -> String {
+        match scan_devices(self.config.list_md_devices) {
+            Ok((devices, filtered)) => {
+                let message = explain_empty_devices(&devices, &filtered);
+
+                Message::ok(&DevicesDiagnostic {
+                    devices,
+                    filtered,
+                    message,
+                })
             }
+            Err(e) => Message::err(e),
         }
+    }
 
-        Message::ok(&res)
+    fn get_list_devices2(&self) -> fdo::Result<Vec<types::Device>> {
+        let res = list_devices_inner(self.config.list_md_devices)
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        Ok(res.into_iter().map(types::Device::from).collect())
     }
 
     fn get_list_partitions(&self, dev: &str) -> String {
@@ -227,6 +872,15 @@ impl DeploykitServer {
         Message::ok(&res)
     }
 
+    fn get_list_partitions2(&self, dev: &str) -> Vec<types::Partition> {
+        let path = PathBuf::from(dev);
+
+        list_partitions(path)
+            .iter()
+            .map(dk_partition_to_typed)
+            .collect()
+    }
+
     fn get_all_esp_partitions(&self) -> String {
         match all_esp_partitions() {
             Ok(res) => Message::ok(&res),
@@ -234,48 +888,207 @@ impl DeploykitServer {
         }
     }
 
-    fn auto_partition(&mut self, dev: &str) -> String {
-        let path = if cfg!(debug_assertions) {
+    /// `options` is a JSON encoded `AutoPartitionOptions`, an empty string means default options
+    /// The `root_fs` and `efi_size` configs are used if `root_fs_type` and `efi_size` are not
+    /// given in `options`, so is the size of `swapfile` set to `Partition` for `swap_size`
+    /// `discard` discards (TRIM) the whole disk first if it supports discard, it can not be
+    /// used with `free_space_only`, and no partition table backup is kept for undoing
+    /// Disks holding LUKS or BitLocker partitions are refused unless `force` is set in `options`
+    async fn auto_partition(
+        &mut self,
+        #[zbus(connection)] conn: &Connection,
+        dev: &str,
+        options: &str,
+        discard: bool,
+    ) -> String {
+        let path = if is_dev_mode() {
             PathBuf::from("/dev/loop30")
         } else {
             PathBuf::from(dev)
         };
 
+        if !is_dev_mode() {
+            if let Err(e) = check_not_live_medium(&path) {
+                return Message::err(e);
+            }
+        }
+
+        // 未在选项中指定文件系统、ESP 与 swap 分区大小时沿用 root_fs、efi_size、swapfile 配置
+        let given = serde_json::from_str::<Value>(options).ok();
+        let has_root_fs_type = given
+            .as_ref()
+            .is_some_and(|x| x.get("root_fs_type").is_some());
+        let has_efi_size = given.as_ref().is_some_and(|x| x.get("efi_size").is_some());
+        let has_swap_size = given.as_ref().is_some_and(|x| x.get("swap_size").is_some());
+
+        let mut options = if options.is_empty() {
+            AutoPartitionOptions::default()
+        } else {
+            match serde_json::from_str::<AutoPartitionOptions>(options) {
+                Ok(options) => options,
+                Err(e) => {
+                    return Message::err(DkError {
+                        message: e.to_string(),
+                        t: "InvalidAutoPartitionOptions".to_string(),
+                        data: json!({
+                            "options": options.to_string(),
+                        }),
+                    })
+                }
+            }
+        };
+
+        if !has_root_fs_type {
+            if let Some(ref root_fs) = self.config.root_fs {
+                options.root_fs_type.clone_from(root_fs);
+            }
+        }
+
+        if !has_efi_size {
+            if let Some(efi_size) = self.config.efi_size {
+                options.efi_size = efi_size;
+            }
+        }
+
+        if !has_swap_size {
+            if let SwapFile::Partition(size) = self.config.swapfile {
+                options.swap_size = Some(size);
+            }
+        }
+
+        if discard {
+            options.discard = true;
+        }
+
+        // 先按 home_split 生成 /home 分区布局，以便记录到 data_layout
+        if let Err(e) = options.resolve_home_split(&path, is_efi_booted()) {
+            return Message::err(DkError {
+                message: e.to_string(),
+                t: "InvalidAutoPartitionOptions".to_string(),
+                data: json!({
+                    "options": serde_json::to_string(&options).unwrap_or_default(),
+                }),
+            });
+        }
+
+        // 未在选项中指定数据分区时沿用 data_layout 配置
+        if options.data.is_none() {
+            options.data.clone_from(&self.config.data_layout);
+        }
+
+        if let Err(e) = options.check() {
+            return Message::err(DkError {
+                message: e.to_string(),
+                t: "InvalidAutoPartitionOptions".to_string(),
+                data: json!({
+                    "options": serde_json::to_string(&options).unwrap_or_default(),
+                }),
+            });
+        }
+
+        // 数据分区需在安装时挂载，记录实际使用的布局
+        self.config.data_layout.clone_from(&options.data);
+
         let efi_arc = self.config.efi_partition.clone();
         let target_part = self.config.target_partition.clone();
+        let data_arc = self.config.data_partition.clone();
+        let swap_arc = self.config.swap_partition.clone();
+        let snapshot_arc = self.table_snapshot.clone();
 
         {
-            let mut lock = self.auto_partition_progress.lock().unwrap();
+            let mut lock = self
+                .auto_partition_progress
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
             *lock = AutoPartitionProgress::Working;
         }
 
         let auto_partition_progress = self.auto_partition_progress.clone();
+        let wake_lock = take_wake_lock_or_warn(conn).await;
 
         self.partition_thread = Some(thread::spawn(move || {
-            let p = auto_create_partitions(&path);
+            // 分区结束后释放唤醒锁
+            let _wake_lock = wake_lock;
+
+            // 保存原分区表，以便安装后撤销
+            // 整盘 discard 后原有数据已不可恢复，恢复分区表毫无意义，故不保存
+            let backup = {
+                let mut lock = snapshot_arc.lock().unwrap_or_else(|e| e.into_inner());
+                *lock = if options.discard {
+                    None
+                } else {
+                    TableSnapshot::capture(&path)
+                        .inspect_err(|e| warn!("Failed to snapshot partition table: {e}"))
+                        .ok()
+                };
+
+                // 同时写入文件，以便前端提供撤销操作
+                lock.as_ref().and_then(|snapshot| {
+                    snapshot
+                        .save_backup()
+                        .inspect_err(|e| warn!("Failed to back up partition table: {e}"))
+                        .ok()
+                })
+            };
+
+            let progress = |stage| {
+                let mut lock = auto_partition_progress
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner());
+                *lock = match stage {
+                    AutoPartitionStage::Discard => AutoPartitionProgress::Discarding,
+                    AutoPartitionStage::Partition => AutoPartitionProgress::Working,
+                };
+            };
+
+            let p = auto_create_partitions_with_progress(&path, &options, progress).map(|res| {
+                {
+                    let mut lock = data_arc.lock().unwrap_or_else(|e| e.into_inner());
+                    *lock = res.data;
+                }
+
+                {
+                    let mut lock = swap_arc.lock().unwrap_or_else(|e| e.into_inner());
+                    *lock = res.swap;
+                }
+
+                (res.efi, res.system, res.removed_lvm)
+            });
 
             match p {
-                Ok((efi, p)) => {
+                Ok((efi, p, removed_lvm)) => {
                     {
-                        let mut lock = efi_arc.lock().unwrap();
+                        let mut lock = efi_arc.lock().unwrap_or_else(|e| e.into_inner());
                         lock.clone_from(&efi);
                     }
 
                     {
-                        let mut lock = target_part.lock().unwrap();
+                        let mut lock = target_part.lock().unwrap_or_else(|e| e.into_inner());
                         *lock = Some(p.clone());
                     }
 
                     {
-                        let mut lock = auto_partition_progress.lock().unwrap();
-                        *lock = AutoPartitionProgress::Finish { res: Ok((efi, p)) };
+                        let mut lock = auto_partition_progress
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner());
+                        *lock = AutoPartitionProgress::Finish {
+                            res: Ok((efi, p)),
+                            backup,
+                            removed_lvm,
+                        };
                     }
                 }
                 Err(e) => {
                     error!("Failed to auto partition: {e}");
                     {
-                        let mut lock = auto_partition_progress.lock().unwrap();
-                        *lock = AutoPartitionProgress::Finish { res: Err(e) };
+                        let mut lock = auto_partition_progress
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner());
+                        *lock = AutoPartitionProgress::Finish {
+                            res: Err(e),
+                            backup,
+                            removed_lvm: vec![],
+                        };
                     }
                 }
             }
@@ -285,48 +1098,83 @@ impl DeploykitServer {
     }
 
     fn get_auto_partition_progress(&self) -> String {
-        let ps = self.auto_partition_progress.lock().unwrap();
+        let ps = self
+            .auto_partition_progress
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
 
         match &*ps {
-            AutoPartitionProgress::Finish { res } => match res {
+            AutoPartitionProgress::Finish { res, .. } => match res {
                 Ok(_) => Message::ok(&*ps),
                 Err(e) => Message::err(DkError {
                     message: e.to_string(),
                     t: "AutoPartition".to_string(),
-                    // TODO
-                    data: json!({}),
+                    data: serde_json::to_value(DkError::from(e)).unwrap_or_else(|e| {
+                        json!({
+                            "message": format!("Failed to ser error message: {e}"),
+                        })
+                    }),
                 }),
             },
             _ => Message::ok(&*ps),
         }
     }
 
-    fn start_install(&mut self) -> String {
+    async fn start_install(&mut self, #[zbus(connection)] conn: &Connection) -> String {
         {
-            let ps = self.progress.lock().unwrap();
+            let ps = self.progress.lock().unwrap_or_else(|e| e.into_inner());
             if let ProgressStatus::Working { .. } = *ps {
                 return Message::err("Another installation is working.");
             }
         }
 
+        let wake_lock = take_wake_lock_or_warn(conn).await;
+
+        self.warnings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+
+        // 上一次安装遗留的暂停状态不应影响本次安装
+        self.pause_install.store(false, Ordering::SeqCst);
+
         match start_install_inner(
             self.config.clone(),
             self.step.clone(),
             self.progress_num.clone(),
             self.v.clone(),
+            self.eta.clone(),
             self.progress.clone(),
             self.cancel_run_install.clone(),
+            self.pause_install.clone(),
+            self.install_env.clone(),
+            wake_lock,
+            self.warnings.clone(),
+            conn.clone(),
         ) {
             Ok(j) => self.install_thread = Some(j),
             Err(e) => return Message::err(e),
         }
 
         {
-            let mut ps = self.progress.lock().unwrap();
+            let mut ps = self.progress.lock().unwrap_or_else(|e| e.into_inner());
+            let plan = InstallPlan::new(self.config.download.as_ref())
+                .with_stop_after(self.config.stop_after.as_ref());
+
             *ps = ProgressStatus::Working {
                 step: self.step.clone(),
                 progress: self.progress_num.clone(),
                 v: self.v.clone(),
+                eta: self.eta.clone(),
+                overall: OverallProgress {
+                    step: self.step.clone(),
+                    progress: self.progress_num.clone(),
+                    weights: plan.weights(),
+                },
+                plan: PlanProgress {
+                    step: self.step.clone(),
+                    plan,
+                },
             };
         }
 
@@ -334,22 +1182,185 @@ impl DeploykitServer {
     }
 
     fn reset_progress_status(&mut self) -> String {
-        let mut ps = self.progress.lock().unwrap();
+        let mut ps = self.progress.lock().unwrap_or_else(|e| e.into_inner());
         *ps = ProgressStatus::Pending;
 
         Message::ok(&"")
     }
 
-    fn cancel_install(&mut self) -> String {
+    /// Pause the installation before its next stage, the running stage is not interrupted
+    fn pause_install(&mut self) -> String {
         if self.install_thread.is_some() {
-            self.cancel_run_install.store(true, Ordering::SeqCst);
+            self.pause_install.store(true, Ordering::SeqCst);
         }
 
         Message::ok(&"")
     }
 
-    fn get_recommend_swap_size(&self) -> String {
-        let mut sys = System::new_all();
+    fn resume_install(&mut self) -> String {
+        self.pause_install.store(false, Ordering::SeqCst);
+
+        Message::ok(&"")
+    }
+
+    fn cancel_install(&mut self) -> String {
+        if self.install_thread.is_some() {
+            self.cancel_run_install.store(true, Ordering::SeqCst);
+        }
+
+        Message::ok(&"")
+    }
+
+    /// Best-effort rollback of the last install, only valid before reboot
+    /// Umount the target disk and restore the partition table captured before auto partitioning
+    fn undo_last_install(&mut self) -> String {
+        let err = |message: &str, t: &str| {
+            Message::err(DkError {
+                message: message.to_string(),
+                t: t.to_string(),
+                data: json!({}),
+            })
+        };
+
+        if self
+            .install_env
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+        {
+            return err("Installation is running", "InstallInProgress");
+        }
+
+        if self
+            .partition_thread
+            .as_ref()
+            .map(|t| !t.is_finished())
+            .unwrap_or(false)
+        {
+            return err("Auto partitioning is running", "AutoPartitionInProgress");
+        }
+
+        let snapshot = {
+            let lock = self
+                .table_snapshot
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+
+            match lock.clone() {
+                Some(snapshot) => snapshot,
+                None => return err("No partition table snapshot", "NoTableSnapshot"),
+            }
+        };
+
+        let path = &snapshot.device_path;
+        info!("Restoring partition table of {}", path.display());
+
+        if let Err(e) = swapoff_active_swaps(path)
+            .and_then(|_| umount_partitions_on(path))
+            .and_then(|_| snapshot.restore())
+        {
+            error!("Failed to undo last install: {e}");
+            return Message::err(DkError {
+                message: e.to_string(),
+                t: "UndoInstall".to_string(),
+                data: json!({
+                    "path": path.display().to_string(),
+                }),
+            });
+        }
+
+        self.table_snapshot
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+
+        // 分区已不存在，需要重新选择
+        self.config.target_partition = Arc::new(Mutex::new(None));
+        self.config.efi_partition = Arc::new(Mutex::new(None));
+        self.config.data_partition = Arc::new(Mutex::new(None));
+        self.config.swap_partition = Arc::new(Mutex::new(None));
+
+        {
+            let mut ps = self.progress.lock().unwrap_or_else(|e| e.into_inner());
+            *ps = ProgressStatus::Pending;
+        }
+
+        {
+            let mut ps = self
+                .auto_partition_progress
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            *ps = AutoPartitionProgress::Pending;
+        }
+
+        Message::ok(&"")
+    }
+
+    /// Write a partition table backup reported by `get_auto_partition_progress` back to `dev`
+    /// and re-read the partition table, the data overwritten by mkfs is not restored
+    fn restore_partition_table(&mut self, dev: &str, backup_path: &str) -> String {
+        let err = |message: &str, t: &str| {
+            Message::err(DkError {
+                message: message.to_string(),
+                t: t.to_string(),
+                data: json!({}),
+            })
+        };
+
+        if self
+            .install_env
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+        {
+            return err("Installation is running", "InstallInProgress");
+        }
+
+        if self
+            .partition_thread
+            .as_ref()
+            .map(|t| !t.is_finished())
+            .unwrap_or(false)
+        {
+            return err("Auto partitioning is running", "AutoPartitionInProgress");
+        }
+
+        let path = Path::new(dev);
+        info!(
+            "Restoring partition table of {} from {backup_path}",
+            path.display()
+        );
+
+        if let Err(e) =
+            TableSnapshot::load_backup(Path::new(backup_path), path).and_then(|snapshot| {
+                swapoff_active_swaps(path)
+                    .and_then(|_| umount_partitions_on(path))
+                    .and_then(|_| snapshot.restore())
+            })
+        {
+            error!("Failed to restore partition table: {e}");
+            return Message::err(DkError::from(&e));
+        }
+
+        // 分区已不存在，需要重新选择
+        self.config.target_partition = Arc::new(Mutex::new(None));
+        self.config.efi_partition = Arc::new(Mutex::new(None));
+        self.config.data_partition = Arc::new(Mutex::new(None));
+        self.config.swap_partition = Arc::new(Mutex::new(None));
+
+        {
+            let mut ps = self
+                .auto_partition_progress
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            *ps = AutoPartitionProgress::Pending;
+        }
+
+        Message::ok(&"")
+    }
+
+    fn get_recommend_swap_size(&self) -> String {
+        let mut sys = System::new_all();
         sys.refresh_memory();
         let total_memory = sys.total_memory();
         let size = get_recommend_swap_size(total_memory);
@@ -357,6 +1368,35 @@ impl DeploykitServer {
         Message::ok(&size)
     }
 
+    /// Like `get_recommend_swap_size`, the size needed to hibernate if `hibernate` is set
+    fn get_recommend_swap_size2(&self, hibernate: bool) -> String {
+        let mut sys = System::new_all();
+        sys.refresh_memory();
+        let total_memory = sys.total_memory();
+        let size = match hibernate {
+            true => get_hibernate_swap_size(total_memory),
+            false => get_recommend_swap_size(total_memory),
+        };
+
+        Message::ok(&size)
+    }
+
+    /// Locales accepted by `set_config("locale", ...)`, e.g. `en_US.UTF-8`
+    fn list_locales(&self) -> String {
+        match list_locales() {
+            Ok(res) => Message::ok(&res),
+            Err(e) => Message::err(e.to_string()),
+        }
+    }
+
+    /// Timezones accepted by `set_config("timezone", ...)`, e.g. `Asia/Shanghai`
+    fn list_timezones(&self) -> String {
+        match list_timezones() {
+            Ok(res) => Message::ok(&res),
+            Err(e) => Message::err(e.to_string()),
+        }
+    }
+
     fn get_memory(&self) -> String {
         let mut sys = System::new_all();
         sys.refresh_memory();
@@ -380,6 +1420,145 @@ impl DeploykitServer {
         }
     }
 
+    fn list_esp_partitions(&self, dev: &str) -> String {
+        let path = Path::new(dev);
+
+        match partition::list_esp_partitions(path) {
+            Ok(res) => Message::ok(&res),
+            Err(e) => Message::err(e),
+        }
+    }
+
+    /// Unallocated regions of `dev`, `start_lba` and `size_bytes` can be passed to `create_partition`
+    fn get_free_space(&self, dev: &str) -> String {
+        let path = Path::new(dev);
+
+        match partition::get_free_space(path) {
+            Ok(res) => Message::ok(&res),
+            Err(e) => Message::err(e),
+        }
+    }
+
+    /// Create and format a partition in the free space of `dev`, `start` and `size` are in bytes
+    /// `flags` may contain `esp` and `boot`
+    fn create_partition(
+        &self,
+        dev: &str,
+        start: u64,
+        size: u64,
+        fs_type: &str,
+        flags: Vec<String>,
+    ) -> String {
+        let path = Path::new(dev);
+
+        match partition::create_partition(path, start, size, fs_type, &flags) {
+            Ok(p) => Message::ok(&p),
+            Err(e) => {
+                error!("Failed to create partition on {dev}: {e}");
+                Message::err(DkError::from(&e))
+            }
+        }
+    }
+
+    /// Delete partition `num` of `dev`, returns the remaining partitions of `dev`
+    fn delete_partition(&self, dev: &str, num: u32) -> String {
+        let path = PathBuf::from(dev);
+
+        match partition::delete_partition(&path, num) {
+            Ok(()) => Message::ok(&list_partitions(path)),
+            Err(e) => {
+                error!("Failed to delete partition {num} of {dev}: {e}");
+                Message::err(DkError {
+                    message: e.to_string(),
+                    t: "DeletePartition".to_string(),
+                    data: json!({
+                        "path": dev.to_string(),
+                        "num": num,
+                        "data": DkError::from(&e),
+                    }),
+                })
+            }
+        }
+    }
+
+    /// Shrink the partition at `path` and its filesystem to `new_size` bytes
+    /// Only unmounted ext2/3/4 and NTFS partitions are supported
+    fn shrink_partition(&self, path: &str, new_size: u64) -> String {
+        match partition::shrink_partition(Path::new(path), new_size) {
+            Ok(p) => Message::ok(&p),
+            Err(e) => {
+                error!("Failed to shrink {path}: {e}");
+                Message::err(DkError::from(&e))
+            }
+        }
+    }
+
+    /// Shrink partition `num` of `dev` and its filesystem to `new_size` bytes in the background
+    /// Shrinking a filesystem may take minutes, poll `get_resize_partition_progress` for the result
+    async fn resize_partition(
+        &mut self,
+        #[zbus(connection)] conn: &Connection,
+        dev: &str,
+        num: u32,
+        new_size: u64,
+    ) -> String {
+        if self
+            .partition_thread
+            .as_ref()
+            .is_some_and(|t| !t.is_finished())
+        {
+            return Message::err(DkError {
+                message: "Another partitioning is running".to_string(),
+                t: "PartitionInProgress".to_string(),
+                data: json!({}),
+            });
+        }
+
+        {
+            let mut lock = self
+                .resize_partition_progress
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            *lock = ResizePartitionProgress::Working;
+        }
+
+        let path = PathBuf::from(dev);
+        let resize_partition_progress = self.resize_partition_progress.clone();
+        let wake_lock = take_wake_lock_or_warn(conn).await;
+
+        self.partition_thread = Some(thread::spawn(move || {
+            // 调整结束后释放唤醒锁
+            let _wake_lock = wake_lock;
+
+            let res = resize_partition(&path, num, new_size);
+            if let Err(ref e) = res {
+                error!(
+                    "Failed to resize partition {num} of {}: {e}",
+                    path.display()
+                );
+            }
+
+            let mut lock = resize_partition_progress
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            *lock = ResizePartitionProgress::Finish { res };
+        }));
+
+        Message::ok(&"")
+    }
+
+    fn get_resize_partition_progress(&self) -> String {
+        let ps = self
+            .resize_partition_progress
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        match &*ps {
+            ResizePartitionProgress::Finish { res: Err(e) } => Message::err(DkError::from(e)),
+            _ => Message::ok(&*ps),
+        }
+    }
+
     fn disk_is_right_combo(&self, dev: &str) -> String {
         let path = Path::new(dev);
         let res = disk::right_combine(path);
@@ -406,6 +1585,15 @@ impl DeploykitServer {
         Message::ok(&is_efi_booted())
     }
 
+    /// Loader path on the ESP the firmware falls back to, e.g. /EFI/BOOT/BOOTAA64.EFI
+    fn get_firmware_boot_file(&self) -> String {
+        let path = firmware_boot_file()
+            .filter(|_| is_efi_booted())
+            .map(|p| Path::new("/").join(p));
+
+        Message::ok(&path)
+    }
+
     fn sync_disk(&self) -> String {
         sync_disk();
 
@@ -421,16 +1609,218 @@ impl DeploykitServer {
         }
     }
 
+    /// Whether any LVM logical volume is on the disk or partition
     fn is_lvm_device(&self, p: &str) -> String {
         let res = is_lvm_device(Path::new(p));
 
         match res {
-            Ok(v) => Message::ok(&v),
+            Ok(v) => Message::ok(&!v.is_empty()),
             Err(e) => Message::err(e.to_string()),
         }
     }
 }
 
+fn export_config_inner(config: &InstallConfigPrepare, path: &Path) -> Result<(), DkError> {
+    let err = |message: String| DkError {
+        message,
+        t: "ExportConfig".to_string(),
+        data: json!({
+            "path": path.display().to_string(),
+        }),
+    };
+
+    let content = serde_json::to_string_pretty(config)
+        .map_err(|e| err(format!("Failed to serialize config: {e}")))?;
+
+    // 配置中有用户密码和加密口令，只允许 root 读取
+    // 临时文件的权限为 0600，替换后已存在的文件也不会保留原有权限
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut f = tempfile::NamedTempFile::new_in(dir)
+        .map_err(|e| err(format!("Failed to create {}: {e}", path.display())))?;
+
+    f.write_all(content.as_bytes())
+        .map_err(|e| err(format!("Failed to write {}: {e}", path.display())))?;
+
+    f.persist(path)
+        .map_err(|e| err(format!("Failed to write {}: {}", path.display(), e.error)))?;
+
+    Ok(())
+}
+
+fn import_config_inner(path: &Path) -> Result<(InstallConfigPrepare, Vec<&'static str>), DkError> {
+    let err = |message: String| DkError {
+        message,
+        t: "ImportConfig".to_string(),
+        data: json!({
+            "path": path.display().to_string(),
+        }),
+    };
+
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| err(format!("Failed to read {}: {e}", path.display())))?;
+
+    let config = serde_json::from_str(&content)
+        .map_err(|e| err(format!("Invalid config {}: {e}", path.display())))?;
+
+    let cleared = clear_stale_partitions(&config);
+    check_imported_config(&config)?;
+
+    Ok((config, cleared))
+}
+
+/// Clear the partitions that are not on the disks any more, returns the cleared fields
+/// 同一配置用于多台机器时，自动分区得到的 PARTUUID 各不相同，须重新选择分区
+fn clear_stale_partitions(config: &InstallConfigPrepare) -> Vec<&'static str> {
+    // 开发模式下固定使用 /dev/loop30 上的分区
+    if is_dev_mode() {
+        return vec![];
+    }
+
+    let partitions = [
+        ("target_partition", &config.target_partition),
+        ("efi_partition", &config.efi_partition),
+        ("data_partition", &config.data_partition),
+        ("swap_partition", &config.swap_partition),
+    ];
+
+    let mut cleared = vec![];
+
+    for (field, partition) in partitions {
+        let mut lock = partition.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(p) = lock.as_ref().filter(|p| !is_partition_unchanged(p)) {
+            warn!(
+                "Partition {:?} of {field} is not on the disk any more, clearing it",
+                p.path
+            );
+            *lock = None;
+            cleared.push(field);
+        }
+    }
+
+    cleared
+}
+
+/// Check an imported config as `set_config` would
+fn check_imported_config(config: &InstallConfigPrepare) -> Result<(), DkError> {
+    fn to_json<T: Serialize>(value: &T) -> String {
+        serde_json::to_string(value).unwrap_or_default()
+    }
+
+    let mut fields = vec![("user_on_first_boot", config.user_on_first_boot.to_string())];
+
+    if let Some(ref locale) = config.locale {
+        fields.push(("locale", locale.clone()));
+    }
+
+    fields.push(("extra_locales", to_json(&config.extra_locales)));
+
+    if let Some(ref timezone) = config.timezone {
+        fields.push(("timezone", timezone.clone()));
+    }
+
+    if let Some(ref hostname) = config.hostname {
+        fields.push(("hostname", hostname.clone()));
+    }
+
+    if let Some(ref keymap) = config.keymap {
+        fields.push(("keymap", keymap.clone()));
+    }
+
+    fields.push(("kernel_cmdline", to_json(&config.kernel_cmdline)));
+
+    if let Some(ref user) = config.user {
+        fields.push(("user", to_json(user)));
+    }
+
+    fields.push(("extra_users", to_json(&config.extra_users)));
+
+    if let Some(ref identity) = config.identity {
+        fields.push(("identity", to_json(identity)));
+    }
+
+    if let Some(ref stage) = config.stop_after {
+        if let Value::String(stage) = serde_json::to_value(stage).unwrap_or_default() {
+            fields.push(("stop_after", stage));
+        }
+    }
+
+    fields.push(("branding", to_json(&config.branding)));
+
+    if let Some(ref layout) = config.data_layout {
+        fields.push(("data_layout", to_json(layout)));
+    }
+
+    if let Some(ref root_fs) = config.root_fs {
+        fields.push(("root_fs", root_fs.clone()));
+    }
+
+    if let Some(efi_size) = config.efi_size {
+        fields.push(("efi_size", efi_size.to_string()));
+    }
+
+    if let Some(ref p) = *config
+        .target_partition
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+    {
+        fields.push(("target_partition", to_json(p)));
+    }
+
+    let mut scratch = InstallConfigPrepare::default();
+    for (field, value) in fields {
+        set_config_inner(&mut scratch, field, &value)?;
+    }
+
+    Ok(())
+}
+
+/// The partition must still be on its disk, with the same PARTUUID if known
+fn is_partition_unchanged(p: &DkPartition) -> bool {
+    let current = match (&p.path, &p.parent_path) {
+        (Some(path), Some(parent)) => list_partitions(parent.clone())
+            .into_iter()
+            .find(|x| x.path.as_ref() == Some(path)),
+        _ => None,
+    };
+
+    current.is_some_and(|x| match (&x.partuuid, &p.partuuid) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    })
+}
+
+/// Usernames and UIDs of the main user and the extra users must not be used more than once
+fn check_unique_users(user: Option<&User>, extra_users: &[User]) -> Result<(), DkError> {
+    let users = user.into_iter().chain(extra_users);
+
+    check_unique_usernames(users.clone().map(|u| u.username.as_str()))
+        .and_then(|_| check_unique_uids(users.filter_map(|u| u.uid)))
+        .map_err(|e| DkError::from(&e))
+}
+
+/// Boolean settings accept `0`/`false` and `1`/`true`
+fn parse_bool_value(field: &str, value: &str) -> Result<bool, DkError> {
+    match value {
+        "0" | "false" => Ok(false),
+        "1" | "true" => Ok(true),
+        _ => Err(DkError {
+            message: format!("{field} must be 0 or 1"),
+            t: "SetValue".to_string(),
+            data: {
+                json!({
+                    "field": field.to_string(),
+                    "value": value.to_string(),
+                })
+            },
+        }),
+    }
+}
+
 fn set_config_inner(
     config: &mut InstallConfigPrepare,
     field: &str,
@@ -438,9 +1828,44 @@ fn set_config_inner(
 ) -> Result<(), DkError> {
     match field {
         "locale" => {
+            if !is_valid_locale_name(value) {
+                return Err(DkError {
+                    message: format!("Invalid locale: {value:?}"),
+                    t: "SetValue".to_string(),
+                    data: {
+                        json!({
+                            "field": "locale".to_string(),
+                            "value": value.to_string(),
+                        })
+                    },
+                });
+            }
+
             config.locale = Some(value.to_string());
             Ok(())
         }
+        "extra_locales" => {
+            let err = |message: String| DkError {
+                message,
+                t: "SetValue".to_string(),
+                data: {
+                    json!({
+                        "field": "extra_locales".to_string(),
+                        "value": value.to_string(),
+                    })
+                },
+            };
+
+            let locales =
+                serde_json::from_str::<Vec<String>>(value).map_err(|e| err(e.to_string()))?;
+
+            if let Some(locale) = locales.iter().find(|x| !is_valid_locale_name(x)) {
+                return Err(err(format!("Invalid locale: {locale:?}")));
+            }
+
+            config.extra_locales = locales;
+            Ok(())
+        }
         "timezone" => {
             config.timezone = Some(value.to_string());
             Ok(())
@@ -458,124 +1883,455 @@ fn set_config_inner(
                     },
                 })?;
 
-            config.download = Some(download_type);
+            config.download = Some(download_type);
+
+            Ok(())
+        }
+        "user" => {
+            let user = serde_json::from_str::<User>(value).map_err(|e| DkError {
+                message: e.to_string(),
+                t: "SetValue".to_string(),
+                data: {
+                    json!({
+                        "field": "user".to_string(),
+                        "value": value.to_string(),
+                    })
+                },
+            })?;
+
+            check_username(&user.username).map_err(|e| DkError::from(&e))?;
+            if let Some(uid) = user.uid {
+                check_uid(uid).map_err(|e| DkError::from(&e))?;
+            }
+
+            check_unique_users(Some(&user), &config.extra_users)?;
+
+            config.user = Some(user);
+            Ok(())
+        }
+        "extra_users" => {
+            let err = |message: String| DkError {
+                message,
+                t: "SetValue".to_string(),
+                data: {
+                    // 不回显取值，其中包含密码
+                    json!({
+                        "field": "extra_users".to_string(),
+                    })
+                },
+            };
+
+            let users = serde_json::from_str::<Vec<User>>(value).map_err(|e| err(e.to_string()))?;
+
+            for user in &users {
+                check_username(&user.username).map_err(|e| DkError::from(&e))?;
+                if let Some(uid) = user.uid {
+                    check_uid(uid).map_err(|e| DkError::from(&e))?;
+                }
+            }
+
+            // OEM 模式下所有账户均由最终用户在首次启动时创建
+            if config.user_on_first_boot && !users.is_empty() {
+                return Err(err(
+                    "Extra users can not be created when the user is created on first boot"
+                        .to_string(),
+                ));
+            }
+
+            check_unique_users(config.user.as_ref(), &users)?;
+
+            config.extra_users = users;
+            Ok(())
+        }
+        "hostname" => {
+            if !is_valid_hostname(value) {
+                return Err(DkError::from(&SetHostnameError::InvalidHostname {
+                    hostname: value.to_string(),
+                }));
+            }
+
+            config.hostname = Some(value.to_string());
+            Ok(())
+        }
+        "keymap" => {
+            if !is_valid_keymap(value) {
+                return Err(DkError {
+                    message: format!("Invalid keymap: {value}"),
+                    t: "SetValue".to_string(),
+                    data: {
+                        json!({
+                            "field": "keymap".to_string(),
+                            "value": value.to_string(),
+                        })
+                    },
+                });
+            }
+
+            config.keymap = Some(value.to_string());
+            Ok(())
+        }
+        "kernel_cmdline" => {
+            let err = |message: String| DkError {
+                message,
+                t: "SetValue".to_string(),
+                data: {
+                    json!({
+                        "field": "kernel_cmdline".to_string(),
+                        "value": value.to_string(),
+                    })
+                },
+            };
+
+            let params =
+                serde_json::from_str::<Vec<String>>(value).map_err(|e| err(e.to_string()))?;
+
+            if let Some(param) = params.iter().find(|p| !is_valid_kernel_param(p)) {
+                return Err(err(format!("Invalid kernel parameter: {param:?}")));
+            }
+
+            config.kernel_cmdline = params;
+            Ok(())
+        }
+        "rtc_as_localtime" => {
+            config.rtc_as_localtime = parse_bool_value(field, value)?;
+            Ok(())
+        }
+        "list_md_devices" => {
+            config.list_md_devices = parse_bool_value(field, value)?;
+            Ok(())
+        }
+        "dry_run" => {
+            config.dry_run = parse_bool_value(field, value)?;
+            Ok(())
+        }
+        "passwordless_sudo" => {
+            config.passwordless_sudo = parse_bool_value(field, value)?;
+            Ok(())
+        }
+        // OEM 模式：不需要设置 user，最终用户在首次启动时创建账户
+        "user_on_first_boot" => {
+            let user_on_first_boot = parse_bool_value(field, value)?;
+
+            if user_on_first_boot && !config.extra_users.is_empty() {
+                return Err(DkError {
+                    message:
+                        "Extra users can not be created when the user is created on first boot"
+                            .to_string(),
+                    t: "SetValue".to_string(),
+                    data: {
+                        json!({
+                            "field": "user_on_first_boot".to_string(),
+                            "value": value.to_string(),
+                        })
+                    },
+                });
+            }
+
+            config.user_on_first_boot = user_on_first_boot;
+            Ok(())
+        }
+        "strict_configure" => {
+            config.strict_configure = parse_bool_value(field, value)?;
+            Ok(())
+        }
+        "allow_no_bootloader" => {
+            config.allow_no_bootloader = parse_bool_value(field, value)?;
+            Ok(())
+        }
+        "skip_bootloader" => {
+            config.skip_bootloader = parse_bool_value(field, value)?;
+            Ok(())
+        }
+        "install_locale_extras" => {
+            config.install_locale_extras = parse_bool_value(field, value)?;
+            Ok(())
+        }
+        "locale_extras" => {
+            // 空值表示恢复默认映射表
+            if value.is_empty() {
+                config.locale_extras = default_locale_extras();
+                return Ok(());
+            }
+
+            config.locale_extras = serde_json::from_str::<BTreeMap<String, LocaleExtra>>(value)
+                .map_err(|e| DkError {
+                    message: e.to_string(),
+                    t: "SetValue".to_string(),
+                    data: {
+                        json!({
+                            "field": "locale_extras".to_string(),
+                            "value": value.to_string(),
+                        })
+                    },
+                })?;
+
+            Ok(())
+        }
+        "identity" => {
+            if value.is_empty() {
+                config.identity = None;
+                return Ok(());
+            }
+
+            let err = |message: String| DkError {
+                message,
+                t: "SetValue".to_string(),
+                data: {
+                    // 不回显取值，其中包含私钥
+                    json!({
+                        "field": "identity".to_string(),
+                    })
+                },
+            };
+
+            let identity =
+                serde_json::from_str::<Identity>(value).map_err(|e| err(e.to_string()))?;
+            identity.check().map_err(|e| err(e.to_string()))?;
+
+            // 身份包中的主机名即为安装后系统的主机名
+            config.hostname = Some(identity.hostname.clone());
+            config.identity = Some(identity);
+
+            Ok(())
+        }
+        "encrypt" => {
+            // 空值表示不加密
+            config.encrypt = if value.is_empty() {
+                None
+            } else {
+                Some(value.to_string())
+            };
+
+            Ok(())
+        }
+        "stop_after" => {
+            let err = |message: String| DkError {
+                message,
+                t: "SetValue".to_string(),
+                data: {
+                    json!({
+                        "field": "stop_after".to_string(),
+                        "value": value.to_string(),
+                    })
+                },
+            };
+
+            // 空值表示完整安装
+            if value.is_empty() {
+                config.stop_after = None;
+                return Ok(());
+            }
+
+            let stage =
+                serde_json::from_value::<InstallationStage>(Value::String(value.to_string()))
+                    .map_err(|e| err(e.to_string()))?;
+
+            if !stage.can_stop_after() {
+                return Err(err(format!("Can not stop after stage: {stage}")));
+            }
+
+            config.stop_after = Some(stage);
+
+            Ok(())
+        }
+        "branding" => {
+            let err = |message: String| DkError {
+                message,
+                t: "SetValue".to_string(),
+                data: {
+                    json!({
+                        "field": "branding".to_string(),
+                        "value": value.to_string(),
+                    })
+                },
+            };
+
+            // 空值表示不定制品牌信息
+            if value.is_empty() {
+                config.branding.clear();
+                return Ok(());
+            }
+
+            let branding = serde_json::from_str::<BTreeMap<String, String>>(value)
+                .map_err(|e| err(e.to_string()))?;
+            check_branding(&branding).map_err(|e| err(e.to_string()))?;
+            config.branding = branding;
+
+            Ok(())
+        }
+        "target_partition" => {
+            let p = serde_json::from_str::<DkPartition>(value).map_err(|e| DkError {
+                message: e.to_string(),
+                t: "SetValue".to_string(),
+                data: {
+                    json!({
+                        "field": "target_partition".to_string(),
+                        "value": value.to_string(),
+                    })
+                },
+            })?;
+
+            // 开发模式下固定使用 /dev/loop30 上的分区
+            let p = if is_dev_mode() {
+                DkPartition {
+                    path: Some(PathBuf::from("/dev/loop30p1")),
+                    parent_path: Some(PathBuf::from("/dev/loop30")),
+                    fs_type: Some("ext4".to_string()),
+                    size: 50 * 1024 * 1024 * 1024,
+                    format: true,
+                    part_number: Some(1),
+                    ..Default::default()
+                }
+            } else {
+                for path in [&p.path, &p.parent_path].into_iter().flatten() {
+                    check_not_live_medium(path)?;
+                }
+
+                p
+            };
 
+            config.target_partition = Arc::new(Mutex::new(Some(p)));
             Ok(())
         }
-        "user" => {
-            let user = serde_json::from_str::<User>(value).map_err(|e| DkError {
+        "efi_partition" => {
+            let p = serde_json::from_str::<DkPartition>(value).map_err(|e| DkError {
                 message: e.to_string(),
                 t: "SetValue".to_string(),
                 data: {
                     json!({
-                        "field": "user".to_string(),
+                        "field": "efi_partition".to_string(),
                         "value": value.to_string(),
                     })
                 },
             })?;
 
-            config.user = Some(user);
+            let p = if is_dev_mode() {
+                DkPartition {
+                    path: Some(PathBuf::from("/dev/loop30p2")),
+                    parent_path: Some(PathBuf::from("/dev/loop30")),
+                    fs_type: Some("vfat".to_string()),
+                    size: 512 * 1024 * 1024,
+                    format: true,
+                    part_number: Some(2),
+                    ..Default::default()
+                }
+            } else {
+                p
+            };
+
+            config.efi_partition = Arc::new(Mutex::new(Some(p)));
             Ok(())
         }
-        "hostname" => {
-            config.hostname = Some(value.to_string());
+        "data_layout" => {
+            let err = |message: String| DkError {
+                message,
+                t: "SetValue".to_string(),
+                data: {
+                    json!({
+                        "field": "data_layout".to_string(),
+                        "value": value.to_string(),
+                    })
+                },
+            };
+
+            // 空值表示不创建数据分区
+            if value.is_empty() {
+                config.data_layout = None;
+                return Ok(());
+            }
+
+            let layout =
+                serde_json::from_str::<DataLayout>(value).map_err(|e| err(e.to_string()))?;
+            layout.check().map_err(|e| err(e.to_string()))?;
+            config.data_layout = Some(layout);
+
             Ok(())
         }
-        "rtc_as_localtime" => match value {
-            "0" | "false" => {
-                config.rtc_as_localtime = false;
-                Ok(())
-            }
-            "1" | "true" => {
-                config.rtc_as_localtime = true;
-                Ok(())
+        "root_fs" | "auto_partition_fs" => {
+            // 空值表示使用默认的 ext4
+            if value.is_empty() {
+                config.root_fs = None;
+                return Ok(());
             }
-            _ => Err(DkError {
-                message: "rtc_as_localtime must be 0 or 1".to_string(),
+
+            let options = AutoPartitionOptions {
+                root_fs_type: value.to_string(),
+                ..Default::default()
+            };
+
+            options.check().map_err(|e| DkError {
+                message: e.to_string(),
                 t: "SetValue".to_string(),
                 data: {
                     json!({
-                        "field": "rtc_as_localtime".to_string(),
+                        "field": field.to_string(),
                         "value": value.to_string(),
                     })
                 },
-            }),
-        },
-        "target_partition" => {
-            #[cfg(not(debug_assertions))]
-            {
-                let p = serde_json::from_str::<DkPartition>(value).map_err(|e| DkError {
-                    message: e.to_string(),
-                    t: "SetValue".to_string(),
-                    data: {
-                        json!({
-                            "field": "target_partition".to_string(),
-                            "value": value.to_string(),
-                        })
-                    },
-                })?;
-                config.target_partition = Arc::new(Mutex::new(Some(p)));
-                Ok(())
-            }
-            #[cfg(debug_assertions)]
-            {
-                let _p = serde_json::from_str::<DkPartition>(value).map_err(|e| DkError {
-                    message: e.to_string(),
-                    t: "SetValue".to_string(),
-                    data: {
-                        json!({
-                            "field": "target_partition".to_string(),
-                            "value": value.to_string(),
-                        })
-                    },
-                })?;
-                config.target_partition = Arc::new(Mutex::new(Some(DkPartition {
-                    path: Some(PathBuf::from("/dev/loop30p1")),
-                    parent_path: Some(PathBuf::from("/dev/loop30")),
-                    fs_type: Some("ext4".to_string()),
-                    size: 50 * 1024 * 1024 * 1024,
-                })));
-                Ok(())
-            }
+            })?;
+
+            config.root_fs = Some(value.to_string());
+
+            Ok(())
         }
-        "efi_partition" => {
-            #[cfg(not(debug_assertions))]
-            {
-                let p = serde_json::from_str::<DkPartition>(value).map_err(|e| DkError {
-                    message: e.to_string(),
-                    t: "SetValue".to_string(),
-                    data: {
-                        json!({
-                            "field": "efi_partition".to_string(),
-                            "value": value.to_string(),
-                        })
-                    },
-                })?;
-                config.efi_partition = Arc::new(Mutex::new(Some(p)));
+        "efi_size" => {
+            let err = |message: String| DkError {
+                message,
+                t: "SetValue".to_string(),
+                data: {
+                    json!({
+                        "field": "efi_size".to_string(),
+                        "value": value.to_string(),
+                    })
+                },
+            };
+
+            // 空值表示使用默认的 512MiB
+            if value.is_empty() {
+                config.efi_size = None;
+                return Ok(());
             }
 
-            #[cfg(debug_assertions)]
-            {
-                let _p = serde_json::from_str::<DkPartition>(value).map_err(|e| DkError {
-                    message: e.to_string(),
+            let efi_size = value.parse::<u64>().map_err(|e| err(e.to_string()))?;
+
+            // 是否超过磁盘的四分之一需在分区时检查
+            let options = AutoPartitionOptions {
+                efi_size,
+                ..Default::default()
+            };
+            options.check().map_err(|e| err(e.to_string()))?;
+
+            config.efi_size = Some(efi_size);
+
+            Ok(())
+        }
+        // 休眠需要足够大的 swapfile，即 `swapfile` 设为 `Hibernate`
+        "hibernation" => match parse_bool_value(field, value)? {
+            false => {
+                if config.swapfile == SwapFile::Hibernate {
+                    config.swapfile = SwapFile::Automatic;
+                }
+                Ok(())
+            }
+            // 休眠交换文件取代自动大小的交换文件，不覆盖用户另行选择的交换方式
+            true => match config.swapfile {
+                SwapFile::Automatic | SwapFile::Hibernate => {
+                    config.swapfile = SwapFile::Hibernate;
+                    Ok(())
+                }
+                ref swapfile => Err(DkError {
+                    message: "hibernation conflicts with the swapfile setting".to_string(),
                     t: "SetValue".to_string(),
                     data: {
                         json!({
-                            "field": "efi_partition".to_string(),
+                            "field": "hibernation".to_string(),
                             "value": value.to_string(),
+                            "swapfile": serde_json::to_string(swapfile).unwrap_or_default(),
                         })
                     },
-                })?;
-                config.efi_partition = Arc::new(Mutex::new(Some(DkPartition {
-                    path: Some(PathBuf::from("/dev/loop30p2")),
-                    parent_path: Some(PathBuf::from("/dev/loop30")),
-                    fs_type: Some("vfat".to_string()),
-                    size: 512 * 1024 * 1024,
-                })));
-            }
-
-            Ok(())
-        }
+                }),
+            },
+        },
         "swapfile" => {
             config.swapfile = serde_json::from_str::<SwapFile>(value).map_err(|e| DkError {
                 message: e.to_string(),
@@ -605,14 +2361,28 @@ fn set_config_inner(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn start_install_inner(
     config: InstallConfigPrepare,
     step: Arc<AtomicU8>,
     progress: Arc<AtomicU8>,
     v: Arc<AtomicUsize>,
+    eta: Arc<AtomicUsize>,
     ps: Arc<Mutex<ProgressStatus>>,
     cancel_install: Arc<AtomicBool>,
+    pause_install: Arc<AtomicBool>,
+    install_env: Arc<Mutex<Option<InstallEnv>>>,
+    wake_lock: Vec<zvariant::OwnedFd>,
+    warnings: Arc<Mutex<Vec<InstallWarning>>>,
+    conn: Connection,
 ) -> Result<JoinHandle<()>, DkError> {
+    for i in disk_impact_inner(&config) {
+        info!("Disk impact: {i:?}");
+    }
+
+    // 模拟安装不挂载任何分区，提前停止时也没有可供检查的目标系统
+    let stop_after = config.stop_after.is_some() && !config.dry_run;
+
     let mut config = InstallConfig::try_from(config).map_err(|e| DkError::from(&e))?;
 
     info!("Starting install");
@@ -625,9 +2395,10 @@ fn start_install_inner(
 
     let tmp_dir = Arc::new(temp_dir);
     let tmp_dir_clone2 = tmp_dir.clone();
-    let tmp_dir_clone3 = tmp_dir.clone();
 
-    if let DownloadType::Http { to_path, .. } = &mut config.download {
+    if let DownloadType::Http { to_path, .. } | DownloadType::HttpMulti { to_path, .. } =
+        &mut config.download
+    {
         *to_path = Some(tmp_dir.join("squashfs"));
     }
 
@@ -640,22 +2411,39 @@ fn start_install_inner(
         .map_err(|e| InstallErr::CloneFd { source: e })
         .map_err(|e| DkError::from(&e))?;
 
-    ctrlc::set_handler(move || {
-        if let Ok(root_fd) = root_fd_clone.try_clone() {
-            exit_env(root_fd, tmp_dir_clone3.clone());
-        } else {
-            warn!("Failed to clone root_fd");
-        }
-
-        exit(1);
-    })
-    .ok();
+    {
+        let mut env = install_env.lock().unwrap_or_else(|e| e.into_inner());
+        *env = Some(InstallEnv {
+            root_fd: root_fd_clone,
+            tmp_dir: tmp_dir.clone(),
+        });
+    }
 
     let ps_clone = ps.clone();
 
     let cancel_install_clone = cancel_install.clone();
 
+    let boot_stub = Arc::new(Mutex::new(None));
+    let boot_stub_clone = boot_stub.clone();
+
+    let step_clone = step.clone();
+    let progress_clone = progress.clone();
+    let v_clone = v.clone();
+    let current_progress = move || {
+        (
+            step_clone.load(Ordering::SeqCst),
+            progress_clone.load(Ordering::SeqCst),
+            v_clone.load(Ordering::SeqCst) as u64,
+        )
+    };
+
+    let mut last_progress = current_progress();
+    let (progress_tx, progress_rx) = watch::channel(last_progress);
+    spawn_progress_emitter(&Handle::current(), conn, progress_rx);
+
     let t = thread::spawn(move || {
+        // 安装结束、出错或取消后释放唤醒锁
+        let _wake_lock = wake_lock;
         let t = tmp_dir_clone2.clone();
         let t2 = tmp_dir_clone2.clone();
         let install_thread = thread::spawn(move || {
@@ -664,14 +2452,18 @@ fn start_install_inner(
                     step.clone(),
                     progress.clone(),
                     v.clone(),
+                    eta.clone(),
                     t.clone(),
                     cancel_install_clone,
+                    pause_install.clone(),
+                    warnings,
+                    boot_stub_clone,
                 )
                 .map_err(|e| DkError::from(&e));
 
             if let Err(e) = res {
                 {
-                    let mut ps = ps_clone.lock().unwrap();
+                    let mut ps = ps_clone.lock().unwrap_or_else(|e| e.into_inner());
                     *ps = ProgressStatus::Error(e);
                 }
             }
@@ -680,6 +2472,12 @@ fn start_install_inner(
         let mut is_cancel = false;
 
         loop {
+            let cur = current_progress();
+            if is_progress_changed(last_progress, cur) {
+                last_progress = cur;
+                progress_tx.send_replace(cur);
+            }
+
             if !is_cancel {
                 is_cancel = cancel_install.load(Ordering::SeqCst);
             };
@@ -688,23 +2486,35 @@ fn start_install_inner(
                 // 需要先确保安装线程已经结束再退出环境
                 if is_cancel {
                     exit_env(root_fd, tmp_dir_clone2.clone());
+                    install_env.lock().unwrap_or_else(|e| e.into_inner()).take();
                     cancel_install.store(false, Ordering::SeqCst);
                     {
-                        let mut ps = ps.lock().unwrap();
+                        let mut ps = ps.lock().unwrap_or_else(|e| e.into_inner());
                         *ps = ProgressStatus::Pending;
                     }
                     return;
                 }
 
-                let mut ps = ps.lock().unwrap();
+                let mut ps = ps.lock().unwrap_or_else(|e| e.into_inner());
 
                 if let ProgressStatus::Error(e) = &*ps {
                     error!("Failed to install system: {e:?}");
                     exit_env(root_fd, t2);
+                    install_env.lock().unwrap_or_else(|e| e.into_inner()).take();
                     return;
                 }
 
-                *ps = ProgressStatus::Finish;
+                // 提前停止时保留挂载，不再清理安装环境
+                install_env.lock().unwrap_or_else(|e| e.into_inner()).take();
+                *ps = if stop_after {
+                    ProgressStatus::Prepared {
+                        mount_path: t2.to_path_buf(),
+                    }
+                } else {
+                    ProgressStatus::Finish {
+                        boot_stub: boot_stub.lock().unwrap_or_else(|e| e.into_inner()).take(),
+                    }
+                };
                 return;
             }
 
@@ -715,6 +2525,37 @@ fn start_install_inner(
     Ok(t)
 }
 
+/// Emit `ProgressChanged` for every update sent by the install thread
+/// 发送端随安装线程退出而关闭，此时任务结束
+fn spawn_progress_emitter(rt: &Handle, conn: Connection, mut rx: watch::Receiver<ProgressSignal>) {
+    rt.spawn(async move {
+        let emitter = match SignalEmitter::new(&conn, OBJECT_PATH) {
+            Ok(emitter) => emitter,
+            Err(e) => {
+                warn!("Failed to create signal emitter: {e}");
+                return;
+            }
+        };
+
+        while rx.changed().await.is_ok() {
+            let (step, progress, velocity) = *rx.borrow_and_update();
+
+            if let Err(e) =
+                DeploykitServer::progress_changed(&emitter, step, progress, velocity).await
+            {
+                warn!("Failed to emit ProgressChanged: {e}");
+            }
+        }
+    });
+}
+
+async fn take_wake_lock_or_warn(conn: &Connection) -> Vec<zvariant::OwnedFd> {
+    take_wake_lock(conn).await.unwrap_or_else(|e| {
+        warn!("Failed to take wake lock: {e}");
+        vec![]
+    })
+}
+
 fn exit_env(root_fd: OwnedFd, tmp_dir: Arc<PathBuf>) {
     sync_disk();
     escape_chroot(root_fd).ok();
@@ -752,3 +2593,688 @@ fn exit_env(root_fd: OwnedFd, tmp_dir: Arc<PathBuf>) {
         umount_all(&tmp_dir);
     }
 }
+
+#[test]
+fn test_get_progress_with_poisoned_mutex() {
+    let server = DeploykitServer::default();
+    let progress = server.progress.clone();
+
+    thread::spawn(move || {
+        let _lock = progress.lock().unwrap();
+        panic!("poison progress mutex");
+    })
+    .join()
+    .ok();
+
+    assert!(server.progress.is_poisoned());
+
+    let res = serde_json::from_str::<Value>(&server.get_progress()).unwrap();
+    assert_eq!(res["result"], "Ok");
+    assert_eq!(res["data"]["status"], "Pending");
+}
+
+#[test]
+fn test_get_progress_reports_eta() {
+    let server = DeploykitServer::default();
+    let plan = InstallPlan::new(None);
+
+    server.v.store(2 * 1024 * 1024, Ordering::SeqCst);
+    server.eta.store(90, Ordering::SeqCst);
+
+    *server.progress.lock().unwrap() = ProgressStatus::Working {
+        step: server.step.clone(),
+        progress: server.progress_num.clone(),
+        v: server.v.clone(),
+        eta: server.eta.clone(),
+        overall: OverallProgress {
+            step: server.step.clone(),
+            progress: server.progress_num.clone(),
+            weights: plan.weights(),
+        },
+        plan: PlanProgress {
+            step: server.step.clone(),
+            plan,
+        },
+    };
+
+    let res = serde_json::from_str::<Value>(&server.get_progress()).unwrap();
+    assert_eq!(res["data"]["status"], "Working");
+    assert_eq!(res["data"]["v"], 2 * 1024 * 1024);
+    assert_eq!(res["data"]["eta"], 90);
+}
+
+#[tokio::test]
+async fn test_get_progress2_round_trip() {
+    let guid = zbus::Guid::generate();
+    let (p0, p1) = tokio::net::UnixStream::pair().unwrap();
+
+    let server = DeploykitServer::default();
+    let step = Arc::new(AtomicU8::new(3));
+    let progress = Arc::new(AtomicU8::new(42));
+    let v = Arc::new(AtomicUsize::new(1024));
+
+    {
+        let mut ps = server.progress.lock().unwrap();
+        let plan = InstallPlan::new(None);
+
+        *ps = ProgressStatus::Working {
+            step: step.clone(),
+            progress: progress.clone(),
+            v,
+            eta: Arc::new(AtomicUsize::new(90)),
+            overall: OverallProgress {
+                step: step.clone(),
+                progress,
+                weights: plan.weights(),
+            },
+            plan: PlanProgress { step, plan },
+        };
+    }
+
+    let (_service, client) = tokio::try_join!(
+        zbus::connection::Builder::unix_stream(p0)
+            .server(guid)
+            .unwrap()
+            .p2p()
+            .serve_at("/io/aosc/Deploykit", server)
+            .unwrap()
+            .build(),
+        zbus::connection::Builder::unix_stream(p1).p2p().build(),
+    )
+    .unwrap();
+
+    let proxy = zbus::Proxy::new(
+        &client,
+        "io.aosc.Deploykit",
+        "/io/aosc/Deploykit",
+        "io.aosc.Deploykit1",
+    )
+    .await
+    .unwrap();
+
+    let res: types::Progress = proxy.call("GetProgress2", &()).await.unwrap();
+    assert_eq!(res, types::Progress::working(3, 42, 1024, 90, 53));
+
+    let res: Vec<types::Partition> = proxy
+        .call("GetListPartitions2", &("/dev/nonexistent",))
+        .await
+        .unwrap();
+    assert!(res.is_empty());
+}
+
+#[test]
+fn test_is_progress_changed() {
+    assert!(!is_progress_changed((1, 10, 1000), (1, 10, 1000)));
+    assert!(is_progress_changed((1, 10, 1000), (2, 0, 1000)));
+    assert!(is_progress_changed((1, 10, 1000), (1, 11, 1000)));
+    assert!(!is_progress_changed((1, 10, 1000), (1, 10, 1050)));
+    assert!(is_progress_changed((1, 10, 1000), (1, 10, 1200)));
+    assert!(is_progress_changed((1, 10, 1000), (1, 10, 800)));
+    assert!(is_progress_changed((1, 10, 0), (1, 10, 1)));
+    assert!(is_progress_changed((1, 10, 1), (1, 10, 0)));
+}
+
+#[test]
+fn test_explain_empty_devices() {
+    let root_device = || FilteredDevice {
+        path: "/dev/sda".to_string(),
+        model: "USB Disk".to_string(),
+        reason: FilterReason::RootDevice {
+            root: "/dev/sda1".to_string(),
+        },
+    };
+
+    let device = DkDevice {
+        path: "/dev/nvme0n1".to_string(),
+        model: "SSD".to_string(),
+        size: 1 << 40,
+        traits: DeviceTraits {
+            removable: false,
+            rotational: false,
+            transport: disk::devices::Transport::Nvme,
+        },
+    };
+
+    assert_eq!(explain_empty_devices(&[device], &[root_device()]), None);
+
+    let msg = explain_empty_devices(&[], &[root_device()]).unwrap();
+    assert!(msg.contains("/dev/sda") && msg.contains("boot device"));
+
+    assert!(explain_empty_devices(&[], &[]).is_some());
+
+    let msg = explain_empty_devices(
+        &[],
+        &[
+            root_device(),
+            FilteredDevice {
+                path: "/dev/sdb".to_string(),
+                model: "Broken".to_string(),
+                reason: FilterReason::ImplausibleGeometry {
+                    sector_size: 0,
+                    length: 0,
+                },
+            },
+        ],
+    )
+    .unwrap();
+    assert!(msg.contains("/dev/sdb reports an implausible size"));
+}
+
+#[test]
+fn test_device_size() {
+    assert_eq!(device_size(512, 2048), Some(1024 * 1024));
+    assert_eq!(device_size(4096, 1 << 30), Some(4096 << 30));
+    assert_eq!(device_size(0, 2048), None);
+    assert_eq!(device_size(520, 2048), None);
+    assert_eq!(device_size(256, 2048), None);
+    assert_eq!(device_size(1 << 20, 2048), None);
+    assert_eq!(device_size(512, 0), None);
+    assert_eq!(device_size(4096, u64::MAX), None);
+    assert_eq!(device_size(512, MAX_DEVICE_SIZE / 512 + 1), None);
+}
+
+#[test]
+fn test_validate_config() {
+    let server = DeploykitServer::default();
+
+    let res = serde_json::from_str::<Value>(&server.validate_config()).unwrap();
+    assert_eq!(res["result"], "Error");
+    assert_eq!(res["data"]["t"], "ValueNotSet");
+    assert_eq!(
+        res["data"]["data"]["missing"],
+        json!([
+            "locale",
+            "timezone",
+            "download",
+            "user",
+            "hostname",
+            "target partition"
+        ])
+    );
+
+    let mut server = server;
+    server.config.locale = Some("en_US.UTF-8".to_string());
+    server.config.hostname = Some("aosc".to_string());
+
+    let res = serde_json::from_str::<Value>(&server.validate_config()).unwrap();
+    assert_eq!(
+        res["data"]["data"]["missing"],
+        json!(["timezone", "download", "user", "target partition"])
+    );
+
+    set_config_inner(&mut server.config, "user_on_first_boot", "1").unwrap();
+    let res = serde_json::from_str::<Value>(&server.validate_config()).unwrap();
+    assert_eq!(
+        res["data"]["data"]["missing"],
+        json!(["timezone", "download", "target partition"])
+    );
+}
+
+#[test]
+fn test_set_config_hostname() {
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(&mut config, "hostname", "aosc-os").unwrap();
+    assert_eq!(config.hostname.as_deref(), Some("aosc-os"));
+
+    for hostname in ["", "-aosc", "aosc_os", "aosc os"] {
+        let err = set_config_inner(&mut config, "hostname", hostname).unwrap_err();
+        assert_eq!(err.t, "InvalidHostname");
+    }
+    assert_eq!(config.hostname.as_deref(), Some("aosc-os"));
+}
+
+#[test]
+fn test_set_config_extra_users() {
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(
+        &mut config,
+        "user",
+        r#"{"username":"aosc","password":"anthon","root_password":null,"full_name":null}"#,
+    )
+    .unwrap();
+    assert!(config.user.as_ref().unwrap().admin);
+    assert_eq!(config.user.as_ref().unwrap().shell, None);
+
+    set_config_inner(
+        &mut config,
+        "user",
+        r#"{"username":"aosc","password":"anthon","root_password":null,"full_name":null,"shell":"/usr/bin/zsh"}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.user.as_ref().unwrap().shell.as_deref(),
+        Some("/usr/bin/zsh")
+    );
+    assert_eq!(config.user.as_ref().unwrap().uid, None);
+
+    set_config_inner(
+        &mut config,
+        "user",
+        r#"{"username":"aosc","password":"anthon","root_password":null,"full_name":null,"uid":1500}"#,
+    )
+    .unwrap();
+    assert_eq!(config.user.as_ref().unwrap().uid, Some(1500));
+
+    let err = set_config_inner(
+        &mut config,
+        "user",
+        r#"{"username":"aosc","password":"anthon","root_password":null,"full_name":null,"uid":999}"#,
+    )
+    .unwrap_err();
+    assert_eq!(err.t, "InvalidUid");
+    assert_eq!(err.data["uid"], 999);
+
+    set_config_inner(
+        &mut config,
+        "extra_users",
+        r#"[
+            {"username":"alice","password":"a","root_password":null,"full_name":"Alice","admin":true},
+            {"username":"bob","password":"b","root_password":null,"full_name":null,"admin":false}
+        ]"#,
+    )
+    .unwrap();
+    assert_eq!(config.extra_users.len(), 2);
+    assert!(!config.extra_users[1].admin);
+
+    let err = set_config_inner(
+        &mut config,
+        "extra_users",
+        r#"[{"username":"aosc","password":"a","root_password":null,"full_name":null}]"#,
+    )
+    .unwrap_err();
+    assert_eq!(err.t, "DuplicateUsername");
+    assert_eq!(err.data["username"], "aosc");
+    assert_eq!(config.extra_users.len(), 2);
+
+    let err = set_config_inner(
+        &mut config,
+        "extra_users",
+        r#"[{"username":"Bob","password":"b","root_password":null,"full_name":null}]"#,
+    )
+    .unwrap_err();
+    assert_eq!(err.t, "InvalidUsername");
+    assert_eq!(err.data["username"], "Bob");
+
+    let err = set_config_inner(
+        &mut config,
+        "user",
+        r#"{"username":"-f","password":"a","root_password":null,"full_name":null}"#,
+    )
+    .unwrap_err();
+    assert_eq!(err.t, "InvalidUsername");
+    assert_eq!(config.user.as_ref().unwrap().username, "aosc");
+}
+
+#[test]
+fn test_set_config_duplicate_uid() {
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(
+        &mut config,
+        "user",
+        r#"{"username":"aosc","password":"anthon","root_password":null,"full_name":null,"uid":1500}"#,
+    )
+    .unwrap();
+
+    let err = set_config_inner(
+        &mut config,
+        "extra_users",
+        r#"[{"username":"alice","password":"a","root_password":null,"full_name":null,"uid":1500}]"#,
+    )
+    .unwrap_err();
+    assert_eq!(err.t, "DuplicateUid");
+    assert_eq!(err.data["uid"], 1500);
+
+    let err = set_config_inner(
+        &mut config,
+        "extra_users",
+        r#"[
+            {"username":"alice","password":"a","root_password":null,"full_name":null,"uid":1600},
+            {"username":"bob","password":"b","root_password":null,"full_name":null,"uid":1600}
+        ]"#,
+    )
+    .unwrap_err();
+    assert_eq!(err.t, "DuplicateUid");
+    assert!(config.extra_users.is_empty());
+}
+
+#[test]
+fn test_set_config_user_conflicts_with_extra_users() {
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(
+        &mut config,
+        "extra_users",
+        r#"[{"username":"alice","password":"a","root_password":null,"full_name":null,"uid":1600}]"#,
+    )
+    .unwrap();
+
+    // 后设置主用户时同样检查与额外用户的冲突
+    let err = set_config_inner(
+        &mut config,
+        "user",
+        r#"{"username":"alice","password":"anthon","root_password":null,"full_name":null}"#,
+    )
+    .unwrap_err();
+    assert_eq!(err.t, "DuplicateUsername");
+
+    let err = set_config_inner(
+        &mut config,
+        "user",
+        r#"{"username":"aosc","password":"anthon","root_password":null,"full_name":null,"uid":1600}"#,
+    )
+    .unwrap_err();
+    assert_eq!(err.t, "DuplicateUid");
+    assert!(config.user.is_none());
+}
+
+#[test]
+fn test_set_config_user_on_first_boot_conflicts_with_extra_users() {
+    let users = r#"[{"username":"alice","password":"a","root_password":null,"full_name":null}]"#;
+
+    let mut config = InstallConfigPrepare::default();
+    set_config_inner(&mut config, "user_on_first_boot", "1").unwrap();
+    let err = set_config_inner(&mut config, "extra_users", users).unwrap_err();
+    assert_eq!(err.t, "SetValue");
+    assert!(config.extra_users.is_empty());
+    // 清空额外用户不受影响
+    set_config_inner(&mut config, "extra_users", "[]").unwrap();
+
+    let mut config = InstallConfigPrepare::default();
+    set_config_inner(&mut config, "extra_users", users).unwrap();
+    let err = set_config_inner(&mut config, "user_on_first_boot", "true").unwrap_err();
+    assert_eq!(err.t, "SetValue");
+    assert!(!config.user_on_first_boot);
+}
+
+#[test]
+fn test_set_config_kernel_cmdline() {
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(
+        &mut config,
+        "kernel_cmdline",
+        r#"["i915.enable_psr=0", "amd_iommu=on"]"#,
+    )
+    .unwrap();
+    assert_eq!(config.kernel_cmdline, ["i915.enable_psr=0", "amd_iommu=on"]);
+
+    let err =
+        set_config_inner(&mut config, "kernel_cmdline", r#"["quiet", "a=$(reboot)"]"#).unwrap_err();
+    assert_eq!(err.t, "SetValue");
+    assert_eq!(config.kernel_cmdline, ["i915.enable_psr=0", "amd_iommu=on"]);
+
+    assert!(set_config_inner(&mut config, "kernel_cmdline", "quiet").is_err());
+
+    set_config_inner(&mut config, "kernel_cmdline", "[]").unwrap();
+    assert!(config.kernel_cmdline.is_empty());
+}
+
+#[test]
+fn test_set_config_extra_locales() {
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(
+        &mut config,
+        "extra_locales",
+        r#"["zh_CN.UTF-8", "ja_JP.UTF-8"]"#,
+    )
+    .unwrap();
+    assert_eq!(config.extra_locales, ["zh_CN.UTF-8", "ja_JP.UTF-8"]);
+
+    let err =
+        set_config_inner(&mut config, "extra_locales", r#"["zh_CN.UTF-8 UTF-8"]"#).unwrap_err();
+    assert_eq!(err.t, "SetValue");
+    assert_eq!(config.extra_locales, ["zh_CN.UTF-8", "ja_JP.UTF-8"]);
+}
+
+#[test]
+fn test_set_config_dry_run() {
+    use install::NotSetValue;
+
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(&mut config, "dry_run", "1").unwrap();
+    assert!(config.dry_run);
+    // 模拟安装不要求目标分区与安装源
+    assert!(!config
+        .missing_values()
+        .contains(&NotSetValue::TargetPartition));
+    assert!(!config.missing_values().contains(&NotSetValue::Download));
+
+    set_config_inner(&mut config, "dry_run", "false").unwrap();
+    assert!(!config.dry_run);
+    assert!(config
+        .missing_values()
+        .contains(&NotSetValue::TargetPartition));
+
+    assert!(set_config_inner(&mut config, "dry_run", "yes").is_err());
+    assert!(!config.dry_run);
+}
+
+#[test]
+fn test_set_config_locale() {
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(&mut config, "locale", "zh_CN.UTF-8").unwrap();
+    assert_eq!(config.locale.as_deref(), Some("zh_CN.UTF-8"));
+
+    let err = set_config_inner(&mut config, "locale", "en_US\nLC_ALL=C").unwrap_err();
+    assert_eq!(err.t, "SetValue");
+    assert_eq!(config.locale.as_deref(), Some("zh_CN.UTF-8"));
+}
+
+#[test]
+fn test_set_config_keymap() {
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(&mut config, "keymap", "us").unwrap();
+    assert_eq!(config.keymap.as_deref(), Some("us"));
+
+    let err = set_config_inner(&mut config, "keymap", "us\nFONT=x").unwrap_err();
+    assert_eq!(err.t, "SetValue");
+    assert_eq!(config.keymap.as_deref(), Some("us"));
+}
+
+#[test]
+fn test_set_config_zram_swap() {
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(&mut config, "swapfile", r#"{"Zram":{"size_mb":4096}}"#).unwrap();
+    assert_eq!(config.swapfile, SwapFile::Zram { size_mb: 4096 });
+
+    assert!(set_config_inner(&mut config, "swapfile", r#"{"Zram":{}}"#).is_err());
+}
+
+#[test]
+fn test_set_config_hibernation() {
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(&mut config, "hibernation", "1").unwrap();
+    assert_eq!(config.swapfile, SwapFile::Hibernate);
+
+    set_config_inner(&mut config, "hibernation", "0").unwrap();
+    assert_eq!(config.swapfile, SwapFile::Automatic);
+
+    // 关闭休眠不影响其他 swap 设置
+    set_config_inner(&mut config, "swapfile", r#"{"Custom": 1024}"#).unwrap();
+    set_config_inner(&mut config, "hibernation", "false").unwrap();
+    assert_eq!(config.swapfile, SwapFile::Custom(1024));
+
+    assert!(set_config_inner(&mut config, "hibernation", "yes").is_err());
+}
+
+#[test]
+fn test_set_config_hibernation_conflicts_with_swapfile() {
+    for swapfile in [
+        r#"{"Custom": 1024}"#,
+        r#"{"Zram": {"size_mb": 4096}}"#,
+        r#"{"Partition": 8589934592}"#,
+        r#""Disable""#,
+    ] {
+        let mut config = InstallConfigPrepare::default();
+        set_config_inner(&mut config, "swapfile", swapfile).unwrap();
+        let before = config.swapfile.clone();
+
+        let err = set_config_inner(&mut config, "hibernation", "1").unwrap_err();
+        assert_eq!(err.t, "SetValue");
+        assert_eq!(config.swapfile, before);
+    }
+}
+
+#[test]
+fn test_set_config_swap_partition() {
+    let mut config = InstallConfigPrepare::default();
+
+    set_config_inner(&mut config, "swapfile", r#"{"Partition": 8589934592}"#).unwrap();
+    assert_eq!(config.swapfile, SwapFile::Partition(8589934592));
+}
+
+#[test]
+fn test_get_auto_partition_progress_device_in_use() {
+    let server = DeploykitServer::default();
+
+    *server.auto_partition_progress.lock().unwrap() = AutoPartitionProgress::Finish {
+        res: Err(PartitionError::DeviceInUse {
+            path: "/dev/sda".to_string(),
+            mounted_points: vec![
+                "/run/media/live".to_string(),
+                "/dev/sda3 (swap)".to_string(),
+            ],
+        }),
+        backup: None,
+        removed_lvm: vec![],
+    };
+
+    let res = serde_json::from_str::<Value>(&server.get_auto_partition_progress()).unwrap();
+    assert_eq!(res["result"], "Error");
+    assert_eq!(res["data"]["t"], "AutoPartition");
+    assert_eq!(res["data"]["data"]["t"], "DeviceInUse");
+    assert_eq!(
+        res["data"]["data"]["data"]["mounted_points"],
+        json!(["/run/media/live", "/dev/sda3 (swap)"])
+    );
+}
+
+#[test]
+fn test_get_auto_partition_progress_encrypted() {
+    let server = DeploykitServer::default();
+
+    *server.auto_partition_progress.lock().unwrap() = AutoPartitionProgress::Finish {
+        res: Err(PartitionError::EncryptedPartitions {
+            path: "/dev/sda".to_string(),
+            partitions: vec!["/dev/sda3 (bitlocker)".to_string()],
+        }),
+        backup: None,
+        removed_lvm: vec![],
+    };
+
+    let res = serde_json::from_str::<Value>(&server.get_auto_partition_progress()).unwrap();
+    assert_eq!(res["data"]["data"]["t"], "EncryptedPartitions");
+    assert_eq!(
+        res["data"]["data"]["data"]["partitions"],
+        json!(["/dev/sda3 (bitlocker)"])
+    );
+}
+
+#[test]
+fn test_export_import_config() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+
+    let mut server = DeploykitServer::default();
+    set_config_inner(&mut server.config, "hostname", "aosc-test").unwrap();
+    set_config_inner(&mut server.config, "dry_run", "true").unwrap();
+
+    let res = serde_json::from_str::<Value>(&server.export_config(path.to_str().unwrap())).unwrap();
+    assert_eq!(res["result"], "Ok");
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let mut server = DeploykitServer::default();
+    let res = serde_json::from_str::<Value>(&server.import_config(path.to_str().unwrap())).unwrap();
+    assert_eq!(res["result"], "Ok");
+    assert_eq!(server.config.hostname.as_deref(), Some("aosc-test"));
+    assert!(server.config.dry_run);
+
+    std::fs::write(&path, "not json").unwrap();
+    let res = serde_json::from_str::<Value>(&server.import_config(path.to_str().unwrap())).unwrap();
+    assert_eq!(res["data"]["t"], "ImportConfig");
+    // 导入失败时保留原配置
+    assert_eq!(server.config.hostname.as_deref(), Some("aosc-test"));
+}
+
+#[test]
+fn test_export_config_existing_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    std::fs::write(&path, "").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+    export_config_inner(&InstallConfigPrepare::default(), &path).unwrap();
+
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn test_import_config_checks_values() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    let import = |config: &InstallConfigPrepare| {
+        std::fs::write(&path, serde_json::to_string(config).unwrap()).unwrap();
+        import_config_inner(&path)
+    };
+
+    let config = InstallConfigPrepare {
+        hostname: Some("aosc-test".to_string()),
+        ..Default::default()
+    };
+    assert!(import(&config).is_ok());
+
+    // 与 set_config 一样拒绝非法的取值
+    let config = InstallConfigPrepare {
+        hostname: Some("aosc test".to_string()),
+        ..Default::default()
+    };
+    assert!(import(&config).is_err());
+
+    let user = User {
+        username: "alice".to_string(),
+        password: "a".to_string(),
+        root_password: None,
+        full_name: None,
+        admin: true,
+        shell: None,
+        uid: Some(1000),
+    };
+    let config = InstallConfigPrepare {
+        user: Some(user.clone()),
+        extra_users: vec![User {
+            username: "bob".to_string(),
+            ..user
+        }],
+        ..Default::default()
+    };
+    assert_eq!(import(&config).unwrap_err().t, "DuplicateUid");
+
+    // 磁盘上已不存在的分区被清除，需重新选择
+    let config = InstallConfigPrepare::default();
+    *config.efi_partition.lock().unwrap() = Some(DkPartition {
+        path: Some(PathBuf::from("/dev/dk-nonexistent1")),
+        parent_path: Some(PathBuf::from("/dev/dk-nonexistent")),
+        fs_type: Some("vfat".to_string()),
+        size: 512 * 1024 * 1024,
+        part_number: Some(1),
+        ..Default::default()
+    });
+    let (config, cleared) = import(&config).unwrap();
+    assert_eq!(cleared, ["efi_partition"]);
+    assert!(config.efi_partition.lock().unwrap().is_none());
+}
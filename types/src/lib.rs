@@ -0,0 +1,128 @@
+//! Typed return values of the `io.aosc.Deploykit1` D-Bus interface
+//!
+//! All types are encoded as `a{sv}` dictionaries, so fields can be added later without
+//! breaking existing clients. Every dictionary carries a `version` field, bump
+//! [`TYPES_VERSION`] on incompatible changes.
+
+use zvariant::{DeserializeDict, SerializeDict, Type};
+
+pub const TYPES_VERSION: u32 = 1;
+
+/// Return value of `get_progress2`
+#[derive(Debug, Clone, PartialEq, Eq, SerializeDict, DeserializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct Progress {
+    pub version: u32,
+    /// One of `Pending`, `Working`, `Error`, `Finish` and `Prepared`
+    pub status: String,
+    /// Only set when `status` is `Working`
+    pub step: Option<u8>,
+    pub progress: Option<u8>,
+    /// Bytes per second of downloading or extracting
+    pub velocity: Option<u64>,
+    /// Estimated remaining seconds of the current step, 0 if unknown
+    pub eta: Option<u64>,
+    /// Weighted overall progress (0..=100) of all steps
+    pub overall: Option<u8>,
+    /// Only set when `status` is `Error`
+    pub error_type: Option<String>,
+    pub error_message: Option<String>,
+    /// Only set when `status` is `Prepared`, where the target system is mounted
+    pub mount_path: Option<String>,
+    /// Only set when `status` is `Finish` and the bootloader installation is skipped,
+    /// paths are relative to the root partition of the installed system
+    pub boot_kernel: Option<String>,
+    pub boot_initrd: Option<String>,
+    pub boot_cmdline: Option<String>,
+}
+
+impl Progress {
+    pub fn pending() -> Self {
+        Self::with_status("Pending")
+    }
+
+    pub fn finish() -> Self {
+        Self::with_status("Finish")
+    }
+
+    pub fn finish_with_boot_stub(kernel: String, initrd: Option<String>, cmdline: String) -> Self {
+        Self {
+            boot_kernel: Some(kernel),
+            boot_initrd: initrd,
+            boot_cmdline: Some(cmdline),
+            ..Self::with_status("Finish")
+        }
+    }
+
+    pub fn working(step: u8, progress: u8, velocity: u64, eta: u64, overall: u8) -> Self {
+        Self {
+            step: Some(step),
+            progress: Some(progress),
+            velocity: Some(velocity),
+            eta: Some(eta),
+            overall: Some(overall),
+            ..Self::with_status("Working")
+        }
+    }
+
+    pub fn prepared(mount_path: String) -> Self {
+        Self {
+            mount_path: Some(mount_path),
+            ..Self::with_status("Prepared")
+        }
+    }
+
+    pub fn error(t: String, message: String) -> Self {
+        Self {
+            error_type: Some(t),
+            error_message: Some(message),
+            ..Self::with_status("Error")
+        }
+    }
+
+    fn with_status(status: &str) -> Self {
+        Self {
+            version: TYPES_VERSION,
+            status: status.to_string(),
+            step: None,
+            progress: None,
+            velocity: None,
+            eta: None,
+            overall: None,
+            error_type: None,
+            error_message: None,
+            mount_path: None,
+            boot_kernel: None,
+            boot_initrd: None,
+            boot_cmdline: None,
+        }
+    }
+}
+
+/// Element of the return value of `get_list_devices2`
+#[derive(Debug, Clone, PartialEq, Eq, SerializeDict, DeserializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct Device {
+    pub version: u32,
+    pub path: String,
+    pub model: String,
+    pub size: u64,
+}
+
+/// Element of the return value of `get_list_partitions2`
+#[derive(Debug, Clone, PartialEq, Eq, SerializeDict, DeserializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct Partition {
+    pub version: u32,
+    pub path: Option<String>,
+    pub parent_path: Option<String>,
+    pub fs_type: Option<String>,
+    pub size: u64,
+}
+
+#[test]
+fn test_signatures() {
+    assert_eq!(Progress::SIGNATURE, "a{sv}");
+    assert_eq!(<Vec<Device>>::SIGNATURE, "aa{sv}");
+    assert_eq!(<Vec<Partition>>::SIGNATURE, "aa{sv}");
+}